@@ -23,6 +23,8 @@
             lib.fileset.unions [
               ./Cargo.toml
               ./Cargo.lock
+              # Parser regression tests, and the captured sensor data they use
+              ./tests
               (lib.fileset.intersection
                 (lib.fileset.fileFilter (x: x.hasExt "rs") ./src)
                 (lib.fileset.gitTracked ./.)
@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+
+// To start riding you need to actually be pedaling, not just bumping a crank.
+const ACTIVE_CADENCE: u8 = 20;
+
+// Once riding, we're only idle after this long without pedaling at all, so a
+// single slow or dropped reading doesn't flip us back and forth.
+const IDLE_AFTER: Duration = Duration::from_secs(5);
+
+// Shared answer to "am I riding?" so that every feature that cares (display,
+// auto-pause, idle screens) doesn't need to re-derive it.  Cadence is the
+// cleanest source, so crank data from any device is fed in here.
+#[derive(Clone, Copy, Debug)]
+pub struct ActivityState {
+    last_pedaling: Option<Instant>,
+}
+
+impl ActivityState {
+    pub fn new() -> ActivityState {
+        ActivityState {
+            last_pedaling: None,
+        }
+    }
+
+    pub fn update_cadence(&mut self, cadence: u8, now: Instant) {
+        if cadence > ACTIVE_CADENCE || (cadence > 0 && self.is_active(now)) {
+            self.last_pedaling = Some(now);
+        }
+    }
+
+    pub fn is_active(&self, now: Instant) -> bool {
+        self.last_pedaling
+            .map_or(false, |t| now.saturating_duration_since(t) < IDLE_AFTER)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ActivityState;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn idle_until_pedaling() {
+        let now = Instant::now();
+        let mut activity = ActivityState::new();
+        assert!(!activity.is_active(now));
+        activity.update_cadence(0, now);
+        assert!(!activity.is_active(now));
+        activity.update_cadence(90, now);
+        assert!(activity.is_active(now));
+    }
+
+    #[test]
+    fn slow_cadence_does_not_start_activity() {
+        let now = Instant::now();
+        let mut activity = ActivityState::new();
+        activity.update_cadence(10, now);
+        assert!(!activity.is_active(now));
+    }
+
+    #[test]
+    fn slow_cadence_keeps_activity() {
+        let now = Instant::now();
+        let mut activity = ActivityState::new();
+        activity.update_cadence(90, now);
+        activity.update_cadence(10, now + Duration::from_secs(4));
+        assert!(activity.is_active(now + Duration::from_secs(8)));
+    }
+
+    #[test]
+    fn idle_after_no_pedaling() {
+        let now = Instant::now();
+        let mut activity = ActivityState::new();
+        activity.update_cadence(90, now);
+        activity.update_cadence(0, now + Duration::from_secs(1));
+        assert!(activity.is_active(now + Duration::from_secs(4)));
+        assert!(!activity.is_active(now + Duration::from_secs(5)));
+    }
+}
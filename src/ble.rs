@@ -1,4 +1,38 @@
+pub mod csc_feature;
 pub mod csc_measurement;
 pub mod cycling_power_measurement;
+pub mod device_information;
 pub mod heart_rate_measurement;
+pub mod indoor_bike_data;
 pub mod revolution_data;
+pub mod rsc_measurement;
+
+// Why a notification couldn't be parsed.  Peripherals on a flaky connection
+// occasionally send a notification that's cut short.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    // The flags call for more bytes than were sent
+    TooShort { needed: usize, got: usize },
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseError::TooShort { needed, got } => {
+                write!(f, "needed {} bytes but got {}", needed, got)
+            }
+        }
+    }
+}
+
+// Fails unless data has at least needed bytes
+pub fn check_len(data: &[u8], needed: usize) -> Result<(), ParseError> {
+    if data.len() < needed {
+        Err(ParseError::TooShort {
+            needed,
+            got: data.len(),
+        })
+    } else {
+        Ok(())
+    }
+}
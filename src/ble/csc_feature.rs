@@ -0,0 +1,92 @@
+use btleplug::api::bleuuid::uuid_from_u16;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const FEATURE_UUID: Uuid = uuid_from_u16(0x2A5C);
+
+// What a CSC sensor is capable of measuring.  Unlike the flags on each
+// measurement, this tells us what the sensor supports at all, so a combined
+// sensor can be told apart from two individual ones.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CscFeature {
+    pub wheel_revolution_data: bool,
+    pub crank_revolution_data: bool,
+    pub multiple_sensor_locations: bool,
+}
+
+impl CscFeature {
+    // Combines the features of all sensors, for when we can't tell which
+    // sensor a measurement came from.
+    pub fn union(&self, other: &CscFeature) -> CscFeature {
+        CscFeature {
+            wheel_revolution_data: self.wheel_revolution_data || other.wheel_revolution_data,
+            crank_revolution_data: self.crank_revolution_data || other.crank_revolution_data,
+            multiple_sensor_locations: self.multiple_sensor_locations
+                || other.multiple_sensor_locations,
+        }
+    }
+}
+
+// Notably, this function always assumes a valid input
+pub fn parse_csc_feature(data: &Vec<u8>) -> CscFeature {
+    let flags = u16::from_le_bytes([data[0], data[1]]);
+    CscFeature {
+        wheel_revolution_data: flags & 1 == 1,
+        crank_revolution_data: flags & 0b10 == 0b10,
+        multiple_sensor_locations: flags & 0b100 == 0b100,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_csc_feature;
+    use super::CscFeature;
+
+    #[test]
+    fn parse_csc_feature_wheel_only() {
+        assert_eq!(
+            CscFeature {
+                wheel_revolution_data: true,
+                crank_revolution_data: false,
+                multiple_sensor_locations: false,
+            },
+            parse_csc_feature(&vec!(1, 0))
+        );
+    }
+
+    #[test]
+    fn parse_csc_feature_crank_only() {
+        assert_eq!(
+            CscFeature {
+                wheel_revolution_data: false,
+                crank_revolution_data: true,
+                multiple_sensor_locations: false,
+            },
+            parse_csc_feature(&vec!(2, 0))
+        );
+    }
+
+    #[test]
+    fn parse_csc_feature_combined() {
+        assert_eq!(
+            CscFeature {
+                wheel_revolution_data: true,
+                crank_revolution_data: true,
+                multiple_sensor_locations: true,
+            },
+            parse_csc_feature(&vec!(7, 0))
+        );
+    }
+
+    #[test]
+    fn parse_csc_feature_ignores_reserved_bits() {
+        assert_eq!(
+            CscFeature {
+                wheel_revolution_data: false,
+                crank_revolution_data: true,
+                multiple_sensor_locations: false,
+            },
+            parse_csc_feature(&vec!(0xFA, 0xFF))
+        );
+    }
+}
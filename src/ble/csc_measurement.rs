@@ -1,5 +1,6 @@
 use crate::ble::revolution_data;
 use crate::ble::revolution_data::RevolutionData;
+use crate::ble::{check_len, ParseError};
 use uuid::Uuid;
 use btleplug::api::bleuuid::uuid_from_u16;
 
@@ -15,7 +16,7 @@ pub struct CscMeasurement {
 }
 
 // Notably, this function always assumes a valid input
-pub fn parse_csc_measurement(data: &Vec<u8>) -> CscMeasurement {
+pub fn parse_csc_measurement(data: &[u8]) -> CscMeasurement {
     let has_wheel_data = data[0] & 1 == 1;
     let has_crank_data = data[0] & 0b10 == 0b10;
     let wheel_index = 1;
@@ -59,6 +60,19 @@ pub fn parse_csc_measurement(data: &Vec<u8>) -> CscMeasurement {
     }
 }
 
+// As parse_csc_measurement, but first checks that the notification is long
+// enough for the flags it sets.
+pub fn try_parse_csc_measurement(data: &[u8]) -> Result<CscMeasurement, ParseError> {
+    check_len(data, 1)?;
+    let has_wheel_data = data[0] & 1 == 1;
+    let has_crank_data = data[0] & 0b10 == 0b10;
+    check_len(
+        data,
+        1 + if has_wheel_data { 6 } else { 0 } + if has_crank_data { 4 } else { 0 },
+    )?;
+    Ok(parse_csc_measurement(data))
+}
+
 pub fn checked_wheel_rpm_and_new_count(
     a: Option<&CscMeasurement>,
     b: &CscMeasurement,
@@ -86,8 +100,33 @@ pub fn checked_crank_rpm_and_new_count(
 #[cfg(test)]
 mod tests {
     use super::parse_csc_measurement;
+    use super::try_parse_csc_measurement;
     use super::CscMeasurement;
     use super::RevolutionData;
+    use crate::ble::ParseError;
+
+    #[test]
+    fn try_parse_csc_rejects_every_truncation() {
+        let data = vec![3, 1, 2, 3, 4, 1, 2, 1, 2, 1, 2];
+        for len in 0..data.len() {
+            assert_eq!(
+                Err(ParseError::TooShort {
+                    needed: if len == 0 { 1 } else { 11 },
+                    got: len
+                }),
+                try_parse_csc_measurement(&data[..len])
+            );
+        }
+        assert_eq!(Ok(parse_csc_measurement(&data)), try_parse_csc_measurement(&data));
+    }
+
+    #[test]
+    fn try_parse_csc_only_needs_the_flagged_fields() {
+        assert!(try_parse_csc_measurement(&[0]).is_ok());
+        assert!(try_parse_csc_measurement(&[1, 1, 2, 3, 4, 1, 2]).is_ok());
+        assert!(try_parse_csc_measurement(&[2, 1, 2, 1, 2]).is_ok());
+        assert!(try_parse_csc_measurement(&[2, 1, 2, 1]).is_err());
+    }
 
     #[test]
     fn parse_csc_with_wheel_and_crank() {
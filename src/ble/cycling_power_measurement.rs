@@ -1,5 +1,12 @@
 use crate::ble::revolution_data;
 use crate::ble::revolution_data::RevolutionData;
+use crate::ble::{check_len, ParseError};
+use btleplug::api::bleuuid::uuid_from_u16;
+use uuid::Uuid;
+
+// The standard characteristic, so any power meter (pedals, trainer or
+// crank) reports through it, whichever peripheral module connected it.
+pub const MEASURE_UUID: Uuid = uuid_from_u16(0x2A63);
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum AccumulatedTorqueSource {
@@ -21,7 +28,15 @@ pub struct CyclingPowerMeasurement {
     pub accumulated_torque: Option<(AccumulatedTorqueSource, f64)>,
     pub wheel_revolution_data: Option<RevolutionData>,
     pub crank_revolution_data: Option<RevolutionData>,
-    // TODO: There are other fields, but they're all after these or in the flags
+    // The strongest and weakest torque over the last revolution (in that
+    // order), in 1/32 Nm.
+    pub extreme_torque: Option<(i16, i16)>,
+    // Where in the pedal stroke the rider stops pushing (top) and pulling
+    // (bottom), in degrees from the crank pointing straight up.
+    pub top_dead_spot_angle: Option<u16>,
+    pub bottom_dead_spot_angle: Option<u16>,
+    // TODO: There are other fields (extreme force and angles, and accumulated
+    // energy), but they're only skipped over for now.
 }
 
 impl CyclingPowerMeasurement {
@@ -34,18 +49,59 @@ impl CyclingPowerMeasurement {
     }
 }
 
+// Power meters occasionally emit an absurd reading (often as they drop out),
+// which would wreck averages and maximums.  Readings over the ceiling are
+// rejected, and the last plausible reading is held in their place.  This is
+// applied as soon as a measurement is parsed, so everything after (the
+// display, live graphs and every export) sees the same power.
+#[derive(Debug, Clone)]
+pub struct PowerFilter {
+    max_power: i16,
+    last_power: i16,
+}
+
+impl PowerFilter {
+    pub fn new(max_power: i16) -> PowerFilter {
+        PowerFilter {
+            max_power,
+            last_power: 0,
+        }
+    }
+
+    pub fn filter(&mut self, power: i16) -> i16 {
+        if power <= self.max_power {
+            self.last_power = power;
+        }
+        self.last_power
+    }
+}
+
 // Notably, this function always assumes a valid input
-pub fn parse_cycling_power_measurement(data: &Vec<u8>) -> CyclingPowerMeasurement {
-    let has_pedal_power_balance = data[0] & 1 == 1;
-    let has_accumulated_torque = data[0] & 0b100 == 0b100;
-    let has_wheel_data = data[0] & 0b10000 == 0b10000;
-    let has_crank_data = data[0] & 0b100000 == 0b100000;
+pub fn parse_cycling_power_measurement(data: &[u8]) -> CyclingPowerMeasurement {
+    let flags = u16::from_le_bytes([data[0], data[1]]);
+    let has_pedal_power_balance = flags & 1 == 1;
+    let has_accumulated_torque = flags & 0b100 == 0b100;
+    let has_wheel_data = flags & 0b10000 == 0b10000;
+    let has_crank_data = flags & 0b100000 == 0b100000;
+    let has_extreme_force = flags & 0b1000000 == 0b1000000;
+    let has_extreme_torque = flags & 0b10000000 == 0b10000000;
+    let has_extreme_angles = flags & 0b100000000 == 0b100000000;
+    let has_top_dead_spot = flags & 0b1000000000 == 0b1000000000;
+    let has_bottom_dead_spot = flags & 0b10000000000 == 0b10000000000;
     let power_index = 2;
     let pedal_power_balance_index = 4;
     let accumulated_torque_index =
         pedal_power_balance_index + if has_pedal_power_balance { 1 } else { 0 };
     let wheel_data_index = accumulated_torque_index + if has_accumulated_torque { 2 } else { 0 };
     let crank_data_index = wheel_data_index + if has_wheel_data { 6 } else { 0 };
+    let extreme_force_index = crank_data_index + if has_crank_data { 4 } else { 0 };
+    let extreme_torque_index = extreme_force_index + if has_extreme_force { 4 } else { 0 };
+    // Both extreme angles are packed as 12 bits each into 3 bytes
+    let extreme_angles_index = extreme_torque_index + if has_extreme_torque { 4 } else { 0 };
+    let top_dead_spot_index = extreme_angles_index + if has_extreme_angles { 3 } else { 0 };
+    let bottom_dead_spot_index = top_dead_spot_index + if has_top_dead_spot { 2 } else { 0 };
+    let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+    let i16_at = |i: usize| i16::from_le_bytes([data[i], data[i + 1]]);
 
     CyclingPowerMeasurement {
         instantaneous_power: i16::from_le_bytes([data[power_index], data[power_index + 1]]),
@@ -55,7 +111,7 @@ pub fn parse_cycling_power_measurement(data: &Vec<u8>) -> CyclingPowerMeasuremen
             None
         },
         accumulated_torque: if has_accumulated_torque {
-            let source = if data[0] & 0b1000 == 0b1000 {
+            let source = if flags & 0b1000 == 0b1000 {
                 AccumulatedTorqueSource::Crank
             } else {
                 AccumulatedTorqueSource::Wheel
@@ -105,9 +161,52 @@ pub fn parse_cycling_power_measurement(data: &Vec<u8>) -> CyclingPowerMeasuremen
         } else {
             None
         },
+        extreme_torque: if has_extreme_torque {
+            Some((i16_at(extreme_torque_index), i16_at(extreme_torque_index + 2)))
+        } else {
+            None
+        },
+        top_dead_spot_angle: if has_top_dead_spot {
+            Some(u16_at(top_dead_spot_index))
+        } else {
+            None
+        },
+        bottom_dead_spot_angle: if has_bottom_dead_spot {
+            Some(u16_at(bottom_dead_spot_index))
+        } else {
+            None
+        },
     }
 }
 
+// As parse_cycling_power_measurement, but first checks that the notification
+// is long enough for the flags it sets.
+pub fn try_parse_cycling_power_measurement(
+    data: &[u8],
+) -> Result<CyclingPowerMeasurement, ParseError> {
+    check_len(data, 2)?;
+    let flags = u16::from_le_bytes([data[0], data[1]]);
+    // The size of each optional field, by the flag bit that says it's present
+    let sizes = [
+        (0, 1),
+        (2, 2),
+        (4, 6),
+        (5, 4),
+        (6, 4),
+        (7, 4),
+        (8, 3),
+        (9, 2),
+        (10, 2),
+        (11, 2),
+    ];
+    let needed = sizes
+        .iter()
+        .filter(|(bit, _)| flags & (1 << bit) != 0)
+        .fold(4, |len, (_, size)| len + size);
+    check_len(data, needed)?;
+    Ok(parse_cycling_power_measurement(data))
+}
+
 pub fn checked_wheel_rpm_and_new_count(
     a: Option<&CyclingPowerMeasurement>,
     b: &CyclingPowerMeasurement,
@@ -137,8 +236,59 @@ mod tests {
     use super::RevolutionData;
 
     use super::parse_cycling_power_measurement;
+    use super::try_parse_cycling_power_measurement;
     use super::AccumulatedTorqueSource;
     use super::CyclingPowerMeasurement;
+    use super::PowerFilter;
+    use crate::ble::ParseError;
+
+    #[test]
+    fn try_parse_cpm_rejects_every_truncation() {
+        let data = vec![
+            0b11110101, 0b111, 2, 1, 99, 1, 2, 1, 2, 3, 4, 1, 2, 1, 2, 1, 2, 9, 9, 9, 9, 5, 6,
+            0xfe, 0xff, 7, 7, 7, 15, 0, 195, 0,
+        ];
+        for len in 0..data.len() {
+            let needed = if len < 2 { 2 } else { data.len() };
+            assert_eq!(
+                Err(ParseError::TooShort { needed, got: len }),
+                try_parse_cycling_power_measurement(&data[..len])
+            );
+        }
+        assert_eq!(
+            Ok(parse_cycling_power_measurement(&data)),
+            try_parse_cycling_power_measurement(&data)
+        );
+    }
+
+    #[test]
+    fn try_parse_cpm_counts_accumulated_energy() {
+        assert!(try_parse_cycling_power_measurement(&[0, 0b1000, 2, 1, 1]).is_err());
+        assert!(try_parse_cycling_power_measurement(&[0, 0b1000, 2, 1, 1, 0]).is_ok());
+    }
+
+    #[test]
+    fn try_parse_cpm_accepts_the_simplest() {
+        assert!(try_parse_cycling_power_measurement(&[0, 0, 2, 1]).is_ok());
+        assert!(try_parse_cycling_power_measurement(&[0, 0, 2]).is_err());
+    }
+
+    #[test]
+    fn power_filter_passes_plausible_power() {
+        let mut filter = PowerFilter::new(1500);
+        assert_eq!(200, filter.filter(200));
+        assert_eq!(1500, filter.filter(1500));
+        assert_eq!(0, filter.filter(0));
+    }
+
+    #[test]
+    fn power_filter_holds_previous_power_over_a_spike() {
+        let mut filter = PowerFilter::new(1500);
+        assert_eq!(0, PowerFilter::new(1500).filter(2000));
+        assert_eq!(250, filter.filter(250));
+        assert_eq!(250, filter.filter(2000));
+        assert_eq!(260, filter.filter(260));
+    }
 
     #[test]
     fn parse_cpm_with_balance_torque_wheel_and_crank() {
@@ -155,6 +305,9 @@ mod tests {
                     revolution_count: 0x0201,
                     last_revolution_event_time: 0x0201 as f64 / 1024.0,
                 }),
+                extreme_torque: None,
+                top_dead_spot_angle: None,
+                bottom_dead_spot_angle: None,
             },
             parse_cycling_power_measurement(&vec!(
                 0b110101, 0, 2, 1, 99, 1, 2, 1, 2, 3, 4, 1, 2, 1, 2, 1, 2
@@ -162,6 +315,39 @@ mod tests {
         );
     }
 
+    // Laid out like the Assioma pedals send it, with every field that can
+    // come between the crank data and the dead spots.
+    #[test]
+    fn parse_cpm_with_crank_extremes_and_dead_spots() {
+        assert_eq!(
+            CyclingPowerMeasurement {
+                instantaneous_power: 0x0102,
+                pedal_power_balance_percent: Some(49.5),
+                accumulated_torque: None,
+                wheel_revolution_data: None,
+                crank_revolution_data: Some(RevolutionData {
+                    revolution_count: 0x0201,
+                    last_revolution_event_time: 0x0403 as f64 / 1024.0,
+                }),
+                extreme_torque: Some((0x0605, -2)),
+                top_dead_spot_angle: Some(15),
+                bottom_dead_spot_angle: Some(195),
+            },
+            parse_cycling_power_measurement(&vec!(
+                0b11100001, 0b111, 2, 1, 99, 1, 2, 3, 4, 9, 9, 9, 9, 5, 6, 0xfe, 0xff, 7, 7,
+                7, 15, 0, 195, 0
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_cpm_with_only_a_bottom_dead_spot() {
+        let measure = parse_cycling_power_measurement(&vec!(0, 0b100, 2, 1, 195, 0));
+        assert_eq!(0x0102, measure.instantaneous_power);
+        assert_eq!(None, measure.top_dead_spot_angle);
+        assert_eq!(Some(195), measure.bottom_dead_spot_angle);
+    }
+
     #[test]
     fn parse_cpm_with_accumulated_crank_torque() {
         assert_eq!(
@@ -174,6 +360,9 @@ mod tests {
                     revolution_count: 0x0201,
                     last_revolution_event_time: 0x0201 as f64 / 1024.0,
                 }),
+                extreme_torque: None,
+                top_dead_spot_angle: None,
+                bottom_dead_spot_angle: None,
             },
             parse_cycling_power_measurement(&vec!(0b101100, 0, 2, 1, 1, 2, 1, 2, 1, 2))
         );
@@ -191,6 +380,9 @@ mod tests {
                     last_revolution_event_time: 0x0201 as f64 / 2048.0,
                 }),
                 crank_revolution_data: None,
+                extreme_torque: None,
+                top_dead_spot_angle: None,
+                bottom_dead_spot_angle: None,
             },
             parse_cycling_power_measurement(&vec!(0b10100, 0, 2, 1, 1, 2, 1, 2, 3, 4, 1, 2))
         );
@@ -205,6 +397,9 @@ mod tests {
                 accumulated_torque: None,
                 wheel_revolution_data: None,
                 crank_revolution_data: None,
+                extreme_torque: None,
+                top_dead_spot_angle: None,
+                bottom_dead_spot_angle: None,
             },
             parse_cycling_power_measurement(&vec!(1, 0, 2, 1, 99))
         );
@@ -219,6 +414,9 @@ mod tests {
                 accumulated_torque: None,
                 wheel_revolution_data: None,
                 crank_revolution_data: None,
+                extreme_torque: None,
+                top_dead_spot_angle: None,
+                bottom_dead_spot_angle: None,
             },
             parse_cycling_power_measurement(&vec!(0, 0, 2, 1))
         );
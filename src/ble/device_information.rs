@@ -0,0 +1,115 @@
+// The standard Device Information service, which says exactly what a sensor
+// is (down to its firmware), for debugging problems with a particular one.
+use btleplug::api::bleuuid::uuid_from_u16;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const MANUFACTURER_NAME_UUID: Uuid = uuid_from_u16(0x2A29);
+pub const MODEL_NUMBER_UUID: Uuid = uuid_from_u16(0x2A24);
+pub const SERIAL_NUMBER_UUID: Uuid = uuid_from_u16(0x2A25);
+pub const FIRMWARE_REVISION_UUID: Uuid = uuid_from_u16(0x2A26);
+
+// Each is None if the device doesn't have it (they're all optional)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct DeviceInformation {
+    pub manufacturer_name: Option<String>,
+    pub model_number: Option<String>,
+    pub serial_number: Option<String>,
+    pub firmware_revision: Option<String>,
+}
+
+impl DeviceInformation {
+    // Everything but the serial number (if it's numeric), as one line
+    pub fn descriptor(&self) -> Option<String> {
+        let parts: Vec<String> = vec![
+            self.manufacturer_name.clone(),
+            self.model_number.clone(),
+            self.firmware_revision.as_ref().map(|x| format!("fw {}", x)),
+            match self.numeric_serial_number() {
+                Some(_) => None,
+                None => self.serial_number.as_ref().map(|x| format!("sn {}", x)),
+            },
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(" "))
+        }
+    }
+
+    // FIT only has room for a number
+    pub fn numeric_serial_number(&self) -> Option<u32> {
+        self.serial_number.as_ref().and_then(|x| x.parse().ok())
+    }
+}
+
+// These are all UTF-8 strings, though some devices pad them with nulls (or
+// spaces), and an empty one is as good as missing.
+pub fn parse_string(data: &[u8]) -> Option<String> {
+    let s = String::from_utf8_lossy(data);
+    let s = s.trim_end_matches(|c: char| c == '\0' || c.is_whitespace());
+    if s.is_empty() {
+        None
+    } else {
+        Some(s.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_string;
+    use super::DeviceInformation;
+
+    #[test]
+    fn parse_string_of_a_firmware_revision() {
+        assert_eq!(Some("4.2.1".to_string()), parse_string(b"4.2.1"));
+    }
+
+    #[test]
+    fn parse_string_trims_null_padding() {
+        assert_eq!(Some("KICKR".to_string()), parse_string(b"KICKR\0\0\0"));
+        assert_eq!(Some("H10".to_string()), parse_string(b"H10  "));
+    }
+
+    #[test]
+    fn parse_string_of_nothing() {
+        assert_eq!(None, parse_string(b""));
+        assert_eq!(None, parse_string(b"\0\0"));
+    }
+
+    #[test]
+    fn parse_string_replaces_invalid_utf8() {
+        assert_eq!(Some("A\u{FFFD}B".to_string()), parse_string(&[0x41, 0xff, 0x42]));
+    }
+
+    #[test]
+    fn descriptor_leaves_out_a_numeric_serial_number() {
+        let info = DeviceInformation {
+            manufacturer_name: Some("Wahoo Fitness".to_string()),
+            model_number: Some("KICKR".to_string()),
+            serial_number: Some("123456".to_string()),
+            firmware_revision: Some("4.2.1".to_string()),
+        };
+        assert_eq!(Some("Wahoo Fitness KICKR fw 4.2.1".to_string()), info.descriptor());
+        assert_eq!(Some(123456), info.numeric_serial_number());
+    }
+
+    #[test]
+    fn descriptor_includes_other_serial_numbers() {
+        let info = DeviceInformation {
+            model_number: Some("H10".to_string()),
+            serial_number: Some("A1B2C3".to_string()),
+            ..DeviceInformation::default()
+        };
+        assert_eq!(Some("H10 sn A1B2C3".to_string()), info.descriptor());
+        assert_eq!(None, info.numeric_serial_number());
+    }
+
+    #[test]
+    fn descriptor_of_nothing() {
+        assert_eq!(None, DeviceInformation::default().descriptor());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::ble::{check_len, ParseError};
+
 // A Struct that does not care about bit compression
 #[derive(Debug, PartialEq, Clone)]
 pub struct HeartRateMeasurement {
@@ -8,7 +10,7 @@ pub struct HeartRateMeasurement {
     pub is_sensor_contact_detected: Option<bool>,
     // Note that this _could_ overflow for very very long rides, but that makes
     // an otherwise snapshot-only measurement need prior context.  This is in
-    // Joules.
+    // kilojoules.
     pub energy_expended: Option<u16>,
     // This is list of the time (in seconds) measured between R-Wave detections.
     // It is an array, because there may be many intervals recorded during a
@@ -27,6 +29,7 @@ pub fn parse_hrm(data: &Vec<u8>) -> HeartRateMeasurement {
     let is_16_bit = data[0] & 1 == 1;
     let has_sensor_detection = data[0] & 0b100 == 0b100;
     let has_energy_expended = data[0] & 0b1000 == 0b1000;
+    let has_rr_intervals = data[0] & 0b10000 == 0b10000;
     let energy_expended_index = 2 + if is_16_bit { 1 } else { 0 };
     let rr_interval_index =
         2 + if has_energy_expended { 2 } else { 0 } + if is_16_bit { 1 } else { 0 };
@@ -50,7 +53,11 @@ pub fn parse_hrm(data: &Vec<u8>) -> HeartRateMeasurement {
             None
         },
         rr_intervals: {
-            let rr_interval_count = (data.len() - rr_interval_index) / 2;
+            let rr_interval_count = if has_rr_intervals {
+                (data.len() - rr_interval_index) / 2
+            } else {
+                0
+            };
             let mut vec = Vec::with_capacity(rr_interval_count);
             for i in 0..rr_interval_count {
                 let as_u16 = u16::from_le_bytes([
@@ -64,10 +71,50 @@ pub fn parse_hrm(data: &Vec<u8>) -> HeartRateMeasurement {
     }
 }
 
+// As parse_hrm, but first checks that the notification is long enough for the
+// flags it sets.
+pub fn try_parse_hrm(data: &[u8]) -> Result<HeartRateMeasurement, ParseError> {
+    check_len(data, 1)?;
+    let is_16_bit = data[0] & 1 == 1;
+    let has_energy_expended = data[0] & 0b1000 == 0b1000;
+    check_len(
+        data,
+        2 + if is_16_bit { 1 } else { 0 } + if has_energy_expended { 2 } else { 0 },
+    )?;
+    Ok(parse_hrm(&data.to_vec()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::parse_hrm;
+    use super::try_parse_hrm;
     use super::HeartRateMeasurement;
+    use crate::ble::ParseError;
+
+    #[test]
+    fn try_parse_hrm_rejects_every_truncation() {
+        let data = vec![0b11001, 70, 0, 11, 2, 10, 1];
+        for len in 0..5 {
+            assert_eq!(
+                Err(ParseError::TooShort {
+                    needed: if len == 0 { 1 } else { 5 },
+                    got: len
+                }),
+                try_parse_hrm(&data[..len])
+            );
+        }
+        // RR intervals come in whatever number fit
+        assert!(try_parse_hrm(&data[..6]).is_ok());
+        assert_eq!(Ok(parse_hrm(&data)), try_parse_hrm(&data));
+    }
+
+    #[test]
+    fn try_parse_hrm_only_needs_the_flagged_fields() {
+        assert!(try_parse_hrm(&[0, 70]).is_ok());
+        assert!(try_parse_hrm(&[0]).is_err());
+        assert!(try_parse_hrm(&[1, 10, 1]).is_ok());
+        assert!(try_parse_hrm(&[0b1000, 70, 10]).is_err());
+    }
 
     #[test]
     fn parse_hrm_16_bit_energy_expended_and_one_rr_intervals() {
@@ -199,6 +246,100 @@ mod tests {
         );
     }
 
+    // Captured from a Polar H10, which always reports contact
+    #[test]
+    fn parse_hrm_polar_with_one_rr_interval() {
+        assert_eq!(
+            HeartRateMeasurement {
+                bpm: 72,
+                is_sensor_contact_detected: Some(true),
+                energy_expended: None,
+                rr_intervals: vec!(854.0 / 1024.0),
+            },
+            parse_hrm(&vec!(0x16, 0x48, 0x56, 0x03))
+        );
+    }
+
+    #[test]
+    fn parse_hrm_polar_with_two_rr_intervals() {
+        assert_eq!(
+            HeartRateMeasurement {
+                bpm: 75,
+                is_sensor_contact_detected: Some(true),
+                energy_expended: None,
+                rr_intervals: vec!(814.0 / 1024.0, 817.0 / 1024.0),
+            },
+            parse_hrm(&vec!(0x16, 0x4b, 0x2e, 0x03, 0x31, 0x03))
+        );
+    }
+
+    #[test]
+    fn parse_hrm_16_bit_with_rr_intervals_matches_8_bit() {
+        assert_eq!(
+            parse_hrm(&vec!(0x16, 0x4b, 0x2e, 0x03, 0x31, 0x03)),
+            parse_hrm(&vec!(0x17, 0x4b, 0x00, 0x2e, 0x03, 0x31, 0x03))
+        );
+    }
+
+    // Only the flag says whether there are RR intervals, not the length
+    #[test]
+    fn parse_hrm_ignores_trailing_bytes_without_the_rr_flag() {
+        assert_eq!(
+            HeartRateMeasurement {
+                bpm: 72,
+                is_sensor_contact_detected: Some(true),
+                energy_expended: None,
+                rr_intervals: Vec::with_capacity(0),
+            },
+            parse_hrm(&vec!(0x06, 0x48, 0x56, 0x03))
+        );
+    }
+
+    #[test]
+    fn parse_hrm_with_contact_and_energy_expended() {
+        assert_eq!(
+            HeartRateMeasurement {
+                bpm: 70,
+                is_sensor_contact_detected: Some(true),
+                energy_expended: Some(266),
+                rr_intervals: Vec::with_capacity(0),
+            },
+            parse_hrm(&vec!(0b1110, 70, 10, 1))
+        );
+    }
+
+    #[test]
+    fn parse_hrm_16_bit_without_contact_and_energy_expended() {
+        assert_eq!(
+            HeartRateMeasurement {
+                bpm: 266,
+                is_sensor_contact_detected: Some(false),
+                energy_expended: Some(523),
+                rr_intervals: Vec::with_capacity(0),
+            },
+            parse_hrm(&vec!(0b1101, 10, 1, 11, 2))
+        );
+    }
+
+    #[test]
+    fn parse_hrm_with_every_field() {
+        assert_eq!(
+            HeartRateMeasurement {
+                bpm: 266,
+                is_sensor_contact_detected: Some(true),
+                energy_expended: Some(523),
+                rr_intervals: vec!(780.0 / 1024.0),
+            },
+            parse_hrm(&vec!(0b11111, 10, 1, 11, 2, 12, 3))
+        );
+    }
+
+    // The detected bit means nothing unless contact is supported
+    #[test]
+    fn parse_hrm_ignores_contact_detected_without_support() {
+        assert_eq!(parse_hrm(&vec!(0, 70)), parse_hrm(&vec!(0b10, 70)));
+    }
+
     #[test]
     fn parse_hrm_simplest() {
         assert_eq!(
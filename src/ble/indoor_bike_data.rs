@@ -0,0 +1,204 @@
+use crate::ble::{check_len, ParseError};
+
+// A Struct that does not care about bit compression.  Only the fields we use
+// are kept, though every field's offset is accounted for.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IndoorBikeData {
+    // In m/s (sent in 0.01 km/h)
+    pub instantaneous_speed: Option<f64>,
+    // In revolutions per minute (sent in 0.5 rpm)
+    pub instantaneous_cadence: Option<f64>,
+    // In watts
+    pub instantaneous_power: Option<i16>,
+    // In meters, since the trainer started counting
+    pub total_distance: Option<u32>,
+}
+
+// The size of each optional field, by the flag bit that says it's present.
+// Instantaneous speed is the exception, since its bit (0, "more data") is
+// set when it's _absent_.
+const FIELD_SIZES: [(u16, usize); 12] = [
+    // Average speed
+    (1, 2),
+    // Instantaneous cadence
+    (2, 2),
+    // Average cadence
+    (3, 2),
+    // Total distance
+    (4, 3),
+    // Resistance level
+    (5, 2),
+    // Instantaneous power
+    (6, 2),
+    // Average power
+    (7, 2),
+    // Expended energy (total, per hour and per minute)
+    (8, 5),
+    // Heart rate
+    (9, 1),
+    // Metabolic equivalent
+    (10, 1),
+    // Elapsed time
+    (11, 2),
+    // Remaining time
+    (12, 2),
+];
+
+// Where each field starts, given the flags, with the flags (and the speed
+// that may follow them) first, and the end of the data last.
+fn field_indexes(flags: u16) -> [usize; 14] {
+    let mut indexes = [0; 14];
+    indexes[0] = 2;
+    indexes[1] = 2 + if flags & 1 == 0 { 2 } else { 0 };
+    for (i, (bit, size)) in FIELD_SIZES.iter().enumerate() {
+        let present = flags & (1 << bit) != 0;
+        indexes[i + 2] = indexes[i + 1] + if present { *size } else { 0 };
+    }
+    indexes
+}
+
+// Notably, this function always assumes a valid input
+pub fn parse_indoor_bike_data(data: &[u8]) -> IndoorBikeData {
+    let flags = u16::from_le_bytes([data[0], data[1]]);
+    let has_speed = flags & 1 == 0;
+    let has_cadence = flags & 0b100 == 0b100;
+    let has_total_distance = flags & 0b10000 == 0b10000;
+    let has_power = flags & 0b1000000 == 0b1000000;
+    let indexes = field_indexes(flags);
+    let speed_index = indexes[0];
+    let cadence_index = indexes[2];
+    let total_distance_index = indexes[4];
+    let power_index = indexes[6];
+
+    IndoorBikeData {
+        instantaneous_speed: if has_speed {
+            let centi_kph = u16::from_le_bytes([data[speed_index], data[speed_index + 1]]);
+            Some(centi_kph as f64 / 360.0)
+        } else {
+            None
+        },
+        instantaneous_cadence: if has_cadence {
+            let half_rpm = u16::from_le_bytes([data[cadence_index], data[cadence_index + 1]]);
+            Some(half_rpm as f64 / 2.0)
+        } else {
+            None
+        },
+        instantaneous_power: if has_power {
+            Some(i16::from_le_bytes([data[power_index], data[power_index + 1]]))
+        } else {
+            None
+        },
+        total_distance: if has_total_distance {
+            Some(u32::from_le_bytes([
+                data[total_distance_index],
+                data[total_distance_index + 1],
+                data[total_distance_index + 2],
+                0,
+            ]))
+        } else {
+            None
+        },
+    }
+}
+
+// As parse_indoor_bike_data, but first checks that the notification is long
+// enough for the flags it sets.
+pub fn try_parse_indoor_bike_data(data: &[u8]) -> Result<IndoorBikeData, ParseError> {
+    check_len(data, 2)?;
+    let flags = u16::from_le_bytes([data[0], data[1]]);
+    check_len(data, field_indexes(flags)[13])?;
+    Ok(parse_indoor_bike_data(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_indoor_bike_data;
+    use super::try_parse_indoor_bike_data;
+    use super::IndoorBikeData;
+    use crate::ble::ParseError;
+
+    #[test]
+    fn parse_ibd_with_only_speed() {
+        assert_eq!(
+            IndoorBikeData {
+                instantaneous_speed: Some(3600.0 / 360.0),
+                instantaneous_cadence: None,
+                instantaneous_power: None,
+                total_distance: None,
+            },
+            parse_indoor_bike_data(&[0, 0, 0x10, 0x0e])
+        );
+    }
+
+    #[test]
+    fn parse_ibd_without_speed() {
+        assert_eq!(
+            IndoorBikeData {
+                instantaneous_speed: None,
+                instantaneous_cadence: None,
+                instantaneous_power: None,
+                total_distance: None,
+            },
+            parse_indoor_bike_data(&[1, 0])
+        );
+    }
+
+    #[test]
+    fn parse_ibd_with_speed_cadence_and_power() {
+        assert_eq!(
+            IndoorBikeData {
+                instantaneous_speed: Some(0x0201 as f64 / 360.0),
+                instantaneous_cadence: Some(180.0 / 2.0),
+                instantaneous_power: Some(250),
+                total_distance: None,
+            },
+            parse_indoor_bike_data(&[0b1000100, 0, 1, 2, 180, 0, 250, 0])
+        );
+    }
+
+    #[test]
+    fn parse_ibd_with_negative_power() {
+        let data = parse_indoor_bike_data(&[0b1000001, 0, 0xfb, 0xff]);
+        assert_eq!(Some(-5), data.instantaneous_power);
+    }
+
+    // Every field before power, so each of their sizes has to be right
+    #[test]
+    fn parse_ibd_with_every_field_before_power() {
+        assert_eq!(
+            IndoorBikeData {
+                instantaneous_speed: Some(0x0201 as f64 / 360.0),
+                instantaneous_cadence: Some(0x0403 as f64 / 2.0),
+                instantaneous_power: Some(0x0d0c),
+                total_distance: Some(0x090807),
+            },
+            parse_indoor_bike_data(&[
+                0b1111110, 0, 1, 2, 9, 9, 3, 4, 9, 9, 7, 8, 9, 9, 9, 0x0c, 0x0d
+            ])
+        );
+    }
+
+    // Fields after power don't move it, but do need to be there
+    #[test]
+    fn try_parse_ibd_counts_fields_after_power() {
+        let data = [0b11000001, 0b11111, 250, 0, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9];
+        assert_eq!(Some(250), try_parse_indoor_bike_data(&data).unwrap().instantaneous_power);
+        assert_eq!(
+            Err(ParseError::TooShort { needed: 17, got: 16 }),
+            try_parse_indoor_bike_data(&data[..16])
+        );
+    }
+
+    #[test]
+    fn try_parse_ibd_rejects_every_truncation() {
+        let data = [0b1010100, 0, 1, 2, 180, 0, 7, 8, 9, 250, 0];
+        for len in 0..data.len() {
+            let needed = if len < 2 { 2 } else { data.len() };
+            assert_eq!(
+                Err(ParseError::TooShort { needed, got: len }),
+                try_parse_indoor_bike_data(&data[..len])
+            );
+        }
+        assert_eq!(Ok(parse_indoor_bike_data(&data)), try_parse_indoor_bike_data(&data));
+    }
+}
@@ -0,0 +1,120 @@
+use btleplug::api::bleuuid::uuid_from_u16;
+use uuid::Uuid;
+
+pub const MEASURE_UUID: Uuid = uuid_from_u16(0x2A53);
+
+// A Struct that does not care about bit compression
+#[derive(Debug, PartialEq, Clone)]
+pub struct RscMeasurement {
+    // In meters/s
+    pub speed: f32,
+    // since this is not in SI, its units are defined in its name.
+    pub steps_per_minute: u8,
+    // In meters, if supported by the sensor
+    pub stride_length: Option<f32>,
+    // Cumulative distance in meters, if supported by the sensor
+    pub total_distance: Option<f32>,
+    // Walking otherwise
+    pub is_running: bool,
+}
+
+// Notably, this function always assumes a valid input
+pub fn parse_rsc_measurement(data: &Vec<u8>) -> RscMeasurement {
+    let has_stride_length = data[0] & 1 == 1;
+    let has_total_distance = data[0] & 0b10 == 0b10;
+    let stride_length_index = 4;
+    let total_distance_index = stride_length_index + if has_stride_length { 2 } else { 0 };
+
+    RscMeasurement {
+        // Resolution of 1/256 m/s
+        speed: u16::from_le_bytes([data[1], data[2]]) as f32 / 256.0,
+        steps_per_minute: data[3],
+        // Resolution of 1/100 m
+        stride_length: if has_stride_length {
+            Some(
+                u16::from_le_bytes([data[stride_length_index], data[stride_length_index + 1]])
+                    as f32
+                    / 100.0,
+            )
+        } else {
+            None
+        },
+        // Resolution of 1/10 m
+        total_distance: if has_total_distance {
+            Some(
+                u32::from_le_bytes([
+                    data[total_distance_index],
+                    data[total_distance_index + 1],
+                    data[total_distance_index + 2],
+                    data[total_distance_index + 3],
+                ]) as f32
+                    / 10.0,
+            )
+        } else {
+            None
+        },
+        is_running: data[0] & 0b100 == 0b100,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_rsc_measurement;
+    use super::RscMeasurement;
+
+    #[test]
+    fn parse_rsc_walking_without_optional_fields() {
+        assert_eq!(
+            RscMeasurement {
+                speed: 1.5,
+                steps_per_minute: 110,
+                stride_length: None,
+                total_distance: None,
+                is_running: false,
+            },
+            parse_rsc_measurement(&vec!(0, 0x80, 0x01, 110))
+        );
+    }
+
+    #[test]
+    fn parse_rsc_running_with_stride_length() {
+        assert_eq!(
+            RscMeasurement {
+                speed: 3.0,
+                steps_per_minute: 170,
+                stride_length: Some(1.05),
+                total_distance: None,
+                is_running: true,
+            },
+            parse_rsc_measurement(&vec!(0b101, 0x00, 0x03, 170, 105, 0))
+        );
+    }
+
+    #[test]
+    fn parse_rsc_running_with_total_distance() {
+        assert_eq!(
+            RscMeasurement {
+                speed: 3.0,
+                steps_per_minute: 170,
+                stride_length: None,
+                total_distance: Some(6553.8),
+                is_running: true,
+            },
+            parse_rsc_measurement(&vec!(0b110, 0x00, 0x03, 170, 0x02, 0x00, 0x01, 0x00))
+        );
+    }
+
+    #[test]
+    fn parse_rsc_running_with_stride_length_and_total_distance() {
+        assert_eq!(
+            RscMeasurement {
+                speed: 3.0,
+                steps_per_minute: 170,
+                stride_length: Some(1.05),
+                total_distance: Some(100.0),
+                is_running: true,
+            },
+            parse_rsc_measurement(&vec!(0b111, 0x00, 0x03, 170, 105, 0, 0xe8, 0x03, 0, 0))
+        );
+    }
+}
@@ -1,8 +1,12 @@
 // Port of the Pimomori button shim Python module
+use crate::power_mode::SharedIntervals;
 use rppal::i2c::I2c;
 use std::{
     mem,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     thread,
     thread::JoinHandle,
     time::{Duration, Instant},
@@ -12,6 +16,44 @@ const ADDR: u16 = 0x3f;
 const REG_INPUT: u8 = 0x00;
 const REG_CONFIG: u8 = 0x03;
 
+// A button has to read the same this many times in a row before it's
+// considered pressed or released, which filters out contact bounce and noise.
+// This delays every press and release by one extra poll (50ms, or 200ms in
+// low power mode), and holds and repeats are timed from the debounced press.
+const DEBOUNCE_READS: u8 = 2;
+
+// Tracks the (active low) button states as of the last reads that agreed
+struct Debouncer {
+    stable: u8,
+    // Per bit, how many reads in a row have disagreed with the stable state
+    pending: [u8; 8],
+}
+
+impl Debouncer {
+    fn new(initial: u8) -> Debouncer {
+        Debouncer {
+            stable: initial,
+            pending: [0; 8],
+        }
+    }
+
+    fn update(&mut self, raw: u8) -> u8 {
+        for i in 0..8 {
+            let bit = 1 << i;
+            if (raw ^ self.stable) & bit == 0 {
+                self.pending[i] = 0;
+            } else {
+                self.pending[i] += 1;
+                if self.pending[i] >= DEBOUNCE_READS {
+                    self.stable ^= bit;
+                    self.pending[i] = 0;
+                }
+            }
+        }
+        self.stable
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Button {
     ButtonA,
@@ -41,13 +83,13 @@ impl ButtonHandler {
 
 pub struct Buttons {
     join_handle: Option<JoinHandle<()>>,
-    running_mutex: Arc<Mutex<bool>>,
+    running: Arc<AtomicBool>,
     handlers_mutex: Arc<Mutex<Vec<ButtonHandler>>>,
 }
 
 // Need to doublecheck this whole 'static thing
 impl Buttons {
-    pub fn new() -> Buttons {
+    pub fn new(intervals: SharedIntervals) -> Buttons {
         // TODO: Make the buttons actually work in simulator mode
         #[cfg(not(feature = "simulator"))]
         let mut bus = I2c::with_bus(1).unwrap();
@@ -57,8 +99,24 @@ impl Buttons {
         #[cfg(not(feature = "simulator"))]
         bus.smbus_write_byte(REG_CONFIG, 0b00011111).unwrap();
 
+        #[cfg(not(feature = "simulator"))]
+        let read = move || bus.smbus_read_byte(REG_INPUT).unwrap();
+        // Nothing is ever pressed
+        #[cfg(feature = "simulator")]
+        let read = || 0b00011111;
+
+        Buttons::polling(intervals, read)
+    }
+
+    // Polls with the given read of the (active low) button states until
+    // dropped.
+    fn polling<R>(intervals: SharedIntervals, mut read: R) -> Buttons
+    where
+        R: FnMut() -> u8 + Send + 'static,
+    {
         let mut last_states = 0b00011111;
-        let running_mutex = Arc::new(Mutex::new(true));
+        let mut debouncer = Debouncer::new(last_states);
+        let running = Arc::new(AtomicBool::new(true));
         let handlers_mutex: Arc<Mutex<Vec<ButtonHandler>>> = Arc::new(Mutex::new(vec![
             ButtonHandler::new(),
             ButtonHandler::new(),
@@ -70,45 +128,45 @@ impl Buttons {
         // TODO: Handlers should really execute in a separate thread.  This is a bit more
         // challenging to do for FnMut handlers (because they're stateful).
         let handlers_mutex_thread = handlers_mutex.clone();
-        let running_mutex_thread = running_mutex.clone();
+        let running_thread = running.clone();
         let join_handle = Some(thread::spawn(move || {
-            #[cfg(not(feature = "simulator"))]
-            loop {
-                {
-                    if !*running_mutex_thread.lock().unwrap() {
-                        break;
-                    }
-                }
-                let states = bus.smbus_read_byte(REG_INPUT).unwrap();
+            while running_thread.load(Ordering::SeqCst) {
+                let states = debouncer.update(read());
 
                 let mut handlers = handlers_mutex_thread.lock().unwrap();
                 for i in 0..handlers.len() {
                     let last = (last_states >> i) & 1;
                     let curr = (states >> i) & 1;
-                    // TODO: It's worth reconsidering this behavior a little, in
-                    // that _everything_ that happens fires an event.  There's
-                    // no "smart" events that are exclusive like "click" that only
-                    // fires on a reasonably fast press and release that is not
-                    // a hold, or a hold that only fires once we're sure it's
-                    // not a repeat (that would invalide certain holds).
+                    // TODO: A hold still isn't exclusive of a repeat, so
+                    // holding a button with both fires each of them.
                     if let Some(handler) = handlers.get_mut(i) {
-                        // If last > curr then it's a transition from 1 to 0
-                        // since the buttons are active low, that's a press event
+                        // A button that can be held is only pressed once it's
+                        // released before the hold fires, so that holding it
+                        // doesn't press it too.  Otherwise, pressing is when
+                        // last > curr, a transition from 1 to 0 since the
+                        // buttons are active low.
+                        let is_pressed = match handler.hold.as_ref() {
+                            Some(hold) => last < curr && !hold.3,
+                            None => last > curr,
+                        };
+
                         if last > curr {
                             if let Some(hold) = handler.hold.as_mut() {
                                 hold.2 = Instant::now();
                                 hold.3 = false;
                             };
 
-                            if let Some(press) = handler.press.as_mut() {
-                                press();
-                            };
-
                             if let Some(repeat) = handler.repeat.as_mut() {
                                 repeat.2 = Instant::now();
                             };
                         }
 
+                        if is_pressed {
+                            if let Some(press) = handler.press.as_mut() {
+                                press();
+                            };
+                        }
+
                         if last < curr {
                             if let Some(release) = handler.release.as_mut() {
                                 release();
@@ -138,14 +196,15 @@ impl Buttons {
                 last_states = states;
 
                 // TODO: I believe this can be arbitrary...
-                thread::sleep(Duration::from_millis(50));
+                let button_poll = intervals.lock().unwrap().button_poll;
+                thread::sleep(button_poll);
             }
         }));
 
         Buttons {
             handlers_mutex,
             join_handle,
-            running_mutex,
+            running,
         }
     }
 
@@ -190,16 +249,120 @@ impl Buttons {
 
 impl Drop for Buttons {
     fn drop(&mut self) {
-        {
-            let mut running = self.running_mutex.lock().unwrap();
-            *running = false;
-        }
+        self.running.store(false, Ordering::SeqCst);
         if let Some(jh) = mem::replace(&mut self.join_handle, None) {
             jh.join().unwrap();
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{Button, Buttons, Debouncer};
+    use crate::power_mode;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    fn intervals() -> power_mode::SharedIntervals {
+        Arc::new(Mutex::new(power_mode::NORMAL))
+    }
+
+    #[test]
+    fn a_press_needs_two_reads() {
+        let mut debouncer = Debouncer::new(0b00011111);
+        assert_eq!(0b00011111, debouncer.update(0b00011110));
+        assert_eq!(0b00011110, debouncer.update(0b00011110));
+        assert_eq!(0b00011110, debouncer.update(0b00011110));
+    }
+
+    #[test]
+    fn a_release_needs_two_reads() {
+        let mut debouncer = Debouncer::new(0b00011110);
+        assert_eq!(0b00011110, debouncer.update(0b00011111));
+        assert_eq!(0b00011111, debouncer.update(0b00011111));
+    }
+
+    #[test]
+    fn a_single_bounce_is_ignored() {
+        let mut debouncer = Debouncer::new(0b00011111);
+        assert_eq!(0b00011111, debouncer.update(0b00011110));
+        assert_eq!(0b00011111, debouncer.update(0b00011111));
+        assert_eq!(0b00011111, debouncer.update(0b00011110));
+        assert_eq!(0b00011111, debouncer.update(0b00011111));
+    }
+
+    #[test]
+    fn buttons_are_debounced_independently() {
+        let mut debouncer = Debouncer::new(0b00011111);
+        assert_eq!(0b00011111, debouncer.update(0b00011110));
+        assert_eq!(0b00011110, debouncer.update(0b00001110));
+        assert_eq!(0b00001110, debouncer.update(0b00001111));
+        assert_eq!(0b00001111, debouncer.update(0b00001111));
+    }
+
+    #[test]
+    fn dropping_stops_polling_promptly() {
+        let start = Instant::now();
+        let buttons = Buttons::polling(intervals(), || 0b00011111);
+        drop(buttons);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn polled_presses_reach_handlers() {
+        let (tx, rx) = mpsc::channel();
+        let pressed = Arc::new(AtomicBool::new(false));
+        let pressed_for_read = pressed.clone();
+        let buttons = Buttons::polling(intervals(), move || {
+            if pressed_for_read.load(Ordering::SeqCst) {
+                0b00011110
+            } else {
+                0b00011111
+            }
+        });
+        buttons.on_press(Button::ButtonA, Box::new(move || tx.send(()).unwrap()));
+        pressed.store(true, Ordering::SeqCst);
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+        drop(buttons);
+    }
+
+    #[test]
+    fn a_button_that_can_be_held_presses_on_release_unless_held() {
+        let (tx, rx) = mpsc::channel();
+        let pressed = Arc::new(AtomicBool::new(false));
+        let pressed_for_read = pressed.clone();
+        let buttons = Buttons::polling(intervals(), move || {
+            if pressed_for_read.load(Ordering::SeqCst) {
+                0b00011110
+            } else {
+                0b00011111
+            }
+        });
+        let tx_press = tx.clone();
+        buttons.on_press(Button::ButtonA, Box::new(move || tx_press.send("press").unwrap()));
+        buttons.on_hold(
+            Button::ButtonA,
+            Duration::from_millis(500),
+            Box::new(move || tx.send("hold").unwrap()),
+        );
+
+        // A quick press only counts once it's let go
+        pressed.store(true, Ordering::SeqCst);
+        assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+        pressed.store(false, Ordering::SeqCst);
+        assert_eq!(Ok("press"), rx.recv_timeout(Duration::from_secs(1)));
+
+        // While a hold is never also a press
+        pressed.store(true, Ordering::SeqCst);
+        assert_eq!(Ok("hold"), rx.recv_timeout(Duration::from_secs(2)));
+        pressed.store(false, Ordering::SeqCst);
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+        drop(buttons);
+    }
+}
+
 /*
 
 TODO: Logic for controlling the LED
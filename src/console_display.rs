@@ -0,0 +1,98 @@
+// A stand in for the LCD and buttons when there's no screen, so a ride can be
+// run (and debugged) entirely over SSH.  Live metrics are redrawn in place on
+// a single line, while messages and menus are printed as they change.
+use crate::buttons::Button::{self, ButtonA, ButtonB, ButtonC, ButtonD, ButtonE};
+use std::io::{BufRead, Write};
+use std::sync::mpsc::Sender;
+use std::thread;
+
+pub struct ConsoleDisplay {
+    // What's printed above the status line, so repeated renders don't spam
+    last_printed: Option<String>,
+}
+
+impl ConsoleDisplay {
+    // Keys are read a line at a time (so follow them with enter), using the
+    // same mapping as the simulator: 1-5 press buttons E through A, and the
+    // shifted digits hold them.
+    pub fn new(tx: Sender<(Button, bool)>) -> ConsoleDisplay {
+        thread::spawn(move || {
+            let stdin = std::io::stdin();
+            for line in stdin.lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                for event in line.chars().filter_map(key_to_button_event) {
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        ConsoleDisplay { last_printed: None }
+    }
+
+    pub fn print_msg(&mut self, s: &str) {
+        self.print_once(s.to_string());
+    }
+
+    pub fn print_options(&mut self, label: &str, options: &[&str]) {
+        let lines: Vec<String> = options
+            .iter()
+            .enumerate()
+            .map(|(i, option)| format!("{}: {}", i + 1, option))
+            .collect();
+        self.print_once(format!("{}\n{}", label, lines.join("\n")));
+    }
+
+    // Overwrites the previous status line
+    pub fn print_status(&mut self, status: &str) {
+        self.last_printed = None;
+        print!("\r{}\x1b[K", status);
+        std::io::stdout().flush().unwrap();
+    }
+
+    fn print_once(&mut self, s: String) {
+        if self.last_printed.as_ref() != Some(&s) {
+            println!("\n{}", s);
+            self.last_printed = Some(s);
+        }
+    }
+}
+
+fn key_to_button_event(key: char) -> Option<(Button, bool)> {
+    match key {
+        '1' => Some((ButtonE, false)),
+        '2' => Some((ButtonD, false)),
+        '3' => Some((ButtonC, false)),
+        '4' => Some((ButtonB, false)),
+        '5' => Some((ButtonA, false)),
+        '!' => Some((ButtonE, true)),
+        '@' => Some((ButtonD, true)),
+        '#' => Some((ButtonC, true)),
+        '$' => Some((ButtonB, true)),
+        '%' => Some((ButtonA, true)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::key_to_button_event;
+    use crate::buttons::Button::{ButtonA, ButtonE};
+
+    #[test]
+    fn digits_press_and_shifted_digits_hold() {
+        assert_eq!(Some((ButtonE, false)), key_to_button_event('1'));
+        assert_eq!(Some((ButtonA, false)), key_to_button_event('5'));
+        assert_eq!(Some((ButtonE, true)), key_to_button_event('!'));
+        assert_eq!(Some((ButtonA, true)), key_to_button_event('%'));
+    }
+
+    #[test]
+    fn other_keys_are_ignored() {
+        assert_eq!(None, key_to_button_event('6'));
+        assert_eq!(None, key_to_button_event(' '));
+    }
+}
@@ -0,0 +1,70 @@
+// One row per second, for quick analysis in a spreadsheet.  Anything that
+// wasn't recorded that second is left blank.
+use crate::fit::FitRecord;
+
+const HEADER: &str = "timestamp,power,heart_rate,cadence,speed,distance,lat,lon,altitude\n";
+
+fn show<T: ToString>(o: Option<T>) -> String {
+    o.map_or(String::new(), |x| x.to_string())
+}
+
+fn row(record: &FitRecord) -> String {
+    format!(
+        "{},{},{},{},{},{},{},{},{}\n",
+        record.seconds_since_unix_epoch,
+        show(record.power),
+        show(record.heart_rate),
+        show(record.cadence),
+        show(record.speed),
+        show(record.distance),
+        show(record.latitude),
+        show(record.longitude),
+        show(record.altitude),
+    )
+}
+
+pub fn to_csv(list: &Vec<FitRecord>) -> String {
+    let mut csv = HEADER.to_string();
+    for record in list {
+        csv.push_str(&row(record));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_csv;
+    use crate::fit::FitRecord;
+
+    #[test]
+    fn only_the_header_without_records() {
+        assert_eq!(
+            to_csv(&vec![]),
+            "timestamp,power,heart_rate,cadence,speed,distance,lat,lon,altitude\n"
+        );
+    }
+
+    #[test]
+    fn missing_values_are_blank() {
+        let full = FitRecord {
+            power: Some(250),
+            heart_rate: Some(150),
+            cadence: Some(90),
+            latitude: Some(45.5),
+            longitude: Some(-122.25),
+            altitude: Some(30.0),
+            distance: Some(1000.5),
+            speed: Some(8.25),
+            ..FitRecord::new(1700000001)
+        };
+        let csv = to_csv(&vec![FitRecord::new(1700000000), full]);
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(
+            rows,
+            vec![
+                "1700000000,,,,,,,,",
+                "1700000001,250,150,90,8.25,1000.5,45.5,-122.25,30"
+            ]
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 /// A CycleTree is a simple way to construct repeating structures with arbitrary
 /// leaf nodes.  This is useful for things like workouts, where intervals are
 /// repetitions, and then you might repeat sections of intervals, and then have
@@ -11,7 +13,7 @@
 ///     ]),
 ///     Leaf(10),
 ///   ])
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
 pub enum CycleTree<L> {
     Leaf(L),
     Node((usize, Vec<CycleTree<L>>)),
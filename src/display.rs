@@ -1,3 +1,9 @@
+use crate::activity::ActivityState;
+use crate::console_display::ConsoleDisplay;
+use crate::peripherals::LostDevices;
+use crate::telemetry_db::SessionClock;
+use crate::utils::lock_or_recover;
+use crate::workout::{self, IntervalStats, IntervalSummary, Progress, WorkoutState};
 #[cfg(not(feature = "simulator"))]
 use crate::memory_lcd::MemoryLcd;
 #[cfg(feature = "simulator")]
@@ -5,7 +11,7 @@ use crate::memory_lcd_simulator::MemoryLcd;
 use chrono::Local;
 use embedded_graphics::{
     drawable::Drawable,
-    fonts::{Font24x32, Font6x6, Font8x16, Text},
+    fonts::{Font, Font12x16, Font24x32, Font6x6, Font6x8, Font8x16, Text},
     geometry,
     geometry::Size,
     pixelcolor::BinaryColor,
@@ -14,26 +20,75 @@ use embedded_graphics::{
     transform::Transform,
     DrawTarget,
 };
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use xi_unicode::LineBreakIterator;
 
+// Where the display is drawn
+enum Screen {
+    Lcd(MemoryLcd),
+    Console(ConsoleDisplay),
+}
+
 pub struct Display {
-    memory_lcd: MemoryLcd,
+    screen: Screen,
     workout: WorkoutDisplay,
     version: String,
+    // A brief message drawn over the workout, to confirm an action
+    toast: Option<Toast>,
+    interval_stats: IntervalStats,
+    // How long the summary of a work interval is shown once it ends (if at all)
+    interval_summary_dwell: Option<Duration>,
+    // Which page is shown when the workout's target changes
+    target_change_page: TargetChangePage,
+    // When to go back to the Standard page, after switching away on a target
+    // change
+    auto_return_at: Option<Instant>,
 }
 
 impl Display {
     pub fn new(version: String, memory_lcd: MemoryLcd) -> Display {
+        Display::with_screen(version, Screen::Lcd(memory_lcd))
+    }
+
+    // For running without a screen, such as over SSH
+    pub fn new_console(version: String, console: ConsoleDisplay) -> Display {
+        Display::with_screen(version, Screen::Console(console))
+    }
+
+    fn with_screen(version: String, screen: Screen) -> Display {
         let workout = WorkoutDisplay::new();
         Display {
-            memory_lcd,
+            screen,
             workout,
             version: version,
+            toast: None,
+            interval_stats: IntervalStats::new(),
+            interval_summary_dwell: None,
+            target_change_page: TargetChangePage::PowerTrack,
+            auto_return_at: None,
         }
     }
 
+    // Shown on top of the workout until it expires (replacing any other)
+    pub fn show_toast(&mut self, msg: &str, duration: Duration) {
+        self.toast = Some(Toast::new(msg, Instant::now() + duration));
+    }
+
+    pub fn set_interval_summary_dwell(&mut self, dwell: Option<Duration>) {
+        self.interval_summary_dwell = dwell;
+    }
+
+    pub fn set_target_change_page(&mut self, target_change_page: TargetChangePage) {
+        self.target_change_page = target_change_page;
+    }
+
     pub fn update_power(&mut self, power: Option<i16>) {
+        if let Some(power) = power {
+            self.interval_stats.add_power(power);
+        }
         self.workout.update_power(power);
     }
 
@@ -41,7 +96,10 @@ impl Display {
         self.workout.update_cadence(cadence);
     }
 
-    pub fn update_heart_rate(&mut self, heart_rate: Option<u8>) {
+    pub fn update_heart_rate(&mut self, heart_rate: Option<u16>) {
+        if let Some(heart_rate) = heart_rate {
+            self.interval_stats.add_heart_rate(heart_rate);
+        }
         self.workout.update_heart_rate(heart_rate);
     }
 
@@ -49,6 +107,10 @@ impl Display {
         self.workout.update_external_energy(external_energy);
     }
 
+    pub fn update_w_bal(&mut self, remaining: f64) {
+        self.workout.update_w_bal(remaining);
+    }
+
     pub fn update_crank_count(&mut self, crank_count: u32) {
         self.workout.update_crank_count(crank_count);
     }
@@ -65,78 +127,559 @@ impl Display {
         self.workout.set_gps_fix(has_fix);
     }
 
-    pub fn set_start(&mut self, start: Option<Instant>) {
+    pub fn set_gps_stalled(&mut self, is_stalled: bool) {
+        self.workout.set_gps_stalled(is_stalled);
+    }
+
+    pub fn set_hr_sensor_contact(&mut self, sensor_contact: Option<bool>) {
+        self.workout.set_hr_sensor_contact(sensor_contact);
+    }
+
+    pub fn update_battery(&mut self, battery: Option<u8>) {
+        self.workout.update_battery(battery);
+    }
+
+    pub fn set_start(&mut self, start: Option<SessionClock>) {
         self.workout.set_start(start);
     }
 
     pub fn set_page(&mut self, page: Page) {
+        self.auto_return_at = None;
         self.workout.set_page(page);
     }
 
-    fn add_version(&mut self) {
+    // A new target from the workout, which may switch pages (as configured).
+    // A new step also ends the interval before it, which is summarized if it
+    // was work.
+    pub fn set_target(&mut self, goal: i16, is_new_step: bool) {
+        let now = Instant::now();
+        let o_summary = self.interval_stats.set_target(goal, is_new_step, now);
+        if let (Some(summary), Some(dwell)) = (o_summary, self.interval_summary_dwell) {
+            self.show_toast(&interval_summary_msg(&summary), dwell);
+        }
+        self.workout.goal = Some(goal);
+        self.workout.set_page(page_for_target(self.workout.page, goal, self.target_change_page));
+        self.auto_return_at = match self.target_change_page {
+            TargetChangePage::AutoReturn(after) => Some(now + after),
+            _ => None,
+        };
+    }
+
+    // The Standard page gives way to the Progress page, and any other page
+    // goes back to the Standard page.
+    pub fn toggle_progress_page(&mut self) {
+        self.set_page(match self.workout.page {
+            Page::Standard => Page::Progress,
+            _ => Page::Standard,
+        });
+    }
+
+    // Returns to the PowerTrack page for the latest target (if there's been
+    // one at all).
+    pub fn show_power_track(&mut self) {
+        if let Some(goal) = self.workout.goal {
+            self.set_page(Page::PowerTrack(goal));
+        }
+    }
+
+    pub fn set_activity(&mut self, activity: Arc<Mutex<ActivityState>>) {
+        self.workout.set_activity(activity);
+    }
+
+    pub fn set_workout_state(&mut self, workout_state: Arc<Mutex<WorkoutState>>) {
+        self.workout.set_workout_state(workout_state);
+    }
+
+    pub fn set_erg_error_window(&mut self, window: usize) {
+        self.workout.set_erg_error_window(window);
+    }
+
+    pub fn set_power_track_labels(&mut self, deltas: Vec<i16>) {
+        self.workout.set_power_track_labels(deltas);
+    }
+
+    pub fn set_running(&mut self, is_running: bool) {
+        self.workout.set_running(is_running);
+    }
+
+    pub fn set_units(&mut self, units: Units) {
+        self.workout.set_units(units);
+    }
+
+    pub fn set_layout(&mut self, layout: Vec<FieldKind>) {
+        self.workout.set_layout(layout);
+    }
+
+    pub fn set_stale_policies(&mut self, stale_policies: Vec<(FieldKind, StalePolicy)>) {
+        self.workout.set_stale_policies(stale_policies);
+    }
+
+    pub fn set_scale(&mut self, scale: Scale) {
+        self.workout.set_scale(scale);
+    }
+
+    pub fn set_speed_smoothing(&mut self, window: Duration) {
+        self.workout.set_speed_smoothing(window);
+    }
+
+    pub fn set_lost_devices(&mut self, lost_devices: LostDevices) {
+        self.workout.set_lost_devices(lost_devices);
+    }
+
+    // Swaps light and dark across the whole screen, returning whether it's
+    // now inverted.  There's nothing to invert on the console.
+    pub fn toggle_inverted(&mut self) -> bool {
+        match &mut self.screen {
+            Screen::Lcd(memory_lcd) => {
+                let inverted = !memory_lcd.is_inverted();
+                memory_lcd.set_inverted(inverted);
+                inverted
+            }
+            Screen::Console(_) => false,
+        }
+    }
+
+    fn add_version(memory_lcd: &mut MemoryLcd, version: &str) {
         // TODO: The position here shouldn't be hard coded
-        Text::new(&self.version, geometry::Point::new(10, 156))
+        Text::new(version, geometry::Point::new(10, VERSION_Y))
             .into_styled(
                 TextStyleBuilder::new(Font6x6)
                     .text_color(BinaryColor::On)
                     .background_color(BinaryColor::Off)
                     .build(),
             )
-            .draw(&mut self.memory_lcd)
+            .draw(memory_lcd)
             .unwrap();
     }
 
     pub fn render_msg(&mut self, s: &str) {
-        self.memory_lcd.clear(BinaryColor::Off).unwrap();
-        MsgDisplay::new(s).draw(&mut self.memory_lcd).unwrap();
-        self.add_version();
-        #[cfg(feature = "simulator")]
-        self.memory_lcd.update();
+        match &mut self.screen {
+            Screen::Lcd(memory_lcd) => {
+                memory_lcd.clear(BinaryColor::Off).unwrap();
+                MsgDisplay::new(s).draw(memory_lcd).unwrap();
+                Display::add_version(memory_lcd, &self.version);
+                #[cfg(feature = "simulator")]
+                memory_lcd.update();
+            }
+            Screen::Console(console) => console.print_msg(s),
+        }
     }
 
     pub fn render_options(&mut self, label: &str, options: &Vec<&str>) {
-        // TODO: This also flickers, but stince it doesn't always
-        // over draw like rendering does, it not safe to use the
-        // same has_rendered approach.
-        self.memory_lcd.clear(BinaryColor::Off).unwrap();
-        OptionDisplay::new(label, &options[..])
-            .draw(&mut self.memory_lcd)
-            .unwrap();
-        self.add_version();
-        #[cfg(feature = "simulator")]
-        self.memory_lcd.update();
+        match &mut self.screen {
+            Screen::Lcd(memory_lcd) => {
+                // TODO: This also flickers, but stince it doesn't always
+                // over draw like rendering does, it not safe to use the
+                // same has_rendered approach.
+                memory_lcd.clear(BinaryColor::Off).unwrap();
+                OptionDisplay::new(label, &options[..])
+                    .draw(memory_lcd)
+                    .unwrap();
+                Display::add_version(memory_lcd, &self.version);
+                #[cfg(feature = "simulator")]
+                memory_lcd.update();
+            }
+            Screen::Console(console) => console.print_options(label, &options[..]),
+        }
+    }
+
+    // The shape of a workout, so it can be started (1) or not (2)
+    pub fn render_workout_preview(&mut self, profile: &[(Duration, u16)], tail: Option<u16>) {
+        match &mut self.screen {
+            Screen::Lcd(memory_lcd) => {
+                memory_lcd.clear(BinaryColor::Off).unwrap();
+                WorkoutPreview::new(profile, tail).draw(memory_lcd).unwrap();
+                Display::add_version(memory_lcd, &self.version);
+                #[cfg(feature = "simulator")]
+                memory_lcd.update();
+            }
+            Screen::Console(console) => console.print_msg(&format!(
+                "{}\n1: Start\n2: Back",
+                workout_preview_summary(profile, tail)
+            )),
+        }
     }
 
     pub fn render(&mut self) {
-        // TODO: Need a better strategy than clearing to prevent flickering
-        self.memory_lcd.clear(BinaryColor::Off).unwrap();
-        self.workout.clone().draw(&mut self.memory_lcd).unwrap();
-        self.add_version();
-        // TODO: Make the simulator act more like the real deal, and don't
-        // require a manual screen refresh.
-        #[cfg(feature = "simulator")]
-        self.memory_lcd.update();
+        self.workout.resample_power_history(Instant::now());
+        if self.toast.as_ref().map_or(false, |t| t.is_expired(Instant::now())) {
+            self.toast = None;
+        }
+        if self.auto_return_at.map_or(false, |t| Instant::now() >= t) {
+            self.set_page(Page::Standard);
+        }
+        match &mut self.screen {
+            Screen::Lcd(memory_lcd) => {
+                // TODO: Need a better strategy than clearing to prevent flickering
+                memory_lcd.clear(BinaryColor::Off).unwrap();
+                self.workout.clone().draw(memory_lcd).unwrap();
+                if let Some(toast) = &self.toast {
+                    ToastDisplay::new(&toast.msg).draw(memory_lcd).unwrap();
+                }
+                Display::add_version(memory_lcd, &self.version);
+                // TODO: Make the simulator act more like the real deal, and don't
+                // require a manual screen refresh.
+                #[cfg(feature = "simulator")]
+                memory_lcd.update();
+            }
+            Screen::Console(console) => {
+                let status = self.workout.status_line();
+                match &self.toast {
+                    Some(toast) => console.print_status(&format!(
+                        "{}  {}",
+                        status,
+                        toast.msg.replace('\n', "  ")
+                    )),
+                    None => console.print_status(&status),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Units {
+    Metric,
+    Imperial,
+}
+
+impl Units {
+    fn speed_label(&self) -> &'static str {
+        match self {
+            Units::Metric => "V (km/h)",
+            Units::Imperial => "V (mph)",
+        }
+    }
+
+    // From m/s
+    fn speed(&self, speed: f32) -> f32 {
+        match self {
+            Units::Metric => speed * 60.0 * 60.0 / 1000.0,
+            Units::Imperial => speed * 60.0 * 60.0 / 1609.344,
+        }
+    }
+}
+
+// The metrics that can be placed on the Standard page
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FieldKind {
+    Distance,
+    // Pace, when running
+    Speed,
+    Cadence,
+    // Metabolic energy
+    Energy,
+    Gps,
+    // The time of day
+    Clock,
+    Elapsed,
+    Power,
+    HeartRate,
+}
+
+impl FieldKind {
+    fn parse(s: &str) -> Option<FieldKind> {
+        match s {
+            "distance" => Some(FieldKind::Distance),
+            "speed" => Some(FieldKind::Speed),
+            "cadence" => Some(FieldKind::Cadence),
+            "energy" => Some(FieldKind::Energy),
+            "gps" => Some(FieldKind::Gps),
+            "clock" => Some(FieldKind::Clock),
+            "elapsed" => Some(FieldKind::Elapsed),
+            "power" => Some(FieldKind::Power),
+            "hr" => Some(FieldKind::HeartRate),
+            _ => None,
+        }
+    }
+}
+
+// Fields fill the Standard page's slots in order: five down the first column,
+// then two down the second, and the last two (drawn huge) below those.
+pub const DEFAULT_LAYOUT: &[FieldKind] = &[
+    FieldKind::Distance,
+    FieldKind::Speed,
+    FieldKind::Cadence,
+    FieldKind::Energy,
+    FieldKind::Gps,
+    FieldKind::Clock,
+    FieldKind::Elapsed,
+    FieldKind::Power,
+    FieldKind::HeartRate,
+];
+
+// The large scale has fewer slots: two regular values, then two huge ones
+pub const DEFAULT_LARGE_LAYOUT: &[FieldKind] = &[
+    FieldKind::Elapsed,
+    FieldKind::Speed,
+    FieldKind::Power,
+    FieldKind::HeartRate,
+];
+
+// How big the Standard page is drawn.  Large trades fields for bigger text,
+// for riders who need it (or for bigger screens).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Scale {
+    Normal,
+    Large,
+}
+
+impl Scale {
+    pub fn parse(s: &str) -> Option<Scale> {
+        match s {
+            "normal" => Some(Scale::Normal),
+            "large" => Some(Scale::Large),
+            _ => None,
+        }
+    }
+}
+
+// A comma separated list of field names (like "distance,speed,hr"), which
+// is None if any isn't recognized.  Slots without a field are left blank.
+pub fn parse_layout(s: &str) -> Option<Vec<FieldKind>> {
+    s.split(',').map(|f| FieldKind::parse(f.trim())).collect()
+}
+
+// How a field shows a stale reading (like during a sensor dropout)
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StalePolicy {
+    // Dashes, as if there were no reading at all
+    Blank,
+    // The last reading, as if it were fresh
+    Hold,
+    // The last reading, followed by a ? while it's stale
+    HoldWithMarker,
+}
+
+impl StalePolicy {
+    fn parse(s: &str) -> Option<StalePolicy> {
+        match s {
+            "blank" => Some(StalePolicy::Blank),
+            "hold" => Some(StalePolicy::Hold),
+            "marker" => Some(StalePolicy::HoldWithMarker),
+            _ => None,
+        }
     }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+// A comma separated list of fields and their policies (like
+// "power:hold,hr:marker"), which is None if any isn't recognized.
+pub fn parse_stale_policies(s: &str) -> Option<Vec<(FieldKind, StalePolicy)>> {
+    s.split(',')
+        .map(|p| {
+            let (field, policy) = p.trim().split_once(':')?;
+            Some((FieldKind::parse(field)?, StalePolicy::parse(policy)?))
+        })
+        .collect()
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Page {
     Standard,
     PowerTrack(i16),
+    // How far along the workout is, and what's next
+    Progress,
+}
+
+// What the display does when the workout's target changes
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TargetChangePage {
+    // Switch to the PowerTrack page, to dial in the new target
+    PowerTrack,
+    // Switch to the PowerTrack page, then go back to the Standard page after
+    // this long
+    AutoReturn(Duration),
+    // Keep showing whichever page is shown (though the PowerTrack page still
+    // tracks the new target)
+    Stay,
+}
+
+impl TargetChangePage {
+    // Like "power-track", "stay" or "auto-return:20" (in seconds)
+    pub fn parse(s: &str) -> Option<TargetChangePage> {
+        match s {
+            "power-track" => Some(TargetChangePage::PowerTrack),
+            "stay" => Some(TargetChangePage::Stay),
+            _ => s
+                .strip_prefix("auto-return:")
+                .and_then(|secs| secs.parse().ok())
+                .map(|secs| TargetChangePage::AutoReturn(Duration::from_secs(secs))),
+        }
+    }
+}
+
+fn page_for_target(current: Page, goal: i16, target_change_page: TargetChangePage) -> Page {
+    match (target_change_page, current) {
+        (TargetChangePage::Stay, Page::Standard) | (TargetChangePage::Stay, Page::Progress) => {
+            current
+        }
+        _ => Page::PowerTrack(goal),
+    }
+}
+
+// Readings older than this aren't shown
+const STALE_AFTER: Duration = Duration::from_secs(5);
+
+// Consecutive no fix readings before we show that the fix is lost
+const GPS_NO_FIX_THRESHOLD: u8 = 3;
+
+// On the PowerTrack page, inside the goal +/- this value, devation is drawn
+// linearly.  Outside of the boundary, we draw it logarithmically.  This helps
+// dial in the power when close, but doesn't worry about drawing detail when
+// you get too far away.
+const LINEAR_BOUNDARY: i16 = 10;
+
+// Axis labels on the PowerTrack page can't be any closer than this (in
+// pixels) without overlapping.
+const CHAR_HEIGHT: u32 = 6;
+
+// Where the version is shown (in small text) along the bottom of every page,
+// so everything else on the page is kept clear of it.
+const VERSION_Y: i32 = 156;
+
+// Spacing of the Standard and PowerTrack pages (in pixels)
+const MARGIN: i32 = 10;
+const SPACING: i32 = 6;
+const LABEL_FONT_SIZE: i32 = 6;
+const VALUE_FONT_SIZE: i32 = 16;
+const VALUE_FONT_WIDTH: i32 = 8;
+const HUGE_VALUE_FONT_SIZE: i32 = 32;
+const HUGE_LABEL_SPACING: i32 = 4;
+const COLUMN_SPACING: i32 = 8;
+const COLUMN_ONE_MAX_CHARS: i32 = 6;
+
+// The same for the large scale, where labels are 6x8, values are 12x16, and
+// huge values are unchanged.  Everything is in a single column, so the top
+// margin is trimmed to fit.
+const LARGE_TOP_MARGIN: i32 = 4;
+const LARGE_SPACING: i32 = 2;
+const LARGE_LABEL_FONT_SIZE: i32 = 8;
+
+// The width of the battery glyph (in pixels), nub included
+const BATTERY_GLYPH_WIDTH: i32 = 13;
+
+// How many pixels of the battery glyph's inside are filled for a level
+fn battery_glyph_fill(battery: u8) -> i32 {
+    const INSIDE: i32 = BATTERY_GLYPH_WIDTH - 4;
+    (INSIDE * i32::from(u8::min(battery, 100)) + 50) / 100
+}
+
+// Where each field of the Standard page's layout goes, in order (the top left
+// of its label, and whether its value is huge), and then where the line of
+// lost devices goes below them.
+fn standard_slots(scale: Scale) -> (Vec<(i32, i32, bool)>, i32) {
+    match scale {
+        Scale::Normal => {
+            const ROW_HEIGHT: i32 = LABEL_FONT_SIZE + VALUE_FONT_SIZE + SPACING;
+            const HUGE_ROW_HEIGHT: i32 =
+                LABEL_FONT_SIZE + HUGE_LABEL_SPACING + HUGE_VALUE_FONT_SIZE + SPACING;
+            const COLUMN_TWO: i32 = MARGIN + VALUE_FONT_WIDTH * COLUMN_ONE_MAX_CHARS + COLUMN_SPACING;
+
+            // A narrow first column, then a second with the headline metrics
+            // at the bottom.
+            (
+                vec![
+                    (MARGIN, MARGIN, false),
+                    (MARGIN, MARGIN + ROW_HEIGHT, false),
+                    (MARGIN, MARGIN + 2 * ROW_HEIGHT, false),
+                    (MARGIN, MARGIN + 3 * ROW_HEIGHT, false),
+                    (MARGIN, MARGIN + 4 * ROW_HEIGHT, false),
+                    (COLUMN_TWO, MARGIN, false),
+                    (COLUMN_TWO, MARGIN + ROW_HEIGHT, false),
+                    (COLUMN_TWO, MARGIN + 2 * ROW_HEIGHT, true),
+                    (COLUMN_TWO, MARGIN + 2 * ROW_HEIGHT + HUGE_ROW_HEIGHT, true),
+                ],
+                MARGIN + 4 * ROW_HEIGHT + LABEL_FONT_SIZE + VALUE_FONT_SIZE + 4,
+            )
+        }
+        Scale::Large => {
+            const ROW_HEIGHT: i32 =
+                LARGE_LABEL_FONT_SIZE + LARGE_SPACING + VALUE_FONT_SIZE + LARGE_SPACING;
+            const HUGE_ROW_HEIGHT: i32 =
+                LARGE_LABEL_FONT_SIZE + LARGE_SPACING + HUGE_VALUE_FONT_SIZE + LARGE_SPACING;
+
+            (
+                vec![
+                    (MARGIN, LARGE_TOP_MARGIN, false),
+                    (MARGIN, LARGE_TOP_MARGIN + ROW_HEIGHT, false),
+                    (MARGIN, LARGE_TOP_MARGIN + 2 * ROW_HEIGHT, true),
+                    (MARGIN, LARGE_TOP_MARGIN + 2 * ROW_HEIGHT + HUGE_ROW_HEIGHT, true),
+                ],
+                LARGE_TOP_MARGIN + 2 * ROW_HEIGHT + 2 * HUGE_ROW_HEIGHT,
+            )
+        }
+    }
+}
+
+fn draw_text<D: DrawTarget<BinaryColor>, F: Font + Copy>(
+    target: &mut D,
+    font: F,
+    text: &str,
+    x: i32,
+    y: i32,
+) -> Result<(), D::Error> {
+    Text::new(text, geometry::Point::new(x, y))
+        .into_styled(
+            TextStyleBuilder::new(font)
+                .text_color(BinaryColor::On)
+                .background_color(BinaryColor::Off)
+                .build(),
+        )
+        .draw(target)
 }
 
 #[derive(Clone)]
 pub struct WorkoutDisplay {
     power: Option<(i16, Instant)>,
-    power_history: ([i16; 60], usize),
+    // One sample per second (if there was a reading), each holding the goal
+    // that was active when it was recorded
+    power_history: ([(Option<i16>, Option<i16>); 60], usize),
+    // When the most recent power history sample was taken
+    power_history_instant: Option<Instant>,
+    goal: Option<i16>,
+    // How many power samples are averaged to show how far we are from the
+    // goal.
+    erg_error_window: usize,
+    // Deviations from the goal that are labeled on the PowerTrack axis (in
+    // watts, above and below).  These are independent of the scaling, so
+    // they're placed wherever that value is drawn.
+    power_track_labels: Vec<i16>,
     cadence: Option<(u8, Instant)>,
-    heart_rate: Option<(u8, Instant)>,
+    heart_rate: Option<(u16, Instant)>,
     external_energy: Option<f64>,
+    // The fraction of W' remaining, if we know the rider's critical power
+    w_bal_remaining: Option<f64>,
     crank_count: Option<u32>,
     speed: Option<(f32, Instant)>,
+    // Recent speed readings, which are averaged for display, since the speed
+    // from infrequent wheel events jumps around.
+    speed_samples: VecDeque<(f32, Instant)>,
+    // How far back from the latest reading speed is averaged
+    speed_smoothing: Duration,
+    units: Units,
     distance: f64,
     gps_fix: Option<(bool, Instant)>,
-    start_instant: Option<Instant>,
+    // How many no fix readings we've had in a row
+    gps_no_fix_count: u8,
+    // The module has stopped sending anything at all
+    gps_stalled: bool,
+    // None if the strap doesn't report whether it's touching skin
+    hr_sensor_contact: Option<bool>,
+    // The lowest battery level (as a percent) of any sensor that reports it
+    battery: Option<u8>,
+    o_session_clock: Option<SessionClock>,
+    // Runners want pace instead of speed, and cadence in steps
+    is_running: bool,
+    activity: Option<Arc<Mutex<ActivityState>>>,
+    // Where the workout's progress comes from
+    workout_state: Option<Arc<Mutex<WorkoutState>>>,
+    // Devices we've given up reconnecting to
+    lost_devices: Option<LostDevices>,
+    // What's shown in each slot of the Standard page
+    layout: Vec<FieldKind>,
+    // Fields not listed are blanked when stale
+    stale_policies: Vec<(FieldKind, StalePolicy)>,
+    scale: Scale,
     page: Page,
 }
 
@@ -144,31 +687,111 @@ impl WorkoutDisplay {
     pub fn new() -> WorkoutDisplay {
         WorkoutDisplay {
             power: None,
-            power_history: ([0; 60], 0),
+            power_history: ([(None, None); 60], 0),
+            power_history_instant: None,
+            goal: None,
+            erg_error_window: 5,
+            power_track_labels: vec![LINEAR_BOUNDARY, LINEAR_BOUNDARY * LINEAR_BOUNDARY],
             cadence: None,
             heart_rate: None,
             external_energy: None,
+            w_bal_remaining: None,
             crank_count: None,
             speed: None,
+            speed_samples: VecDeque::new(),
+            speed_smoothing: Duration::from_secs(3),
+            units: Units::Metric,
             distance: 0.0,
             gps_fix: None,
-            start_instant: None,
+            gps_no_fix_count: 0,
+            gps_stalled: false,
+            hr_sensor_contact: None,
+            battery: None,
+            o_session_clock: None,
+            is_running: false,
+            activity: None,
+            workout_state: None,
+            lost_devices: None,
+            layout: DEFAULT_LAYOUT.to_vec(),
+            stale_policies: Vec::new(),
+            scale: Scale::Normal,
             page: Page::Standard,
         }
     }
 
     pub fn update_power(&mut self, power: Option<i16>) {
         self.power = power.map(|x| (x, Instant::now()));
-        // TODO: Interpolate!
-        self.power_history.1 = (self.power_history.1 + 1) % 60;
-        self.power_history.0[self.power_history.1] = power.unwrap_or(0);
+    }
+
+    // The headline metrics as one line of text, for the console
+    pub fn status_line(&self) -> String {
+        let power = self.power.and_then(none_if_stale);
+        let cadence = self.cadence.and_then(none_if_stale);
+        let heart_rate = self.heart_rate.and_then(none_if_stale);
+        let speed = self.speed.and_then(none_if_stale);
+        let power_str = power.map_or("---".to_string(), |x| format!("{:03}", x.0));
+        let hr_str = heart_rate.map_or("---".to_string(), |x| format!("{:03}", x.0));
+        let cadence_str = cadence.map_or("---".to_string(), |x| format!("{:03}", x.0));
+        let speed_str = if self.is_running {
+            format!("PACE {}/km", pace_str(speed.map(|x| x.0)).trim_end())
+        } else {
+            format!(
+                "{} {}",
+                self.units.speed_label(),
+                speed
+                    .and(self.smoothed_speed())
+                    .map_or("---".to_string(), |x| format!("{:.2}", self.units.speed(x)))
+            )
+        };
+        let progress_str = match (self.page, self.progress()) {
+            (Page::Progress, Some(progress)) => format!(
+                "  LEFT {}{}",
+                min_sec_str(progress.remaining()),
+                progress_next_str(&progress).map_or(String::new(), |s| format!("  {}", s))
+            ),
+            _ => String::new(),
+        };
+        format!(
+            "PWR {}W  HR {}  CAD {} {}  {}{}",
+            power_str,
+            hr_str,
+            cadence_str,
+            if self.is_running { "SPM" } else { "RPM" },
+            speed_str,
+            progress_str
+        )
+    }
+
+    // The power history advances once per second from the latest power
+    // reading, no matter how many sources are reporting (or how often), so
+    // that it always spans the same amount of time.
+    pub fn resample_power_history(&mut self, now: Instant) {
+        match self.power_history_instant {
+            None => self.power_history_instant = Some(now),
+            Some(last) => {
+                let seconds = now.saturating_duration_since(last).as_secs();
+                let power = self
+                    .power
+                    .filter(|x| now.saturating_duration_since(x.1) <= STALE_AFTER)
+                    .map(|x| x.0);
+                // TODO: Interpolate!
+                for _ in 0..u64::min(seconds, 60) {
+                    self.power_history.1 = (self.power_history.1 + 1) % 60;
+                    // We keep the goal alongside each sample, so that changing
+                    // the goal (like via an offset) doesn't retroactively
+                    // reinterpret the history.
+                    self.power_history.0[self.power_history.1] = (power, self.goal);
+                }
+                self.power_history_instant = Some(last + Duration::from_secs(seconds));
+            }
+        }
     }
 
     pub fn update_cadence(&mut self, cadence: Option<u8>) {
         self.cadence = cadence.map(|x| (x, Instant::now()));
     }
 
-    pub fn update_heart_rate(&mut self, heart_rate: Option<u8>) {
+    pub fn update_heart_rate(&mut self, heart_rate: Option<u16>) {
         self.heart_rate = heart_rate.map(|x| (x, Instant::now()));
     }
 
@@ -176,37 +799,324 @@ impl WorkoutDisplay {
         self.external_energy = Some(external_energy);
     }
 
+    // The fraction of W' remaining, from 0 to 1
+    pub fn update_w_bal(&mut self, remaining: f64) {
+        self.w_bal_remaining = Some(remaining);
+    }
+
     pub fn update_crank_count(&mut self, crank_count: u32) {
         self.crank_count = Some(crank_count);
     }
 
     pub fn update_speed(&mut self, speed: Option<f32>) {
-        self.speed = speed.map(|x| (x, Instant::now()));
+        self.record_speed(speed, Instant::now());
+    }
+
+    fn record_speed(&mut self, speed: Option<f32>, now: Instant) {
+        self.speed = speed.map(|x| (x, now));
+        match speed {
+            Some(x) => self.speed_samples.push_back((x, now)),
+            None => self.speed_samples.clear(),
+        }
+        while self
+            .speed_samples
+            .front()
+            .map_or(false, |s| now.saturating_duration_since(s.1) > self.speed_smoothing)
+        {
+            self.speed_samples.pop_front();
+        }
+    }
+
+    // The mean of the recent speed readings (in m/s)
+    fn smoothed_speed(&self) -> Option<f32> {
+        if self.speed_samples.is_empty() {
+            None
+        } else {
+            let sum: f32 = self.speed_samples.iter().map(|s| s.0).sum();
+            Some(sum / self.speed_samples.len() as f32)
+        }
     }
 
     pub fn update_distance(&mut self, distance: f64) {
         self.distance = distance;
     }
 
+    // A fix is shown immediately, but we only show that it's lost after a few
+    // readings in a row, so that a momentary drop doesn't flicker.
     pub fn set_gps_fix(&mut self, has_fix: bool) {
-        self.gps_fix = Some((has_fix, Instant::now()));
+        let had_fix = self.gps_fix.map_or(false, |(f, _)| f);
+        if has_fix {
+            self.gps_no_fix_count = 0;
+        } else {
+            self.gps_no_fix_count = self.gps_no_fix_count.saturating_add(1);
+        }
+        let show_fix =
+            has_fix || (had_fix && self.gps_no_fix_count < GPS_NO_FIX_THRESHOLD);
+        self.gps_fix = Some((show_fix, Instant::now()));
+    }
+
+    pub fn set_gps_stalled(&mut self, is_stalled: bool) {
+        self.gps_stalled = is_stalled;
+    }
+
+    pub fn set_hr_sensor_contact(&mut self, sensor_contact: Option<bool>) {
+        self.hr_sensor_contact = sensor_contact;
+    }
+
+    pub fn update_battery(&mut self, battery: Option<u8>) {
+        self.battery = battery;
+    }
+
+    // A strap without skin contact may keep sending readings, but they can't
+    // be trusted.  Always 4 characters, so that new values clear the previous.
+    fn heart_rate_str(&self) -> String {
+        let heart_rate = self.heart_rate.and_then(none_if_stale);
+        format!(
+            "{}{}",
+            heart_rate.map_or("---".to_string(), |x| format!("{:03}", x.0)),
+            if self.hr_sensor_contact == Some(false) { "!" } else { " " }
+        )
+    }
+
+    pub fn set_start(&mut self, start: Option<SessionClock>) {
+        self.o_session_clock = start;
+    }
+
+    pub fn set_activity(&mut self, activity: Arc<Mutex<ActivityState>>) {
+        self.activity = Some(activity);
+    }
+
+    pub fn set_workout_state(&mut self, workout_state: Arc<Mutex<WorkoutState>>) {
+        self.workout_state = Some(workout_state);
+    }
+
+    fn progress(&self) -> Option<Progress> {
+        self.workout_state.as_ref().and_then(workout::progress)
+    }
+
+    pub fn set_running(&mut self, is_running: bool) {
+        self.is_running = is_running;
+    }
+
+    pub fn set_units(&mut self, units: Units) {
+        self.units = units;
     }
 
-    pub fn set_start(&mut self, start: Option<Instant>) {
-        self.start_instant = start;
+    pub fn set_layout(&mut self, layout: Vec<FieldKind>) {
+        self.layout = layout;
+    }
+
+    pub fn set_stale_policies(&mut self, stale_policies: Vec<(FieldKind, StalePolicy)>) {
+        self.stale_policies = stale_policies;
+    }
+
+    // A field's reading as it's shown, given how the field treats stale
+    // readings, along with what follows it.  Under HoldWithMarker that's
+    // the marker, or a space to clear it, and otherwise nothing.
+    fn shown_reading<T>(
+        &self,
+        kind: FieldKind,
+        o_reading: Option<(T, Instant)>,
+    ) -> Option<(T, &'static str)> {
+        let (value, instant) = o_reading?;
+        let policy = self
+            .stale_policies
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map_or(StalePolicy::Blank, |(_, p)| *p);
+        let is_stale = instant.elapsed() > STALE_AFTER;
+        match (policy, is_stale) {
+            (StalePolicy::Blank, true) => None,
+            (StalePolicy::HoldWithMarker, true) => Some((value, "?")),
+            (StalePolicy::HoldWithMarker, false) => Some((value, " ")),
+            (StalePolicy::Blank, false) | (StalePolicy::Hold, _) => Some((value, "")),
+        }
+    }
+
+    pub fn set_scale(&mut self, scale: Scale) {
+        self.scale = scale;
+    }
+
+    // The label and value of a field on the Standard page.  Values that can
+    // get shorter are padded, so that new values clear the previous.
+    fn field(&self, kind: FieldKind) -> (&'static str, String) {
+        match kind {
+            FieldKind::Distance => (
+                "D (km)",
+                // We only show this if we've gotten a speed measurement
+                // before (but we don't care if it's stale).
+                self.speed.map_or("---   ".to_string(), |_| {
+                    format!("{:.2}", self.distance / 1000.0)
+                }),
+            ),
+            FieldKind::Speed => {
+                let speed = self.shown_reading(kind, self.speed);
+                let marker = speed.map_or("", |x| x.1);
+                if self.is_running {
+                    let pace = pace_str(speed.map(|x| x.0));
+                    if marker.is_empty() {
+                        ("PACE (/km)", pace)
+                    } else {
+                        // The marker takes the place of the padding
+                        ("PACE (/km)", format!("{}{}", pace.trim_end(), marker))
+                    }
+                } else {
+                    (
+                        self.units.speed_label(),
+                        // Only shown smoothed, if we have a reading to show at all
+                        speed.and(self.smoothed_speed()).map_or("---   ".to_string(), |x| {
+                            format!("{:.2}{}", self.units.speed(x), marker)
+                        }),
+                    )
+                }
+            }
+            FieldKind::Cadence => (
+                if self.is_running { "CAD (SPM)" } else { "CAD (RPM)" },
+                self.shown_reading(kind, self.cadence)
+                    .map_or("---".to_string(), |x| format!("{:03}{}", x.0, x.1)),
+            ),
+            FieldKind::Energy => {
+                let elapsed_secs = self.o_session_clock.map(|x| x.elapsed().as_secs());
+                (
+                    "ME (KCAL)",
+                    // We only show this if we've gotten a power reading before (but
+                    // we don't care if it's stale).
+                    self.external_energy.map_or("---   ".to_string(), |e| {
+                        format!(
+                            "{:04}",
+                            // We assume 80rpm unless otherwise known
+                            metabolic_cost_in_kcal(
+                                e,
+                                self.crank_count
+                                    .unwrap_or((elapsed_secs.unwrap_or(0) * 80 / 60) as u32)
+                            ) as u16
+                        )
+                    }),
+                )
+            }
+            FieldKind::Gps => (
+                "GPS",
+                // Must always be 6 characters, so that new values clear the previous
+                match self.gps_fix.and_then(none_if_stale) {
+                    _ if self.gps_stalled => "STALL ",
+                    None => "NO GPS",
+                    Some((false, _)) => "NO FIX",
+                    Some((true, _)) => "FIX   ",
+                }
+                .to_string(),
+            ),
+            FieldKind::Clock => ("CURRENT", format!("{}", Local::now().format("%T"))),
+            FieldKind::Elapsed => {
+                let is_active = self
+                    .activity
+                    .as_ref()
+                    .map_or(true, |a| lock_or_recover(a).is_active(Instant::now()));
+                (
+                    if is_active { "ELAPSED" } else { "ELAPSED (IDLE)" },
+                    self.o_session_clock.map_or("--:--:--".to_string(), |x| {
+                        let s = x.elapsed().as_secs();
+                        format!("{:02}:{:02}:{:02}", s / 3600, (s / 60) % 60, s % 60)
+                    }),
+                )
+            }
+            FieldKind::Power => (
+                "POW (W)",
+                self.shown_reading(kind, self.power)
+                    .map_or("---   ".to_string(), |x| format!("{:03}{}", x.0, x.1)),
+            ),
+            FieldKind::HeartRate => (
+                "HR (BPM)",
+                self.shown_reading(kind, self.heart_rate)
+                    .map_or("---".to_string(), |x| format!("{:03}{}", x.0, x.1)),
+            ),
+        }
+    }
+
+    // Zero shows each reading as it arrives
+    pub fn set_speed_smoothing(&mut self, window: Duration) {
+        self.speed_smoothing = window;
+    }
+
+    pub fn set_lost_devices(&mut self, lost_devices: LostDevices) {
+        self.lost_devices = Some(lost_devices);
+    }
+
+    // The window is clamped to the power history we actually keep
+    pub fn set_erg_error_window(&mut self, window: usize) {
+        self.erg_error_window = usize::min(usize::max(window, 1), 60);
+    }
+
+    pub fn set_power_track_labels(&mut self, deltas: Vec<i16>) {
+        self.power_track_labels = deltas;
     }
 
     pub fn set_page(&mut self, page: Page) {
+        if let Page::PowerTrack(goal) = page {
+            self.goal = Some(goal);
+        }
         self.page = page;
     }
+
+    // Each power sample and the goal that was active when it was recorded
+    // (most recent first)
+    fn power_history_samples(&self) -> impl Iterator<Item = (Option<i16>, Option<i16>)> + '_ {
+        ((self.power_history.1 + 1)..(self.power_history.1 + 61))
+            .rev()
+            .map(|i| self.power_history.0[i % 60])
+    }
+
+    // The deviation of each power sample from the goal that was active when it
+    // was recorded (most recent first).  Samples without a known goal are
+    // compared to the current one, and those without a reading are as if 0W.
+    fn power_history_deltas(&self, current_goal: i16) -> Vec<i16> {
+        self.power_history_samples()
+            .map(|(o_power, o_goal)| o_power.unwrap_or(0) - o_goal.unwrap_or(current_goal))
+            .collect()
+    }
+
+    // The mean of the most recent deviations from the goal, so a trainer
+    // that's consistently under or over is easy to spot.  Only samples with
+    // both a reading and a goal count, so there may be none.
+    fn average_erg_error(&self) -> Option<i16> {
+        let (sum, count) = self
+            .power_history_samples()
+            .take(self.erg_error_window)
+            .filter_map(|(o_power, o_goal)| Some(o_power? as i32 - o_goal? as i32))
+            .fold((0, 0), |(sum, count), d| (sum + d, count + 1));
+        if count == 0 {
+            None
+        } else {
+            Some((sum / count) as i16)
+        }
+    }
+
+    // Where each labeled deviation sits relative to the reference line (in
+    // pixels, above it).  Labels that would overlap a label closer to the
+    // line are dropped, as are any past the edge of the graph.
+    fn power_track_label_offsets(&self, y_scale: f64) -> Vec<(i16, i32)> {
+        let mut deltas: Vec<i16> = self
+            .power_track_labels
+            .iter()
+            .copied()
+            .filter(|&d| d > 0)
+            .collect();
+        deltas.sort();
+        deltas.dedup();
+        let max_offset = (2.0 * y_scale) as i32;
+        let mut offsets: Vec<(i16, i32)> = vec![];
+        for delta in deltas {
+            let offset = -deviation_len(delta, y_scale) as i32;
+            let last_offset = offsets.last().map_or(0, |&(_, o)| o);
+            if offset - last_offset >= CHAR_HEIGHT as i32 && offset <= max_offset {
+                offsets.push((delta, offset));
+            }
+        }
+        offsets
+    }
 }
 
 impl Drawable<BinaryColor> for WorkoutDisplay {
     fn draw<D: DrawTarget<BinaryColor>>(self, target: &mut D) -> Result<(), D::Error> {
-        let style_huge = TextStyleBuilder::new(Font24x32)
-            .text_color(BinaryColor::On)
-            .background_color(BinaryColor::Off)
-            .build();
         let style_large = TextStyleBuilder::new(Font8x16)
             .text_color(BinaryColor::On)
             .background_color(BinaryColor::Off)
@@ -216,12 +1126,9 @@ impl Drawable<BinaryColor> for WorkoutDisplay {
             .background_color(BinaryColor::Off)
             .build();
 
-        let elapsed_secs = self.start_instant.map(|x| x.elapsed().as_secs());
+        let elapsed_secs = self.o_session_clock.map(|x| x.elapsed().as_secs());
         // We lazily purge any values that are older than 5s just before render
-        let power = self.power.and_then(none_if_stale);
         let cadence = self.cadence.and_then(none_if_stale);
-        let heart_rate = self.heart_rate.and_then(none_if_stale);
-        let speed = self.speed.and_then(none_if_stale);
         let gps_fix = self.gps_fix.and_then(none_if_stale);
 
         // We only show this if we've gotten a speed measurement before (but we
@@ -229,147 +1136,135 @@ impl Drawable<BinaryColor> for WorkoutDisplay {
         let distance_str = &self.speed.map_or("---   ".to_string(), |_| {
             format!("{:.2}", self.distance / 1000.0)
         });
-        let hr_str = heart_rate.map_or("---".to_string(), |x| format!("{:03}", x.0));
-        let current_str = format!("{}", Local::now().format("%T"));
+        let hr_str = self.heart_rate_str();
         let elapsed_str = elapsed_secs.map_or("--:--:--".to_string(), |s| {
             format!("{:02}:{:02}:{:02}", s / 3600, (s / 60) % 60, s % 60)
         });
         let cadence_str = cadence.map_or("---".to_string(), |x| format!("{:03}", x.0));
-
-        const MARGIN: i32 = 10;
-        const SPACING: i32 = 6;
-        const LABEL_FONT_SIZE: i32 = 6;
-        const VALUE_FONT_SIZE: i32 = 16;
-        const VALUE_FONT_WIDTH: i32 = 8;
-        const HUGE_VALUE_FONT_SIZE: i32 = 32;
-        const HUGE_LABEL_SPACING: i32 = 4;
-        const COLUMN_SPACING: i32 = 8;
-        const COLUMN_ONE_MAX_CHARS: i32 = 6;
+        let lost_str = self.lost_devices.as_ref().and_then(|l| {
+            let lost = lock_or_recover(l);
+            if lost.is_empty() {
+                None
+            } else {
+                let names: Vec<&str> = lost.iter().map(|d| d.name()).collect();
+                Some(format!("LOST {}", names.join(" ")))
+            }
+        });
 
         match self.page {
             Page::Standard => {
-                let x = MARGIN;
-                let y = MARGIN;
-                Text::new("D (km)", geometry::Point::new(x, y))
-                    .into_styled(style_tiny)
-                    .draw(target)?;
-
-                let y = y + LABEL_FONT_SIZE;
-                Text::new(&distance_str, geometry::Point::new(x, y))
-                    .into_styled(style_large)
-                    .draw(target)?;
-
-                let y = y + VALUE_FONT_SIZE + SPACING;
-                Text::new("V (km/h)", geometry::Point::new(x, y))
-                    .into_styled(style_tiny)
-                    .draw(target)?;
-
-                let y = y + LABEL_FONT_SIZE;
-                Text::new(
-                    &speed.map_or("---   ".to_string(), |x| {
-                        format!("{:.2}", x.0 * 60.0 * 60.0 / 1000.0)
-                    }),
-                    geometry::Point::new(x, y),
-                )
-                .into_styled(style_large)
-                .draw(target)?;
-
-                let y = y + VALUE_FONT_SIZE + SPACING;
-                Text::new("CAD (RPM)", geometry::Point::new(x, y))
-                    .into_styled(style_tiny)
-                    .draw(target)?;
+                let (slots, lost_y) = standard_slots(self.scale);
+                for ((x, y, is_huge), kind) in slots.iter().zip(self.layout.iter()) {
+                    let (label, value) = self.field(*kind);
+                    match self.scale {
+                        Scale::Normal => {
+                            draw_text(target, Font6x6, label, *x, *y)?;
+                            if *is_huge {
+                                let y = y + LABEL_FONT_SIZE + HUGE_LABEL_SPACING;
+                                draw_text(target, Font24x32, &value, *x, y)?;
+                            } else {
+                                let y = y + LABEL_FONT_SIZE;
+                                draw_text(target, Font8x16, &value, *x, y)?;
+                            }
+                        }
+                        Scale::Large => {
+                            draw_text(target, Font6x8, label, *x, *y)?;
+                            let y = y + LARGE_LABEL_FONT_SIZE + LARGE_SPACING;
+                            if *is_huge {
+                                draw_text(target, Font24x32, &value, *x, y)?;
+                            } else {
+                                draw_text(target, Font12x16, &value, *x, y)?;
+                            }
+                        }
+                    }
+                }
 
-                let y = y + LABEL_FONT_SIZE;
-                Text::new(&cadence_str, geometry::Point::new(x, y))
-                    .into_styled(style_large)
-                    .draw(target)?;
+                if let Some(lost_str) = &lost_str {
+                    Text::new(lost_str, geometry::Point::new(MARGIN, lost_y))
+                        .into_styled(style_tiny)
+                        .draw(target)?;
+                }
 
-                let y = y + VALUE_FONT_SIZE + SPACING;
-                Text::new("ME (KCAL)", geometry::Point::new(x, y))
-                    .into_styled(style_tiny)
+                // W' balance, as a bar along the bottom (below the version)
+                // that empties as it's used
+                if let Some(remaining) = self.w_bal_remaining {
+                    const BAR_HEIGHT: i32 = 6;
+                    const BAR_LABEL_WIDTH: i32 = 3 * 6;
+                    let Size { width, .. } = target.size();
+                    let y = VERSION_Y + CHAR_HEIGHT as i32;
+                    Text::new("W'", geometry::Point::new(MARGIN, y))
+                        .into_styled(style_tiny)
+                        .draw(target)?;
+
+                    let left = MARGIN + BAR_LABEL_WIDTH;
+                    let right = width as i32 - MARGIN;
+                    Rectangle::new(
+                        geometry::Point::new(left, y),
+                        geometry::Point::new(right, y + BAR_HEIGHT - 1),
+                    )
+                    .into_styled(
+                        PrimitiveStyleBuilder::new()
+                            .fill_color(BinaryColor::Off)
+                            .stroke_color(BinaryColor::On)
+                            .stroke_width(1)
+                            .build(),
+                    )
                     .draw(target)?;
 
-                let y = y + LABEL_FONT_SIZE;
-                Text::new(
-                    // We only show this if we've gotten a power reading before (but we
-                    // don't care if it's stale).
-                    &self.external_energy.map_or("---   ".to_string(), |e| {
-                        format!(
-                            "{:04}",
-                            // We assume 80rpm unless otherwise known
-                            metabolic_cost_in_kcal(
-                                e,
-                                self.crank_count
-                                    .unwrap_or((elapsed_secs.unwrap_or(0) * 80 / 60) as u32)
-                            ) as u16
+                    let filled = ((right - left) as f64 * remaining) as i32;
+                    if filled > 0 {
+                        Rectangle::new(
+                            geometry::Point::new(left, y),
+                            geometry::Point::new(left + filled, y + BAR_HEIGHT - 1),
                         )
-                    }),
-                    geometry::Point::new(x, y),
-                )
-                .into_styled(style_large)
-                .draw(target)?;
-
-                let y = y + VALUE_FONT_SIZE + SPACING;
-                Text::new("GPS", geometry::Point::new(x, y))
-                    .into_styled(style_tiny)
-                    .draw(target)?;
-
-                let y = y + LABEL_FONT_SIZE;
-                Text::new(
-                    // Must always be 6 characters, so that new values clear the previous
-                    &match gps_fix {
-                        None => "NO GPS",
-                        Some((false, _)) => "NO FIX",
-                        Some((true, _)) => "FIX   ",
-                    },
-                    geometry::Point::new(x, y),
-                )
-                .into_styled(style_large)
-                .draw(target)?;
-
-                let x = x + VALUE_FONT_WIDTH * COLUMN_ONE_MAX_CHARS + COLUMN_SPACING;
-                let y = MARGIN;
-                Text::new("CURRENT", geometry::Point::new(x, y))
-                    .into_styled(style_tiny)
-                    .draw(target)?;
-
-                let y = y + LABEL_FONT_SIZE;
-                Text::new(&current_str, geometry::Point::new(x, y))
-                    .into_styled(style_large)
-                    .draw(target)?;
-
-                let y = y + VALUE_FONT_SIZE + SPACING;
-                Text::new("ELAPSED", geometry::Point::new(x, y))
-                    .into_styled(style_tiny)
-                    .draw(target)?;
-
-                let y = y + LABEL_FONT_SIZE;
-                Text::new(&elapsed_str, geometry::Point::new(x, y))
-                    .into_styled(style_large)
-                    .draw(target)?;
-
-                let y = y + VALUE_FONT_SIZE + SPACING;
-                Text::new("POW (W)", geometry::Point::new(x, y))
-                    .into_styled(style_tiny)
-                    .draw(target)?;
-
-                let y = y + LABEL_FONT_SIZE + HUGE_LABEL_SPACING;
-                Text::new(
-                    &power.map_or("---   ".to_string(), |x| format!("{:03}", x.0)),
-                    geometry::Point::new(x, y),
-                )
-                .into_styled(style_huge)
-                .draw(target)?;
+                        .into_styled(
+                            PrimitiveStyleBuilder::new()
+                                .fill_color(BinaryColor::On)
+                                .stroke_width(0)
+                                .build(),
+                        )
+                        .draw(target)?;
+                    }
+                }
 
-                let y = y + HUGE_VALUE_FONT_SIZE + SPACING;
-                Text::new("HR (BPM)", geometry::Point::new(x, y))
-                    .into_styled(style_tiny)
+                // The lowest sensor battery, as a small glyph in the top right
+                // that empties as it drains
+                if let Some(battery) = self.battery {
+                    let Size { width, .. } = target.size();
+                    let right = width as i32 - 2;
+                    let left = right - BATTERY_GLYPH_WIDTH + 1;
+                    let outline = PrimitiveStyleBuilder::new()
+                        .fill_color(BinaryColor::Off)
+                        .stroke_color(BinaryColor::On)
+                        .stroke_width(1)
+                        .build();
+                    let fill = PrimitiveStyleBuilder::new()
+                        .fill_color(BinaryColor::On)
+                        .stroke_width(0)
+                        .build();
+                    Rectangle::new(
+                        geometry::Point::new(left, 2),
+                        geometry::Point::new(right - 2, 7),
+                    )
+                    .into_styled(outline)
                     .draw(target)?;
-
-                let y = y + LABEL_FONT_SIZE + HUGE_LABEL_SPACING;
-                Text::new(&hr_str, geometry::Point::new(x, y))
-                    .into_styled(style_huge)
+                    // The terminal nub
+                    Rectangle::new(
+                        geometry::Point::new(right - 1, 4),
+                        geometry::Point::new(right, 5),
+                    )
+                    .into_styled(fill)
                     .draw(target)?;
+                    let filled = battery_glyph_fill(battery);
+                    if filled > 0 {
+                        Rectangle::new(
+                            geometry::Point::new(left + 1, 3),
+                            geometry::Point::new(left + filled, 6),
+                        )
+                        .into_styled(fill)
+                        .draw(target)?;
+                    }
+                }
 
                 Rectangle::new(geometry::Point::new(187, 3), geometry::Point::new(193, 9))
                     .into_styled(
@@ -385,15 +1280,8 @@ impl Drawable<BinaryColor> for WorkoutDisplay {
             Page::PowerTrack(goal) => {
                 let Size { height, width } = target.size();
 
-                // Inside the goal +/- this value, devation is drawn linearly.
-                // Outside of the boundary, we draw it logarithmically.  This
-                // helps dial in the power when close, but doesn't worry about
-                // drawing detail when you get too far away.
-                const LINEAR_BOUNDARY: i16 = 10;
-
                 const CHAR_COUNT: u32 = 3;
                 const CHAR_WIDTH: u32 = 6;
-                const CHAR_HEIGHT: u32 = 6;
                 const GRAPH_SPACING: u32 = 3;
 
                 // TODO: determine graph scale and center automatically based on
@@ -449,26 +1337,10 @@ impl Drawable<BinaryColor> for WorkoutDisplay {
                 let second_width = std::cmp::max(graph_width / 30, 1);
 
                 let mut x = graph_width - second_width / 2;
-                for i in ((self.power_history.1 + 1)..(self.power_history.1 + 61)).rev() {
-                    let p = self.power_history.0[i % 60];
-                    let delta = (p - goal).abs();
-                    let len = y_scale
-                        * (if delta > LINEAR_BOUNDARY {
-                            (delta as f64).log(LINEAR_BOUNDARY as f64)
-                        } else {
-                            delta as f64 / LINEAR_BOUNDARY as f64
-                        })
-                        * (if p > goal { -1.0 } else { 1.0 });
+                for delta in self.power_history_deltas(goal) {
                     draw_line(
                         (x as i32, graph_center_y),
-                        (
-                            x as i32,
-                            graph_center_y
-                                + std::cmp::min(
-                                    std::cmp::max(len as i32, (2.0 * -y_scale) as i32),
-                                    (2.0 * y_scale) as i32,
-                                ),
-                        ),
+                        (x as i32, graph_center_y + deviation_offset(delta, y_scale)),
                         second_width,
                     )?;
                     match x.checked_sub(second_width) {
@@ -477,57 +1349,53 @@ impl Drawable<BinaryColor> for WorkoutDisplay {
                     };
                 }
 
+                // Drawn over the top of the oldest samples, just inside the
+                // max value line
                 Text::new(
-                    &goal.to_string(),
+                    &self
+                        .average_erg_error()
+                        .map_or("AVG --".to_string(), |e| format!("AVG {:+}W", e)),
                     geometry::Point::new(
-                        (graph_width + GRAPH_SPACING) as i32,
-                        graph_center_y - CHAR_HEIGHT as i32 / 2,
+                        0,
+                        graph_center_y - (y_scale * 2.0) as i32 + GRAPH_SPACING as i32,
                     ),
                 )
                 .into_styled(style_tiny)
                 .draw(target)?;
 
                 Text::new(
-                    &(goal + LINEAR_BOUNDARY * LINEAR_BOUNDARY).to_string(),
-                    geometry::Point::new(
-                        (graph_width + GRAPH_SPACING) as i32,
-                        graph_center_y - CHAR_HEIGHT as i32 / 2 - (2.0 * y_scale) as i32,
-                    ),
-                )
-                .into_styled(style_tiny)
-                .draw(target)?;
-
-                Text::new(
-                    &(goal + LINEAR_BOUNDARY).to_string(),
-                    geometry::Point::new(
-                        (graph_width + GRAPH_SPACING) as i32,
-                        graph_center_y - CHAR_HEIGHT as i32 / 2 - y_scale as i32,
-                    ),
-                )
-                .into_styled(style_tiny)
-                .draw(target)?;
-
-                Text::new(
-                    &(goal - LINEAR_BOUNDARY).to_string(),
-                    geometry::Point::new(
-                        (graph_width + GRAPH_SPACING) as i32,
-                        graph_center_y - CHAR_HEIGHT as i32 / 2 + y_scale as i32,
-                    ),
-                )
-                .into_styled(style_tiny)
-                .draw(target)?;
-
-                // TODO: It's a bit silly if this goes below 0
-                Text::new(
-                    &(goal - LINEAR_BOUNDARY * LINEAR_BOUNDARY).to_string(),
+                    &goal.to_string(),
                     geometry::Point::new(
                         (graph_width + GRAPH_SPACING) as i32,
-                        graph_center_y - CHAR_HEIGHT as i32 / 2 + (2.0 * y_scale) as i32,
+                        graph_center_y - CHAR_HEIGHT as i32 / 2,
                     ),
                 )
                 .into_styled(style_tiny)
                 .draw(target)?;
 
+                for (delta, offset) in self.power_track_label_offsets(y_scale) {
+                    Text::new(
+                        &(goal + delta).to_string(),
+                        geometry::Point::new(
+                            (graph_width + GRAPH_SPACING) as i32,
+                            graph_center_y - CHAR_HEIGHT as i32 / 2 - offset,
+                        ),
+                    )
+                    .into_styled(style_tiny)
+                    .draw(target)?;
+
+                    // TODO: It's a bit silly if this goes below 0
+                    Text::new(
+                        &(goal - delta).to_string(),
+                        geometry::Point::new(
+                            (graph_width + GRAPH_SPACING) as i32,
+                            graph_center_y - CHAR_HEIGHT as i32 / 2 + offset,
+                        ),
+                    )
+                    .into_styled(style_tiny)
+                    .draw(target)?;
+                }
+
                 let x = MARGIN;
                 let y = MARGIN;
                 Text::new("D (km)", geometry::Point::new(x, y))
@@ -583,10 +1451,79 @@ impl Drawable<BinaryColor> for WorkoutDisplay {
 
                 Ok(())
             }
+            Page::Progress => {
+                let progress = match self.progress() {
+                    Some(progress) => progress,
+                    None => return draw_text(target, Font8x16, "NO WORKOUT", MARGIN, MARGIN),
+                };
+                let width = target.size().width as i32 - 2 * MARGIN;
+
+                let y = MARGIN;
+                draw_text(target, Font6x6, "STEP LEFT", MARGIN, y)?;
+                let y = y + LABEL_FONT_SIZE;
+                let step_str = progress.step_remaining.map_or("HOLD".to_string(), min_sec_str);
+                draw_text(target, Font24x32, &step_str, MARGIN, y)?;
+
+                let y = y + 32 + SPACING;
+                if let Some(next_str) = progress_next_str(&progress) {
+                    draw_text(target, Font6x8, &next_str, MARGIN, y)?;
+                }
+
+                // The whole workout, filled in as it's ridden
+                let y = y + 8 + SPACING;
+                const BAR_HEIGHT: i32 = 12;
+                Rectangle::new(
+                    geometry::Point::new(MARGIN, y),
+                    geometry::Point::new(MARGIN + width - 1, y + BAR_HEIGHT),
+                )
+                .into_styled(
+                    PrimitiveStyleBuilder::new()
+                        .stroke_color(BinaryColor::On)
+                        .stroke_width(1)
+                        .build(),
+                )
+                .draw(target)?;
+                let filled = progress_bar_width(progress.fraction(), width);
+                if filled > 0 {
+                    Rectangle::new(
+                        geometry::Point::new(MARGIN, y),
+                        geometry::Point::new(MARGIN + filled - 1, y + BAR_HEIGHT),
+                    )
+                    .into_styled(PrimitiveStyleBuilder::new().fill_color(BinaryColor::On).build())
+                    .draw(target)?;
+                }
+
+                let y = y + BAR_HEIGHT + SPACING;
+                draw_text(target, Font6x6, "WORKOUT LEFT", MARGIN, y)?;
+                let y = y + LABEL_FONT_SIZE;
+                draw_text(target, Font8x16, &min_sec_str(progress.remaining()), MARGIN, y)
+            }
         }
     }
 }
 
+// Minutes and seconds, rounded up, so a countdown only shows 0:00 once it's
+// actually done.
+fn min_sec_str(d: Duration) -> String {
+    let secs = (d.as_millis() + 999) / 1000;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+// When the current step ends, and what's after it (there's nothing to say
+// while holding the tail).
+fn progress_next_str(progress: &Progress) -> Option<String> {
+    let remaining = min_sec_str(progress.step_remaining?);
+    Some(match progress.o_next {
+        Some(next) => format!("next: {}W in {}", next, remaining),
+        None => format!("done in {}", remaining),
+    })
+}
+
+// How much of a bar this wide is filled in (in pixels)
+fn progress_bar_width(fraction: f64, width: i32) -> i32 {
+    (fraction.max(0.0).min(1.0) * width as f64).round() as i32
+}
+
 pub struct MsgDisplay<'a>(&'a str);
 
 impl<'a> MsgDisplay<'a> {
@@ -654,6 +1591,184 @@ impl<'a> Drawable<BinaryColor> for MsgDisplay<'a> {
     }
 }
 
+#[derive(Clone, Debug)]
+struct Toast {
+    msg: String,
+    expires: Instant,
+}
+
+impl Toast {
+    fn new(msg: &str, expires: Instant) -> Toast {
+        Toast {
+            msg: msg.to_string(),
+            expires,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        now >= self.expires
+    }
+}
+
+fn interval_summary_msg(summary: &IntervalSummary) -> String {
+    let secs = summary.duration.as_secs();
+    format!(
+        "INTERVAL {:02}:{:02}\nAVG {}W\nAVG HR {}",
+        secs / 60,
+        secs % 60,
+        summary.average_power.map_or("---".to_string(), |x| x.to_string()),
+        summary.average_heart_rate.map_or("---".to_string(), |x| x.to_string()),
+    )
+}
+
+// Lines in a box across the middle of the screen.  Anything that doesn't fit
+// is cut off, so toasts should be short.
+pub struct ToastDisplay<'a>(&'a str);
+
+impl<'a> ToastDisplay<'a> {
+    pub fn new(msg: &'a str) -> ToastDisplay<'a> {
+        ToastDisplay(msg)
+    }
+}
+
+impl<'a> Drawable<BinaryColor> for ToastDisplay<'a> {
+    fn draw<D: DrawTarget<BinaryColor>>(self, target: &mut D) -> Result<(), D::Error> {
+        let style_large = TextStyleBuilder::new(Font8x16)
+            .text_color(BinaryColor::On)
+            .background_color(BinaryColor::Off)
+            .build();
+
+        let Size { height, width } = target.size();
+        const PADDING: i32 = 4;
+        const LINE_HEIGHT: i32 = 16;
+        let max_chars = (width as i32 - 2 * PADDING) / 8;
+        let lines: Vec<String> = self
+            .0
+            .lines()
+            .map(|line| line.chars().take(max_chars as usize).collect())
+            .collect();
+        let text_height = LINE_HEIGHT * lines.len() as i32;
+        let top = (height as i32 - text_height) / 2 - PADDING;
+        let bottom = top + text_height + 2 * PADDING;
+
+        Rectangle::new(
+            geometry::Point::new(0, top),
+            geometry::Point::new(width as i32 - 1, bottom),
+        )
+        .into_styled(
+            PrimitiveStyleBuilder::new()
+                .fill_color(BinaryColor::Off)
+                .stroke_color(BinaryColor::On)
+                .stroke_width(1)
+                .build(),
+        )
+        .draw(target)?;
+
+        for (i, line) in lines.iter().enumerate() {
+            let x = (width as i32 - 8 * line.chars().count() as i32) / 2;
+            Text::new(line, geometry::Point::new(x, top + PADDING + LINE_HEIGHT * i as i32))
+                .into_styled(style_large)
+                .draw(target)?;
+        }
+        Ok(())
+    }
+}
+
+// The total time and peak power of a workout (or what it holds if it's just a
+// tail).
+fn workout_preview_summary(profile: &[(Duration, u16)], tail: Option<u16>) -> String {
+    let secs: u64 = profile.iter().map(|(d, _)| d.as_secs()).sum();
+    let max_power = profile.iter().map(|(_, p)| *p).chain(tail).max().unwrap_or(0);
+    if profile.is_empty() {
+        format!("HOLD {}W", max_power)
+    } else {
+        format!("{:02}:{:02} MAX {}W", secs / 60, secs % 60, max_power)
+    }
+}
+
+// Each step of a workout as a bar (left, right, and height in pixels), with
+// time across the width and power up the height.
+fn workout_preview_bars(
+    profile: &[(Duration, u16)],
+    width: u32,
+    height: u32,
+) -> Vec<(i32, i32, i32)> {
+    let total: u128 = profile.iter().map(|(d, _)| d.as_millis()).sum();
+    let max_power = profile.iter().map(|(_, p)| *p as u32).max().unwrap_or(0);
+    if total == 0 || max_power == 0 {
+        return vec![];
+    }
+    let mut elapsed: u128 = 0;
+    profile
+        .iter()
+        .map(|(d, p)| {
+            let left = (elapsed * width as u128 / total) as i32;
+            elapsed += d.as_millis();
+            let right = (elapsed * width as u128 / total) as i32;
+            (left, right, (*p as u32 * height / max_power) as i32)
+        })
+        .collect()
+}
+
+pub struct WorkoutPreview<'a> {
+    profile: &'a [(Duration, u16)],
+    tail: Option<u16>,
+}
+
+impl<'a> WorkoutPreview<'a> {
+    pub fn new(profile: &'a [(Duration, u16)], tail: Option<u16>) -> WorkoutPreview<'a> {
+        WorkoutPreview { profile, tail }
+    }
+}
+
+impl<'a> Drawable<BinaryColor> for WorkoutPreview<'a> {
+    fn draw<D: DrawTarget<BinaryColor>>(self, target: &mut D) -> Result<(), D::Error> {
+        let style_large = TextStyleBuilder::new(Font8x16)
+            .text_color(BinaryColor::On)
+            .background_color(BinaryColor::Off)
+            .build();
+        let style_tiny = TextStyleBuilder::new(Font6x6)
+            .text_color(BinaryColor::On)
+            .background_color(BinaryColor::Off)
+            .build();
+
+        // Matches the OptionDisplay, so the numbers line up with the buttons
+        for (i, label) in ["1: Start", "2: Back"].iter().enumerate() {
+            Text::new(&format!("{}", i + 1), geometry::Point::new(42 + i as i32 * 37, 2))
+                .into_styled(style_large)
+                .draw(target)?;
+            Text::new(label, geometry::Point::new(10 + i as i32 * 72, 22))
+                .into_styled(style_large)
+                .draw(target)?;
+        }
+
+        const MARGIN: i32 = 10;
+        const GRAPH_TOP: i32 = 44;
+        const GRAPH_BOTTOM: i32 = 140;
+        let width = target.size().width as i32 - 2 * MARGIN;
+        let graph_height = GRAPH_BOTTOM - GRAPH_TOP;
+        let bars = workout_preview_bars(self.profile, width as u32, graph_height as u32);
+        for (left, right, height) in bars {
+            // Bars are never less than a pixel wide, so short steps still show
+            Rectangle::new(
+                geometry::Point::new(MARGIN + left, GRAPH_BOTTOM - height),
+                geometry::Point::new(MARGIN + i32::max(right - 1, left), GRAPH_BOTTOM),
+            )
+            .into_styled(PrimitiveStyleBuilder::new().fill_color(BinaryColor::On).build())
+            .draw(target)?;
+        }
+
+        Text::new(
+            &workout_preview_summary(self.profile, self.tail),
+            geometry::Point::new(MARGIN, GRAPH_BOTTOM + 4),
+        )
+        .into_styled(style_tiny)
+        .draw(target)?;
+
+        Ok(())
+    }
+}
+
 pub struct OptionDisplay<'a, 'b, 'c> {
     label: &'c str,
     options: &'a [&'b str],
@@ -697,8 +1812,43 @@ impl<'a, 'b, 'c> Drawable<BinaryColor> for OptionDisplay<'a, 'b, 'c> {
     }
 }
 
+// How far from the reference line (in pixels, positive is down) a deviation
+// from the goal is drawn on the PowerTrack page.
+fn deviation_len(delta: i16, y_scale: f64) -> f64 {
+    let magnitude = delta.abs();
+    y_scale
+        * (if magnitude > LINEAR_BOUNDARY {
+            (magnitude as f64).log(LINEAR_BOUNDARY as f64)
+        } else {
+            magnitude as f64 / LINEAR_BOUNDARY as f64
+        })
+        * (if delta > 0 { -1.0 } else { 1.0 })
+}
+
+// The same, but clamped to the max and min value lines
+fn deviation_offset(delta: i16, y_scale: f64) -> i32 {
+    let len = deviation_len(delta, y_scale);
+    std::cmp::min(
+        std::cmp::max(len as i32, (2.0 * -y_scale) as i32),
+        (2.0 * y_scale) as i32,
+    )
+}
+
+// Minutes and seconds per km, from m/s.  Standing still (or close to it)
+// would be an absurd pace, so we don't show one.
+fn pace_str(o_speed: Option<f32>) -> String {
+    match o_speed {
+        Some(speed) if speed >= 0.5 => {
+            let secs_per_km = (1000.0 / speed).round() as u32;
+            // Must always be 6 characters, so that new values clear the previous
+            format!("{:02}:{:02} ", secs_per_km / 60, secs_per_km % 60)
+        }
+        _ => "--:-- ".to_string(),
+    }
+}
+
 fn none_if_stale<T>(x: (T, Instant)) -> Option<(T, Instant)> {
-    if x.1.elapsed() > Duration::from_secs(5) {
+    if x.1.elapsed() > STALE_AFTER {
         None
     } else {
         Some(x)
@@ -712,3 +1862,611 @@ fn metabolic_cost_in_kcal(external_energy: f64, crank_revolutions: u32) -> f64 {
     let ml_of_oxygen = 10.38 / 60.0 * external_energy + 4.9 * crank_revolutions as f64;
     ml_of_oxygen / 1000.0 * 4.74
 }
+
+#[cfg(test)]
+mod tests {
+    use super::battery_glyph_fill;
+    use super::interval_summary_msg;
+    use super::pace_str;
+    use super::page_for_target;
+    use super::{min_sec_str, progress_bar_width, progress_next_str};
+    use super::parse_layout;
+    use super::parse_stale_policies;
+    use super::standard_slots;
+    use super::{workout_preview_bars, workout_preview_summary};
+    use super::FieldKind;
+    use super::DEFAULT_LAYOUT;
+    use super::DEFAULT_LARGE_LAYOUT;
+    use super::LINEAR_BOUNDARY;
+    use super::OptionDisplay;
+    use super::Page;
+    use super::Scale;
+    use super::StalePolicy;
+    use super::TargetChangePage;
+    use super::Toast;
+    use super::Units;
+    use super::VERSION_Y;
+    use super::WorkoutDisplay;
+    use crate::workout::{IntervalSummary, Progress};
+    use embedded_graphics::{
+        drawable::{Drawable, Pixel},
+        fonts::{Font8x16, Text},
+        geometry::{Point, Size},
+        pixelcolor::BinaryColor,
+        style::TextStyleBuilder,
+        DrawTarget,
+    };
+    use std::time::{Duration, Instant};
+
+    const CANVAS_WIDTH: i32 = 144;
+    const CANVAS_HEIGHT: i32 = 168;
+
+    // An in-memory screen, so what's drawn can be checked without hardware
+    struct Canvas {
+        pixels: Vec<BinaryColor>,
+    }
+
+    impl Canvas {
+        fn new() -> Canvas {
+            Canvas { pixels: vec![BinaryColor::Off; (CANVAS_WIDTH * CANVAS_HEIGHT) as usize] }
+        }
+
+        fn region(&self, x: i32, y: i32, width: i32, height: i32) -> Vec<BinaryColor> {
+            (y..y + height)
+                .flat_map(|y| (x..x + width).map(move |x| (x, y)))
+                .map(|(x, y)| self.pixels[(y * CANVAS_WIDTH + x) as usize])
+                .collect()
+        }
+    }
+
+    impl DrawTarget<BinaryColor> for Canvas {
+        type Error = core::convert::Infallible;
+
+        fn draw_pixel(&mut self, Pixel(p, color): Pixel<BinaryColor>) -> Result<(), Self::Error> {
+            if (0..CANVAS_WIDTH).contains(&p.x) && (0..CANVAS_HEIGHT).contains(&p.y) {
+                self.pixels[(p.y * CANVAS_WIDTH + p.x) as usize] = color;
+            }
+            Ok(())
+        }
+
+        fn size(&self) -> Size {
+            Size::new(CANVAS_WIDTH as u32, CANVAS_HEIGHT as u32)
+        }
+    }
+
+    // Large text drawn by itself, to compare against part of a drawable
+    fn large_text_alone(text: &str, x: i32, y: i32) -> Canvas {
+        let mut canvas = Canvas::new();
+        Text::new(text, Point::new(x, y))
+            .into_styled(
+                TextStyleBuilder::new(Font8x16)
+                    .text_color(BinaryColor::On)
+                    .background_color(BinaryColor::Off)
+                    .build(),
+            )
+            .draw(&mut canvas)
+            .unwrap();
+        canvas
+    }
+
+    fn draw_options(options: &[&str]) -> Canvas {
+        let mut canvas = Canvas::new();
+        OptionDisplay::new("Mode", options).draw(&mut canvas).unwrap();
+        canvas
+    }
+
+    // Feeds power readings one second apart
+    fn record_power(workout: &mut WorkoutDisplay, start: Instant, powers: &[i16]) {
+        workout.resample_power_history(start);
+        for (i, power) in powers.iter().enumerate() {
+            workout.update_power(Some(*power));
+            workout.resample_power_history(start + Duration::from_secs(i as u64 + 1));
+        }
+    }
+
+    #[test]
+    fn power_history_deltas_keep_the_goal_of_each_sample() {
+        let now = Instant::now();
+        let mut workout = WorkoutDisplay::new();
+        workout.resample_power_history(now);
+        workout.set_page(Page::PowerTrack(200));
+        workout.update_power(Some(210));
+        workout.resample_power_history(now + Duration::from_secs(1));
+        workout.set_page(Page::PowerTrack(205));
+        workout.update_power(Some(205));
+        workout.resample_power_history(now + Duration::from_secs(2));
+        workout.set_page(Page::Standard);
+        workout.update_power(Some(200));
+        workout.resample_power_history(now + Duration::from_secs(3));
+
+        assert_eq!(vec![-5, 0, 10], workout.power_history_deltas(205)[..3].to_vec());
+    }
+
+    #[test]
+    fn power_history_deltas_use_the_current_goal_when_unknown() {
+        let mut workout = WorkoutDisplay::new();
+        record_power(&mut workout, Instant::now(), &[180]);
+        workout.set_page(Page::PowerTrack(200));
+
+        let deltas = workout.power_history_deltas(200);
+        assert_eq!(-20, deltas[0]);
+        assert_eq!(-200, deltas[1]);
+    }
+
+    #[test]
+    fn average_erg_error_over_the_window() {
+        let mut workout = WorkoutDisplay::new();
+        workout.set_erg_error_window(3);
+        workout.set_page(Page::PowerTrack(200));
+        record_power(&mut workout, Instant::now(), &[100, 190, 205, 195]);
+
+        assert_eq!(Some(-3), workout.average_erg_error());
+    }
+
+    #[test]
+    fn average_erg_error_only_counts_samples_with_a_reading_and_a_goal() {
+        let now = Instant::now();
+        let mut workout = WorkoutDisplay::new();
+        workout.set_erg_error_window(10);
+        assert_eq!(None, workout.average_erg_error());
+
+        // Riding without a goal doesn't count
+        record_power(&mut workout, now, &[100, 100]);
+        assert_eq!(None, workout.average_erg_error());
+
+        workout.set_page(Page::PowerTrack(200));
+        workout.update_power(Some(190));
+        workout.resample_power_history(now + Duration::from_secs(3));
+        // Nor do the seconds once the reading goes stale
+        workout.resample_power_history(now + Duration::from_secs(9));
+        assert_eq!(Some(-10), workout.average_erg_error());
+    }
+
+    #[test]
+    fn power_history_is_not_advanced_by_each_reading() {
+        let now = Instant::now();
+        let mut workout = WorkoutDisplay::new();
+        workout.set_page(Page::PowerTrack(200));
+        workout.resample_power_history(now);
+        // Two sources reporting within the same second
+        workout.update_power(Some(150));
+        workout.update_power(Some(210));
+        workout.resample_power_history(now + Duration::from_millis(500));
+        workout.resample_power_history(now + Duration::from_millis(1500));
+
+        assert_eq!(vec![10, -200], workout.power_history_deltas(200)[..2].to_vec());
+    }
+
+    #[test]
+    fn power_history_fills_every_elapsed_second() {
+        let now = Instant::now();
+        let mut workout = WorkoutDisplay::new();
+        workout.resample_power_history(now);
+        workout.update_power(Some(180));
+        workout.resample_power_history(now + Duration::from_secs(3));
+
+        assert_eq!(vec![-20, -20, -20, -200], workout.power_history_deltas(200)[..4].to_vec());
+    }
+
+    #[test]
+    fn toast_expires() {
+        let now = Instant::now();
+        let toast = Toast::new("Offset +5W", now + Duration::from_secs(1));
+        assert!(!toast.is_expired(now));
+        assert!(toast.is_expired(now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn gps_fix_is_kept_through_a_brief_drop() {
+        let mut workout = WorkoutDisplay::new();
+        workout.set_gps_fix(true);
+        workout.set_gps_fix(false);
+        workout.set_gps_fix(false);
+        assert_eq!(Some(true), workout.gps_fix.map(|x| x.0));
+        workout.set_gps_fix(false);
+        assert_eq!(Some(false), workout.gps_fix.map(|x| x.0));
+    }
+
+    #[test]
+    fn power_track_labels_default_to_the_scaling_boundaries() {
+        let workout = WorkoutDisplay::new();
+        assert_eq!(
+            workout.power_track_label_offsets(22.0),
+            vec![(LINEAR_BOUNDARY, 22), (LINEAR_BOUNDARY * LINEAR_BOUNDARY, 44)]
+        );
+    }
+
+    #[test]
+    fn power_track_labels_are_placed_on_the_scale() {
+        let mut workout = WorkoutDisplay::new();
+        workout.set_power_track_labels(vec![5, 10, 50]);
+        assert_eq!(
+            workout.power_track_label_offsets(22.0),
+            vec![(5, 11), (10, 22), (50, 37)]
+        );
+    }
+
+    #[test]
+    fn power_track_labels_skip_overlaps_and_off_graph_values() {
+        let mut workout = WorkoutDisplay::new();
+        workout.set_power_track_labels(vec![30, 20, 10, 1000]);
+        assert_eq!(
+            workout.power_track_label_offsets(22.0),
+            vec![(10, 22), (20, 28)]
+        );
+    }
+
+    #[test]
+    fn heart_rate_field_shows_rates_over_255() {
+        let mut workout = WorkoutDisplay::new();
+        workout.update_heart_rate(Some(266));
+        assert_eq!("266", workout.field(FieldKind::HeartRate).1);
+    }
+
+    #[test]
+    fn battery_glyph_fills_with_the_level() {
+        assert_eq!(0, battery_glyph_fill(0));
+        assert_eq!(1, battery_glyph_fill(10));
+        assert_eq!(5, battery_glyph_fill(50));
+        assert_eq!(9, battery_glyph_fill(100));
+    }
+
+    #[test]
+    fn battery_glyph_is_only_drawn_with_a_level() {
+        let glyph = |battery| {
+            let mut workout = WorkoutDisplay::new();
+            workout.update_battery(battery);
+            let mut canvas = Canvas::new();
+            workout.draw(&mut canvas).unwrap();
+            canvas.region(CANVAS_WIDTH - 14, 0, 14, 10)
+        };
+        assert!(glyph(None).iter().all(|p| *p == BinaryColor::Off));
+        let full = glyph(Some(100));
+        let empty = glyph(Some(0));
+        assert!(empty.iter().any(|p| *p == BinaryColor::On));
+        assert!(
+            full.iter().filter(|p| **p == BinaryColor::On).count()
+                > empty.iter().filter(|p| **p == BinaryColor::On).count()
+        );
+    }
+
+    #[test]
+    fn w_bal_bar_is_drawn_below_the_version() {
+        let mut workout = WorkoutDisplay::new();
+        workout.update_w_bal(0.5);
+        let mut canvas = Canvas::new();
+        workout.draw(&mut canvas).unwrap();
+        let version_row = canvas.region(0, VERSION_Y, CANVAS_WIDTH, 6);
+        assert!(version_row.iter().all(|p| *p == BinaryColor::Off));
+        let bar = canvas.region(0, VERSION_Y + 6, CANVAS_WIDTH, CANVAS_HEIGHT - VERSION_Y - 6);
+        assert!(bar.iter().any(|p| *p == BinaryColor::On));
+    }
+
+    #[test]
+    fn heart_rate_is_marked_when_the_strap_loses_contact() {
+        let mut workout = WorkoutDisplay::new();
+        workout.update_heart_rate(Some(150));
+        assert_eq!("150 ", workout.heart_rate_str());
+        workout.set_hr_sensor_contact(Some(true));
+        assert_eq!("150 ", workout.heart_rate_str());
+        workout.set_hr_sensor_contact(Some(false));
+        assert_eq!("150!", workout.heart_rate_str());
+        workout.update_heart_rate(None);
+        assert_eq!("---!", workout.heart_rate_str());
+    }
+
+    #[test]
+    fn status_line_shows_fresh_values() {
+        let mut workout = WorkoutDisplay::new();
+        workout.update_power(Some(250));
+        workout.update_heart_rate(Some(150));
+        workout.update_speed(Some(10.0));
+        assert_eq!(
+            "PWR 250W  HR 150  CAD --- RPM  V (km/h) 36.00",
+            workout.status_line()
+        );
+    }
+
+    #[test]
+    fn target_change_switches_to_power_track() {
+        assert_eq!(
+            Page::PowerTrack(250),
+            page_for_target(Page::Standard, 250, TargetChangePage::PowerTrack)
+        );
+        assert_eq!(
+            Page::PowerTrack(250),
+            page_for_target(
+                Page::PowerTrack(200),
+                250,
+                TargetChangePage::AutoReturn(Duration::from_secs(5))
+            )
+        );
+    }
+
+    #[test]
+    fn target_change_can_stay_on_the_standard_page() {
+        assert_eq!(
+            Page::Standard,
+            page_for_target(Page::Standard, 250, TargetChangePage::Stay)
+        );
+        // But an already shown PowerTrack follows the new target
+        assert_eq!(
+            Page::PowerTrack(250),
+            page_for_target(Page::PowerTrack(200), 250, TargetChangePage::Stay)
+        );
+    }
+
+    #[test]
+    fn parse_layout_of_field_names() {
+        assert_eq!(
+            Some(vec![FieldKind::HeartRate, FieldKind::Energy, FieldKind::Power]),
+            parse_layout("hr, energy,power")
+        );
+    }
+
+    #[test]
+    fn parse_layout_rejects_unknown_fields() {
+        assert_eq!(None, parse_layout("distance,np"));
+        assert_eq!(None, parse_layout(""));
+    }
+
+    #[test]
+    fn default_layout_fills_every_slot() {
+        assert_eq!(standard_slots(Scale::Normal).0.len(), DEFAULT_LAYOUT.len());
+        assert_eq!(standard_slots(Scale::Large).0.len(), DEFAULT_LARGE_LAYOUT.len());
+    }
+
+    #[test]
+    fn large_slots_fit_above_the_version() {
+        let (slots, lost_y) = standard_slots(Scale::Large);
+        let (_, last_y, _) = slots[slots.len() - 1];
+        assert!(last_y + 8 + 2 + 32 <= lost_y);
+        // The lost devices line, and then the version text
+        assert!(lost_y + 6 <= VERSION_Y);
+    }
+
+    #[test]
+    fn target_change_page_from_name() {
+        assert_eq!(Some(TargetChangePage::PowerTrack), TargetChangePage::parse("power-track"));
+        assert_eq!(Some(TargetChangePage::Stay), TargetChangePage::parse("stay"));
+        assert_eq!(
+            Some(TargetChangePage::AutoReturn(Duration::from_secs(20))),
+            TargetChangePage::parse("auto-return:20")
+        );
+        assert_eq!(None, TargetChangePage::parse("auto-return"));
+        assert_eq!(None, TargetChangePage::parse("progress"));
+    }
+
+    #[test]
+    fn scale_from_name() {
+        assert_eq!(Some(Scale::Large), Scale::parse("large"));
+        assert_eq!(Some(Scale::Normal), Scale::parse("normal"));
+        assert_eq!(None, Scale::parse("huge"));
+    }
+
+    #[test]
+    fn fields_are_labeled_and_padded() {
+        let mut workout = WorkoutDisplay::new();
+        workout.update_power(Some(95));
+        assert_eq!(("POW (W)", "095".to_string()), workout.field(FieldKind::Power));
+        assert_eq!(("HR (BPM)", "---".to_string()), workout.field(FieldKind::HeartRate));
+        assert_eq!(("GPS", "NO GPS".to_string()), workout.field(FieldKind::Gps));
+    }
+
+    #[test]
+    fn parse_stale_policies_of_fields() {
+        assert_eq!(
+            Some(vec![
+                (FieldKind::Power, StalePolicy::Hold),
+                (FieldKind::HeartRate, StalePolicy::HoldWithMarker),
+            ]),
+            parse_stale_policies("power:hold, hr:marker")
+        );
+        assert_eq!(None, parse_stale_policies("power:dim"));
+        assert_eq!(None, parse_stale_policies("power"));
+    }
+
+    #[test]
+    fn stale_readings_follow_the_field_policy() {
+        let mut workout = WorkoutDisplay::new();
+        workout.set_stale_policies(vec![
+            (FieldKind::Power, StalePolicy::Hold),
+            (FieldKind::HeartRate, StalePolicy::HoldWithMarker),
+        ]);
+        let stale = Instant::now() - Duration::from_secs(10);
+        workout.power = Some((95, stale));
+        workout.heart_rate = Some((120, stale));
+        workout.cadence = Some((90, stale));
+        assert_eq!("095", workout.field(FieldKind::Power).1);
+        assert_eq!("120?", workout.field(FieldKind::HeartRate).1);
+        // Blank is the default
+        assert_eq!("---", workout.field(FieldKind::Cadence).1);
+    }
+
+    #[test]
+    fn fresh_readings_clear_the_stale_marker() {
+        let mut workout = WorkoutDisplay::new();
+        workout.set_stale_policies(vec![(FieldKind::HeartRate, StalePolicy::HoldWithMarker)]);
+        workout.update_heart_rate(Some(120));
+        assert_eq!("120 ", workout.field(FieldKind::HeartRate).1);
+    }
+
+    #[test]
+    fn speed_field_is_pace_when_running() {
+        let mut workout = WorkoutDisplay::new();
+        workout.set_running(true);
+        assert_eq!("PACE (/km)", workout.field(FieldKind::Speed).0);
+        assert_eq!("CAD (SPM)", workout.field(FieldKind::Cadence).0);
+    }
+
+    #[test]
+    fn status_line_uses_the_units() {
+        let mut workout = WorkoutDisplay::new();
+        workout.set_units(Units::Imperial);
+        workout.update_speed(Some(1609.344 / 60.0 / 60.0 * 20.0));
+        assert_eq!(
+            "PWR ---W  HR ---  CAD --- RPM  V (mph) 20.00",
+            workout.status_line()
+        );
+    }
+
+    #[test]
+    fn speed_is_averaged_over_the_smoothing_window() {
+        let mut workout = WorkoutDisplay::new();
+        workout.set_speed_smoothing(Duration::from_secs(3));
+        let start = Instant::now();
+        workout.record_speed(Some(10.0), start);
+        workout.record_speed(Some(20.0), start + Duration::from_secs(1));
+        assert_eq!(Some(15.0), workout.smoothed_speed());
+        workout.record_speed(Some(30.0), start + Duration::from_secs(5));
+        assert_eq!(Some(30.0), workout.smoothed_speed());
+    }
+
+    #[test]
+    fn no_speed_clears_the_smoothing() {
+        let mut workout = WorkoutDisplay::new();
+        let start = Instant::now();
+        workout.record_speed(Some(10.0), start);
+        workout.record_speed(None, start);
+        assert_eq!(None, workout.smoothed_speed());
+        workout.record_speed(Some(20.0), start);
+        assert_eq!(Some(20.0), workout.smoothed_speed());
+    }
+
+    #[test]
+    fn status_line_shows_pace_when_running() {
+        let mut workout = WorkoutDisplay::new();
+        workout.set_running(true);
+        workout.update_cadence(Some(170));
+        workout.update_speed(Some(1000.0 / 300.0));
+        assert_eq!(
+            "PWR ---W  HR ---  CAD 170 SPM  PACE 05:00/km",
+            workout.status_line()
+        );
+    }
+
+    #[test]
+    fn interval_summary_msg_shows_each_average() {
+        let summary = IntervalSummary {
+            duration: Duration::from_secs(245),
+            average_power: Some(301),
+            average_heart_rate: None,
+        };
+        assert_eq!(
+            "INTERVAL 04:05\nAVG 301W\nAVG HR ---",
+            interval_summary_msg(&summary)
+        );
+    }
+
+    #[test]
+    fn workout_preview_bars_span_the_width_and_scale_to_the_max() {
+        let profile = [
+            (Duration::from_secs(60), 100),
+            (Duration::from_secs(30), 200),
+            (Duration::from_secs(30), 50),
+        ];
+        assert_eq!(
+            vec![(0, 60, 50), (60, 90, 100), (90, 120, 25)],
+            workout_preview_bars(&profile, 120, 100)
+        );
+    }
+
+    #[test]
+    fn workout_preview_bars_of_an_empty_profile() {
+        assert_eq!(Vec::<(i32, i32, i32)>::new(), workout_preview_bars(&[], 120, 100));
+    }
+
+    #[test]
+    fn workout_preview_summary_shows_duration_and_max() {
+        let profile = [(Duration::from_secs(300), 140), (Duration::from_secs(90), 320)];
+        assert_eq!("06:30 MAX 320W", workout_preview_summary(&profile, Some(160)));
+        assert_eq!("HOLD 150W", workout_preview_summary(&[], Some(150)));
+    }
+
+    #[test]
+    fn min_sec_str_rounds_up() {
+        assert_eq!("0:45", min_sec_str(Duration::from_secs(45)));
+        assert_eq!("0:45", min_sec_str(Duration::from_millis(44_200)));
+        assert_eq!("12:00", min_sec_str(Duration::from_secs(720)));
+        assert_eq!("0:00", min_sec_str(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn progress_next_str_shows_the_next_target() {
+        let progress = Progress {
+            total: Duration::from_secs(600),
+            elapsed: Duration::from_secs(135),
+            step_remaining: Some(Duration::from_secs(45)),
+            o_next: Some(320),
+        };
+        assert_eq!(Some("next: 320W in 0:45".to_string()), progress_next_str(&progress));
+        let last = Progress { o_next: None, ..progress };
+        assert_eq!(Some("done in 0:45".to_string()), progress_next_str(&last));
+        let tail = Progress { step_remaining: None, o_next: None, ..progress };
+        assert_eq!(None, progress_next_str(&tail));
+    }
+
+    #[test]
+    fn progress_bar_width_is_clamped() {
+        assert_eq!(0, progress_bar_width(0.0, 124));
+        assert_eq!(62, progress_bar_width(0.5, 124));
+        assert_eq!(124, progress_bar_width(1.5, 124));
+    }
+
+    #[test]
+    fn page_for_target_stays_on_progress() {
+        assert_eq!(
+            Page::Progress,
+            page_for_target(Page::Progress, 250, TargetChangePage::Stay)
+        );
+    }
+
+    #[test]
+    fn pace_str_from_speed() {
+        assert_eq!("05:00 ", pace_str(Some(1000.0 / 300.0)));
+        assert_eq!("--:-- ", pace_str(Some(0.1)));
+        assert_eq!("--:-- ", pace_str(None));
+    }
+
+    #[test]
+    fn options_are_numbered_along_the_top() {
+        let canvas = draw_options(&["Ride", "Run", "Sim"]);
+        for i in 1..=3 {
+            let x = 42 + (i - 1) * 37;
+            let number = large_text_alone(&i.to_string(), x, 2);
+            assert_eq!(number.region(x, 2, 8, 16), canvas.region(x, 2, 8, 16));
+            assert!(canvas.region(x, 2, 8, 16).contains(&BinaryColor::On));
+        }
+    }
+
+    #[test]
+    fn options_are_listed_below_the_label() {
+        let options = ["Ride", "Run", "Sim"];
+        let canvas = draw_options(&options);
+        for (i, option) in options.iter().enumerate() {
+            let text = format!("{}: {}", i + 1, option);
+            let y = (i as i32 + 1) * 16 + 22;
+            let width = 8 * text.len() as i32;
+            let row = large_text_alone(&text, 10, y);
+            assert_eq!(row.region(10, y, width, 16), canvas.region(10, y, width, 16));
+        }
+        // Nothing is drawn for a fourth option
+        assert!(!canvas.region(0, 4 * 16 + 22, CANVAS_WIDTH, 16).contains(&BinaryColor::On));
+    }
+
+    #[test]
+    fn only_given_options_are_numbered() {
+        let canvas = draw_options(&["Ride"]);
+        assert!(canvas.region(42, 2, 8, 16).contains(&BinaryColor::On));
+        assert!(!canvas.region(79, 2, CANVAS_WIDTH - 79, 16).contains(&BinaryColor::On));
+    }
+
+    #[test]
+    fn gps_fix_is_shown_immediately() {
+        let mut workout = WorkoutDisplay::new();
+        workout.set_gps_fix(false);
+        assert_eq!(Some(false), workout.gps_fix.map(|x| x.0));
+        workout.set_gps_fix(true);
+        assert_eq!(Some(true), workout.gps_fix.map(|x| x.0));
+    }
+}
@@ -1,11 +1,64 @@
 // This is just a quick port of the original JS I had written--there's room for
 // improvement
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+// FIT power is unsigned, but a power meter may report negative power (from
+// braking, regen, or just noise around zero).  Rather than letting that wrap
+// around to an absurd wattage, it's recorded as zero.  The signed value is
+// still kept in the DB, and shown as is on the display.
+pub fn power_from_signed(power: i16) -> u16 {
+    i16::max(power, 0) as u16
+}
+
+// FIT heart rate is a single byte (where 255 means there's no value), while
+// a monitor may report more in its 16 bit format, so that's recorded as the
+// most FIT can hold, rather than wrapping around.
+pub fn heart_rate_from_bpm(bpm: u16) -> u8 {
+    u16::min(bpm, 254) as u8
+}
+
+// Seconds since the start of a recording, for exporting only part of it.
+// Either end may be left open.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TimeWindow {
+    pub start: Option<u32>,
+    pub end: Option<u32>,
+}
+
+// Only the records within the (inclusive) window of a recording that started
+// at start_time.  Distance is made relative to the start of the window, so the
+// cropped file's totals are only for the window itself.  Records before the
+// first distance in the window don't have one either, so only later distances
+// need to be adjusted.
+pub fn crop_iter(
+    records: impl Iterator<Item = FitRecord>,
+    start_time: u32,
+    window: TimeWindow,
+) -> impl Iterator<Item = FitRecord> {
+    let mut o_first_distance = None;
+    records
+        .filter(move |r| {
+            let elapsed = r.seconds_since_unix_epoch.saturating_sub(start_time);
+            window.start.map_or(true, |s| elapsed >= s) && window.end.map_or(true, |e| elapsed <= e)
+        })
+        .map(move |mut r| {
+            if window.start.is_some() {
+                if let Some(d) = r.distance {
+                    let first_distance = *o_first_distance.get_or_insert(d);
+                    r.distance = Some(d - first_distance);
+                }
+            }
+            r
+        })
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct FitRecord {
     // We use the same bitdepth, but not the same epoch
     pub seconds_since_unix_epoch: u32,
-    // Wattage
+    // Wattage, which FIT only allows to be positive (see power_from_signed)
     pub power: Option<u16>,
     // BPM
     pub heart_rate: Option<u8>,
@@ -21,6 +74,190 @@ pub struct FitRecord {
     pub distance: Option<f64>,
     // Instantaneous speed in meters/s
     pub speed: Option<f32>,
+    // Percent of power from one pedal (which one isn't known).  This isn't
+    // written to FIT files yet, but is available to other exports.
+    pub pedal_power_balance: Option<f32>,
+}
+
+impl FitRecord {
+    // Just the time, with nothing recorded for it yet
+    pub fn new(seconds_since_unix_epoch: u32) -> FitRecord {
+        FitRecord {
+            seconds_since_unix_epoch,
+            power: None,
+            heart_rate: None,
+            cadence: None,
+            latitude: None,
+            longitude: None,
+            altitude: None,
+            distance: None,
+            speed: None,
+            pedal_power_balance: None,
+        }
+    }
+}
+
+// Totals for a whole ride, which is what apps show for it (rather than
+// working them out from the records).
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionSummary {
+    pub start_time: u32,
+    pub end_time: u32,
+    // Seconds from the first record through the last
+    pub total_elapsed_time: u32,
+    // Seconds that have a record, so gaps (like pauses) aren't counted
+    pub total_timer_time: u32,
+    // Meters
+    pub total_distance: Option<f64>,
+    pub avg_power: Option<u16>,
+    pub max_power: Option<u16>,
+    pub avg_heart_rate: Option<u8>,
+    pub max_heart_rate: Option<u8>,
+    pub avg_cadence: Option<u8>,
+    pub max_cadence: Option<u8>,
+}
+
+// What a summary is made from, kept as records go by so that they don't all
+// need to be held at once.  Averages are only over the records that have that
+// value, and records are expected to be one per second.
+#[derive(Default)]
+struct SummaryTotals {
+    start_time: Option<u32>,
+    end_time: u32,
+    count: u32,
+    // Distance is cumulative, so this is just the furthest
+    total_distance: Option<f64>,
+    power: MeanAndMax,
+    heart_rate: MeanAndMax,
+    cadence: MeanAndMax,
+}
+
+impl SummaryTotals {
+    fn add(&mut self, record: &FitRecord) {
+        self.start_time.get_or_insert(record.seconds_since_unix_epoch);
+        self.end_time = record.seconds_since_unix_epoch;
+        self.count += 1;
+        if let Some(d) = record.distance {
+            self.total_distance = Some(self.total_distance.map_or(d, |m| m.max(d)));
+        }
+        self.power.add(record.power.map(u32::from));
+        self.heart_rate.add(record.heart_rate.map(u32::from));
+        self.cadence.add(record.cadence.map(u32::from));
+    }
+
+    fn summary(&self) -> SessionSummary {
+        let start_time = self.start_time.unwrap_or(0);
+        let power = self.power.get();
+        let heart_rate = self.heart_rate.get();
+        let cadence = self.cadence.get();
+        SessionSummary {
+            start_time,
+            end_time: self.end_time,
+            total_elapsed_time: if self.count == 0 { 0 } else { self.end_time - start_time + 1 },
+            total_timer_time: self.count,
+            total_distance: self.total_distance,
+            avg_power: power.map(|p| p.0 as u16),
+            max_power: power.map(|p| p.1 as u16),
+            avg_heart_rate: heart_rate.map(|hr| hr.0 as u8),
+            max_heart_rate: heart_rate.map(|hr| hr.1 as u8),
+            avg_cadence: cadence.map(|c| c.0 as u8),
+            max_cadence: cadence.map(|c| c.1 as u8),
+        }
+    }
+}
+
+#[derive(Default)]
+struct MeanAndMax {
+    count: u64,
+    sum: u64,
+    max: u32,
+}
+
+impl MeanAndMax {
+    fn add(&mut self, o_value: Option<u32>) {
+        if let Some(v) = o_value {
+            self.count += 1;
+            self.sum += v as u64;
+            self.max = u32::max(self.max, v);
+        }
+    }
+
+    // The (rounded) mean and the max, if there are any values at all
+    fn get(&self) -> Option<(u32, u32)> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(((self.sum as f64 / self.count as f64).round() as u32, self.max))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub seconds_since_unix_epoch: u32,
+    // Distinguishes devices within a file (0 is reserved for the creator)
+    pub device_index: u8,
+    // As a FIT ble_device_type (since all our sensors are BLE)
+    pub device_type: u8,
+    pub product_name: String,
+    // Percent
+    pub battery_level: Option<u8>,
+    pub serial_number: Option<u32>,
+    // Free text about the device, like its manufacturer and firmware
+    pub descriptor: Option<String>,
+}
+
+// FIT strings are sized by a single byte (null included), and a descriptor
+// has no need to be anywhere near that long anyway.
+const MAX_DESCRIPTOR_LEN: usize = 63;
+
+fn descriptor_str(descriptor: &str) -> &str {
+    let mut end = usize::min(descriptor.len(), MAX_DESCRIPTOR_LEN);
+    while !descriptor.is_char_boundary(end) {
+        end -= 1;
+    }
+    &descriptor[..end]
+}
+
+// What kind of ride this was, which apps use to categorize (and treat
+// differently, like virtual rides on Strava).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sport {
+    Cycling,
+    IndoorCycling,
+    Treadmill,
+}
+
+impl Sport {
+    // As FIT (sport, sub_sport)
+    fn sport_and_sub_sport(&self) -> (u8, u8) {
+        match self {
+            // Cycling, Generic
+            Sport::Cycling => (2, 0),
+            // Cycling, Indoor Cycling
+            Sport::IndoorCycling => (2, 6),
+            // Running, Treadmill
+            Sport::Treadmill => (1, 1),
+        }
+    }
+}
+
+// We identify ourselves as a development device, since we have no
+// manufacturer id of our own.
+const MANUFACTURER_DEVELOPMENT: u16 = 255;
+const PRODUCT_ID: u16 = 1;
+// A serial number of zero is invalid
+const SERIAL_NUMBER: u32 = 1;
+const PRODUCT_NAME: &str = "rust-cycle";
+
+// FIT times are seconds since 1989-12-31T00:00:00Z
+const FIT_EPOCH: u32 = 631065600;
+
+// Written when there's no time to give (as there are no records)
+const INVALID_TIME: u32 = 0xffffffff;
+
+fn fit_time(o_seconds_since_unix_epoch: Option<u32>) -> [u8; 4] {
+    u32::to_le_bytes(o_seconds_since_unix_epoch.map_or(INVALID_TIME, |t| t - FIT_EPOCH))
 }
 
 fn make_header(length: usize) -> Vec<u8> {
@@ -45,227 +282,1622 @@ fn make_header(length: usize) -> Vec<u8> {
     ]
 }
 
-fn record_to_bytes(record: &FitRecord) -> Vec<u8> {
+// FIT positions are in semicircles, where 2^31 semicircles is 180 degrees
+fn to_semicircles(degrees: f64) -> i32 {
+    (degrees * ((1 << 29) as f64) / 45.0) as i32
+}
+
+fn from_semicircles(semicircles: i32) -> f64 {
+    semicircles as f64 * 180.0 / 2_147_483_648.0
+}
+
+// How a record's time is written: as a timestamp field, or compressed into
+// the record header as an offset from the last time written.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum RecordTime {
+    Full,
+    Compressed,
+}
+
+// A compressed header's 5 bit offset only reaches this far past the last time
+const MAX_COMPRESSED_OFFSET: u32 = 31;
+
+fn record_time(o_last_time: Option<u32>, time: u32, compress: bool) -> RecordTime {
+    match o_last_time {
+        Some(last) if compress && time >= last && time - last <= MAX_COMPRESSED_OFFSET => {
+            RecordTime::Compressed
+        }
+        _ => RecordTime::Full,
+    }
+}
+
+fn record_to_bytes(record: &FitRecord, time: RecordTime) -> Vec<u8> {
     let ts = record.seconds_since_unix_epoch - 631065600;
+    let mut bytes = match time {
+        RecordTime::Full => vec![
+            // Type 0 (we only ever define type 0, and just continually change the definition)
+            0,
+            // Time
+            ts as u8 & 0xff,
+            (ts >> 8) as u8 & 0xff,
+            (ts >> 16) as u8 & 0xff,
+            (ts >> 24) as u8 & 0xff,
+        ],
+        // Compressed, type 0, and the low 5 bits of the time (readers add
+        // the offset from the last time's low bits, rolling over as needed)
+        RecordTime::Compressed => vec![0x80 | (ts & 0x1f) as u8],
+    };
+
+    if let Some(l) = record.latitude {
+        bytes.extend(&i32::to_le_bytes(to_semicircles(l)));
+    }
+
+    if let Some(l) = record.longitude {
+        bytes.extend(&i32::to_le_bytes(to_semicircles(l)));
+    }
+
+    if let Some(a) = record.altitude {
+        let x = u16::to_le_bytes((5.0 * (a + 500.0)) as u16);
+        bytes.extend(&x);
+    }
+
+    if let Some(p) = record.power {
+        bytes.push(p as u8 & 0xff);
+        bytes.push((p >> 8) as u8 & 0xff);
+    };
+
+    if let Some(hr) = record.heart_rate {
+        bytes.push(hr);
+    }
+
+    if let Some(c) = record.cadence {
+        bytes.push(c);
+    }
+
+    if let Some(d) = record.distance {
+        let x = u32::to_le_bytes((100.0 * d) as u32);
+        bytes.extend(&x);
+    }
+
+    if let Some(s) = record.speed {
+        let x = u16::to_le_bytes((1000.0 * s) as u16);
+        bytes.extend(&x);
+    }
+
+    bytes
+}
+
+fn record_def(record: &FitRecord, time: RecordTime) -> Vec<u8> {
+    let field_count = if time == RecordTime::Full { 1 } else { 0 }
+        + if let Some(_) = record.latitude { 1 } else { 0 }
+        + if let Some(_) = record.longitude { 1 } else { 0 }
+        + if let Some(_) = record.altitude { 1 } else { 0 }
+        + if let Some(_) = record.power { 1 } else { 0 }
+        + if let Some(_) = record.heart_rate {
+            1
+        } else {
+            0
+        }
+        + if let Some(_) = record.cadence { 1 } else { 0 }
+        + if let Some(_) = record.distance { 1 } else { 0 }
+        + if let Some(_) = record.speed { 1 } else { 0 };
+
     let mut bytes = vec![
-        // Type 0 (we only ever define type 0, and just continually change the definition)
+        // Field definition for message type 0
+        64,
+        // Reserved
+        0,
+        // Little Endian
         0,
+        // Global Message Number (20 is for a typical data record)
+        20,
+        0,
+        // Number of fields
+        field_count,
+    ];
+
+    if time == RecordTime::Full {
+        bytes.extend(vec![
+            // Timestamp (field definition number, byte count, default type (u32))
+            253, 4, 0x86,
+        ]);
+    }
+
+    let lat_def = vec![
+        // Latitude (field definition number, byte count, default type (i32))
+        0, 4, 0x85,
+    ];
+    let lon_def = vec![
+        // Longitude (field definition number, byte count, default type (i32))
+        1, 4, 0x85,
+    ];
+    let alt_def = vec![
+        // Altitude (field definition number, byte count, default type (u16))
+        2, 2, 0x84,
+    ];
+    let power_def = vec![
+        // Power (field definition number, byte count, default type (u16))
+        7, 2, 0x84,
+    ];
+    let hr_def = vec![
+        // HeartRate (field definition number, byte count, default type (u8))
+        3, 1, 2,
+    ];
+    let cadence_def = vec![
+        // Cadence (field definition number, byte count, default type (u8))
+        4, 1, 2,
+    ];
+    let distance_def = vec![
+        // distance (field definition number, byte count, default type (u32))
+        5, 4, 0x86,
+    ];
+    let speed_def = vec![
+        // Speed (field definition number, byte count, default type (u16))
+        6, 2, 0x84,
+    ];
+
+    if let Some(_) = record.latitude {
+        bytes.extend(lat_def);
+    };
+
+    if let Some(_) = record.longitude {
+        bytes.extend(lon_def);
+    };
+
+    if let Some(_) = record.altitude {
+        bytes.extend(alt_def);
+    };
+
+    if let Some(_) = record.power {
+        bytes.extend(power_def);
+    };
+
+    if let Some(_) = record.heart_rate {
+        bytes.extend(hr_def);
+    }
+
+    if let Some(_) = record.cadence {
+        bytes.extend(cadence_def);
+    }
+
+    if let Some(_) = record.distance {
+        bytes.extend(distance_def);
+    }
+
+    if let Some(_) = record.speed {
+        bytes.extend(speed_def);
+    }
+
+    bytes
+}
+
+// FIT's battery_status is coarse, so we bucket our percentage into it
+fn battery_status(battery_level: u8) -> u8 {
+    match battery_level {
+        // Good
+        51..=u8::MAX => 2,
+        // Ok
+        26..=50 => 3,
+        // Low
+        11..=25 => 4,
+        // Critical
+        _ => 5,
+    }
+}
+
+fn device_info_to_bytes(device_info: &DeviceInfo) -> Vec<u8> {
+    let ts = device_info.seconds_since_unix_epoch - 631065600;
+    let mut bytes = vec![
+        // Type 1 (type 0 is reserved for records)
+        1,
         // Time
         ts as u8 & 0xff,
         (ts >> 8) as u8 & 0xff,
         (ts >> 16) as u8 & 0xff,
         (ts >> 24) as u8 & 0xff,
+        device_info.device_index,
+        device_info.device_type,
+        // Source Type (Bluetooth Low Energy)
+        3,
     ];
 
-    if let Some(l) = record.latitude {
-        let x = i32::to_le_bytes((l * ((1 << 29) as f64) / 45.0) as i32);
-        bytes.extend(&x);
+    bytes.extend(device_info.product_name.as_bytes());
+    // Strings are null terminated
+    bytes.push(0);
+
+    if let Some(b) = device_info.battery_level {
+        bytes.push(battery_status(b));
+        bytes.push(b);
+    }
+
+    if let Some(serial_number) = device_info.serial_number {
+        bytes.extend(serial_number.to_le_bytes());
+    }
+
+    if let Some(descriptor) = &device_info.descriptor {
+        bytes.extend(descriptor_str(descriptor).as_bytes());
+        bytes.push(0);
+    }
+
+    bytes
+}
+
+fn device_info_def(device_info: &DeviceInfo) -> Vec<u8> {
+    let field_count = 5
+        + if let Some(_) = device_info.battery_level {
+            2
+        } else {
+            0
+        }
+        + device_info.serial_number.map_or(0, |_| 1)
+        + device_info.descriptor.as_ref().map_or(0, |_| 1);
+
+    let mut bytes = vec![
+        // Field definition for message type 1
+        65,
+        // Reserved
+        0,
+        // Little Endian
+        0,
+        // Global Message Number (23 is for device info)
+        23,
+        0,
+        // Number of fields
+        field_count,
+        // Timestamp (field definition number, byte count, default type (u32))
+        253,
+        4,
+        0x86,
+        // Device Index (field definition number, byte count, default type (u8))
+        0,
+        1,
+        2,
+        // Device Type (field definition number, byte count, default type (u8))
+        1,
+        1,
+        2,
+        // Source Type (field definition number, byte count, default type (enum))
+        25,
+        1,
+        0,
+        // Product Name (field definition number, byte count, default type (string))
+        27,
+        device_info.product_name.len() as u8 + 1,
+        7,
+    ];
+
+    if let Some(_) = device_info.battery_level {
+        bytes.extend(vec![
+            // Battery Status (field definition number, byte count, default type (u8))
+            11, 1, 2,
+            // Battery Level (field definition number, byte count, default type (u8))
+            32, 1, 2,
+        ]);
+    }
+
+    if let Some(_) = device_info.serial_number {
+        // Serial Number (field definition number, byte count, default type (u32z))
+        bytes.extend(vec![3, 4, 0x8c]);
+    }
+
+    if let Some(descriptor) = &device_info.descriptor {
+        // Descriptor (field definition number, byte count, default type (string))
+        bytes.extend(vec![19, descriptor_str(descriptor).len() as u8 + 1, 7]);
+    }
+
+    bytes
+}
+
+// Every FIT file must start with a file_id, which says what kind of file it
+// is and what made it.
+fn make_file_id(o_time_created: Option<u32>) -> Vec<u8> {
+    let mut bytes = vec![
+        // Definition for message type 6 (0-5 are records through laps)
+        70,
+        // Reserved
+        0,
+        // Little Endian
+        0,
+        // Global Message Number (0 is for file id)
+        0,
+        0,
+        // Number of fields
+        5,
+        // Type (field definition number, byte count, default type (enum))
+        0, 1, 0,
+        // Manufacturer (field definition number, byte count, default type (u16))
+        1, 2, 0x84,
+        // Product (field definition number, byte count, default type (u16))
+        2, 2, 0x84,
+        // Serial Number (field definition number, byte count, default type (u32z))
+        3, 4, 0x8c,
+        // Time Created (field definition number, byte count, default type (u32))
+        4, 4, 0x86,
+        // Type 6
+        6,
+        // Activity
+        4,
+    ];
+    bytes.extend(&u16::to_le_bytes(MANUFACTURER_DEVELOPMENT));
+    bytes.extend(&u16::to_le_bytes(PRODUCT_ID));
+    bytes.extend(&u32::to_le_bytes(SERIAL_NUMBER));
+    bytes.extend(&fit_time(o_time_created));
+    bytes
+}
+
+// The device info for the creator (us), as opposed to our sensors
+fn make_creator_device_info(o_timestamp: Option<u32>) -> Vec<u8> {
+    let mut bytes = vec![
+        // Definition for message type 7
+        71,
+        // Reserved
+        0,
+        // Little Endian
+        0,
+        // Global Message Number (23 is for device info)
+        23,
+        0,
+        // Number of fields
+        7,
+        // Timestamp (field definition number, byte count, default type (u32))
+        253, 4, 0x86,
+        // Device Index (field definition number, byte count, default type (u8))
+        0, 1, 2,
+        // Manufacturer (field definition number, byte count, default type (u16))
+        2, 2, 0x84,
+        // Product (field definition number, byte count, default type (u16))
+        4, 2, 0x84,
+        // Serial Number (field definition number, byte count, default type (u32z))
+        3, 4, 0x8c,
+        // Source Type (field definition number, byte count, default type (enum))
+        25, 1, 0,
+        // Product Name (field definition number, byte count, default type (string))
+        27,
+        PRODUCT_NAME.len() as u8 + 1,
+        7,
+        // Type 7
+        7,
+    ];
+    bytes.extend(&fit_time(o_timestamp));
+    // Creator
+    bytes.push(0);
+    bytes.extend(&u16::to_le_bytes(MANUFACTURER_DEVELOPMENT));
+    bytes.extend(&u16::to_le_bytes(PRODUCT_ID));
+    bytes.extend(&u32::to_le_bytes(SERIAL_NUMBER));
+    // Local
+    bytes.push(5);
+    bytes.extend(PRODUCT_NAME.as_bytes());
+    bytes.push(0);
+    bytes
+}
+
+fn sport_to_bytes(sport: Sport) -> Vec<u8> {
+    let (sport, sub_sport) = sport.sport_and_sub_sport();
+    vec![
+        // Definition for message type 2 (0 and 1 are records and device info)
+        66,
+        // Reserved
+        0,
+        // Little Endian
+        0,
+        // Global Message Number (12 is for sport)
+        12,
+        0,
+        // Number of fields
+        2,
+        // Sport (field definition number, byte count, default type (enum))
+        0,
+        1,
+        0,
+        // Sub Sport (field definition number, byte count, default type (enum))
+        1,
+        1,
+        0,
+        // Type 2
+        2,
+        sport,
+        sub_sport,
+    ]
+}
+
+fn lap_def() -> Vec<u8> {
+    vec![
+        // Definition for message type 5 (3 and 4 are the session and activity)
+        69,
+        // Reserved
+        0,
+        // Little Endian
+        0,
+        // Global Message Number (19 is for lap)
+        19,
+        0,
+        // Number of fields
+        7,
+        // Timestamp (field definition number, byte count, default type (u32))
+        253, 4, 0x86,
+        // Start Time (field definition number, byte count, default type (u32))
+        2, 4, 0x86,
+        // Total Elapsed Time (field definition number, byte count, default type (u32))
+        7, 4, 0x86,
+        // Total Timer Time (field definition number, byte count, default type (u32))
+        8, 4, 0x86,
+        // Avg Power (field definition number, byte count, default type (u16))
+        19, 2, 0x84,
+        // Event (field definition number, byte count, default type (enum))
+        0, 1, 0,
+        // Event Type (field definition number, byte count, default type (enum))
+        1, 1, 0,
+    ]
+}
+
+// A lap is summarized just like a session, but only some of it is written
+fn lap_to_bytes(summary: &SessionSummary) -> Vec<u8> {
+    // Type 5
+    let mut bytes = vec![5];
+    bytes.extend(&u32::to_le_bytes(summary.end_time - 631065600));
+    bytes.extend(&u32::to_le_bytes(summary.start_time - 631065600));
+    bytes.extend(&u32::to_le_bytes(summary.total_elapsed_time * 1000));
+    bytes.extend(&u32::to_le_bytes(summary.total_timer_time * 1000));
+    bytes.extend(&u16::to_le_bytes(summary.avg_power.unwrap_or(0xffff)));
+    // Lap, Stop
+    bytes.extend(&[9, 1]);
+    bytes
+}
+
+fn session_to_bytes(summary: &SessionSummary) -> Vec<u8> {
+    // Missing values are written as FIT's invalid value for the type
+    let u8_or_invalid = |o: Option<u8>| o.unwrap_or(0xff);
+    let u16_or_invalid = |o: Option<u16>| o.unwrap_or(0xffff);
+    let timestamp = summary.end_time - 631065600;
+    let mut bytes = vec![
+        // Definition for message type 3 (0-2 are records, device info and sport)
+        67,
+        // Reserved
+        0,
+        // Little Endian
+        0,
+        // Global Message Number (18 is for session)
+        18,
+        0,
+        // Number of fields
+        13,
+        // Timestamp (field definition number, byte count, default type (u32))
+        253, 4, 0x86,
+        // Start Time (field definition number, byte count, default type (u32))
+        2, 4, 0x86,
+        // Total Elapsed Time (field definition number, byte count, default type (u32))
+        7, 4, 0x86,
+        // Total Timer Time (field definition number, byte count, default type (u32))
+        8, 4, 0x86,
+        // Total Distance (field definition number, byte count, default type (u32))
+        9, 4, 0x86,
+        // Avg Power (field definition number, byte count, default type (u16))
+        20, 2, 0x84,
+        // Max Power (field definition number, byte count, default type (u16))
+        21, 2, 0x84,
+        // Avg Heart Rate (field definition number, byte count, default type (u8))
+        16, 1, 2,
+        // Max Heart Rate (field definition number, byte count, default type (u8))
+        17, 1, 2,
+        // Avg Cadence (field definition number, byte count, default type (u8))
+        18, 1, 2,
+        // Max Cadence (field definition number, byte count, default type (u8))
+        19, 1, 2,
+        // Event (field definition number, byte count, default type (enum))
+        0, 1, 0,
+        // Event Type (field definition number, byte count, default type (enum))
+        1, 1, 0,
+        // Type 3
+        3,
+    ];
+    bytes.extend(&u32::to_le_bytes(timestamp));
+    bytes.extend(&u32::to_le_bytes(summary.start_time - 631065600));
+    // Times are in milliseconds, and distance in centimeters
+    bytes.extend(&u32::to_le_bytes(summary.total_elapsed_time * 1000));
+    bytes.extend(&u32::to_le_bytes(summary.total_timer_time * 1000));
+    bytes.extend(&u32::to_le_bytes(
+        summary.total_distance.map_or(0xffffffff, |d| (100.0 * d) as u32),
+    ));
+    bytes.extend(&u16::to_le_bytes(u16_or_invalid(summary.avg_power)));
+    bytes.extend(&u16::to_le_bytes(u16_or_invalid(summary.max_power)));
+    bytes.push(u8_or_invalid(summary.avg_heart_rate));
+    bytes.push(u8_or_invalid(summary.max_heart_rate));
+    bytes.push(u8_or_invalid(summary.avg_cadence));
+    bytes.push(u8_or_invalid(summary.max_cadence));
+    // Session, Stop
+    bytes.extend(&[8, 1]);
+
+    bytes.extend(vec![
+        // Definition for message type 4
+        68,
+        // Reserved
+        0,
+        // Little Endian
+        0,
+        // Global Message Number (34 is for activity)
+        34,
+        0,
+        // Number of fields
+        6,
+        // Timestamp (field definition number, byte count, default type (u32))
+        253, 4, 0x86,
+        // Total Timer Time (field definition number, byte count, default type (u32))
+        0, 4, 0x86,
+        // Number of Sessions (field definition number, byte count, default type (u16))
+        1, 2, 0x84,
+        // Type (field definition number, byte count, default type (enum))
+        2, 1, 0,
+        // Event (field definition number, byte count, default type (enum))
+        3, 1, 0,
+        // Event Type (field definition number, byte count, default type (enum))
+        4, 1, 0,
+        // Type 4
+        4,
+    ]);
+    bytes.extend(&u32::to_le_bytes(timestamp));
+    bytes.extend(&u32::to_le_bytes(summary.total_timer_time * 1000));
+    bytes.extend(&u16::to_le_bytes(1));
+    // Manual, Activity, Stop
+    bytes.extend(&[0, 26, 1]);
+
+    bytes
+}
+
+// Definitions persist until they are redefined, so we only need to write one
+// out when it differs from the last one for that message type.
+fn write_def(
+    w: &mut impl Write,
+    last_def: &mut Option<Vec<u8>>,
+    new_def: Vec<u8>,
+) -> io::Result<()> {
+    if last_def.as_ref() != Some(&new_def) {
+        w.write_all(&new_def)?;
+        *last_def = Some(new_def);
+    }
+    Ok(())
+}
+
+fn calculate_crc(blob: &Vec<u8>) -> u16 {
+    blob.iter().fold(0, |crc, byte| update_crc(crc, *byte))
+}
+
+fn update_crc(crc: u16, byte: u8) -> u16 {
+    let crc_table = [
+        0x0000, 0xcc01, 0xd801, 0x1400, 0xf001, 0x3c00, 0x2800, 0xe401, 0xa001, 0x6c00, 0x7800,
+        0xb401, 0x5000, 0x9c01, 0x8801, 0x4400,
+    ];
+
+    let byte = byte as u16;
+    let mut crc = crc;
+    let mut tmp = crc_table[(crc & 0xf) as usize];
+    crc = (crc >> 4) & 0x0fff;
+    crc = crc ^ tmp ^ crc_table[(byte & 0xf) as usize];
+    tmp = crc_table[(crc & 0xf) as usize];
+    crc = (crc >> 4) & 0x0fff;
+    crc ^ tmp ^ crc_table[((byte >> 4) & 0xf) as usize]
+}
+
+// Passes writes through, keeping the CRC of everything written so far
+struct CrcWriter<W> {
+    inner: W,
+    crc: u16,
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc = buf[..n].iter().fold(self.crc, |crc, byte| update_crc(crc, *byte));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Only counts what would have been written
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Everything between the header and the CRC, which fails (with a FitError) at
+// the first record whose time can't be written.  When compressing, records use
+// compressed timestamp headers whenever they can, which only needs the
+// timestamp field when they can't (after a gap).  With lap marks, it's
+// finished with a lap for each segment between them, and then a session (and
+// activity) that summarizes all the records, which are totaled as they're
+// written.
+fn write_records(
+    w: &mut impl Write,
+    records: impl Iterator<Item = FitRecord>,
+    device_infos: &[DeviceInfo],
+    o_sport: Option<Sport>,
+    compress: bool,
+    o_lap_marks: Option<&[u32]>,
+) -> io::Result<()> {
+    let mut records = records.peekable();
+    let o_time_created = records.peek().map(|r| r.seconds_since_unix_epoch);
+    if let Some(t) = o_time_created {
+        check_time(None, t)?;
+    }
+    w.write_all(&make_file_id(o_time_created))?;
+    w.write_all(&make_creator_device_info(o_time_created))?;
+    if let Some(sport) = o_sport {
+        w.write_all(&sport_to_bytes(sport))?;
+    }
+    let mut last_def: Option<Vec<u8>> = None;
+    let mut last_device_info_def: Option<Vec<u8>> = None;
+    let mut device_infos = device_infos.iter().peekable();
+    // The last time written, which compressed times are relative to (and
+    // the creator's device info has the first record's time)
+    let mut o_last_time = o_time_created;
+    let mut lap_marks = o_lap_marks.unwrap_or(&[]).iter().peekable();
+    let mut laps: Vec<SessionSummary> = Vec::new();
+    let mut lap = SummaryTotals::default();
+    let mut session = SummaryTotals::default();
+
+    let mut o_last_record_time: Option<u32> = None;
+    for record in records {
+        check_time(o_last_record_time, record.seconds_since_unix_epoch)?;
+        o_last_record_time = Some(record.seconds_since_unix_epoch);
+
+        // Device info is interleaved with records by time
+        while let Some(device_info) = device_infos
+            .next_if(|x| x.seconds_since_unix_epoch <= record.seconds_since_unix_epoch)
+        {
+            write_def(w, &mut last_device_info_def, device_info_def(device_info))?;
+            w.write_all(&device_info_to_bytes(device_info))?;
+            o_last_time = Some(device_info.seconds_since_unix_epoch);
+        }
+
+        let time = record_time(o_last_time, record.seconds_since_unix_epoch, compress);
+        write_def(w, &mut last_def, record_def(&record, time))?;
+        w.write_all(&record_to_bytes(&record, time))?;
+        o_last_time = Some(record.seconds_since_unix_epoch);
+
+        // Marks without any records between them don't make empty laps
+        let mut is_new_lap = false;
+        while lap_marks.next_if(|m| **m <= record.seconds_since_unix_epoch).is_some() {
+            is_new_lap = true;
+        }
+        if is_new_lap && lap.count > 0 {
+            laps.push(lap.summary());
+            lap = SummaryTotals::default();
+        }
+        lap.add(&record);
+        session.add(&record);
+    }
+
+    for device_info in device_infos {
+        write_def(w, &mut last_device_info_def, device_info_def(device_info))?;
+        w.write_all(&device_info_to_bytes(device_info))?;
+    }
+
+    if let Some(lap_marks) = o_lap_marks {
+        if !lap_marks.is_empty() && lap.count > 0 {
+            laps.push(lap.summary());
+        }
+        let mut last_lap_def: Option<Vec<u8>> = None;
+        for lap in laps {
+            write_def(w, &mut last_lap_def, lap_def())?;
+            w.write_all(&lap_to_bytes(&lap))?;
+        }
+        if session.count > 0 {
+            w.write_all(&session_to_bytes(&session.summary()))?;
+        }
+    }
+
+    Ok(())
+}
+
+// The header needs the length of everything after it, so records are read
+// twice (once to count, and once to write), rather than held all at once.
+// Records may still be being added (to a ride that's being recorded), so the
+// second read stops at however many the first had.
+fn write_file<I: Iterator<Item = FitRecord>>(
+    w: impl Write,
+    records: impl Fn() -> I,
+    device_infos: &[DeviceInfo],
+    o_sport: Option<Sport>,
+    compress: bool,
+    o_lap_marks: Option<&[u32]>,
+) -> io::Result<()> {
+    let mut counter = ByteCounter(0);
+    let mut count = 0;
+    let counted = records().inspect(|_| count += 1);
+    write_records(&mut counter, counted, device_infos, o_sport, compress, o_lap_marks)?;
+    let mut w = CrcWriter { inner: w, crc: 0 };
+    w.write_all(&make_header(counter.0))?;
+    let records = records().take(count);
+    write_records(&mut w, records, device_infos, o_sport, compress, o_lap_marks)?;
+    let crc = w.crc;
+    w.inner.write_all(&u16::to_le_bytes(crc))
+}
+
+// Finished with a lap for each segment between the lap marks, and then a
+// session (and activity) that summarizes all the records.  It's written as
+// it's made, so that even the longest rides never need to be in memory all at
+// once.  Each call of records must give the same records (or more of them).
+// Compressing makes the file smaller, since records that follow closely after
+// the last don't need their own timestamp (which is most of them, recording
+// each second).
+pub fn write_file_with_summary<I: Iterator<Item = FitRecord>>(
+    w: impl Write,
+    records: impl Fn() -> I,
+    device_infos: &[DeviceInfo],
+    o_sport: Option<Sport>,
+    lap_marks: &[u32],
+    compress: bool,
+) -> io::Result<()> {
+    write_file(w, records, device_infos, o_sport, compress, Some(lap_marks))
+}
+
+// Why records can't be written to a FIT file
+#[derive(Debug, Clone, PartialEq)]
+pub enum FitError {
+    // A time before the FIT epoch, which can't be represented
+    BeforeFitEpoch(u32),
+    // A time before the previous record's (like after the clock was
+    // corrected mid-ride), which readers don't expect
+    OutOfOrder { previous: u32, next: u32 },
+    // When reading, the file doesn't have a FIT header
+    NotFit,
+    // When reading, the file ends before its header says it does (or in
+    // the middle of a message)
+    Truncated,
+    // When reading, the file's CRC doesn't match its content
+    BadCrc,
+    // When reading, a data message of a local type that hasn't been defined
+    UndefinedMessage(u8),
+    // When reading, a record without a timestamp we can use (or a compressed
+    // one, but no earlier time to offset it from)
+    MissingTime,
+}
+
+impl std::fmt::Display for FitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FitError::BeforeFitEpoch(t) => write!(f, "time {} is before the FIT epoch", t),
+            FitError::OutOfOrder { previous, next } => {
+                write!(f, "time {} comes after the later time {}", next, previous)
+            }
+            FitError::NotFit => write!(f, "not a FIT file"),
+            FitError::Truncated => write!(f, "the file is truncated"),
+            FitError::BadCrc => write!(f, "the file's CRC doesn't match"),
+            FitError::UndefinedMessage(t) => write!(f, "message type {} isn't defined", t),
+            FitError::MissingTime => write!(f, "a record has no time"),
+        }
+    }
+}
+
+impl std::error::Error for FitError {}
+
+// Whether a record's time (after the previous record's, if any) can be
+// written, since it would otherwise panic (or produce garbage).  Failures are
+// io errors, so that they stop writing like any other.
+fn check_time(o_previous: Option<u32>, t: u32) -> io::Result<()> {
+    let o_error = if t < FIT_EPOCH {
+        Some(FitError::BeforeFitEpoch(t))
+    } else {
+        o_previous.filter(|p| *p > t).map(|previous| FitError::OutOfOrder { previous, next: t })
+    };
+    match o_error {
+        Some(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        None => Ok(()),
+    }
+}
+
+// A definition message, as a reader keeps it for the local type's data
+// messages that follow
+struct Definition {
+    global: u16,
+    is_big_endian: bool,
+    // Each field's number (or None for developer fields) and size
+    fields: Vec<(Option<u8>, usize)>,
+}
+
+fn read_uint(bytes: &[u8], is_big_endian: bool) -> u64 {
+    let fold = |acc: u64, (i, b): (usize, &u8)| acc | (*b as u64) << (8 * i);
+    if is_big_endian {
+        bytes.iter().rev().enumerate().fold(0, fold)
+    } else {
+        bytes.iter().enumerate().fold(0, fold)
+    }
+}
+
+// Each FIT type's invalid value is all ones in its size (for the unsigned
+// types we read), which means the field has no value.
+fn valid_uint(value: u64, size: usize) -> Option<u64> {
+    if size < 8 && value == (1 << (8 * size)) - 1 {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+// Readers add a compressed offset to the low 5 bits of the last time,
+// rolling over if it's smaller.
+fn compressed_time(last_time: u32, offset: u32) -> u32 {
+    let rollover = if offset >= last_time & 0x1f { 0 } else { 0x20 };
+    (last_time & !0x1f) + offset + rollover
+}
+
+// Reads the records back out of a FIT file (like one we've written),
+// skipping any other messages, and fields we don't write.
+pub fn from_file(bytes: &[u8]) -> Result<Vec<FitRecord>, FitError> {
+    let header_size = *bytes.first().ok_or(FitError::NotFit)? as usize;
+    if header_size < 12 || bytes.len() < header_size || &bytes[8..12] != b".FIT" {
+        return Err(FitError::NotFit);
+    }
+    let data_size = read_uint(&bytes[4..8], false) as usize;
+    // A data size this big can't be in bytes we have, so it's truncated too
+    let end = header_size.checked_add(data_size).ok_or(FitError::Truncated)?;
+    if bytes.len() < end.checked_add(2).ok_or(FitError::Truncated)? {
+        return Err(FitError::Truncated);
+    }
+    // The CRC over everything, the stored CRC included, is zero when it matches
+    if calculate_crc(&bytes[..end + 2].to_vec()) != 0 {
+        return Err(FitError::BadCrc);
+    }
+
+    let take = |i: &mut usize, n: usize| -> Result<&[u8], FitError> {
+        let taken_end = i.checked_add(n).filter(|e| *e <= end).ok_or(FitError::Truncated)?;
+        let taken = bytes.get(*i..taken_end).ok_or(FitError::Truncated)?;
+        *i = taken_end;
+        Ok(taken)
+    };
+    let mut definitions: HashMap<u8, Definition> = HashMap::new();
+    let mut records = Vec::new();
+    // Compressed times are offsets from the last time read from any message
+    let mut o_last_time: Option<u32> = None;
+    let mut i = header_size;
+    while i < end {
+        let header = take(&mut i, 1)?[0];
+        if header & 0x80 == 0 && header & 0x40 == 0x40 {
+            let fixed = take(&mut i, 5)?;
+            let is_big_endian = fixed[1] == 1;
+            let global = read_uint(&fixed[2..4], is_big_endian) as u16;
+            let field_count = fixed[4] as usize;
+            let mut fields: Vec<(Option<u8>, usize)> = take(&mut i, 3 * field_count)?
+                .chunks(3)
+                .map(|f| (Some(f[0]), f[1] as usize))
+                .collect();
+            if header & 0x20 == 0x20 {
+                let developer_field_count = take(&mut i, 1)?[0] as usize;
+                let developer_fields = take(&mut i, 3 * developer_field_count)?;
+                fields.extend(developer_fields.chunks(3).map(|f| (None, f[1] as usize)));
+            }
+            let definition = Definition {
+                global,
+                is_big_endian,
+                fields,
+            };
+            definitions.insert(header & 0x0f, definition);
+            continue;
+        }
+
+        let (local_type, o_offset) = if header & 0x80 == 0x80 {
+            ((header >> 5) & 0x03, Some((header & 0x1f) as u32))
+        } else {
+            (header & 0x0f, None)
+        };
+        let definition = definitions
+            .get(&local_type)
+            .ok_or(FitError::UndefinedMessage(local_type))?;
+        let mut o_time = o_offset.and_then(|o| o_last_time.map(|t| compressed_time(t, o)));
+        let mut record = FitRecord {
+            seconds_since_unix_epoch: 0,
+            power: None,
+            heart_rate: None,
+            cadence: None,
+            latitude: None,
+            longitude: None,
+            altitude: None,
+            distance: None,
+            speed: None,
+            pedal_power_balance: None,
+        };
+        for (o_number, size) in &definition.fields {
+            let value = take(&mut i, *size)?;
+            // Only the fields we write are read, all of which are numbers
+            if *size > 4 {
+                continue;
+            }
+            let raw = read_uint(value, definition.is_big_endian);
+            let o_value = valid_uint(raw, *size);
+            // Positions are signed, so their invalid value is different
+            let o_position = Some(raw as u32 as i32)
+                .filter(|s| *s != i32::MAX)
+                .map(from_semicircles);
+            match (definition.global, *o_number) {
+                (_, Some(253)) => {
+                    if let Some(t) = o_value {
+                        o_time = Some(t as u32);
+                    }
+                }
+                (20, Some(0)) => record.latitude = o_position,
+                (20, Some(1)) => record.longitude = o_position,
+                (20, Some(2)) => record.altitude = o_value.map(|a| a as f32 / 5.0 - 500.0),
+                (20, Some(3)) => record.heart_rate = o_value.map(|hr| hr as u8),
+                (20, Some(4)) => record.cadence = o_value.map(|c| c as u8),
+                (20, Some(5)) => record.distance = o_value.map(|d| d as f64 / 100.0),
+                (20, Some(6)) => record.speed = o_value.map(|s| s as f32 / 1000.0),
+                (20, Some(7)) => record.power = o_value.map(|p| p as u16),
+                _ => (),
+            }
+        }
+        if o_time.is_some() {
+            o_last_time = o_time;
+        }
+        if definition.global == 20 {
+            record.seconds_since_unix_epoch = o_time
+                .and_then(|t| t.checked_add(FIT_EPOCH))
+                .ok_or(FitError::MissingTime)?;
+            records.push(record);
+        }
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::calculate_crc;
+    use super::crop_iter;
+    use super::from_file;
+    use super::from_semicircles;
+    use super::heart_rate_from_bpm;
+    use super::power_from_signed;
+    use super::to_semicircles;
+    use super::write_file;
+    use super::write_file_with_summary;
+    use super::DeviceInfo;
+    use super::FitError;
+    use super::FitRecord;
+    use super::SessionSummary;
+    use super::Sport;
+    use super::SummaryTotals;
+    use super::TimeWindow;
+    use std::cell::Cell;
+    use std::io::{self, Write};
+    use std::collections::HashMap;
+
+    fn record_at(seconds_since_unix_epoch: u32, distance: Option<f64>) -> FitRecord {
+        FitRecord { distance, ..FitRecord::new(seconds_since_unix_epoch) }
+    }
+
+    #[test]
+    fn semicircles_of_the_poles() {
+        assert_eq!(to_semicircles(90.0), 1 << 30);
+        assert_eq!(to_semicircles(-90.0), -(1 << 30));
+        assert_eq!(to_semicircles(0.0), 0);
+    }
+
+    #[test]
+    fn semicircles_round_trip() {
+        // Within one semicircle, which is less than a centimeter
+        let resolution = 180.0 / 2_147_483_648.0;
+        for degrees in [45.48707197420299, -122.67648, 179.9999, -0.000001].iter() {
+            let round_tripped = from_semicircles(to_semicircles(*degrees));
+            assert!(
+                (degrees - round_tripped).abs() < resolution,
+                "{} came back as {}",
+                degrees,
+                round_tripped
+            );
+        }
+    }
+
+    // Only for records whose times can be written
+    fn to_bytes<I: Iterator<Item = FitRecord>>(
+        records: impl Fn() -> I,
+        device_infos: &[DeviceInfo],
+        o_sport: Option<Sport>,
+        compress: bool,
+        o_lap_marks: Option<&[u32]>,
+    ) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_file(&mut bytes, records, device_infos, o_sport, compress, o_lap_marks).unwrap();
+        bytes
+    }
+
+    fn to_file(list: &Vec<FitRecord>) -> Vec<u8> {
+        to_file_with_device_infos(list, &Vec::new())
+    }
+
+    fn to_file_with_device_infos(list: &Vec<FitRecord>, device_infos: &Vec<DeviceInfo>) -> Vec<u8> {
+        to_file_with_sport(list, device_infos, None)
+    }
+
+    fn to_file_with_sport(
+        list: &Vec<FitRecord>,
+        device_infos: &Vec<DeviceInfo>,
+        o_sport: Option<Sport>,
+    ) -> Vec<u8> {
+        to_bytes(|| list.iter().cloned(), device_infos, o_sport, false, None)
+    }
+
+    fn to_file_with_summary(
+        list: &Vec<FitRecord>,
+        device_infos: &Vec<DeviceInfo>,
+        o_sport: Option<Sport>,
+        lap_marks: &[u32],
+    ) -> Vec<u8> {
+        to_bytes(|| list.iter().cloned(), device_infos, o_sport, false, Some(lap_marks))
+    }
+
+    fn to_file_compressed(list: &Vec<FitRecord>) -> Vec<u8> {
+        to_bytes(|| list.iter().cloned(), &[], None, true, None)
+    }
+
+    fn to_file_checked(list: &Vec<FitRecord>) -> Result<Vec<u8>, FitError> {
+        let mut bytes = Vec::new();
+        match write_file(&mut bytes, || list.iter().cloned(), &[], None, false, None) {
+            Ok(()) => Ok(bytes),
+            Err(e) => Err(*e.into_inner().unwrap().downcast::<FitError>().unwrap()),
+        }
+    }
+
+    fn crop(records: Vec<FitRecord>, start_time: u32, window: TimeWindow) -> Vec<FitRecord> {
+        crop_iter(records.into_iter(), start_time, window).collect()
+    }
+
+    #[test]
+    fn crop_keeps_the_inclusive_window() {
+        let records = (0..10).map(|t| record_at(1000 + t, None)).collect();
+        let window = TimeWindow {
+            start: Some(3),
+            end: Some(5),
+        };
+        assert_eq!(
+            vec![1003, 1004, 1005],
+            crop(records, 1000, window)
+                .iter()
+                .map(|r| r.seconds_since_unix_epoch)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn crop_with_open_ends_keeps_everything() {
+        let records: Vec<FitRecord> = (0..3).map(|t| record_at(1000 + t, Some(t as f64))).collect();
+        assert_eq!(records.clone(), crop(records, 1000, TimeWindow::default()));
+    }
+
+    #[test]
+    fn crop_makes_distance_relative_to_the_window() {
+        let records = vec![
+            record_at(1000, Some(0.0)),
+            record_at(1001, None),
+            record_at(1002, Some(10.0)),
+            record_at(1003, Some(15.0)),
+        ];
+        let window = TimeWindow {
+            start: Some(1),
+            end: None,
+        };
+        assert_eq!(
+            vec![None, Some(0.0), Some(5.0)],
+            crop(records, 1000, window)
+                .iter()
+                .map(|r| r.distance)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    // Three records with a one second gap
+    fn ride() -> Vec<FitRecord> {
+        vec![
+            FitRecord {
+                power: Some(100),
+                heart_rate: Some(120),
+                cadence: Some(80),
+                ..record_at(1583801576, Some(0.0))
+            },
+            FitRecord {
+                power: Some(200),
+                heart_rate: Some(130),
+                ..record_at(1583801577, Some(5.0))
+            },
+            FitRecord {
+                heart_rate: Some(140),
+                cadence: Some(91),
+                ..record_at(1583801579, Some(12.5))
+            },
+        ]
+    }
+
+    fn session_summary(records: &[FitRecord]) -> SessionSummary {
+        let mut totals = SummaryTotals::default();
+        for record in records {
+            totals.add(record);
+        }
+        totals.summary()
+    }
+
+    #[test]
+    fn session_summary_totals_the_records() {
+        assert_eq!(
+            SessionSummary {
+                start_time: 1583801576,
+                end_time: 1583801579,
+                total_elapsed_time: 4,
+                total_timer_time: 3,
+                total_distance: Some(12.5),
+                avg_power: Some(150),
+                max_power: Some(200),
+                avg_heart_rate: Some(130),
+                max_heart_rate: Some(140),
+                avg_cadence: Some(86),
+                max_cadence: Some(91),
+            },
+            session_summary(&ride())
+        );
     }
 
-    if let Some(l) = record.longitude {
-        let x = i32::to_le_bytes((l * ((1 << 29) as f64) / 45.0) as i32);
-        bytes.extend(&x);
+    #[test]
+    fn session_summary_without_values() {
+        let summary = session_summary(&[record_at(1583801576, None)]);
+        assert_eq!(1, summary.total_elapsed_time);
+        assert_eq!(None, summary.total_distance);
+        assert_eq!(None, summary.avg_power);
+        assert_eq!(None, summary.max_heart_rate);
     }
 
-    if let Some(a) = record.altitude {
-        let x = u16::to_le_bytes((5.0 * (a + 500.0)) as u16);
-        bytes.extend(&x);
+    #[test]
+    fn to_file_with_summary_ends_with_the_activity() {
+        let file = to_file_with_summary(&ride(), &vec![], None, &[]);
+        let (content, _) = file.split_at(file.len() - 2);
+        let mut activity = vec![4];
+        activity.extend(&u32::to_le_bytes(1583801579 - 631065600));
+        activity.extend(&u32::to_le_bytes(3000));
+        activity.extend(&[1, 0, 0, 26, 1]);
+        assert!(content.ends_with(&activity));
+        assert_eq!(0, calculate_crc(&file));
     }
 
-    if let Some(p) = record.power {
-        bytes.push(p as u8 & 0xff);
-        bytes.push((p >> 8) as u8 & 0xff);
-    };
+    #[test]
+    fn to_file_with_summary_of_nothing_has_no_session() {
+        assert_eq!(to_file(&vec![]), to_file_with_summary(&vec![], &vec![], None, &[]));
+    }
 
-    if let Some(hr) = record.heart_rate {
-        bytes.push(hr);
+    #[test]
+    fn to_file_with_summary_writes_each_lap() {
+        let lap_def_header: &[u8] = &[69, 0, 0, 19, 0];
+        let without_laps = to_file_with_summary(&ride(), &vec![], None, &[]);
+        assert!(!without_laps.windows(5).any(|w| w == lap_def_header));
+
+        let file = to_file_with_summary(&ride(), &vec![], None, &[1583801577]);
+        assert_eq!(1, file.windows(5).filter(|w| *w == lap_def_header).count());
+        // One definition (of 7 fields), and then two laps
+        assert_eq!(without_laps.len() + 6 + 7 * 3 + 2 * 21, file.len());
+        assert_eq!(0, calculate_crc(&file));
     }
 
-    if let Some(c) = record.cadence {
-        bytes.push(c);
+    // Only takes a byte at a time
+    struct Trickle(Vec<u8>);
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.extend(&buf[..1]);
+            Ok(1)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
     }
 
-    if let Some(d) = record.distance {
-        let x = u32::to_le_bytes((100.0 * d) as u32);
-        bytes.extend(&x);
+    #[test]
+    fn write_file_with_summary_is_the_same_as_to_file_with_summary() {
+        let records = ride();
+        let mut writer = Trickle(Vec::new());
+        let marks = [1583801577];
+        write_file_with_summary(&mut writer, || records.iter().cloned(), &[], None, &marks, false)
+            .unwrap();
+        assert_eq!(
+            to_file_with_summary(&records, &vec![], None, &[1583801577]),
+            writer.0
+        );
     }
 
-    if let Some(s) = record.speed {
-        let x = u16::to_le_bytes((1000.0 * s) as u16);
-        bytes.extend(&x);
+    #[test]
+    fn write_file_with_summary_only_has_the_records_there_were_at_first() {
+        // Another record is recorded each time they're read
+        let reads = Cell::new(0);
+        let records = || {
+            reads.set(reads.get() + 1);
+            ride().into_iter().take(reads.get())
+        };
+        let mut file = Vec::new();
+        write_file_with_summary(&mut file, records, &[], None, &[], false).unwrap();
+        assert_eq!(Ok(ride()[..1].to_vec()), from_file(&file));
     }
 
-    bytes
-}
+    // What every file starts with, given the time of its first record
+    fn file_id_and_creator(o_seconds_since_unix_epoch: Option<u32>) -> Vec<u8> {
+        let time = o_seconds_since_unix_epoch.map_or(0xffffffff, |t| t - 631065600);
+        let time = u32::to_le_bytes(time);
+        let mut bytes = vec![
+            0x46, 0x00, 0x00, 0x00, 0x00, 0x05, // file id def
+            0x00, 0x01, 0x00, // type def
+            0x01, 0x02, 0x84, // manufacturer def
+            0x02, 0x02, 0x84, // product def
+            0x03, 0x04, 0x8c, // serial number def
+            0x04, 0x04, 0x86, // time created def
+            0x06, // file id type
+            0x04, // activity
+            0xff, 0x00, 0x01, 0x00, // development manufacturer and product
+            0x01, 0x00, 0x00, 0x00, // serial number
+        ];
+        bytes.extend(&time);
+        bytes.extend(vec![
+            0x47, 0x00, 0x00, 0x17, 0x00, 0x07, 0xfd, 0x04, 0x86, // device info def
+            0x00, 0x01, 0x02, // index def
+            0x02, 0x02, 0x84, // manufacturer def
+            0x04, 0x02, 0x84, // product def
+            0x03, 0x04, 0x8c, // serial number def
+            0x19, 0x01, 0x00, // source type def
+            0x1b, 0x0b, 0x07, // product name def
+            0x07, // device info type
+        ]);
+        bytes.extend(&time);
+        bytes.extend(vec![
+            0x00, // creator index
+            0xff, 0x00, 0x01, 0x00, // development manufacturer and product
+            0x01, 0x00, 0x00, 0x00, // serial number
+            0x05, // local source type
+        ]);
+        bytes.extend(b"rust-cycle\0");
+        bytes
+    }
 
-fn record_def(record: &FitRecord) -> Vec<u8> {
-    let field_count = 1
-        + if let Some(_) = record.latitude { 1 } else { 0 }
-        + if let Some(_) = record.longitude { 1 } else { 0 }
-        + if let Some(_) = record.altitude { 1 } else { 0 }
-        + if let Some(_) = record.power { 1 } else { 0 }
-        + if let Some(_) = record.heart_rate {
-            1
-        } else {
-            0
+    // Just enough of a FIT decoder to check a file the way readers do: its
+    // header and CRC, and that each data message follows its definition.
+    // Returns each data message's global number, content (without any
+    // timestamp) and FIT time, if it has one.
+    fn decode(file: &[u8]) -> Vec<(u16, Vec<u8>, Option<u32>)> {
+        assert_eq!(12, file[0]);
+        assert_eq!(b".FIT", &file[8..12]);
+        let data_size = u32::from_le_bytes([file[4], file[5], file[6], file[7]]) as usize;
+        assert_eq!(12 + data_size + 2, file.len());
+        assert_eq!(0, calculate_crc(&file.to_vec()));
+
+        // The global number, and each field's number and size
+        let mut definitions: HashMap<u8, (u16, Vec<(u8, usize)>)> = HashMap::new();
+        let mut messages = vec![];
+        let mut o_last_time: Option<u32> = None;
+        let mut i = 12;
+        while i < 12 + data_size {
+            let header = file[i];
+            if header & 0x40 == 0x40 && header & 0x80 == 0 {
+                let global = u16::from_le_bytes([file[i + 3], file[i + 4]]);
+                let field_count = file[i + 5] as usize;
+                let fields = &file[i + 6..i + 6 + 3 * field_count];
+                let fields = fields.chunks(3).map(|f| (f[0], f[1] as usize)).collect();
+                definitions.insert(header & 0x0f, (global, fields));
+                i += 6 + 3 * field_count;
+                continue;
+            }
+
+            let (local_type, o_offset) = if header & 0x80 == 0x80 {
+                ((header >> 5) & 0x03, Some((header & 0x1f) as u32))
+            } else {
+                (header & 0x0f, None)
+            };
+            let (global, fields) = definitions
+                .get(&local_type)
+                .expect("data message without a definition");
+            i += 1;
+            let mut content = vec![];
+            let mut o_time = o_offset.map(|offset| {
+                let last = o_last_time.expect("compressed time without a previous time");
+                let rollover = if offset >= last & 0x1f { 0 } else { 0x20 };
+                (last & !0x1f) + offset + rollover
+            });
+            for (number, size) in fields {
+                let value = &file[i..i + size];
+                if *number == 253 {
+                    o_time = Some(u32::from_le_bytes([value[0], value[1], value[2], value[3]]));
+                } else {
+                    content.extend(value);
+                }
+                i += size;
+            }
+            if let Some(time) = o_time.filter(|t| *t != 0xffffffff) {
+                o_last_time = Some(time);
+            }
+            messages.push((*global, content, o_time));
         }
-        + if let Some(_) = record.cadence { 1 } else { 0 }
-        + if let Some(_) = record.distance { 1 } else { 0 }
-        + if let Some(_) = record.speed { 1 } else { 0 };
+        assert_eq!(12 + data_size, i);
+        messages
+    }
 
-    let mut bytes = vec![
-        // Field definition for message type 0
-        64,
-        // Reserved
-        0,
-        // Little Endian
-        0,
-        // Global Message Number (20 is for a typical data record)
-        20,
-        0,
-        // Number of fields
-        field_count,
-        // Timestamp (field definition number, byte count, default type (u32))
-        253,
-        4,
-        0x86,
-    ];
+    #[test]
+    fn decoded_file_starts_with_the_file_id_and_creator() {
+        let file = to_file_with_summary(&ride(), &vec![], Some(Sport::Cycling), &[1583801577]);
+        let messages = decode(&file);
+        let globals: Vec<u16> = messages.iter().map(|m| m.0).collect();
+        // File id, creator, sport, three records, two laps, session, activity
+        assert_eq!(vec![0, 23, 12, 20, 20, 20, 19, 19, 18, 34], globals);
 
-    let lat_def = vec![
-        // Latitude (field definition number, byte count, default type (i32))
-        0, 4, 0x85,
-    ];
-    let lon_def = vec![
-        // Longitude (field definition number, byte count, default type (i32))
-        1, 4, 0x85,
-    ];
-    let alt_def = vec![
-        // Altitude (field definition number, byte count, default type (u16))
-        2, 2, 0x84,
-    ];
-    let power_def = vec![
-        // Power (field definition number, byte count, default type (u16))
-        7, 2, 0x84,
-    ];
-    let hr_def = vec![
-        // HeartRate (field definition number, byte count, default type (u8))
-        3, 1, 2,
-    ];
-    let cadence_def = vec![
-        // Cadence (field definition number, byte count, default type (u8))
-        4, 1, 2,
-    ];
-    let distance_def = vec![
-        // distance (field definition number, byte count, default type (u32))
-        5, 4, 0x86,
-    ];
-    let speed_def = vec![
-        // Speed (field definition number, byte count, default type (u16))
-        6, 2, 0x84,
-    ];
+        let mut file_id = vec![4, 0xff, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00];
+        file_id.extend(&u32::to_le_bytes(1583801576 - 631065600));
+        assert_eq!(file_id, messages[0].1);
+        assert!(messages[1].1.ends_with(b"rust-cycle\0"));
+    }
 
-    if let Some(_) = record.latitude {
-        bytes.extend(lat_def);
-    };
+    // A record a second, with power, and then after a gap too long to
+    // compress, distance too
+    fn long_ride() -> Vec<FitRecord> {
+        let with_power = |mut r: FitRecord| {
+            r.power = Some(200);
+            r
+        };
+        let mut records: Vec<FitRecord> =
+            (0..40).map(|s| with_power(record_at(1583801576 + s, None))).collect();
+        records.extend(
+            (100..140).map(|s| with_power(record_at(1583801576 + s, Some(s as f64)))),
+        );
+        records
+    }
 
-    if let Some(_) = record.longitude {
-        bytes.extend(lon_def);
-    };
+    fn record_times(file: &[u8]) -> Vec<u32> {
+        decode(file)
+            .into_iter()
+            .filter(|m| m.0 == 20)
+            .map(|m| m.2.expect("record without a time"))
+            .collect()
+    }
 
-    if let Some(_) = record.altitude {
-        bytes.extend(alt_def);
-    };
+    #[test]
+    fn to_file_compressed_decodes_to_the_same_records() {
+        let records = long_ride();
+        let times: Vec<u32> =
+            records.iter().map(|r| r.seconds_since_unix_epoch - 631065600).collect();
+        let compressed = to_file_compressed(&records);
+        assert_eq!(times, record_times(&compressed));
+        assert_eq!(times, record_times(&to_file(&records)));
 
-    if let Some(_) = record.power {
-        bytes.extend(power_def);
-    };
+        let contents = |file: &[u8]| -> Vec<Vec<u8>> {
+            decode(file).into_iter().filter(|m| m.0 == 20).map(|m| m.1).collect()
+        };
+        assert_eq!(contents(&to_file(&records)), contents(&compressed));
+    }
 
-    if let Some(_) = record.heart_rate {
-        bytes.extend(hr_def);
+    #[test]
+    fn to_file_compressed_is_smaller() {
+        let records = long_ride();
+        // Every record but the one after the gap saves its 4 byte timestamp.
+        // The gap costs another definition (of 2 fields), though the first
+        // doesn't need its 3 byte timestamp field.
+        assert_eq!(
+            to_file(&records).len() - 4 * (records.len() - 1) + (6 + 2 * 3) - 3,
+            to_file_compressed(&records).len()
+        );
     }
 
-    if let Some(_) = record.cadence {
-        bytes.extend(cadence_def);
+    // Records whose values survive FIT's units exactly
+    fn exact_rides() -> Vec<Vec<FitRecord>> {
+        vec![
+            vec![],
+            ride(),
+            long_ride(),
+            vec![FitRecord {
+                power: Some(180),
+                heart_rate: Some(120),
+                cadence: Some(90),
+                ..record_at(1583801576, None)
+            }],
+            vec![FitRecord {
+                speed: Some(6.0),
+                ..record_at(1583801576, Some(1000.0))
+            }],
+        ]
     }
 
-    if let Some(_) = record.distance {
-        bytes.extend(distance_def);
+    #[test]
+    fn from_file_round_trips() {
+        for records in exact_rides() {
+            assert_eq!(Ok(records.clone()), from_file(&to_file(&records)));
+            assert_eq!(Ok(records.clone()), from_file(&to_file_compressed(&records)));
+            let marks = records.get(1).map_or(vec![], |r| vec![r.seconds_since_unix_epoch]);
+            let sport = Some(Sport::Cycling);
+            let with_summary = to_file_with_summary(&records, &vec![], sport, &marks);
+            assert_eq!(Ok(records), from_file(&with_summary));
+        }
     }
 
-    if let Some(_) = record.speed {
-        bytes.extend(speed_def);
+    #[test]
+    fn from_file_reads_positions_to_within_their_precision() {
+        let record = FitRecord {
+            latitude: Some(45.48707197420299),
+            longitude: Some(-122.4767913389951),
+            altitude: Some(81.8),
+            ..record_at(1583801576, None)
+        };
+        let read = from_file(&to_file(&vec![record.clone()])).unwrap();
+        let (latitude, longitude) = (read[0].latitude.unwrap(), read[0].longitude.unwrap());
+        assert!((latitude - record.latitude.unwrap()).abs() < 0.000001);
+        assert!((longitude - record.longitude.unwrap()).abs() < 0.000001);
+        // Altitude is in fifths of a meter
+        assert!((read[0].altitude.unwrap() - 81.8).abs() <= 0.2);
     }
 
-    bytes
-}
+    #[test]
+    fn from_file_rejects_what_isnt_fit() {
+        assert_eq!(Err(FitError::NotFit), from_file(&[]));
+        assert_eq!(Err(FitError::NotFit), from_file(b"definitely not a FIT file"));
+    }
 
-fn calculate_crc(blob: &Vec<u8>) -> u16 {
-    let crc_table = [
-        0x0000, 0xcc01, 0xd801, 0x1400, 0xf001, 0x3c00, 0x2800, 0xe401, 0xa001, 0x6c00, 0x7800,
-        0xb401, 0x5000, 0x9c01, 0x8801, 0x4400,
-    ];
+    #[test]
+    fn from_file_rejects_a_bad_crc() {
+        let mut file = to_file(&ride());
+        let last = file.len() - 1;
+        file[last] ^= 0xff;
+        assert_eq!(Err(FitError::BadCrc), from_file(&file));
+    }
 
-    let mut crc = 0;
-    for i in 0..blob.len() {
-        let byte = blob[i] as u16;
-        let mut tmp = crc_table[(crc & 0xf) as usize];
-        crc = (crc >> 4) & 0x0fff;
-        crc = crc ^ tmp ^ crc_table[(byte & 0xf) as usize];
-        tmp = crc_table[(crc & 0xf) as usize];
-        crc = (crc >> 4) & 0x0fff;
-        crc = crc ^ tmp ^ crc_table[((byte >> 4) & 0xf) as usize];
+    #[test]
+    fn from_file_rejects_a_truncated_file() {
+        let file = to_file(&ride());
+        assert_eq!(Err(FitError::Truncated), from_file(&file[..file.len() - 10]));
     }
 
-    crc
-}
+    #[test]
+    fn from_file_rejects_a_data_size_that_cant_fit() {
+        let mut file = to_file(&ride());
+        file[4..8].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(Err(FitError::Truncated), from_file(&file));
+    }
 
-fn to_file_inner(list: &Vec<FitRecord>) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    let mut last_def: Option<Vec<u8>> = None;
+    #[test]
+    fn from_file_rejects_an_undefined_message() {
+        // A record (local type 0) before any definition
+        let mut file = vec![0x0c, 0x20, 0xeb, 0x07, 0x01, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54];
+        file.push(0);
+        let crc = calculate_crc(&file);
+        file.extend(&u16::to_le_bytes(crc));
+        assert_eq!(Err(FitError::UndefinedMessage(0)), from_file(&file));
+    }
 
-    for record in list {
-        let new_def = record_def(record);
-        match last_def {
-            Some(ld) => {
-                if ld != new_def {
-                    last_def = Some(new_def.clone());
-                    bytes.extend(new_def)
-                } else {
-                    last_def = Some(ld);
-                }
-            }
-            None => {
-                last_def = Some(new_def.clone());
-                bytes.extend(new_def);
+    #[test]
+    fn compressed_times_roll_over() {
+        // The low 5 bits of these FIT times go 30, 31, 0, 1
+        let start = 631065600 + 30;
+        let records: Vec<FitRecord> = (0..4).map(|s| record_at(start + s, None)).collect();
+        assert_eq!(vec![30, 31, 32, 33], record_times(&to_file_compressed(&records)));
+    }
+
+    #[test]
+    fn decoded_file_of_nothing_has_only_the_file_id_and_creator() {
+        let globals: Vec<u16> = decode(&to_file(&vec![])).iter().map(|m| m.0).collect();
+        assert_eq!(vec![0, 23], globals);
+    }
+
+    // The FIT CRC is CRC-16/ARC, so it has the published check value
+    #[test]
+    fn calculate_crc_check_value() {
+        assert_eq!(0xBB3D, calculate_crc(&b"123456789".to_vec()));
+    }
+
+    #[test]
+    fn calculate_crc_of_nothing() {
+        assert_eq!(0, calculate_crc(&Vec::new()));
+    }
+
+    // A straightforward bit at a time CRC-16/ARC, to check the table against
+    fn bitwise_crc(blob: &[u8]) -> u16 {
+        let mut crc: u16 = 0;
+        for byte in blob {
+            crc ^= *byte as u16;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
             }
         }
+        crc
+    }
 
-        bytes.extend(record_to_bytes(record));
+    #[test]
+    fn calculate_crc_matches_bitwise_crc() {
+        let blob: Vec<u8> = (0..=255).collect();
+        assert_eq!(bitwise_crc(&blob), calculate_crc(&blob));
+        assert_eq!(0xEEC5, calculate_crc(&(0..=255).step_by(7).collect()));
     }
 
-    bytes
-}
+    // Readers check a file by running the CRC over all of it (stored CRC
+    // included), which must come out to zero.
+    #[test]
+    fn calculate_crc_over_a_whole_file_is_zero() {
+        let file = to_file(&vec![
+            record_at(1583801576, Some(5.0)),
+            record_at(1583801577, Some(10.0)),
+        ]);
+        let (content, stored) = file.split_at(file.len() - 2);
+        assert_eq!(
+            u16::from_le_bytes([stored[0], stored[1]]),
+            calculate_crc(&content.to_vec())
+        );
+        assert_eq!(0, calculate_crc(&file));
+    }
 
-pub fn to_file(list: &Vec<FitRecord>) -> Vec<u8> {
-    let record_buffer = to_file_inner(list);
-    let mut bytes = make_header(record_buffer.len());
-    bytes.extend(record_buffer);
-    let crc = calculate_crc(&bytes);
-    bytes.extend(vec![(crc & 0xff) as u8, ((crc >> 8) as u8) & 0xff]);
-    bytes
-}
+    #[test]
+    fn to_file_checked_of_ordered_records() {
+        let records = vec![record_at(1583801576, None), record_at(1583801577, None)];
+        assert_eq!(Ok(to_file(&records)), to_file_checked(&records));
+        assert_eq!(Ok(to_file(&vec![])), to_file_checked(&vec![]));
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::to_file;
-    use super::FitRecord;
+    #[test]
+    fn to_file_checked_rejects_times_before_the_fit_epoch() {
+        let records = vec![record_at(1000, None), record_at(1001, None)];
+        assert_eq!(Err(FitError::BeforeFitEpoch(1000)), to_file_checked(&records));
+        assert_eq!(
+            Err(FitError::BeforeFitEpoch(631065599)),
+            to_file_checked(&vec![record_at(631065599, None)])
+        );
+        assert!(to_file_checked(&vec![record_at(631065600, None)]).is_ok());
+    }
+
+    #[test]
+    fn to_file_checked_rejects_times_going_backwards() {
+        let records = vec![
+            record_at(1583801576, None),
+            record_at(1583801578, None),
+            record_at(1583801577, None),
+        ];
+        assert_eq!(
+            Err(FitError::OutOfOrder { previous: 1583801578, next: 1583801577 }),
+            to_file_checked(&records)
+        );
+        // Records within the same second are fine
+        let records = vec![record_at(1583801576, None), record_at(1583801576, None)];
+        assert!(to_file_checked(&records).is_ok());
+    }
+
+    #[test]
+    fn write_file_with_summary_writes_nothing_for_times_it_cannot_hold() {
+        let records = vec![record_at(1583801577, None), record_at(1583801576, None)];
+        let mut file = Vec::new();
+        let records = || records.iter().cloned();
+        let e = write_file_with_summary(&mut file, records, &[], None, &[], false).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+        assert!(file.is_empty());
+    }
+
+    #[test]
+    fn heart_rate_from_bpm_clamps_to_a_byte() {
+        assert_eq!(heart_rate_from_bpm(70), 70);
+        assert_eq!(heart_rate_from_bpm(254), 254);
+        assert_eq!(heart_rate_from_bpm(266), 254);
+    }
+
+    #[test]
+    fn power_from_signed_clamps_negative_power() {
+        assert_eq!(power_from_signed(-5), 0);
+        assert_eq!(power_from_signed(i16::MIN), 0);
+        assert_eq!(power_from_signed(0), 0);
+        assert_eq!(power_from_signed(250), 250);
+    }
 
     #[test]
     fn to_file_for_empty_vec() {
         assert_eq!(
-            vec!(
-                0x0c, 0x20, 0xeb, 0x07, 0x00, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54, 0x36, 0xc1
-            ),
+            [
+                vec!(0x0c, 0x20, 0xeb, 0x07, 0x58, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54),
+                file_id_and_creator(None),
+                vec!(0x1a, 0xad), // crc
+            ]
+            .concat(),
             to_file(&Vec::new()),
         );
     }
@@ -273,11 +1905,17 @@ mod tests {
     #[test]
     fn to_file_for_single_record() {
         assert_eq!(
-            vec!(
-                0x0c, 0x20, 0xeb, 0x07, 0x1b, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54, 0x40, 0x00,
-                0x00, 0x14, 0x00, 0x04, 0xfd, 0x04, 0x86, 0x07, 0x02, 0x84, 0x03, 0x01, 0x02, 0x04,
-                0x01, 0x02, 0x00, 0xe8, 0x98, 0xc9, 0x38, 0xb4, 0x00, 0x78, 0x5a, 0xe4, 0xc1
-            ),
+            [
+                vec!(0x0c, 0x20, 0xeb, 0x07, 0x73, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54),
+                file_id_and_creator(Some(1583801576)),
+                vec!(
+                    0x40, 0x00, 0x00, 0x14, 0x00, 0x04, 0xfd, 0x04, 0x86, 0x07, 0x02, 0x84, 0x03,
+                    0x01, 0x02, 0x04, 0x01, 0x02, 0x00, 0xe8, 0x98, 0xc9, 0x38, 0xb4, 0x00, 0x78,
+                    0x5a
+                ),
+                vec!(0x20, 0x1c), // crc
+            ]
+            .concat(),
             to_file(&vec!(FitRecord {
                 seconds_since_unix_epoch: 1583801576,
                 power: Some(180),
@@ -288,6 +1926,7 @@ mod tests {
                 altitude: None,
                 distance: None,
                 speed: None,
+                pedal_power_balance: None,
             })),
         );
     }
@@ -295,12 +1934,17 @@ mod tests {
     #[test]
     fn to_file_for_two_records_with_reused_definition() {
         assert_eq!(
-            vec!(
-                0x0c, 0x20, 0xeb, 0x07, 0x24, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54, 0x40, 0x00,
-                0x00, 0x14, 0x00, 0x04, 0xfd, 0x04, 0x86, 0x07, 0x02, 0x84, 0x03, 0x01, 0x02, 0x04,
-                0x01, 0x02, 0x00, 0xe8, 0x98, 0xc9, 0x38, 0xb4, 0x00, 0x78, 0x5a, 0x00, 0xe9, 0x98,
-                0xc9, 0x38, 0xb5, 0x00, 0x79, 0x5b, 0x7b, 0x97
-            ),
+            [
+                vec!(0x0c, 0x20, 0xeb, 0x07, 0x7c, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54),
+                file_id_and_creator(Some(1583801576)),
+                vec!(
+                    0x40, 0x00, 0x00, 0x14, 0x00, 0x04, 0xfd, 0x04, 0x86, 0x07, 0x02, 0x84, 0x03,
+                    0x01, 0x02, 0x04, 0x01, 0x02, 0x00, 0xe8, 0x98, 0xc9, 0x38, 0xb4, 0x00, 0x78,
+                    0x5a, 0x00, 0xe9, 0x98, 0xc9, 0x38, 0xb5, 0x00, 0x79, 0x5b
+                ),
+                vec!(0xfb, 0xa2), // crc
+            ]
+            .concat(),
             to_file(&vec!(
                 FitRecord {
                     seconds_since_unix_epoch: 1583801576,
@@ -312,6 +1956,7 @@ mod tests {
                     altitude: None,
                     distance: None,
                     speed: None,
+                    pedal_power_balance: None,
                 },
                 FitRecord {
                     seconds_since_unix_epoch: 1583801577,
@@ -323,6 +1968,7 @@ mod tests {
                     altitude: None,
                     distance: None,
                     speed: None,
+                    pedal_power_balance: None,
                 }
             )),
         );
@@ -331,15 +1977,20 @@ mod tests {
     #[test]
     fn to_file_for_two_records_with_separate_definitions() {
         assert_eq!(
-            vec!(
-                0x0c, 0x20, 0xeb, 0x07, 0x3c, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54, 0x40, 0x00,
-                0x00, 0x14, 0x00, 0x04, 0xfd, 0x04, 0x86, 0x07, 0x02, 0x84, 0x03, 0x01, 0x02, 0x04,
-                0x01, 0x02, 0x00, 0xe8, 0x98, 0xc9, 0x38, 0xb4, 0x00, 0x78, 0x5a, //
-                0x40, 0x00, 0x00, 0x14, 0x00, 0x04, 253, 0x04, 0x86, 0x00, 0x04, 0x85, 0x01, 0x04,
-                0x85, 0x02, 0x02, 0x84, 0x00, 0xe9, 0x98, 0xc9, 0x38, 0x33, 0xab, 0x58, 0x20, 0xd3,
-                0xc7, 0xe7, 0xa8, 0x5d, 0x0b, //
-                176, 0x0b
-            ),
+            [
+                vec!(0x0c, 0x20, 0xeb, 0x07, 0x94, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54),
+                file_id_and_creator(Some(1583801576)),
+                vec!(
+                    0x40, 0x00, 0x00, 0x14, 0x00, 0x04, 0xfd, 0x04, 0x86, 0x07, 0x02, 0x84, 0x03,
+                    0x01, 0x02, 0x04, 0x01, 0x02, 0x00, 0xe8, 0x98, 0xc9, 0x38, 0xb4, 0x00, 0x78,
+                    0x5a, //
+                    0x40, 0x00, 0x00, 0x14, 0x00, 0x04, 253, 0x04, 0x86, 0x00, 0x04, 0x85, 0x01,
+                    0x04, 0x85, 0x02, 0x02, 0x84, 0x00, 0xe9, 0x98, 0xc9, 0x38, 0x33, 0xab, 0x58,
+                    0x20, 0xd3, 0xc7, 0xe7, 0xa8, 0x5d, 0x0b //
+                ),
+                vec!(0xe1, 0x75), // crc
+            ]
+            .concat(),
             to_file(&vec!(
                 FitRecord {
                     seconds_since_unix_epoch: 1583801576,
@@ -351,6 +2002,7 @@ mod tests {
                     altitude: None,
                     distance: None,
                     speed: None,
+                    pedal_power_balance: None,
                 },
                 FitRecord {
                     seconds_since_unix_epoch: 1583801577,
@@ -362,6 +2014,7 @@ mod tests {
                     altitude: Some(81.79999999999995),
                     distance: None,
                     speed: None,
+                    pedal_power_balance: None,
                 }
             )),
         );
@@ -370,11 +2023,16 @@ mod tests {
     #[test]
     fn to_file_for_single_record_without_power() {
         assert_eq!(
-            vec!(
-                0x0c, 0x20, 0xeb, 0x07, 0x16, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54, 0x40, 0x00,
-                0x00, 0x14, 0x00, 0x03, 0xfd, 0x04, 0x86, 0x03, 0x01, 0x02, 0x04, 0x01, 0x02, 0x00,
-                0xe8, 0x98, 0xc9, 0x38, 0x78, 0x5a, 0x9b, 0x59
-            ),
+            [
+                vec!(0x0c, 0x20, 0xeb, 0x07, 0x6e, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54),
+                file_id_and_creator(Some(1583801576)),
+                vec!(
+                    0x40, 0x00, 0x00, 0x14, 0x00, 0x03, 0xfd, 0x04, 0x86, 0x03, 0x01, 0x02, 0x04,
+                    0x01, 0x02, 0x00, 0xe8, 0x98, 0xc9, 0x38, 0x78, 0x5a
+                ),
+                vec!(0x2d, 0x54), // crc
+            ]
+            .concat(),
             to_file(&vec!(FitRecord {
                 seconds_since_unix_epoch: 1583801576,
                 power: None,
@@ -385,6 +2043,7 @@ mod tests {
                 altitude: None,
                 distance: None,
                 speed: None,
+                pedal_power_balance: None,
             })),
         );
     }
@@ -392,11 +2051,16 @@ mod tests {
     #[test]
     fn to_file_for_single_record_without_heart_rate() {
         assert_eq!(
-            vec!(
-                0x0c, 0x20, 0xeb, 0x07, 0x17, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54, 0x40, 0x00,
-                0x00, 0x14, 0x00, 0x03, 0xfd, 0x04, 0x86, 0x07, 0x02, 0x84, 0x04, 0x01, 0x02, 0x00,
-                0xe8, 0x98, 0xc9, 0x38, 0xb4, 0x00, 0x5a, 0xf9, 0xbe
-            ),
+            [
+                vec!(0x0c, 0x20, 0xeb, 0x07, 0x6f, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54),
+                file_id_and_creator(Some(1583801576)),
+                vec!(
+                    0x40, 0x00, 0x00, 0x14, 0x00, 0x03, 0xfd, 0x04, 0x86, 0x07, 0x02, 0x84, 0x04,
+                    0x01, 0x02, 0x00, 0xe8, 0x98, 0xc9, 0x38, 0xb4, 0x00, 0x5a
+                ),
+                vec!(0xf5, 0xc9), // crc
+            ]
+            .concat(),
             to_file(&vec!(FitRecord {
                 seconds_since_unix_epoch: 1583801576,
                 power: Some(180),
@@ -407,6 +2071,7 @@ mod tests {
                 altitude: None,
                 distance: None,
                 speed: None,
+                pedal_power_balance: None,
             })),
         );
     }
@@ -414,11 +2079,16 @@ mod tests {
     #[test]
     fn to_file_for_single_record_without_cadence() {
         assert_eq!(
-            vec!(
-                0x0c, 0x20, 0xeb, 0x07, 0x17, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54, 0x40, 0x00,
-                0x00, 0x14, 0x00, 0x03, 0xfd, 0x04, 0x86, 0x07, 0x02, 0x84, 0x03, 0x01, 0x02, 0x00,
-                0xe8, 0x98, 0xc9, 0x38, 0xb4, 0x00, 0x78, 0x63, 0xd3
-            ),
+            [
+                vec!(0x0c, 0x20, 0xeb, 0x07, 0x6f, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54),
+                file_id_and_creator(Some(1583801576)),
+                vec!(
+                    0x40, 0x00, 0x00, 0x14, 0x00, 0x03, 0xfd, 0x04, 0x86, 0x07, 0x02, 0x84, 0x03,
+                    0x01, 0x02, 0x00, 0xe8, 0x98, 0xc9, 0x38, 0xb4, 0x00, 0x78
+                ),
+                vec!(0x6f, 0xa4), // crc
+            ]
+            .concat(),
             to_file(&vec!(FitRecord {
                 seconds_since_unix_epoch: 1583801576,
                 power: Some(180),
@@ -429,6 +2099,7 @@ mod tests {
                 altitude: None,
                 distance: None,
                 speed: None,
+                pedal_power_balance: None,
             })),
         );
     }
@@ -436,12 +2107,17 @@ mod tests {
     #[test]
     fn to_file_for_lat_lon_alt() {
         assert_eq!(
-            vec!(
-                0x0c, 0x20, 0xeb, 0x07, 0x21, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54, 0x40, 0x00,
-                0x00, 0x14, 0x00, 0x04, 253, 0x04, 0x86, 0x00, 0x04, 0x85, 0x01, 0x04, 0x85, 0x02,
-                0x02, 0x84, 0x00, 0xe8, 0x98, 0xc9, 0x38, 0x33, 0xab, 0x58, 0x20, 0xd3, 0xc7, 0xe7,
-                0xa8, 0x5d, 0x0b, 0x4d, 0xb6
-            ),
+            [
+                vec!(0x0c, 0x20, 0xeb, 0x07, 0x79, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54),
+                file_id_and_creator(Some(1583801576)),
+                vec!(
+                    0x40, 0x00, 0x00, 0x14, 0x00, 0x04, 253, 0x04, 0x86, 0x00, 0x04, 0x85, 0x01,
+                    0x04, 0x85, 0x02, 0x02, 0x84, 0x00, 0xe8, 0x98, 0xc9, 0x38, 0x33, 0xab, 0x58,
+                    0x20, 0xd3, 0xc7, 0xe7, 0xa8, 0x5d, 0x0b
+                ),
+                vec!(0x6e, 0x01), // crc
+            ]
+            .concat(),
             to_file(&vec!(FitRecord {
                 seconds_since_unix_epoch: 1583801576,
                 power: None,
@@ -452,6 +2128,7 @@ mod tests {
                 altitude: Some(81.79999999999995),
                 distance: None,
                 speed: None,
+                pedal_power_balance: None,
             })),
         );
     }
@@ -459,13 +2136,18 @@ mod tests {
     #[test]
     fn to_file_for_lat_lon_alt_cadence_hr_power() {
         assert_eq!(
-            vec!(
-                0x0c, 0x20, 0xeb, 0x07, 0x2e, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54, 0x40, 0x00,
-                0x00, 0x14, 0x00, 0x07, 253, 0x04, 0x86, 0x00, 0x04, 0x85, 0x01, 0x04, 0x85, 0x02,
-                0x02, 0x84, 0x07, 0x02, 0x84, 0x03, 0x01, 0x02, 0x04, 0x01, 0x02, 0, 0xe8, 0x98,
-                0xc9, 0x38, 0x33, 0xab, 0x58, 0x20, 0xd3, 0xc7, 0xe7, 0xa8, 0x5d, 0x0b, 0xb5, 0x00,
-                0x79, 0x5b, 0xe9, 0x1b
-            ),
+            [
+                vec!(0x0c, 0x20, 0xeb, 0x07, 0x86, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54),
+                file_id_and_creator(Some(1583801576)),
+                vec!(
+                    0x40, 0x00, 0x00, 0x14, 0x00, 0x07, 253, 0x04, 0x86, 0x00, 0x04, 0x85, 0x01,
+                    0x04, 0x85, 0x02, 0x02, 0x84, 0x07, 0x02, 0x84, 0x03, 0x01, 0x02, 0x04, 0x01,
+                    0x02, 0, 0xe8, 0x98, 0xc9, 0x38, 0x33, 0xab, 0x58, 0x20, 0xd3, 0xc7, 0xe7,
+                    0xa8, 0x5d, 0x0b, 0xb5, 0x00, 0x79, 0x5b
+                ),
+                vec!(0x8f, 0x76), // crc
+            ]
+            .concat(),
             to_file(&vec!(FitRecord {
                 seconds_since_unix_epoch: 1583801576,
                 power: Some(181),
@@ -476,6 +2158,7 @@ mod tests {
                 altitude: Some(81.79999999999995),
                 distance: None,
                 speed: None,
+                pedal_power_balance: None,
             })),
         );
     }
@@ -483,17 +2166,21 @@ mod tests {
     #[test]
     fn to_file_for_speed_and_distance_only() {
         assert_eq!(
-            vec!(
-                0x0c, 0x20, 0xeb, 0x07, 0x1a, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54, 0x40, 0x00,
-                0x00, 0x14, 0x00, 0x03, 253, 0x04, 0x86, //
-                0x05, 0x04, 0x86, // dist def
-                0x06, 0x02, 0x84, // speed def
-                0,    // record type
-                0xe8, 0x98, 0xc9, 0x38, // time data
-                0xa0, 0x86, 0x01, 0x00, // dist data
-                0x70, 0x17, // speed data
-                0xf3, 0x74 // crc
-            ),
+            [
+                vec!(0x0c, 0x20, 0xeb, 0x07, 0x72, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54),
+                file_id_and_creator(Some(1583801576)),
+                vec!(
+                    0x40, 0x00, 0x00, 0x14, 0x00, 0x03, 253, 0x04, 0x86, //
+                    0x05, 0x04, 0x86, // dist def
+                    0x06, 0x02, 0x84, // speed def
+                    0, // record type
+                    0xe8, 0x98, 0xc9, 0x38, // time data
+                    0xa0, 0x86, 0x01, 0x00, // dist data
+                    0x70, 0x17 // speed data
+                ),
+                vec!(0x8f, 0xc9), // crc
+            ]
+            .concat(),
             to_file(&vec!(FitRecord {
                 seconds_since_unix_epoch: 1583801576,
                 power: None,
@@ -504,7 +2191,118 @@ mod tests {
                 altitude: None,
                 distance: Some(1000.0), // 1km
                 speed: Some(6.0),       // 21.6 km
+                pedal_power_balance: None,
             })),
         );
     }
+
+    #[test]
+    fn to_file_with_device_info_before_record() {
+        assert_eq!(
+            [
+                vec!(0x0c, 0x20, 0xeb, 0x07, 0x96, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54),
+                file_id_and_creator(Some(1583801577)),
+                vec!(
+                    0x41, 0x00, 0x00, 0x17, 0x00, 0x07, 0xfd, 0x04, 0x86, // device info def
+                    0x00, 0x01, 0x02, // index def
+                    0x01, 0x01, 0x02, // type def
+                    0x19, 0x01, 0x00, // source type def
+                    0x1b, 0x06, 0x07, // product name def
+                    0x0b, 0x01, 0x02, // battery status def
+                    0x20, 0x01, 0x02, // battery level def
+                    0x01, // device info type
+                    0xe8, 0x98, 0xc9, 0x38, // time data
+                    0x01, 0x07, 0x03, // index, type, and source type data
+                    0x4b, 0x49, 0x43, 0x4b, 0x52, 0x00, // product name data
+                    0x02, 0x50, // battery data
+                    0x40, 0x00, 0x00, 0x14, 0x00, 0x02, 0xfd, 0x04, 0x86, 0x07, 0x02, 0x84, //
+                    0x00, 0xe9, 0x98, 0xc9, 0x38, 0xb4, 0x00 //
+                ),
+                vec!(0x0c, 0x35), // crc
+            ]
+            .concat(),
+            to_file_with_device_infos(
+                &vec!(FitRecord {
+                    seconds_since_unix_epoch: 1583801577,
+                    power: Some(180),
+                    heart_rate: None,
+                    cadence: None,
+                    latitude: None,
+                    longitude: None,
+                    altitude: None,
+                    distance: None,
+                    speed: None,
+                    pedal_power_balance: None,
+                }),
+                &vec!(DeviceInfo {
+                    seconds_since_unix_epoch: 1583801576,
+                    device_index: 1,
+                    device_type: 7,
+                    product_name: "KICKR".to_string(),
+                    battery_level: Some(80),
+                    serial_number: None,
+                    descriptor: None,
+                })
+            ),
+        );
+    }
+
+    fn kickr_info(serial_number: Option<u32>, descriptor: Option<&str>) -> DeviceInfo {
+        DeviceInfo {
+            seconds_since_unix_epoch: 1583801576,
+            device_index: 1,
+            device_type: 7,
+            product_name: "KICKR".to_string(),
+            battery_level: None,
+            serial_number,
+            descriptor: descriptor.map(|x| x.to_string()),
+        }
+    }
+
+    #[test]
+    fn device_info_includes_the_serial_number_and_descriptor() {
+        let file = to_file_with_device_infos(
+            &vec![],
+            &vec![kickr_info(Some(123456), Some("Wahoo Fitness KICKR fw 4.2.1"))],
+        );
+        let device_info = decode(&file).into_iter().find(|m| m.0 == 23 && m.1[0] == 1).unwrap();
+        assert_eq!(
+            [
+                vec![1, 7, 3],
+                b"KICKR\0".to_vec(),
+                123456u32.to_le_bytes().to_vec(),
+                b"Wahoo Fitness KICKR fw 4.2.1\0".to_vec(),
+            ]
+            .concat(),
+            device_info.1
+        );
+    }
+
+    #[test]
+    fn device_info_descriptor_is_cut_short_on_a_char_boundary() {
+        let descriptor = "é".repeat(40);
+        let file = to_file_with_device_infos(&vec![], &vec![kickr_info(None, Some(&descriptor))]);
+        let device_info = decode(&file).into_iter().find(|m| m.0 == 23 && m.1[0] == 1).unwrap();
+        assert_eq!(
+            [b"KICKR\0".to_vec(), "é".repeat(31).into_bytes(), vec![0]].concat(),
+            device_info.1[3..]
+        );
+    }
+
+    #[test]
+    fn to_file_with_indoor_sport() {
+        assert_eq!(
+            [
+                vec!(0x0c, 0x20, 0xeb, 0x07, 0x67, 0x00, 0x00, 0x00, 0x2e, 0x46, 0x49, 0x54),
+                file_id_and_creator(None),
+                vec!(
+                    0x42, 0x00, 0x00, 0x0c, 0x00, 0x02, 0x00, 0x01, 0x00, 0x01, 0x01, 0x00, //
+                    0x02, 0x02, 0x06 // cycling, indoor cycling
+                ),
+                vec!(0xfa, 0x2b), // crc
+            ]
+            .concat(),
+            to_file_with_sport(&vec!(), &vec!(), Some(Sport::IndoorCycling)),
+        );
+    }
 }
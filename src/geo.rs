@@ -0,0 +1,126 @@
+// Great-circle math for GPS coordinates (in degrees).  The earth is treated as
+// a sphere, which is well within GPS error over the distances between fixes.
+
+// Mean radius of the earth in meters
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+// Distance in meters between two points along the surface of the earth
+pub fn haversine_m(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    // Rounding can push a just past 1 for nearly antipodal points
+    2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin()
+}
+
+// The initial heading (clockwise from true north, in [0, 360)) to travel from
+// the first point to the second along a great circle.
+#[allow(dead_code)]
+pub fn bearing_deg(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lon = (lon2 - lon1).to_radians();
+    let y = d_lon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_lon.cos();
+    (y.atan2(x).to_degrees() + 360.0) % 360.0
+}
+
+// Distance travelled (in meters) along successive fixes, for when there's no
+// wheel sensor to measure it.
+#[derive(Debug, Clone)]
+pub struct Odometer {
+    last: Option<(f64, f64)>,
+    meters: f64,
+}
+
+impl Odometer {
+    pub fn new() -> Odometer {
+        Odometer {
+            last: None,
+            meters: 0.0,
+        }
+    }
+
+    // Adds the distance from the previous fix, returning the new total
+    pub fn update(&mut self, lat: f64, lon: f64) -> f64 {
+        if let Some((last_lat, last_lon)) = self.last {
+            self.meters += haversine_m(last_lat, last_lon, lat, lon);
+        }
+        self.last = Some((lat, lon));
+        self.meters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bearing_deg, haversine_m, Odometer};
+
+    const LONDON: (f64, f64) = (51.5074, -0.1278);
+    const PARIS: (f64, f64) = (48.8566, 2.3522);
+
+    fn assert_close(expected: f64, actual: f64, tolerance: f64) {
+        assert!(
+            (expected - actual).abs() <= tolerance,
+            "expected {} but got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn haversine_same_point_is_zero() {
+        assert_eq!(haversine_m(LONDON.0, LONDON.1, LONDON.0, LONDON.1), 0.0);
+    }
+
+    #[test]
+    fn haversine_one_degree_of_latitude() {
+        assert_close(111_195.0, haversine_m(0.0, 0.0, 1.0, 0.0), 1.0);
+    }
+
+    #[test]
+    fn haversine_london_to_paris() {
+        assert_close(343_500.0, haversine_m(LONDON.0, LONDON.1, PARIS.0, PARIS.1), 500.0);
+    }
+
+    #[test]
+    fn haversine_is_symmetric() {
+        assert_close(
+            haversine_m(LONDON.0, LONDON.1, PARIS.0, PARIS.1),
+            haversine_m(PARIS.0, PARIS.1, LONDON.0, LONDON.1),
+            1e-6,
+        );
+    }
+
+    #[test]
+    fn haversine_antipodes() {
+        assert_close(20_015_087.0, haversine_m(0.0, 0.0, 0.0, 180.0), 1.0);
+    }
+
+    #[test]
+    fn bearing_cardinal_directions() {
+        assert_close(0.0, bearing_deg(0.0, 0.0, 1.0, 0.0), 1e-9);
+        assert_close(90.0, bearing_deg(0.0, 0.0, 0.0, 1.0), 1e-9);
+        assert_close(180.0, bearing_deg(0.0, 0.0, -1.0, 0.0), 1e-9);
+        assert_close(270.0, bearing_deg(0.0, 0.0, 0.0, -1.0), 1e-9);
+    }
+
+    #[test]
+    fn bearing_london_to_paris() {
+        assert_close(148.1, bearing_deg(LONDON.0, LONDON.1, PARIS.0, PARIS.1), 0.5);
+    }
+
+    #[test]
+    fn odometer_starts_at_zero() {
+        let mut odometer = Odometer::new();
+        assert_eq!(0.0, odometer.update(LONDON.0, LONDON.1));
+    }
+
+    #[test]
+    fn odometer_adds_each_leg() {
+        let mut odometer = Odometer::new();
+        odometer.update(0.0, 0.0);
+        assert_close(111_195.0, odometer.update(1.0, 0.0), 1.0);
+        // And back again
+        assert_close(222_390.0, odometer.update(0.0, 0.0), 2.0);
+    }
+}
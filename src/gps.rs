@@ -1,43 +1,174 @@
 use nmea0183::{ParseResult, Parser};
-use rppal::uart::{Parity, Result, Uart};
+use rppal::uart::{Parity, Queue, Result, Uart};
 use std::{
     mem,
-    sync::{Arc, Mutex},
+    sync::{
+        mpsc::{channel, Sender},
+        Arc, Mutex,
+    },
     thread,
     thread::JoinHandle,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+// What most modules start out at
+pub const DEFAULT_BAUD: u32 = 9600;
+
+// The rates GPS modules are commonly set to.  At 9600 there's only room for
+// about 5 updates a second (of GGA and RMC alone), so 10Hz needs at least
+// 38400.
+pub const SUPPORTED_BAUDS: &[u32] = &[4800, 9600, 19200, 38400, 57600, 115200];
+
+pub fn parse_baud(s: &str) -> Option<u32> {
+    s.parse().ok().filter(|baud| SUPPORTED_BAUDS.contains(baud))
+}
+
+// An init command can be named by one of the PMTK presets below, and anything
+// else is taken to be a command body already.
+pub fn init_command(s: &str) -> String {
+    match s {
+        "gga-rmc" => PMTK_ONLY_GGA_AND_RMC,
+        "1hz" => PMTK_UPDATE_1HZ,
+        "5hz" => PMTK_UPDATE_5HZ,
+        "10hz" => PMTK_UPDATE_10HZ,
+        command => command,
+    }
+    .to_string()
+}
+
+// PMTK command bodies (without the $, checksum or line ending) for MediaTek
+// based modules, which can be sent at startup.
+const PMTK_ONLY_GGA_AND_RMC: &str = "PMTK314,0,1,0,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0";
+const PMTK_UPDATE_1HZ: &str = "PMTK220,1000";
+const PMTK_UPDATE_5HZ: &str = "PMTK220,200";
+const PMTK_UPDATE_10HZ: &str = "PMTK220,100";
+// Does nothing but get an acknowledgement, though any input also wakes a
+// module that's gone into standby.
+const PMTK_TEST: &str = "PMTK000";
+
+const METERS_PER_SECOND_PER_KNOT: f32 = 1852.0 / 3600.0;
+
+// Modules send at least one sentence a second, so this much silence means the
+// module has locked up (or lost power).
+const STALL_AFTER: Duration = Duration::from_secs(10);
+
+// Wraps a command body as a full NMEA style sentence, checksum included
+fn sentence(body: &str) -> String {
+    let checksum = body.bytes().fold(0, |acc, b| acc ^ b);
+    format!("${}*{:02X}\r\n", body, checksum)
+}
+
+// Notices when the module has gone quiet, and says when to try waking it
+// (right away, and then again each time it's been quiet that long again).
+struct Watchdog {
+    stall_after: Duration,
+    last_sentence: Instant,
+    last_attempt: Option<Instant>,
+}
+
+impl Watchdog {
+    fn new(stall_after: Duration, now: Instant) -> Watchdog {
+        Watchdog {
+            stall_after,
+            last_sentence: now,
+            last_attempt: None,
+        }
+    }
+
+    fn sentence(&mut self, now: Instant) {
+        self.last_sentence = now;
+        self.last_attempt = None;
+    }
+
+    fn is_stalled(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.last_sentence) >= self.stall_after
+    }
+
+    fn should_recover(&mut self, now: Instant) -> bool {
+        let is_due = self
+            .last_attempt
+            .map_or(true, |a| now.saturating_duration_since(a) >= self.stall_after);
+        if self.is_stalled(now) && is_due {
+            self.last_attempt = Some(now);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Drops anything half received, wakes the module, and then sends the init
+// commands again in case the module lost power and forgot them.
+fn recover(uart: &mut Uart, init_commands: &[String]) -> Result<()> {
+    uart.flush(Queue::Input)?;
+    uart.write(sentence(PMTK_TEST).as_bytes())?;
+    for command in init_commands {
+        uart.write(sentence(command).as_bytes())?;
+    }
+    Ok(())
+}
+
+type SharedHandler = Arc<Mutex<Option<Box<dyn FnMut(ParseResult) + Send>>>>;
+
+// Sentences are handed to the handler on their own thread, so a slow handler
+// (like a DB insert) never holds up reading the UART, where sentences would be
+// dropped.  The thread ends once the sender is dropped.
+fn spawn_dispatcher(handler: SharedHandler) -> (Sender<ParseResult>, JoinHandle<()>) {
+    let (tx, rx) = channel();
+    let join_handle = thread::spawn(move || {
+        for r in rx {
+            if let Some(handler) = handler.lock().unwrap().as_mut() {
+                handler(r);
+            }
+        }
+    });
+    (tx, join_handle)
+}
+
 pub struct Gps {
     running: Option<Arc<()>>,
     join_handle: Option<JoinHandle<()>>,
-    handler: Arc<Mutex<Option<Box<dyn FnMut(ParseResult) + Send>>>>,
+    dispatcher_join_handle: Option<JoinHandle<()>>,
+    handler: SharedHandler,
+    stall_handler: Arc<Mutex<Option<Box<dyn FnMut(bool) + Send>>>>,
 }
 
 impl Gps {
-    pub fn new() -> Result<Gps> {
-        let mut uart = Uart::new(9600, Parity::None, 8, 1)?;
+    // Each init command is sent once before we start reading, which can set
+    // the update rate or cut the sentences down to only the ones we parse (so
+    // there's less to drop at the UART).
+    pub fn new(baud: u32, init_commands: &[String]) -> Result<Gps> {
+        let mut uart = Uart::new(baud, Parity::None, 8, 1)?;
         uart.send_start()?;
-        let handler: Arc<Mutex<Option<Box<dyn FnMut(ParseResult) + Send>>>> =
+        if !init_commands.is_empty() {
+            uart.set_write_mode(true)?;
+            for command in init_commands {
+                uart.write(sentence(command).as_bytes())?;
+            }
+        }
+        let handler: SharedHandler = Arc::new(Mutex::new(None));
+        let stall_handler: Arc<Mutex<Option<Box<dyn FnMut(bool) + Send>>>> =
             Arc::new(Mutex::new(None));
 
-        let handler_for_thread = handler.clone();
+        let (tx, dispatcher_join_handle) = spawn_dispatcher(handler.clone());
+        let stall_handler_for_thread = stall_handler.clone();
+        let init_commands = init_commands.to_vec();
         let running_for_thread = Arc::new(());
         let running = Some(running_for_thread.clone());
         let join_handle = Some(thread::spawn(move || {
             let mut parser = Parser::new();
             let mut buffer = vec![0; 82];
+            let mut watchdog = Watchdog::new(STALL_AFTER, Instant::now());
+            let mut was_stalled = false;
             loop {
                 let byte_count = uart.read(&mut buffer[..]).unwrap();
 
                 if byte_count > 0 {
-                    // TODO: Put handler in a separate thread connected by a queue
-                    // so that the "user" code does not block the read/parse thread
                     for result in parser.parse_from_bytes(&buffer[..byte_count]) {
                         if let Ok(r) = result {
-                            if let Some(handler) = handler_for_thread.lock().unwrap().as_mut() {
-                                handler(r);
-                            }
+                            watchdog.sentence(Instant::now());
+                            // This only fails if the dispatcher has panicked
+                            let _ = tx.send(r);
                         }
                     }
                 } else {
@@ -45,6 +176,21 @@ impl Gps {
                     thread::sleep(Duration::from_millis(50));
                 }
 
+                let now = Instant::now();
+                if watchdog.should_recover(now) {
+                    println!("GPS stalled, trying to wake it");
+                    if let Err(e) = recover(&mut uart, &init_commands) {
+                        println!("Failed to wake the GPS: {:?}", e);
+                    }
+                }
+                let is_stalled = watchdog.is_stalled(now);
+                if is_stalled != was_stalled {
+                    was_stalled = is_stalled;
+                    if let Some(handler) = stall_handler_for_thread.lock().unwrap().as_mut() {
+                        handler(is_stalled);
+                    }
+                }
+
                 // If the thread is  the last owner of the Arc, then there are
                 // no more interested parties and we terminate
                 if Arc::strong_count(&running_for_thread) <= 1 {
@@ -55,7 +201,9 @@ impl Gps {
         Ok(Gps {
             running,
             join_handle,
+            dispatcher_join_handle: Some(dispatcher_join_handle),
             handler,
+            stall_handler,
         })
     }
 
@@ -63,6 +211,29 @@ impl Gps {
         let mut handler = self.handler.lock().unwrap();
         *handler = Some(f);
     }
+
+    // Called with true once no sentences have arrived for a while, and then
+    // with false once they start again.
+    pub fn on_stall_change(&mut self, f: Box<dyn FnMut(bool) + Send>) -> () {
+        let mut stall_handler = self.stall_handler.lock().unwrap();
+        *stall_handler = Some(f);
+    }
+}
+
+// Ground speed (in m/s) from an RMC sentence with a fix
+pub fn speed(s: &ParseResult) -> Option<f32> {
+    match s {
+        ParseResult::RMC(Some(rmc)) => Some(rmc.speed.as_knots() * METERS_PER_SECOND_PER_KNOT),
+        _ => None,
+    }
+}
+
+// Latitude and longitude (in degrees) from a GGA sentence with a fix
+pub fn position(s: &ParseResult) -> Option<(f64, f64)> {
+    match s {
+        ParseResult::GGA(Some(gga)) => Some((gga.latitude.as_f64(), gga.longitude.as_f64())),
+        _ => None,
+    }
 }
 
 impl Drop for Gps {
@@ -72,5 +243,103 @@ impl Drop for Gps {
         if let Some(jh) = mem::replace(&mut self.join_handle, None) {
             jh.join().unwrap();
         }
+        // The reading thread has dropped its sender, so this finishes once
+        // everything read has been handled.
+        if let Some(jh) = mem::replace(&mut self.dispatcher_join_handle, None) {
+            jh.join().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::init_command;
+    use super::parse_baud;
+    use super::sentence;
+    use super::spawn_dispatcher;
+    use super::Watchdog;
+    use super::PMTK_ONLY_GGA_AND_RMC;
+    use super::PMTK_UPDATE_10HZ;
+    use super::PMTK_UPDATE_5HZ;
+    use nmea0183::ParseResult;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const STALL_AFTER: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn watchdog_stalls_without_sentences() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(STALL_AFTER, start);
+        assert!(!watchdog.is_stalled(start + Duration::from_secs(9)));
+        watchdog.sentence(start + Duration::from_secs(9));
+        assert!(!watchdog.is_stalled(start + Duration::from_secs(18)));
+        assert!(watchdog.is_stalled(start + Duration::from_secs(19)));
+    }
+
+    #[test]
+    fn watchdog_retries_recovery_while_stalled() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(STALL_AFTER, start);
+        assert!(!watchdog.should_recover(start + Duration::from_secs(5)));
+        assert!(watchdog.should_recover(start + Duration::from_secs(10)));
+        assert!(!watchdog.should_recover(start + Duration::from_secs(15)));
+        assert!(watchdog.should_recover(start + Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn watchdog_recovery_resets_on_a_sentence() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(STALL_AFTER, start);
+        assert!(watchdog.should_recover(start + Duration::from_secs(10)));
+        watchdog.sentence(start + Duration::from_secs(11));
+        assert!(!watchdog.should_recover(start + Duration::from_secs(12)));
+        assert!(watchdog.should_recover(start + Duration::from_secs(21)));
+    }
+
+    #[test]
+    fn sentence_has_the_checksum() {
+        assert_eq!("$PMTK220,200*2C\r\n", sentence(PMTK_UPDATE_5HZ));
+        assert_eq!("$PMTK220,100*2F\r\n", sentence(PMTK_UPDATE_10HZ));
+        assert_eq!(
+            "$PMTK314,0,1,0,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0*28\r\n",
+            sentence(PMTK_ONLY_GGA_AND_RMC)
+        );
+    }
+
+    #[test]
+    fn parse_baud_only_accepts_common_rates() {
+        assert_eq!(Some(38400), parse_baud("38400"));
+        assert_eq!(Some(115200), parse_baud("115200"));
+        assert_eq!(None, parse_baud("38401"));
+        assert_eq!(None, parse_baud("fast"));
+    }
+
+    #[test]
+    fn init_command_expands_presets() {
+        assert_eq!(PMTK_UPDATE_10HZ, init_command("10hz"));
+        assert_eq!(PMTK_ONLY_GGA_AND_RMC, init_command("gga-rmc"));
+        assert_eq!("PMTK220,500", init_command("PMTK220,500"));
+    }
+
+    #[test]
+    fn a_slow_handler_does_not_block_sending() {
+        let handled = Arc::new(Mutex::new(0));
+        let handled_for_handler = handled.clone();
+        let handler: Box<dyn FnMut(ParseResult) + Send> = Box::new(move |_| {
+            thread::sleep(Duration::from_millis(50));
+            *handled_for_handler.lock().unwrap() += 1;
+        });
+        let (tx, join_handle) = spawn_dispatcher(Arc::new(Mutex::new(Some(handler))));
+        let start = Instant::now();
+        for _ in 0..10 {
+            tx.send(ParseResult::GGA(None)).unwrap();
+        }
+        // Sending is done long before the handler is
+        assert!(start.elapsed() < Duration::from_millis(50));
+        drop(tx);
+        join_handle.join().unwrap();
+        assert_eq!(10, *handled.lock().unwrap());
     }
 }
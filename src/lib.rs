@@ -0,0 +1,4 @@
+// The parts of rust-cycle that don't need any hardware, so that integration
+// tests (in tests/) can use them too.
+pub mod ble;
+pub mod utils;
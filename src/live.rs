@@ -0,0 +1,134 @@
+use std::collections::VecDeque;
+
+// An in-memory window of the most recent samples, so that live graphs can
+// poll cheaply without re-reading the DB.
+pub struct LiveBuffer {
+    // Oldest first, at most one sample per second
+    samples: VecDeque<LiveSample>,
+    capacity: usize,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LiveSample {
+    pub seconds_since_unix_epoch: u64,
+    pub power: Option<i16>,
+    pub heart_rate: Option<u16>,
+    pub cadence: Option<u8>,
+}
+
+impl LiveBuffer {
+    // Holds the last `capacity` seconds of samples
+    pub fn new(capacity: usize) -> LiveBuffer {
+        LiveBuffer {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn update_power(&mut self, seconds_since_unix_epoch: u64, power: i16) {
+        self.sample_for(seconds_since_unix_epoch).power = Some(power);
+    }
+
+    pub fn update_heart_rate(&mut self, seconds_since_unix_epoch: u64, heart_rate: u16) {
+        self.sample_for(seconds_since_unix_epoch).heart_rate = Some(heart_rate);
+    }
+
+    pub fn update_cadence(&mut self, seconds_since_unix_epoch: u64, cadence: u8) {
+        self.sample_for(seconds_since_unix_epoch).cadence = Some(cadence);
+    }
+
+    // Readings for the same second are merged into one sample (later readings
+    // win).  Readings older than the newest sample are also folded into it,
+    // rather than rewriting history.
+    fn sample_for(&mut self, seconds_since_unix_epoch: u64) -> &mut LiveSample {
+        let is_new_second = self
+            .samples
+            .back()
+            .map_or(true, |s| s.seconds_since_unix_epoch < seconds_since_unix_epoch);
+        if is_new_second {
+            if self.samples.len() >= self.capacity {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(LiveSample {
+                seconds_since_unix_epoch,
+                power: None,
+                heart_rate: None,
+                cadence: None,
+            });
+        }
+        // We just ensured there's at least one sample
+        self.samples.back_mut().unwrap()
+    }
+
+    pub fn to_json(&self) -> String {
+        let show = |o: Option<String>| o.unwrap_or("null".to_string());
+        let samples: Vec<String> = self
+            .samples
+            .iter()
+            .map(|s| {
+                format!(
+                    r#"{{"timestamp":{},"power":{},"heart_rate":{},"cadence":{}}}"#,
+                    s.seconds_since_unix_epoch,
+                    show(s.power.map(|x| x.to_string())),
+                    show(s.heart_rate.map(|x| x.to_string())),
+                    show(s.cadence.map(|x| x.to_string())),
+                )
+            })
+            .collect();
+        format!("[{}]", samples.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LiveBuffer;
+    use super::LiveSample;
+
+    #[test]
+    fn readings_in_the_same_second_are_merged() {
+        let mut live = LiveBuffer::new(10);
+        live.update_power(100, 200);
+        live.update_heart_rate(100, 150);
+        live.update_power(100, 210);
+        assert_eq!(
+            live.samples.iter().copied().collect::<Vec<_>>(),
+            vec![LiveSample {
+                seconds_since_unix_epoch: 100,
+                power: Some(210),
+                heart_rate: Some(150),
+                cadence: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn oldest_samples_are_dropped() {
+        let mut live = LiveBuffer::new(2);
+        live.update_power(100, 200);
+        live.update_power(101, 201);
+        live.update_power(102, 202);
+        assert_eq!(
+            live.samples
+                .iter()
+                .map(|s| s.seconds_since_unix_epoch)
+                .collect::<Vec<_>>(),
+            vec![101, 102]
+        );
+    }
+
+    #[test]
+    fn to_json_uses_null_when_missing() {
+        let mut live = LiveBuffer::new(10);
+        live.update_cadence(100, 90);
+        live.update_power(101, 200);
+        assert_eq!(
+            live.to_json(),
+            r#"[{"timestamp":100,"power":null,"heart_rate":null,"cadence":90},{"timestamp":101,"power":200,"heart_rate":null,"cadence":null}]"#
+        );
+    }
+
+    #[test]
+    fn to_json_empty() {
+        assert_eq!(LiveBuffer::new(10).to_json(), "[]");
+    }
+}
@@ -1,48 +1,99 @@
-mod ble;
+mod activity;
 mod buttons;
+mod console_display;
+mod csv;
 mod cycle_tree;
 mod display;
 mod fit;
+mod geo;
 mod gps;
+mod live;
 #[cfg(not(feature = "simulator"))]
 mod memory_lcd;
 #[cfg(feature = "simulator")]
 mod memory_lcd_simulator;
+mod network;
 mod peripherals;
+mod power_mode;
+mod profiles;
+mod status;
 mod telemetry_db;
+mod tcx;
 mod telemetry_server;
-mod utils;
+mod wbal;
 mod workout;
+mod workout_import;
 
 use ble::{
+    csc_feature::CscFeature,
     csc_measurement,
     csc_measurement::{
         checked_crank_rpm_and_new_count, checked_wheel_rpm_and_new_count, parse_csc_measurement,
-        CscMeasurement,
+        try_parse_csc_measurement, CscMeasurement,
     },
     cycling_power_measurement,
-    cycling_power_measurement::{parse_cycling_power_measurement, CyclingPowerMeasurement},
+    cycling_power_measurement::{
+        parse_cycling_power_measurement, try_parse_cycling_power_measurement,
+        CyclingPowerMeasurement, PowerFilter,
+    },
+    device_information::DeviceInformation,
     heart_rate_measurement::parse_hrm,
+    indoor_bike_data::{parse_indoor_bike_data, try_parse_indoor_bike_data},
+    rsc_measurement,
+    rsc_measurement::parse_rsc_measurement,
 };
 use btleplug::api::{Central, Manager as _, ScanFilter, Peripheral};
 use btleplug::platform::Manager;
-use btleplug::Error::DeviceNotFound;
-use peripherals::{kickr, hrm, assioma, speed, cadence};
-use std::collections::BTreeSet;
+use peripherals::{
+    kickr, ftms, hrm, assioma, speed, cadence, rsc, battery, Device, LostDevices, TrainerProtocol,
+};
+use profiles::{Profile, Profiles};
+use rust_cycle::{ble, utils};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
+use std::io::Write;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use futures::stream::StreamExt;
+use telemetry_db::SessionClock;
+use tokio::signal::unix::{signal, SignalKind};
+use utils::lock_or_recover;
 use workout::{create_big_start_interval, ramp_test, single_value};
 #[cfg(not(feature = "simulator"))]
 use crate::memory_lcd::MemoryLcd;
 #[cfg(feature = "simulator")]
 use crate::memory_lcd_simulator::MemoryLcd;
 
-// TODO:  Allow calibration
-// In meters
-const WHEEL_CIRCUMFERENCE: f32 = 2.105;
+// In meters, used until a bike's wheel has been calibrated
+const DEFAULT_WHEEL_CIRCUMFERENCE: f32 = 2.105;
+
+// How many seconds of recent samples are kept in memory for live graphs
+const LIVE_SECONDS: usize = 300;
+
+// How long confirmation of a button action is shown during a ride
+const TOAST_DURATION: Duration = Duration::from_secs(1);
+
+// How long we warn that the trainer is no longer in ERG mode
+const ERG_FALLBACK_TOAST_DURATION: Duration = Duration::from_secs(10);
+
+// How long we warn that a sensor's battery is running low
+const LOW_BATTERY_TOAST_DURATION: Duration = Duration::from_secs(10);
+
+// How long the server's PIN is shown once the ride starts
+const PIN_DURATION: Duration = Duration::from_secs(5);
+
+// A session whose last entry is more recent than this (when we start up) was
+// most likely cut short by a crash, and so can be resumed.
+const RESUME_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+// Anything outside of this (in meters) is not a bike wheel we'd expect, and
+// most likely means the roll-out was miscounted.
+const MIN_WHEEL_CIRCUMFERENCE: f32 = 1.0;
+const MAX_WHEEL_CIRCUMFERENCE: f32 = 2.6;
 
 #[derive(Clone)]
 enum OrExit<T> {
@@ -50,6 +101,13 @@ enum OrExit<T> {
     Exit,
 }
 
+// A session to keep recording into, and how far into it we are now
+#[derive(Clone, Debug, PartialEq)]
+struct Resume {
+    session_key: u64,
+    elapsed: Duration,
+}
+
 #[derive(Clone, Debug)]
 enum SetupNextStep {
     TryAgain,
@@ -63,22 +121,157 @@ enum IgnorableError {
     Exit,
 }
 
-#[derive(Clone)]
-struct SelectedDevices {
-    assioma: bool,
-    cadence: bool,
-    gps: bool,
-    hr: bool,
-    kickr: bool,
-    speed: bool,
-}
-
 #[tokio::main]
 pub async fn main() -> btleplug::Result<()> {
     env_logger::init();
 
-    let args: BTreeSet<String> = env::args().collect();
+    let arg_list: Vec<String> = env::args().collect();
+    let args: BTreeSet<String> = arg_list.iter().cloned().collect();
     let is_version_mode = args.contains("-v") || args.contains("--version");
+    // Rather than holding the final target when a workout (without a tail)
+    // completes, release the trainer and keep recording as a free ride.
+    let is_keep_recording = args.contains("--keep-recording");
+    // Walk through a roll-out to measure the wheel circumference of the
+    // profile's bike before riding.
+    let is_calibrate_wheel = args.contains("--calibrate-wheel");
+    // Additionally keep every BLE notification exactly as it arrived, for
+    // diagnosing sensors.  This is off by default, since it's large.
+    let is_raw_capture = args.contains("--raw-capture");
+    // For devices with no network to serve from (or no need to), exports are
+    // still available via --export.
+    let is_no_server = args.contains("--no-server");
+    // Without a screen or buttons (like over SSH), show metrics in the
+    // terminal and take button presses from the keyboard instead.
+    let is_console = args.contains("--console");
+    // Start with slower screen, button and LCD updates to save battery (this
+    // can also be toggled mid-ride by holding ButtonC).
+    let is_low_power = args.contains("--low-power");
+    // Writes out the profiles (the built in ones, if there's no file yet) as
+    // a starting point for editing them, rather than riding.
+    let is_save_profiles = args.contains("--save-profiles");
+    // How sessions are keyed (--session-keys=seconds|counter).  A counter
+    // doesn't depend on the clock, which may not be set yet without a network.
+    let session_key_strategy = args
+        .iter()
+        .find_map(|a| a.strip_prefix("--session-keys="))
+        .map_or(Some(telemetry_db::SessionKeyStrategy::UnixSeconds), |s| {
+            telemetry_db::SessionKeyStrategy::parse(s)
+        })
+        .expect("--session-keys must be seconds or counter");
+    // Start with light text on a dark background, which can be easier to read
+    // in bright sunlight (this can also be toggled mid-ride by holding ButtonB).
+    let is_inverted = args.contains("--invert");
+    // Turns the whole UI clockwise (--rotate=0|90|180|270), to suit how the
+    // screen is mounted.
+    #[cfg(not(feature = "simulator"))]
+    let rotation = args
+        .iter()
+        .find_map(|a| a.strip_prefix("--rotate="))
+        .map_or(Some(memory_lcd::Rotation::Deg0), |d| {
+            d.parse().ok().and_then(memory_lcd::Rotation::from_degrees)
+        })
+        .expect("--rotate must be 0, 90, 180 or 270");
+    // For GPS modules that have been set to a faster rate (--gps-baud=38400)
+    let gps_baud = args
+        .iter()
+        .find_map(|a| a.strip_prefix("--gps-baud="))
+        .map_or(Some(gps::DEFAULT_BAUD), gps::parse_baud)
+        .expect("--gps-baud must be 4800, 9600, 19200, 38400, 57600 or 115200");
+    // Commands sent to the GPS module as it starts, separated by semicolons
+    // and without the $ or checksum, where 1hz, 5hz, 10hz and gga-rmc are
+    // MediaTek presets (--gps-init=10hz;gga-rmc or --gps-init=PMTK220,200).
+    let gps_init: Vec<String> = args
+        .iter()
+        .find_map(|a| a.strip_prefix("--gps-init="))
+        .map_or(Vec::new(), |i| i.split(';').map(gps::init_command).collect());
+    // Which metrics go where on the Standard page, overriding the profile's
+    // (--layout=distance,speed,cadence,energy,gps,clock,elapsed,power,hr).
+    let o_layout = args.iter().find_map(|a| a.strip_prefix("--layout=")).map(|l| {
+        display::parse_layout(l).expect(
+            "--layout must be a list of distance, speed, cadence, energy, gps, clock, \
+             elapsed, power or hr",
+        )
+    });
+    // How fields show readings that have gone stale, for any that shouldn't be
+    // blanked (--stale=power:hold,hr:marker).
+    let stale_policies = args
+        .iter()
+        .find_map(|a| a.strip_prefix("--stale="))
+        .map_or(Some(Vec::new()), display::parse_stale_policies)
+        .expect("--stale must be a list of field:policy, where policy is blank, hold or marker");
+    // Bigger text on the Standard page, with fewer fields (--scale=large).  The
+    // large scale has its own default layout, though --layout still applies.
+    let scale = args
+        .iter()
+        .find_map(|a| a.strip_prefix("--scale="))
+        .map_or(Some(display::Scale::Normal), display::Scale::parse)
+        .expect("--scale must be normal or large");
+    // What the display does when the workout's target changes, overriding the
+    // profile's (--target-change=power-track|stay|auto-return:SECONDS).
+    let o_target_change_page =
+        args.iter().find_map(|a| a.strip_prefix("--target-change=")).map(|t| {
+            display::TargetChangePage::parse(t)
+                .expect("--target-change must be power-track, stay or auto-return:SECONDS")
+        });
+    // Controls the trainer through the standard Fitness Machine Service rather
+    // than Wahoo's protocol (--trainer=ftms), for other brands of trainer.
+    let trainer_protocol = args
+        .iter()
+        .find_map(|a| a.strip_prefix("--trainer="))
+        .map_or(Some(TrainerProtocol::Kickr), TrainerProtocol::parse)
+        .expect("--trainer must be kickr or ftms");
+    // Writes a FIT file to stdout instead of riding, either for the latest
+    // session (--export) or specific ones (--export=KEY or --export=KEY-KEY).
+    let o_export = args.iter().find_map(|a| {
+        if a == "--export" {
+            Some(None)
+        } else {
+            a.strip_prefix("--export=").map(Some)
+        }
+    });
+    // Exports smaller files, though not every reader handles the compressed
+    // timestamps that makes them smaller.
+    let is_compress_fit = args.contains("--compress-fit");
+    // Copies every ride (and setting) out to or back in from a file, which
+    // unlike the sled directory itself can be restored with any version.
+    let flag_value = |flag: &str| {
+        arg_list
+            .windows(2)
+            .find(|w| w[0] == flag)
+            .map(|w| w[1].clone())
+    };
+    let o_backup = flag_value("--backup");
+    let o_restore = flag_value("--restore");
+    // Reads the records back out of a FIT file (say, of an old ride) and
+    // writes them to stdout as CSV.
+    let o_fit_to_csv = flag_value("--fit-to-csv");
+    // Offers a workout from a .zwo file (--zwo PATH) alongside the built in
+    // ones, where its powers are relative to the rider's FTP (--ftp WATTS).
+    let o_imported_workout = flag_value("--zwo").map(|path| {
+        let ftp = flag_value("--ftp")
+            .and_then(|f| f.parse::<u16>().ok())
+            .expect("--zwo needs --ftp in watts");
+        let imported = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|xml| workout_import::from_zwo(&xml, ftp).map_err(|e| e.to_string()));
+        match imported {
+            Ok(workout) => workout,
+            Err(e) => {
+                eprintln!("Could not import {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    });
+    // Plays a recorded session back through the display instead of riding
+    // (--replay KEY), optionally faster than it was recorded (--speed N).
+    let o_replay = flag_value("--replay")
+        .map(|k| k.parse::<u64>().expect("--replay must be a session key"));
+    let replay_speed = flag_value("--speed").map_or(1.0, |s| {
+        s.parse::<f64>()
+            .ok()
+            .filter(|s| *s > 0.0)
+            .expect("--speed must be a positive number")
+    });
     let version =
         match (core::option_env!("NIX_STORE"), core::option_env!("out")) {
             (Some(store), Some(out)) => out.strip_prefix(&[store, "/"].join("")).unwrap(),
@@ -87,151 +280,214 @@ pub async fn main() -> btleplug::Result<()> {
 
     if is_version_mode {
         println!("{}", version);
-    } else {
+    } else if let Some(path) = o_backup {
         let db = telemetry_db::open_default().unwrap();
-
-        // Serve our telemetry data
-        let server = telemetry_server::TelemetryServer::new(db.clone());
-
+        let file = std::fs::File::create(&path).unwrap();
+        db.export_all(std::io::BufWriter::new(file)).unwrap();
+        println!("Backed up to {}", path);
+    } else if let Some(path) = o_restore {
+        let db = telemetry_db::open_default().unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        db.import_all(std::io::BufReader::new(file)).unwrap();
+        println!("Restored from {}", path);
+    } else if let Some(path) = o_fit_to_csv {
+        let bytes = std::fs::read(&path).unwrap();
+        match fit::from_file(&bytes) {
+            Ok(records) => print!("{}", csv::to_csv(&records)),
+            Err(e) => {
+                eprintln!("Could not read {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    } else if is_save_profiles {
+        let path = Path::new(profiles::DEFAULT_PATH);
+        Profiles::load_or_default(path).save(path).unwrap();
+        println!("Saved profiles to {}", path.display());
+    } else if let Some(o_keys) = o_export {
+        let db = telemetry_db::open_default().unwrap();
+        match export_fit(&db, o_keys, is_compress_fit) {
+            Some(Ok(fit)) => std::io::stdout().write_all(&fit).unwrap(),
+            Some(Err(e)) => {
+                eprintln!("Could not export: {}", e);
+                std::process::exit(1);
+            }
+            None => {
+                eprintln!("No matching sessions to export");
+                std::process::exit(1);
+            }
+        }
+    } else {
         // TODO: The Combo of Buttons and Display should make up a sort of
         // "UserInterface" that hides the buttons (this would make using the
         // simulator much easier, for example).
 
+        let intervals: power_mode::SharedIntervals = Arc::new(Mutex::new(if is_low_power {
+            power_mode::LOW_POWER
+        } else {
+            power_mode::NORMAL
+        }));
+
         // Setup a channel for sending and receiving button signals
         let (button_tx, button_rx) = std::sync::mpsc::channel();
 
         // Create Our Display
-        #[cfg(feature = "simulator")]
-        let memory_lcd = MemoryLcd::new(button_tx.clone()).unwrap();
-        #[cfg(not(feature = "simulator"))]
-        let memory_lcd = MemoryLcd::new().unwrap();
-        let mut display = display::Display::new(version.to_string(), memory_lcd);
+        let mut display = if is_console {
+            let console = console_display::ConsoleDisplay::new(button_tx.clone());
+            display::Display::new_console(version.to_string(), console)
+        } else {
+            #[cfg(feature = "simulator")]
+            let mut memory_lcd = MemoryLcd::new(button_tx.clone()).unwrap();
+            #[cfg(not(feature = "simulator"))]
+            let mut memory_lcd = MemoryLcd::new(intervals.clone()).unwrap();
+            #[cfg(not(feature = "simulator"))]
+            memory_lcd.set_rotation(rotation);
+            memory_lcd.set_inverted(is_inverted);
+            display::Display::new(version.to_string(), memory_lcd)
+        };
 
-        // Create our Buttons
-        let buttons = buttons::Buttons::new();
+        // Shown for as long as the rest of starting up takes, rather than
+        // holding up the profile menu
+        render_boot_splash(&mut display, version);
 
-        // Attach all of our handlers to send button events into the channel
-        let btx = button_tx.clone();
-        buttons.on_press(
-            buttons::Button::ButtonE,
-            Box::new(move || btx.send((buttons::Button::ButtonE, false)).unwrap())
-        );
+        let db = telemetry_db::open_default().unwrap();
 
-        let btx = button_tx.clone();
-        buttons.on_press(
-            buttons::Button::ButtonD,
-            Box::new(move || btx.send((buttons::Button::ButtonD, false)).unwrap())
-        );
+        let profiles = Profiles::load_or_default(Path::new(profiles::DEFAULT_PATH));
 
-        let btx = button_tx.clone();
-        buttons.on_press(
-            buttons::Button::ButtonC,
-            Box::new(move || btx.send((buttons::Button::ButtonC, false)).unwrap())
-        );
+        // Recent samples for live graphing, kept apart from the DB
+        let live = Arc::new(Mutex::new(live::LiveBuffer::new(LIVE_SECONDS)));
 
-        let btx = button_tx.clone();
-        buttons.on_press(
-            buttons::Button::ButtonB,
-            Box::new(move || btx.send((buttons::Button::ButtonB, false)).unwrap())
-        );
+        // What the device is doing, for checking on it remotely
+        let status_mutex: status::SharedStatus = Arc::new(Mutex::new(status::Status::new(version)));
 
-        let btx = button_tx.clone();
-        buttons.on_press(
-            buttons::Button::ButtonA,
-            Box::new(move || btx.send((buttons::Button::ButtonA, false)).unwrap())
-        );
+        // Serve our telemetry data (to anyone with the PIN)
+        let server_pin = telemetry_server::generate_pin();
+        let server = if is_no_server {
+            None
+        } else {
+            Some(telemetry_server::TelemetryServer::new(
+                db.clone(),
+                live.clone(),
+                status_mutex.clone(),
+                server_pin.clone(),
+            ))
+        };
 
-        let btx = button_tx.clone();
-        buttons.on_hold(
-            buttons::Button::ButtonE,
-            Duration::from_secs(3),
-            Box::new(move || btx.send((buttons::Button::ButtonE, true)).unwrap())
-        );
+        if server.is_some() {
+            println!("Server PIN: {}", server_pin);
+        }
 
-        let btx = button_tx.clone();
-        buttons.on_hold(
-            buttons::Button::ButtonD,
-            Duration::from_secs(3),
-            Box::new(move || btx.send((buttons::Button::ButtonD, true)).unwrap())
-        );
+        if let Some(session_key) = o_replay {
+            replay_session(&mut display, &db, session_key, replay_speed, &intervals);
+            return Ok(());
+        }
 
-        let btx = button_tx.clone();
-        buttons.on_hold(
-            buttons::Button::ButtonC,
-            Duration::from_secs(3),
-            Box::new(move || btx.send((buttons::Button::ButtonC, true)).unwrap())
-        );
+        // An orderly stop (systemd or Ctrl-C) is treated just like holding
+        // ButtonA, so that we stop the workout, flush the DB, and drop
+        // everything cleanly rather than being killed mid-write.  This is set
+        // up before any menus, since they wait on the rider for however long,
+        // and they have to stop too (see exit_if_stopping).
+        let m_will_exit = Arc::new(Mutex::new(false));
+        let m_will_exit_for_signal = m_will_exit.clone();
+        let db_for_signal = db.clone();
+        let mut sigterm = signal(SignalKind::terminate()).unwrap();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => (),
+                _ = sigterm.recv() => (),
+            }
+            db_for_signal.flush().unwrap();
+            *lock_or_recover(&m_will_exit_for_signal) = true;
+        });
 
-        let btx = button_tx.clone();
-        buttons.on_hold(
-            buttons::Button::ButtonB,
-            Duration::from_secs(3),
-            Box::new(move || btx.send((buttons::Button::ButtonB, true)).unwrap())
-        );
+        // Create our Buttons (the console reads the keyboard instead)
+        let o_buttons = if is_console { None } else { Some(buttons::Buttons::new(intervals.clone())) };
 
-        let btx = button_tx.clone();
-        buttons.on_hold(
-            buttons::Button::ButtonA,
-            Duration::from_secs(3),
-            Box::new(move || btx.send((buttons::Button::ButtonA, true)).unwrap())
-        );
+        // Attach all of our handlers to send button events into the channel
+        if let Some(buttons) = &o_buttons {
+            let btx = button_tx.clone();
+            buttons.on_press(
+                buttons::Button::ButtonE,
+                Box::new(move || btx.send((buttons::Button::ButtonE, false)).unwrap())
+            );
+
+            let btx = button_tx.clone();
+            buttons.on_press(
+                buttons::Button::ButtonD,
+                Box::new(move || btx.send((buttons::Button::ButtonD, false)).unwrap())
+            );
+
+            let btx = button_tx.clone();
+            buttons.on_press(
+                buttons::Button::ButtonC,
+                Box::new(move || btx.send((buttons::Button::ButtonC, false)).unwrap())
+            );
+
+            let btx = button_tx.clone();
+            buttons.on_press(
+                buttons::Button::ButtonB,
+                Box::new(move || btx.send((buttons::Button::ButtonB, false)).unwrap())
+            );
+
+            let btx = button_tx.clone();
+            buttons.on_press(
+                buttons::Button::ButtonA,
+                Box::new(move || btx.send((buttons::Button::ButtonA, false)).unwrap())
+            );
+
+            let btx = button_tx.clone();
+            buttons.on_hold(
+                buttons::Button::ButtonE,
+                Duration::from_secs(3),
+                Box::new(move || btx.send((buttons::Button::ButtonE, true)).unwrap())
+            );
+
+            let btx = button_tx.clone();
+            buttons.on_hold(
+                buttons::Button::ButtonD,
+                Duration::from_secs(3),
+                Box::new(move || btx.send((buttons::Button::ButtonD, true)).unwrap())
+            );
+
+            let btx = button_tx.clone();
+            buttons.on_hold(
+                buttons::Button::ButtonC,
+                Duration::from_secs(3),
+                Box::new(move || btx.send((buttons::Button::ButtonC, true)).unwrap())
+            );
+
+            let btx = button_tx.clone();
+            buttons.on_hold(
+                buttons::Button::ButtonB,
+                Duration::from_secs(3),
+                Box::new(move || btx.send((buttons::Button::ButtonB, true)).unwrap())
+            );
+
+            let btx = button_tx.clone();
+            buttons.on_hold(
+                buttons::Button::ButtonA,
+                Duration::from_secs(3),
+                Box::new(move || btx.send((buttons::Button::ButtonA, true)).unwrap())
+            );
+        }
+
+        // An interrupted ride can only be resumed with the profile it was
+        // started with (which may have since been removed)
+        let o_resume = db
+            .interrupted_session(seconds_since_unix_epoch(), RESUME_WINDOW)
+            .unwrap()
+            .and_then(|(session_key, elapsed)| {
+                let name = db.get_session_profile(session_key).unwrap()?;
+                let profile = profiles.profiles().iter().find(|p| p.name == name)?;
+                Some((profile.clone(), Resume { session_key, elapsed }))
+            });
 
         // TODO: Select Enums
         use OrExit::{Exit, NotExit};
-        use SelectionTreeValue::{Leaf, Node};
         let devices = selection_tree(
             &mut display,
             &button_rx,
-            vec![
-                SelectionTree {
-                    label: "Zenia".to_string(),
-                    value: Leaf(NotExit(SelectedDevices {
-                        assioma: false,
-                        cadence: true,
-                        gps: false,
-                        hr: false,
-                        kickr: true,
-                        speed: false,
-                    })),
-                },
-                SelectionTree {
-                    label: "Nathan Outdoor".to_string(),
-                    value: Leaf(NotExit(SelectedDevices {
-                        assioma: true,
-                        cadence: false,
-                        gps: true,
-                        hr: true,
-                        kickr: false,
-                        speed: true,
-                    })),
-                },
-                SelectionTree {
-                    label: "Nathan Kickr".to_string(),
-                    value: Leaf(NotExit(SelectedDevices {
-                        assioma: true,
-                        cadence: false,
-                        gps: false,
-                        hr: true,
-                        kickr: true,
-                        speed: false,
-                    })),
-                },
-                SelectionTree {
-                    label: "Nathan Rollers".to_string(),
-                    value: Leaf(NotExit(SelectedDevices {
-                        assioma: true,
-                        cadence: false,
-                        gps: false,
-                        hr: true,
-                        kickr: false,
-                        speed: true,
-                    })),
-                },
-                SelectionTree {
-                    label: "Exit".to_string(),
-                    value: Leaf(Exit),
-                },
-            ],
+            &m_will_exit,
+            profile_tree(profiles.profiles(), o_resume),
             &"Choose profile",
         );
 
@@ -242,7 +498,7 @@ pub async fn main() -> btleplug::Result<()> {
                 drop(db);
                 drop(server);
                 drop(display);
-                drop(buttons);
+                drop(o_buttons);
 
                 #[cfg(not(feature = "simulator"))]
                 std::process::Command::new("sudo")
@@ -254,100 +510,64 @@ pub async fn main() -> btleplug::Result<()> {
             }
             NotExit(x) => x,
         };
+        let (Profile { name: profile, devices }, o_resume) = devices;
 
-        let workout = selection_tree(
-            &mut display,
-            &button_rx,
-            vec![
-                SelectionTree {
-                    label: "Fixed".to_string(),
-                    value: Node(vec![
-                        SelectionTree {
-                            label: "100W".to_string(),
-                            value: Leaf(single_value(100)),
-                        },
-                        SelectionTree {
-                            label: "135W".to_string(),
-                            value: Leaf(single_value(135)),
-                        },
-                        SelectionTree {
-                            label: "140W".to_string(),
-                            value: Leaf(single_value(140)),
-                        },
-                        SelectionTree {
-                            label: "145W".to_string(),
-                            value: Leaf(single_value(145)),
-                        },
-                        SelectionTree {
-                            label: "More".to_string(),
-                            value: Node(vec![
-                                SelectionTree {
-                                    label: "150W".to_string(),
-                                    value: Leaf(single_value(150)),
-                                },
-                                SelectionTree {
-                                    label: "155W".to_string(),
-                                    value: Leaf(single_value(155)),
-                                },
-                                SelectionTree {
-                                    label: "160W".to_string(),
-                                    value: Leaf(single_value(160)),
-                                },
-                                SelectionTree {
-                                    label: "165W".to_string(),
-                                    value: Leaf(single_value(165)),
-                                },
-                                SelectionTree {
-                                    label: "170W".to_string(),
-                                    value: Leaf(single_value(170)),
-                                },
-                            ]),
-                        },
-                    ]),
-                },
-                SelectionTree {
-                    label: "Ramp".to_string(),
-                    value: Leaf(ramp_test(120)),
-                },
-                SelectionTree {
-                    label: "1st Big Interval".to_string(),
-                    value: Leaf(create_big_start_interval(
-                        (Duration::from_secs(300), 140),
-                        14,
-                        Duration::from_secs(150),
-                        (Duration::from_secs(60), 320),
-                        (Duration::from_secs(90), 120),
-                        Some(160),
-                    )),
-                },
-            ],
-            &"Choose workout",
-        );
+        // A resumed ride carries on with the workout it was riding, from the
+        // point it got to.
+        let o_resumed_workout = match &o_resume {
+            Some(resume) => db_session_to_workout(&db, resume.session_key).unwrap(),
+            None => None,
+        };
+        let (workout, workout_at) = match o_resumed_workout {
+            Some(resumed) => resumed,
+            None => (
+                choose_workout(&mut display, &button_rx, &m_will_exit, o_imported_workout.as_ref()),
+                Duration::from_secs(0),
+            ),
+        };
 
         // We want instant, because we want this to be monotonic. We don't want
         // clock drift/corrections to cause events to be processed out of order.
-        let start = Instant::now();
+        // A resumed session picks up from how far in it was, so new entries
+        // follow on from the old ones.
+        let (start, session_key) = match o_resume {
+            Some(Resume { session_key, elapsed }) => (SessionClock::resumed(elapsed), session_key),
+            None => {
+                let start = SessionClock::new();
+                let session_key = db
+                    .start_session(session_key_strategy, seconds_since_unix_epoch())
+                    .unwrap();
+                db.set_session_profile(session_key, &profile).unwrap();
+
+                // Only running profiles use a footpod, and only outdoor
+                // profiles use GPS, so that's what tells them apart
+                db.insert(
+                    session_key,
+                    start.elapsed(),
+                    telemetry_db::Notification::Sport(if devices.rsc {
+                        fit::Sport::Treadmill
+                    } else if devices.gps {
+                        fit::Sport::Cycling
+                    } else {
+                        fit::Sport::IndoorCycling
+                    }),
+                )
+                .unwrap();
+                db.insert(
+                    session_key,
+                    start.elapsed(),
+                    telemetry_db::Notification::MaxPower(devices.max_power),
+                )
+                .unwrap();
+                (start, session_key)
+            }
+        };
 
         display.set_start(Some(start));
 
-        // To make sure we never go backwards (the real time clock is not
-        // reliable especially after a crash or if wifi is unavailable), we make
-        // the session key larger than the most recent previous one.
-        let session_key = u64::max(
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                // This won't fail unless the clock is before epoch, which
-                // sounds like a bigger problem
-                .unwrap()
-                .as_secs(),
-            // TODO: one very late clock ruins the ability to determine when
-            // they were captured from the timestamp.
-            db.get_most_recent_session().unwrap().unwrap_or(0) + 1,
-        );
-
         let mut o_gps =
-            user_connect_or_skip(&mut display, &button_rx, devices.gps, "GPS", || {
-                gps::Gps::new()
+            user_connect_or_skip(&mut display, &button_rx, &m_will_exit, devices.gps, "GPS", || {
+                gps::Gps::new(gps_baud, &gps_init)
             });
 
         // User prompts don't really help us much here, because this is a pretty
@@ -360,16 +580,20 @@ pub async fn main() -> btleplug::Result<()> {
         );
         display.render_msg("Connecting to Devices.");
 
+        // Devices that disconnected and never came back
+        let lost_devices: LostDevices = Arc::new(Mutex::new(BTreeSet::new()));
+
         let mut o_speed =
            if devices.speed {
-               match squish_error(speed::connect(&central).await) {
+               match speed::connect(&central, devices.name_patterns.speed, devices.reconnect, lost_devices.clone()).await {
                    Ok(speed) => Some(speed),
                    Err(e) => {
                        println!("{:?}", e);
                        match prompt_ignore_or_exit(
                            &mut display,
                            &button_rx,
-                           "Speed connect error."
+                           &m_will_exit,
+                           &format!("Speed {}.", e)
                        ) {
                            IgnorableError::Ignore => None,
                            IgnorableError::Exit => {
@@ -382,16 +606,45 @@ pub async fn main() -> btleplug::Result<()> {
                None
            };
 
+        let wheel_circumference = match &o_speed {
+            Some((speed_measure, _))
+                if is_calibrate_wheel && devices.speed_sensor_role.has_speed() =>
+            {
+                let o_circumference = calibrate_wheel_circumference(
+                    &mut display,
+                    &button_rx,
+                    &m_will_exit,
+                    speed_measure,
+                )
+                .await?;
+                match o_circumference {
+                    Some(circumference) => {
+                        db.set_wheel_circumference(&devices.bike, circumference).unwrap();
+                        circumference
+                    }
+                    None => db
+                        .get_wheel_circumference(&devices.bike)
+                        .unwrap()
+                        .unwrap_or(DEFAULT_WHEEL_CIRCUMFERENCE),
+                }
+            }
+            _ => db
+                .get_wheel_circumference(&devices.bike)
+                .unwrap()
+                .unwrap_or(DEFAULT_WHEEL_CIRCUMFERENCE),
+        };
+
         let mut o_hrm =
            if devices.hr {
-               match squish_error(hrm::connect(&central).await) {
+               match hrm::connect(&central, devices.name_patterns.hrm, devices.reconnect, lost_devices.clone()).await {
                    Ok(hrm) => Some(hrm),
                    Err(e) => {
                        println!("{:?}", e);
                        match prompt_ignore_or_exit(
                            &mut display,
                            &button_rx,
-                           "HR Monitor connect error."
+                           &m_will_exit,
+                           &format!("HR Monitor {}.", e)
                        ) {
                            IgnorableError::Ignore => None,
                            IgnorableError::Exit => {
@@ -404,16 +657,50 @@ pub async fn main() -> btleplug::Result<()> {
                None
            };
 
+        let mut o_rsc =
+           if devices.rsc {
+               match rsc::connect(&central, devices.name_patterns.rsc, devices.reconnect, lost_devices.clone()).await {
+                   Ok(rsc) => Some(rsc),
+                   Err(e) => {
+                       println!("{:?}", e);
+                       match prompt_ignore_or_exit(
+                           &mut display,
+                           &button_rx,
+                           &m_will_exit,
+                           &format!("Footpod {}.", e)
+                       ) {
+                           IgnorableError::Ignore => None,
+                           IgnorableError::Exit => {
+                               crash_with_msg(&mut display, "Footpod connect error.")
+                           }
+                       }
+                   }
+               }
+           } else {
+               None
+           };
+
         let mut o_kickr =
            if devices.kickr {
-               match squish_error(kickr::connect(&central).await) {
+               let names = devices.name_patterns.kickr;
+               let lost = lost_devices.clone();
+               let connected = match trainer_protocol {
+                   TrainerProtocol::Kickr => {
+                       kickr::connect(&central, names, devices.reconnect, lost).await
+                   }
+                   TrainerProtocol::Ftms => {
+                       ftms::connect(&central, names, devices.reconnect, lost).await
+                   }
+               };
+               match connected {
                    Ok(kickr) => Some(kickr),
                    Err(e) => {
                        println!("{:?}", e);
                        match prompt_ignore_or_exit(
                            &mut display,
                            &button_rx,
-                           "Kickr connect error."
+                           &m_will_exit,
+                           &format!("Kickr {}.", e)
                        ) {
                            IgnorableError::Ignore => None,
                            IgnorableError::Exit => {
@@ -428,14 +715,15 @@ pub async fn main() -> btleplug::Result<()> {
 
         let mut o_assioma =
            if devices.assioma {
-               match squish_error(assioma::connect(&central).await) {
+               match assioma::connect(&central, devices.name_patterns.assioma, devices.reconnect, lost_devices.clone()).await {
                    Ok(assioma) => Some(assioma),
                    Err(e) => {
                        println!("{:?}", e);
                        match prompt_ignore_or_exit(
                            &mut display,
                            &button_rx,
-                           "Assioma connect error."
+                           &m_will_exit,
+                           &format!("Assioma {}.", e)
                        ) {
                            IgnorableError::Ignore => None,
                            IgnorableError::Exit => {
@@ -450,14 +738,15 @@ pub async fn main() -> btleplug::Result<()> {
 
         let mut o_cadence =
            if devices.cadence {
-               match squish_error(cadence::connect(&central).await) {
+               match cadence::connect(&central, devices.name_patterns.cadence, devices.reconnect, lost_devices.clone()).await {
                    Ok(cadence) => Some(cadence),
                    Err(e) => {
                        println!("{:?}", e);
                        match prompt_ignore_or_exit(
                            &mut display,
                            &button_rx,
-                           "Cadence connect error."
+                           &m_will_exit,
+                           &format!("Cadence {}.", e)
                        ) {
                            IgnorableError::Ignore => None,
                            IgnorableError::Exit => {
@@ -470,22 +759,84 @@ pub async fn main() -> btleplug::Result<()> {
                None
            };
 
+        display.set_erg_error_window(devices.erg_error_window);
+        display.set_power_track_labels(devices.power_track_labels.clone());
+        display.set_running(devices.rsc);
+        display.set_units(devices.units);
+        display.set_scale(scale);
+        display.set_layout(o_layout.clone().unwrap_or_else(|| match scale {
+            display::Scale::Normal => devices.layout.clone(),
+            display::Scale::Large => display::DEFAULT_LARGE_LAYOUT.to_vec(),
+        }));
+        display.set_stale_policies(stale_policies.clone());
+        display.set_speed_smoothing(devices.speed_smoothing);
+        display.set_interval_summary_dwell(devices.interval_summary_dwell);
+        display.set_target_change_page(o_target_change_page.unwrap_or(devices.target_change_page));
+        display.set_lost_devices(lost_devices.clone());
+
+        let connected_devices = [
+            (Device::Speed, o_speed.is_some()),
+            (Device::Hrm, o_hrm.is_some()),
+            (Device::Rsc, o_rsc.is_some()),
+            (Device::Kickr, o_kickr.is_some()),
+            (Device::Assioma, o_assioma.is_some()),
+            (Device::Cadence, o_cadence.is_some()),
+        ]
+        .iter()
+        .filter_map(|(device, is_connected)| if *is_connected { Some(*device) } else { None })
+        .collect();
+        // Whether or not the rider is pedaling, driven by any crank data
+        let activity_mutex = Arc::new(Mutex::new(activity::ActivityState::new()));
+        lock_or_recover(&status_mutex).start_ride(
+            session_key,
+            connected_devices,
+            lost_devices.clone(),
+            activity_mutex.clone(),
+        );
+
         // We now need a mutex, so we can share the display out to multiple
         // peripherals
         let display_mutex = Arc::new(Mutex::new(display));
 
+        // Shown over the ride, rather than holding up getting to it
+        if server.is_some() {
+            lock_or_recover(&display_mutex)
+                .show_toast(&format!("Server PIN\n{}", server_pin), PIN_DURATION);
+        }
+
+        // Sensor batteries, of which the lowest is shown
+        let battery_levels = Arc::new(Mutex::new(battery::BatteryLevels::new()));
+
+        {
+            let mut display = lock_or_recover(&display_mutex);
+            display.set_activity(activity_mutex.clone());
+        }
+
         // Need to make sure we don't consume the optional, or it will be
         // dropped prematurely
+        // Without a wheel sensor (or a footpod), speed and distance come from
+        // the GPS instead.
+        let is_gps_speed =
+            !devices.rsc && !(o_speed.is_some() && devices.speed_sensor_role.has_speed());
         for gps in &mut o_gps {
             let db_gps = db.clone();
             let display_mutex_for_gps = display_mutex.clone();
+            let mut odometer = geo::Odometer::new();
             gps.on_update(Box::new(move |s| {
-                let mut display = display_mutex_for_gps.lock().unwrap();
+                let mut display = lock_or_recover(&display_mutex_for_gps);
                 match s {
                     nmea0183::ParseResult::GGA(Some(_)) => display.set_gps_fix(true),
                     nmea0183::ParseResult::GGA(None) => display.set_gps_fix(false),
                     _ => (),
                 };
+                if is_gps_speed {
+                    if let Some(speed) = gps::speed(&s) {
+                        display.update_speed(Some(speed));
+                    }
+                    if let Some((lat, lon)) = gps::position(&s) {
+                        display.update_distance(odometer.update(lat, lon));
+                    }
+                }
                 db_gps
                     .insert(
                         session_key,
@@ -494,39 +845,96 @@ pub async fn main() -> btleplug::Result<()> {
                     )
                     .unwrap();
             }));
+            let display_mutex_for_stall = display_mutex.clone();
+            gps.on_stall_change(Box::new(move |is_stalled| {
+                let mut display = lock_or_recover(&display_mutex_for_stall);
+                display.set_gps_stalled(is_stalled);
+                let msg = if is_stalled { "GPS stalled" } else { "GPS back" };
+                display.show_toast(msg, TOAST_DURATION);
+            }));
             lock_and_show(&display_mutex, &format!("GPS Ready"));
         }
 
         // Need to make sure we don't consume the optional, or it will be
         // dropped prematurely
-        for speed_measure in &mut o_speed {
+        for (speed_measure, o_feature) in &mut o_speed {
             let mut o_last_speed_measure: Option<CscMeasurement> = None;
             let mut wheel_count = 0;
+            let mut crank_count = 0;
+            let role = devices.speed_sensor_role;
             let db_speed_measure = db.clone();
             let display_mutex_speed = display_mutex.clone();
+            let activity_mutex_speed = activity_mutex.clone();
+            let live_speed = live.clone();
+            let address_speed = speed_measure.address().to_string();
             let mut notifications = speed_measure.notifications().await?;
             tokio::spawn(async move {
                 while let Some(n) = notifications.next().await {
+                    if is_raw_capture {
+                        db_speed_measure
+                            .insert_raw(
+                                session_key,
+                                start.elapsed(),
+                                &address_speed,
+                                n.uuid,
+                                &n.value,
+                            )
+                            .unwrap();
+                    }
                     let elapsed = start.elapsed();
-                    let csc_measure = parse_csc_measurement(&n.value);
-                    let r =
-                        checked_wheel_rpm_and_new_count(o_last_speed_measure.as_ref(), &csc_measure);
-                    if let Some((wheel_rpm, new_wheel_count)) = r {
+                    let csc_measure = match try_parse_csc_measurement(&n.value) {
+                        Ok(csc_measure) => csc_measure,
+                        Err(e) => {
+                            println!("Skipping malformed speed notification: {}", e);
+                            continue;
+                        }
+                    };
+                    let r = role.has_speed().then(|| {
+                        checked_wheel_rpm_and_new_count(o_last_speed_measure.as_ref(), &csc_measure)
+                    });
+                    if let Some(Some((wheel_rpm, new_wheel_count))) = r {
                         wheel_count = wheel_count + new_wheel_count;
-                        let mut display = display_mutex_speed.lock().unwrap();
-                        display.update_speed(Some(wheel_rpm as f32 * WHEEL_CIRCUMFERENCE / 60.0));
-                        display.update_distance(wheel_count as f64 * WHEEL_CIRCUMFERENCE as f64);
+                        let mut display = lock_or_recover(&display_mutex_speed);
+                        display.update_speed(Some(wheel_rpm as f32 * wheel_circumference / 60.0));
+                        display.update_distance(wheel_count as f64 * wheel_circumference as f64);
+                    }
+                    // Just as from a dedicated cadence sensor
+                    let r = role.has_cadence().then(|| {
+                        checked_crank_rpm_and_new_count(o_last_speed_measure.as_ref(), &csc_measure)
+                    });
+                    if let Some(Some((rpm, new_crank_count))) = r {
+                        crank_count = crank_count + new_crank_count;
+                        {
+                            let mut activity = lock_or_recover(&activity_mutex_speed);
+                            activity.update_cadence(rpm as u8, Instant::now());
+                        }
+                        {
+                            let mut live = lock_or_recover(&live_speed);
+                            live.update_cadence(seconds_since_unix_epoch(), rpm as u8);
+                        }
+                        let mut display = lock_or_recover(&display_mutex_speed);
+                        display.update_cadence(Some(rpm as u8));
+                        display.update_crank_count(crank_count);
                     }
                     o_last_speed_measure = Some(csc_measure);
+                    // Tagged, so that only what this sensor supports is used
                     db_speed_measure
                         .insert(
                             session_key,
                             elapsed,
-                            telemetry_db::Notification::Ble((n.uuid, n.value)),
+                            telemetry_db::Notification::DeviceBle((Device::Speed, n.uuid, n.value)),
                         )
                         .unwrap();
                 }
             });
+            record_device_status(&db, session_key, start, Device::Speed, &*speed_measure).await;
+            record_csc_feature(&db, session_key, start, Device::Speed, *o_feature);
+            db.insert(
+                session_key,
+                start.elapsed(),
+                telemetry_db::Notification::WheelCircumference(wheel_circumference),
+            )
+            .unwrap();
             lock_and_show(&display_mutex, &"Setup Complete for Speed Monitor");
         }
 
@@ -535,11 +943,33 @@ pub async fn main() -> btleplug::Result<()> {
         for hrm in &mut o_hrm {
             let db_hrm = db.clone();
             let display_mutex_hrm = display_mutex.clone();
+            let live_hrm = live.clone();
+            let address_hrm = hrm.address().to_string();
             let mut notifications = hrm.notifications().await?;
             tokio::spawn(async move {
                 while let Some(n) = notifications.next().await {
-                    let mut display = display_mutex_hrm.lock().unwrap();
-                    display.update_heart_rate(Some(parse_hrm(&n.value).bpm as u8));
+                    if is_raw_capture {
+                        db_hrm
+                            .insert_raw(
+                                session_key,
+                                start.elapsed(),
+                                &address_hrm,
+                                n.uuid,
+                                &n.value,
+                            )
+                            .unwrap();
+                    }
+                    if n.uuid != hrm::MEASURE_UUID {
+                        continue;
+                    }
+                    let hrm = parse_hrm(&n.value);
+                    {
+                        let mut live = lock_or_recover(&live_hrm);
+                        live.update_heart_rate(seconds_since_unix_epoch(), hrm.bpm);
+                    }
+                    let mut display = lock_or_recover(&display_mutex_hrm);
+                    display.update_heart_rate(Some(hrm.bpm));
+                    display.set_hr_sensor_contact(hrm.is_sensor_contact_detected);
                     let elapsed = start.elapsed();
                     db_hrm
                         .insert(
@@ -550,60 +980,200 @@ pub async fn main() -> btleplug::Result<()> {
                         .unwrap();
                 };
             });
+            let battery_level =
+                record_device_status(&db, session_key, start, Device::Hrm, &*hrm).await;
+            follow_battery_level(&*hrm, Device::Hrm, battery_level, &display_mutex, &battery_levels)
+                .await;
             lock_and_show(&display_mutex, &"Setup Complete for Heart Rate Monitor");
         }
 
-        let use_assioma = devices.assioma;
+        // Need to make sure we don't consume the optional, or it will be
+        // dropped prematurely
+        for rsc in &mut o_rsc {
+            let db_rsc = db.clone();
+            let display_mutex_rsc = display_mutex.clone();
+            let activity_mutex_rsc = activity_mutex.clone();
+            let live_rsc = live.clone();
+            let address_rsc = rsc.address().to_string();
+            let mut notifications = rsc.notifications().await?;
+            tokio::spawn(async move {
+                while let Some(n) = notifications.next().await {
+                    if is_raw_capture {
+                        db_rsc
+                            .insert_raw(
+                                session_key,
+                                start.elapsed(),
+                                &address_rsc,
+                                n.uuid,
+                                &n.value,
+                            )
+                            .unwrap();
+                    }
+                    let rsc_measure = parse_rsc_measurement(&n.value);
+                    {
+                        let mut live = lock_or_recover(&live_rsc);
+                        live.update_cadence(seconds_since_unix_epoch(), rsc_measure.steps_per_minute);
+                    }
+                    {
+                        let mut activity = lock_or_recover(&activity_mutex_rsc);
+                        activity.update_cadence(rsc_measure.steps_per_minute, Instant::now());
+                    }
+                    {
+                        let mut display = lock_or_recover(&display_mutex_rsc);
+                        display.update_speed(Some(rsc_measure.speed));
+                        display.update_cadence(Some(rsc_measure.steps_per_minute));
+                        if let Some(total_distance) = rsc_measure.total_distance {
+                            display.update_distance(total_distance as f64);
+                        }
+                    }
+                    let elapsed = start.elapsed();
+                    db_rsc
+                        .insert(
+                            session_key,
+                            elapsed,
+                            telemetry_db::Notification::Ble((n.uuid, n.value)),
+                        )
+                        .unwrap();
+                }
+            });
+            record_device_status(&db, session_key, start, Device::Rsc, &*rsc).await;
+            lock_and_show(&display_mutex, &"Setup Complete for Footpod");
+        }
+
+        // If the preferred source isn't connected, we fall back to whatever
+        // is.
+        let primary_power_source =
+            match (devices.primary_power_source, o_assioma.is_some(), o_kickr.is_some()) {
+                (Device::Kickr, _, true) | (_, false, true) => Device::Kickr,
+                _ => Device::Assioma,
+            };
+        let kickr_is_primary = primary_power_source == Device::Kickr;
+        let assioma_is_primary = primary_power_source == Device::Assioma;
 
         // Need to make sure we don't consume the optional, or it will be
         // dropped prematurely
         for (kickr, _) in &mut o_kickr {
             let db_kickr = db.clone();
             let display_mutex_kickr = display_mutex.clone();
+            let live_kickr = live.clone();
+            let activity_mutex_kickr = activity_mutex.clone();
             let mut o_last_power_reading: Option<CyclingPowerMeasurement> = None;
+            let mut power_filter = PowerFilter::new(devices.max_power);
+            let mut o_w_bal =
+                devices.critical_power.map(|cp| wbal::WBal::new(cp, devices.w_prime));
             let mut acc_torque = 0.0;
+            let address_kickr = kickr.address().to_string();
             let mut notifications = kickr.notifications().await?;
             tokio::spawn(async move {
                 while let Some(n) = notifications.next().await {
+                    if is_raw_capture {
+                        db_kickr
+                            .insert_raw(
+                                session_key,
+                                start.elapsed(),
+                                &address_kickr,
+                                n.uuid,
+                                &n.value,
+                            )
+                            .unwrap();
+                    }
                     if n.uuid == kickr::MEASURE_UUID {
-                        let mut display = display_mutex_kickr.lock().unwrap();
-                        let power_reading = parse_cycling_power_measurement(&n.value);
+                        let mut power_reading =
+                            match try_parse_cycling_power_measurement(&n.value) {
+                                Ok(power_reading) => power_reading,
+                                Err(e) => {
+                                    println!("Skipping malformed kickr notification: {}", e);
+                                    continue;
+                                }
+                            };
+                        power_reading.instantaneous_power =
+                            power_filter.filter(power_reading.instantaneous_power);
+                        let mut display = lock_or_recover(&display_mutex_kickr);
                         let o_new_acc_torque = o_last_power_reading
                             .as_ref()
                             .and_then(|x| x.new_accumulated_torque(&power_reading));
                         if let Some(new_acc_torque) = o_new_acc_torque {
                             acc_torque = acc_torque + new_acc_torque;
                             //TODO: The display should be able to accept a "wheel" and "crank" external
-                            //energy field separately.  Right now for testing we just disable the
-                            //KICKR's output to the display.
-                            if !use_assioma {
+                            //energy field separately.  Right now only the primary source is shown.
+                            if kickr_is_primary {
                                 display.update_external_energy(2.0 * std::f64::consts::PI * acc_torque);
                             }
                         }
                         //TODO: The display should be able to accept a "wheel" and "crank" power field
-                        //separately.  Right now for testing we just disable the KICKR's output to the
-                        //display.
-                        if !use_assioma {
-                            display.update_power(Some(power_reading.instantaneous_power));
+                        //separately.  Right now only the primary source is shown.
+                        if kickr_is_primary {
+                            let power = power_reading.instantaneous_power;
+                            display.update_power(Some(power));
+                            if let Some(w_bal) = &mut o_w_bal {
+                                w_bal.update(power, Instant::now());
+                                display.update_w_bal(w_bal.remaining());
+                            }
+                            let mut live = lock_or_recover(&live_kickr);
+                            live.update_power(seconds_since_unix_epoch(), power);
                         }
                         o_last_power_reading = Some(power_reading);
                         let elapsed = start.elapsed();
                         //TODO: Not exactly sure how to handle having _both_ power captures for when it
-                        //comes to generating fit files.
-                        if !use_assioma {
-                            db_kickr
-                                .insert(
-                                    session_key,
-                                    elapsed,
-                                    telemetry_db::Notification::Ble((n.uuid, n.value)),
-                                )
-                                .unwrap();
+                        //comes to generating fit files.  For now, secondary sources are tagged so
+                        //they only show up in power comparisons.
+                        let notification = if kickr_is_primary {
+                            telemetry_db::Notification::Ble((n.uuid, n.value))
+                        } else {
+                            telemetry_db::Notification::DeviceBle((Device::Kickr, n.uuid, n.value))
+                        };
+                        db_kickr.insert(session_key, elapsed, notification).unwrap();
+                    } else if n.uuid == ftms::INDOOR_BIKE_DATA_UUID {
+                        // FTMS trainers that don't also report through the
+                        // cycling power service
+                        let bike_data = match try_parse_indoor_bike_data(&n.value) {
+                            Ok(bike_data) => bike_data,
+                            Err(e) => {
+                                println!("Skipping malformed trainer notification: {}", e);
+                                continue;
+                            }
+                        };
+                        if kickr_is_primary {
+                            let mut display = lock_or_recover(&display_mutex_kickr);
+                            let mut live = lock_or_recover(&live_kickr);
+                            if let Some(power) = bike_data.instantaneous_power {
+                                let power = power_filter.filter(power);
+                                display.update_power(Some(power));
+                                if let Some(w_bal) = &mut o_w_bal {
+                                    w_bal.update(power, Instant::now());
+                                    display.update_w_bal(w_bal.remaining());
+                                }
+                                live.update_power(seconds_since_unix_epoch(), power);
+                            }
+                            if let Some(cadence) = bike_data.instantaneous_cadence {
+                                let mut activity = lock_or_recover(&activity_mutex_kickr);
+                                activity.update_cadence(cadence as u8, Instant::now());
+                                display.update_cadence(Some(cadence as u8));
+                                live.update_cadence(seconds_since_unix_epoch(), cadence as u8);
+                            }
                         }
+                        let elapsed = start.elapsed();
+                        let notification = if kickr_is_primary {
+                            telemetry_db::Notification::Ble((n.uuid, n.value))
+                        } else {
+                            telemetry_db::Notification::DeviceBle((Device::Kickr, n.uuid, n.value))
+                        };
+                        db_kickr.insert(session_key, elapsed, notification).unwrap();
                     } else {
                         println!("Non-power notification from kickr: {:?}", n);
                     }
                 }
             });
+            let battery_level =
+                record_device_status(&db, session_key, start, Device::Kickr, &*kickr).await;
+            follow_battery_level(
+                &*kickr,
+                Device::Kickr,
+                battery_level,
+                &display_mutex,
+                &battery_levels,
+            )
+            .await;
             lock_and_show(&display_mutex, &"Setup Complete for Kickr");
         }
 
@@ -611,20 +1181,63 @@ pub async fn main() -> btleplug::Result<()> {
         // dropped prematurely
         for assioma in &mut o_assioma {
             let mut o_last_power_measure: Option<CyclingPowerMeasurement> = None;
+            let mut power_filter = PowerFilter::new(devices.max_power);
+            let mut o_w_bal =
+                devices.critical_power.map(|cp| wbal::WBal::new(cp, devices.w_prime));
             let mut crank_count = 0;
             let mut acc_torque = 0.0;
             let db_power_measure = db.clone();
             let display_mutex_assioma = display_mutex.clone();
+            let activity_mutex_assioma = activity_mutex.clone();
+            let live_assioma = live.clone();
+            let address_assioma = assioma.address().to_string();
             let mut notifications = assioma.notifications().await?;
             tokio::spawn(async move {
                 while let Some(n) = notifications.next().await {
+                    if is_raw_capture {
+                        db_power_measure
+                            .insert_raw(
+                                session_key,
+                                start.elapsed(),
+                                &address_assioma,
+                                n.uuid,
+                                &n.value,
+                            )
+                            .unwrap();
+                    }
+                    if n.uuid != cycling_power_measurement::MEASURE_UUID {
+                        continue;
+                    }
                     let elapsed = start.elapsed();
-                    let power_measure = parse_cycling_power_measurement(&n.value);
+                    let mut power_measure = match try_parse_cycling_power_measurement(&n.value) {
+                        Ok(power_measure) => power_measure,
+                        Err(e) => {
+                            println!("Skipping malformed assioma notification: {}", e);
+                            continue;
+                        }
+                    };
+                    power_measure.instantaneous_power =
+                        power_filter.filter(power_measure.instantaneous_power);
+                    let power = power_measure.instantaneous_power;
                     let r = cycling_power_measurement::checked_crank_rpm_and_new_count(
                         o_last_power_measure.as_ref(),
                         &power_measure,
                     );
-                    let mut display = display_mutex_assioma.lock().unwrap();
+                    if let Some((rpm, _)) = r {
+                        let mut activity = lock_or_recover(&activity_mutex_assioma);
+                        activity.update_cadence(rpm as u8, Instant::now());
+                    }
+                    {
+                        let mut live = lock_or_recover(&live_assioma);
+                        let now = seconds_since_unix_epoch();
+                        if assioma_is_primary {
+                            live.update_power(now, power);
+                        }
+                        if let Some((rpm, _)) = r {
+                            live.update_cadence(now, rpm as u8);
+                        }
+                    }
+                    let mut display = lock_or_recover(&display_mutex_assioma);
                     if let Some((rpm, new_crank_count)) = r {
                         crank_count = crank_count + new_crank_count;
                         display.update_cadence(Some(rpm as u8));
@@ -635,52 +1248,104 @@ pub async fn main() -> btleplug::Result<()> {
                         .and_then(|x| x.new_accumulated_torque(&power_measure));
                     if let Some(new_acc_torque) = o_new_acc_torque {
                         acc_torque = acc_torque + new_acc_torque;
-                        display.update_external_energy(2.0 * std::f64::consts::PI * acc_torque);
+                        if assioma_is_primary {
+                            display.update_external_energy(2.0 * std::f64::consts::PI * acc_torque);
+                        }
+                    }
+                    if assioma_is_primary {
+                        display.update_power(Some(power));
+                        if let Some(w_bal) = &mut o_w_bal {
+                            w_bal.update(power, Instant::now());
+                            display.update_w_bal(w_bal.remaining());
+                        }
                     }
-                    display.update_power(Some(power_measure.instantaneous_power));
                     o_last_power_measure = Some(power_measure);
-                    db_power_measure
-                        .insert(
-                            session_key,
-                            elapsed,
-                            telemetry_db::Notification::Ble((n.uuid, n.value)),
-                        )
-                        .unwrap();
+                    let notification = if assioma_is_primary {
+                        telemetry_db::Notification::Ble((n.uuid, n.value))
+                    } else {
+                        telemetry_db::Notification::DeviceBle((Device::Assioma, n.uuid, n.value))
+                    };
+                    db_power_measure.insert(session_key, elapsed, notification).unwrap();
                 }
             });
+            let battery_level =
+                record_device_status(&db, session_key, start, Device::Assioma, &*assioma).await;
+            follow_battery_level(
+                &*assioma,
+                Device::Assioma,
+                battery_level,
+                &display_mutex,
+                &battery_levels,
+            )
+            .await;
             lock_and_show(&display_mutex, &"Setup Complete for Assioma Pedals!");
         }
 
         // Need to make sure we don't consume the optional, or it will be
         // dropped prematurely
-        for cadence_measure in &mut o_cadence {
+        for (cadence_measure, o_feature) in &mut o_cadence {
             let mut o_last_cadence_measure: Option<CscMeasurement> = None;
             let mut crank_count = 0;
             let db_cadence_measure = db.clone();
             let display_mutex_cadence = display_mutex.clone();
+            let activity_mutex_cadence = activity_mutex.clone();
+            let live_cadence = live.clone();
+            let address_cadence = cadence_measure.address().to_string();
             let mut notifications = cadence_measure.notifications().await?;
             tokio::spawn(async move {
                 while let Some(n) = notifications.next().await {
+                    if is_raw_capture {
+                        db_cadence_measure
+                            .insert_raw(
+                                session_key,
+                                start.elapsed(),
+                                &address_cadence,
+                                n.uuid,
+                                &n.value,
+                            )
+                            .unwrap();
+                    }
                     let elapsed = start.elapsed();
-                    let csc_measure = parse_csc_measurement(&n.value);
+                    let csc_measure = match try_parse_csc_measurement(&n.value) {
+                        Ok(csc_measure) => csc_measure,
+                        Err(e) => {
+                            println!("Skipping malformed cadence notification: {}", e);
+                            continue;
+                        }
+                    };
                     let r =
                         checked_crank_rpm_and_new_count(o_last_cadence_measure.as_ref(), &csc_measure);
                     if let Some((rpm, new_crank_count)) = r {
                         crank_count = crank_count + new_crank_count;
-                        let mut display = display_mutex_cadence.lock().unwrap();
+                        {
+                            let mut activity = lock_or_recover(&activity_mutex_cadence);
+                            activity.update_cadence(rpm as u8, Instant::now());
+                        }
+                        {
+                            let mut live = lock_or_recover(&live_cadence);
+                            live.update_cadence(seconds_since_unix_epoch(), rpm as u8);
+                        }
+                        let mut display = lock_or_recover(&display_mutex_cadence);
                         display.update_cadence(Some(rpm as u8));
                         display.update_crank_count(crank_count);
                     }
                     o_last_cadence_measure = Some(csc_measure);
+                    // Tagged, so that only what this sensor supports is used
                     db_cadence_measure
                         .insert(
                             session_key,
                             elapsed,
-                            telemetry_db::Notification::Ble((n.uuid, n.value)),
+                            telemetry_db::Notification::DeviceBle((
+                                Device::Cadence,
+                                n.uuid,
+                                n.value,
+                            )),
                         )
                         .unwrap();
                 }
             });
+            record_device_status(&db, session_key, start, Device::Cadence, &*cadence_measure).await;
+            record_csc_feature(&db, session_key, start, Device::Cadence, *o_feature);
             lock_and_show(&display_mutex, &"Setup Complete for Cadence Monitor");
         }
 
@@ -688,74 +1353,194 @@ pub async fn main() -> btleplug::Result<()> {
         // Our workout will drop the closure after the workout ends (last
         // power_set) and if we don't hold a reference to our kickr, it will be
         // dropped along with the closure.  Dropping the kickr ends all of its
-        // subscriptions.
+        // subscriptions.  Since we hold all of our peripherals until the very
+        // end of main, they (and their recordings) outlive the workout.
         // TODO: Maybe all workouts should have an explicit end, rather than a
         // tail?  That would make this more intuitive.  Then at the end of the
         // workout, the program exits (and systemd restarts it).
 
-        // TODO: It's dumb that were managing these two separate mutexes (power
-        // target and display).  The target should just be private state of the
-        // display, so if we modify the workout's offset or, that later sets the
-        // power, which modifies the internal state of the display, which is
-        // reflected on the next display render.
-        let power_target_mutex = Arc::new(Mutex::new(0));
-
-        let power_target_mutex_workout = power_target_mutex.clone();
-
+        // The display holds the only copy of the target, and it's set from the
+        // very same value the trainer is sent (so offsets included), so the
+        // PowerTrack reference line can't disagree with the trainer.
+        let erg_floor = devices.erg_floor;
+        let erg_failures =
+            Arc::new(Mutex::new(workout::ErgFailures::new(devices.erg_fallback_after)));
         let o_kickr_for_workout = o_kickr.clone();
         let display_mutex_workout = display_mutex.clone();
-        let mut workout_handle = workout.run(Instant::now(), move |p| {
-            // Update our power target used by the display, and update the
-            // display immediately
-            {
-                let mut power_target = power_target_mutex_workout.lock().unwrap();
-                *power_target = p;
-                let mut display = display_mutex_workout.lock().unwrap();
-                display.set_page(display::Page::PowerTrack(p as i16));
+        let db_workout = db.clone();
+        // Recorded (along with where it's up to) so that a ride cut short can
+        // carry on with the same workout.
+        let (ct, tail) = workout.plan();
+        db.insert(session_key, start.elapsed(), telemetry_db::Notification::Workout((ct, tail)))
+            .unwrap();
+        let workout = workout
+            .starting_at(workout_at)
+            .with_interval(lock_or_recover(&intervals).workout);
+        let mut workout_handle = workout.run(Instant::now(), move |p, o_step_start| {
+            // Each step of the workout is its own lap in exports
+            if let Some(at) = o_step_start {
+                db_workout
+                    .insert(session_key, start.elapsed(), telemetry_db::Notification::Lap)
+                    .unwrap();
+                db_workout
+                    .insert(
+                        session_key,
+                        start.elapsed(),
+                        telemetry_db::Notification::WorkoutPosition((at, false)),
+                    )
+                    .unwrap();
+            }
+
+            // Offsets are already included in p, so they are floored too
+            let p = erg_floor.map_or(p, |floor| u16::max(p, floor));
+
+            // Once we've given up on ERG the rider is riding by feel, so there
+            // are no targets to show or set.
+            let gave_up = lock_or_recover(&erg_failures).gave_up();
+
+            // Update the target on the display immediately
+            if !gave_up {
+                let mut display = lock_or_recover(&display_mutex_workout);
+                display.set_target(p as i16, o_step_start.is_some());
             }
 
             // TODO: got to be a better way than this!
             let o_kickr_for_workout = o_kickr_for_workout.clone();
+            let erg_failures = erg_failures.clone();
+            let display_mutex_workout = display_mutex_workout.clone();
             async move {
+                if gave_up {
+                    return;
+                }
                 // If there's a connected Kickr, set its ERG mode power
                 for (kickr, target_power) in o_kickr_for_workout.iter() {
-                    kickr::set_power(kickr, target_power, p).await.unwrap();
+                    let result = trainer_protocol.set_power(kickr, target_power, p).await;
+                    if let Err(e) = &result {
+                        println!("Failed to set ERG power: {:?}", e);
+                    }
+                    let should_give_up = lock_or_recover(&erg_failures).record(result.is_ok());
+                    if should_give_up {
+                        // Best effort, this may well fail for the same reason
+                        if let Err(e) = trainer_protocol.release(kickr, target_power).await {
+                            println!("Failed to release the trainer: {:?}", e);
+                        }
+                        let mut display = lock_or_recover(&display_mutex_workout);
+                        display.set_page(display::Page::Standard);
+                        display.show_toast("ERG lost, ride by feel", ERG_FALLBACK_TOAST_DURATION);
+                    }
                 }
             }
         });
 
+        lock_or_recover(&display_mutex).set_workout_state(workout_handle.state.clone());
+
+        // Once the workout is done, we release the trainer so the rider can
+        // keep riding (and recording) freely until they explicitly exit.
+        if is_keep_recording {
+            let workout_state_free_ride = workout_handle.state.clone();
+            let o_kickr_free_ride = o_kickr.clone();
+            let display_mutex_free_ride = display_mutex.clone();
+            tokio::spawn(async move {
+                while !workout::is_finished(&workout_state_free_ride) {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+
+                // If this fails, the trainer is just stuck at the last target,
+                // which is still rideable, so we carry on recording
+                for (kickr, target_power) in o_kickr_free_ride.iter() {
+                    if let Err(e) = trainer_protocol.release(kickr, target_power).await {
+                        println!("Failed to release the trainer: {:?}", e);
+                    }
+                }
+
+                let mut display = lock_or_recover(&display_mutex_free_ride);
+                display.set_page(display::Page::Standard);
+            });
+        }
+
         // Clear all events that happened before the workout started
         for _ in button_rx.try_iter() {}
 
         let display_mutex_button_rx = display_mutex.clone();
-        let power_target_mutex_button_rx = power_target_mutex.clone();
         let workout_state = workout_handle.state.clone();
-        let m_will_exit = Arc::new(Mutex::new(false));
+        let db_button = db.clone();
         let m_will_exit_for_button = m_will_exit.clone();
+        let intervals_for_button = intervals.clone();
         let _ = thread::spawn(move || {
             for event in button_rx {
                 match event {
                     // Presses
                     (buttons::Button::ButtonE, false) => {
-                        let mut display = display_mutex_button_rx.lock().unwrap();
-                        display.set_page(display::Page::Standard);
+                        let mut display = lock_or_recover(&display_mutex_button_rx);
+                        display.toggle_progress_page();
                     },
                     (buttons::Button::ButtonD, false) => {
-                        let mut display = display_mutex_button_rx.lock().unwrap();
-                        let power = power_target_mutex_button_rx.lock().unwrap();
-                        // TODO: This should be configurable
-                        display.set_page(display::Page::PowerTrack(*power as i16));
+                        let mut display = lock_or_recover(&display_mutex_button_rx);
+                        display.show_power_track();
+                    },
+                    (buttons::Button::ButtonB, false) => {
+                        workout::skip_back(&workout_state);
+                        let mut display = lock_or_recover(&display_mutex_button_rx);
+                        display.show_toast("Skipped back", TOAST_DURATION);
+                    },
+                    (buttons::Button::ButtonA, false) => {
+                        workout::skip_forward(&workout_state);
+                        let mut display = lock_or_recover(&display_mutex_button_rx);
+                        display.show_toast("Skipped ahead", TOAST_DURATION);
+                    },
+                    (buttons::Button::ButtonC, false) => {
+                        let is_paused = workout::toggle_pause(&workout_state);
+                        // Time spent paused doesn't move the workout along,
+                        // which a resumed ride needs to know.
+                        if let Some(progress) = workout::progress(&workout_state) {
+                            db_button
+                                .insert(
+                                    session_key,
+                                    start.elapsed(),
+                                    telemetry_db::Notification::WorkoutPosition((
+                                        progress.elapsed,
+                                        is_paused,
+                                    )),
+                                )
+                                .unwrap();
+                        }
+                        let mut display = lock_or_recover(&display_mutex_button_rx);
+                        display.show_toast(
+                            if is_paused { "Workout paused" } else { "Workout resumed" },
+                            TOAST_DURATION,
+                        );
                     },
                     // Holds
                     (buttons::Button::ButtonE, true) => {
-                        workout::add_offset(&workout_state, -5);
+                        let offset = workout::add_offset(&workout_state, -5);
+                        let o_target = workout::target(&workout_state)
+                            .map(|p| erg_floor.map_or(p, |floor| u16::max(p, floor)));
+                        show_offset_toast(&display_mutex_button_rx, offset, o_target);
                     },
                     (buttons::Button::ButtonD, true) => {
-                        workout::add_offset(&workout_state, 5);
+                        let offset = workout::add_offset(&workout_state, 5);
+                        let o_target = workout::target(&workout_state)
+                            .map(|p| erg_floor.map_or(p, |floor| u16::max(p, floor)));
+                        show_offset_toast(&display_mutex_button_rx, offset, o_target);
+                    },
+                    (buttons::Button::ButtonC, true) => {
+                        let is_low_power = power_mode::toggle(&intervals_for_button);
+                        let mut display = lock_or_recover(&display_mutex_button_rx);
+                        display.show_toast(
+                            if is_low_power { "Low power on" } else { "Low power off" },
+                            TOAST_DURATION,
+                        );
+                    },
+                    (buttons::Button::ButtonB, true) => {
+                        let mut display = lock_or_recover(&display_mutex_button_rx);
+                        let is_inverted = display.toggle_inverted();
+                        display.show_toast(
+                            if is_inverted { "Inverted on" } else { "Inverted off" },
+                            TOAST_DURATION,
+                        );
                     },
                     (buttons::Button::ButtonA, true) => {
-                        let mut will_exit = m_will_exit_for_button.lock().unwrap();
-                        *will_exit = true;
+                        *lock_or_recover(&m_will_exit_for_button) = true;
                         break;
                     },
                     _ => ()
@@ -766,22 +1551,33 @@ pub async fn main() -> btleplug::Result<()> {
         // Update it every second
         let display_mutex_for_render = display_mutex.clone();
         let m_will_exit_for_render = m_will_exit.clone();
+        let intervals_for_render = intervals.clone();
         let render_handle = thread::spawn(move || loop {
             {
-                if *m_will_exit_for_render.lock().unwrap() {
+                if *lock_or_recover(&m_will_exit_for_render) {
                     break;
                 }
             };
             {
-                let mut display = display_mutex_for_render.lock().unwrap();
-                display.render();
+                let mut display = lock_or_recover(&display_mutex_for_render);
+                // A bad frame shouldn't take down the render loop for the rest
+                // of the ride, so we just log it and try again next time.
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    display.render();
+                }));
+                if let Err(e) = result {
+                    println!("Render failed: {:?}", e);
+                }
             }
-            thread::sleep(Duration::from_millis(100));
+            let render = lock_or_recover(&intervals_for_render).render;
+            thread::sleep(render);
         });
 
         // TODO: Idealy, the end of a workout ends the program
         render_handle.join().unwrap();
         workout_handle.exit().await;
+        db.flush().unwrap();
+        lock_or_recover(&status_mutex).end_ride();
         lock_and_show(&display_mutex, &"Goodbye");
     }
 
@@ -808,18 +1604,81 @@ impl<T> std::fmt::Display for SelectionTree<T> {
 
 // TODO: Sets of choices should also likely have labels, like "choose your
 // favorite breakfast food:"
-fn selection_tree<O: Clone>(
-    mut display: &mut display::Display,
-    button_rx: &std::sync::mpsc::Receiver<(crate::buttons::Button, bool)>,
-    tree: Vec<SelectionTree<O>>,
-    label: &str,
-) -> O {
-    let mut t = tree;
-    loop {
-        match selection(&mut display, &button_rx, &t, label).value {
-            SelectionTreeValue::Node(selected_tree) => {
-                t = selected_tree;
-            }
+// Each profile in order, with Exit always on the first screen (and Resume
+// before everything, when there's an interrupted ride).  Only five options
+// fit on a screen, so any that don't are moved to a "More" screen (and so on).
+fn profile_tree(
+    profiles: &[Profile],
+    o_resume: Option<(Profile, Resume)>,
+) -> Vec<SelectionTree<OrExit<(Profile, Option<Resume>)>>> {
+    let leaves = profiles
+        .iter()
+        .map(|p| SelectionTree {
+            label: p.name.clone(),
+            value: SelectionTreeValue::Leaf(OrExit::NotExit((p.clone(), None))),
+        })
+        .collect();
+    let mut tree = match o_resume {
+        Some((profile, resume)) => {
+            let mut tree = vec![SelectionTree {
+                label: "Resume".to_string(),
+                value: SelectionTreeValue::Leaf(OrExit::NotExit((profile, Some(resume)))),
+            }];
+            tree.extend(paged_tree(leaves, 3));
+            tree
+        }
+        None => paged_tree(leaves, 4),
+    };
+    tree.push(SelectionTree {
+        label: "Exit".to_string(),
+        value: SelectionTreeValue::Leaf(OrExit::Exit),
+    });
+    tree
+}
+
+// A workout imported from a file comes first, since it was asked for
+fn with_imported_workout<T: Clone>(
+    o_imported: Option<&T>,
+    mut workouts: Vec<SelectionTree<T>>,
+) -> Vec<SelectionTree<T>> {
+    if let Some(imported) = o_imported {
+        workouts.insert(
+            0,
+            SelectionTree {
+                label: "Imported".to_string(),
+                value: SelectionTreeValue::Leaf(imported.clone()),
+            },
+        );
+    }
+    workouts
+}
+
+fn paged_tree<T>(mut options: Vec<SelectionTree<T>>, slots: usize) -> Vec<SelectionTree<T>> {
+    if options.len() <= slots {
+        options
+    } else {
+        let rest = options.split_off(slots - 1);
+        options.push(SelectionTree {
+            label: "More".to_string(),
+            value: SelectionTreeValue::Node(paged_tree(rest, 5)),
+        });
+        options
+    }
+}
+
+fn selection_tree<O: Clone>(
+    mut display: &mut display::Display,
+    button_rx: &std::sync::mpsc::Receiver<(crate::buttons::Button, bool)>,
+    will_exit: &Mutex<bool>,
+    tree: Vec<SelectionTree<O>>,
+    label: &str,
+) -> O {
+    let mut t = tree;
+    loop {
+        match selection(&mut display, &button_rx, will_exit, &t, label).value {
+            SelectionTreeValue::Node(selected_tree) => {
+                t = selected_tree;
+            }
             SelectionTreeValue::Leaf(x) => {
                 break x;
             }
@@ -827,9 +1686,131 @@ fn selection_tree<O: Clone>(
     }
 }
 
+// Workouts are previewed before starting, going back to the list if it's not
+// the one we wanted.
+fn choose_workout(
+    display: &mut display::Display,
+    button_rx: &std::sync::mpsc::Receiver<(crate::buttons::Button, bool)>,
+    will_exit: &Mutex<bool>,
+    o_imported_workout: Option<&workout::Workout>,
+) -> workout::Workout {
+    use SelectionTreeValue::{Leaf, Node};
+    loop {
+        let workout = selection_tree(
+            display,
+            button_rx,
+            will_exit,
+            with_imported_workout(
+                o_imported_workout,
+                vec![
+                    SelectionTree {
+                        label: "Fixed".to_string(),
+                        value: Node(vec![
+                            SelectionTree {
+                                label: "100W".to_string(),
+                                value: Leaf(single_value(100)),
+                            },
+                            SelectionTree {
+                                label: "135W".to_string(),
+                                value: Leaf(single_value(135)),
+                            },
+                            SelectionTree {
+                                label: "140W".to_string(),
+                                value: Leaf(single_value(140)),
+                            },
+                            SelectionTree {
+                                label: "145W".to_string(),
+                                value: Leaf(single_value(145)),
+                            },
+                            SelectionTree {
+                                label: "More".to_string(),
+                                value: Node(vec![
+                                    SelectionTree {
+                                        label: "150W".to_string(),
+                                        value: Leaf(single_value(150)),
+                                    },
+                                    SelectionTree {
+                                        label: "155W".to_string(),
+                                        value: Leaf(single_value(155)),
+                                    },
+                                    SelectionTree {
+                                        label: "160W".to_string(),
+                                        value: Leaf(single_value(160)),
+                                    },
+                                    SelectionTree {
+                                        label: "165W".to_string(),
+                                        value: Leaf(single_value(165)),
+                                    },
+                                    SelectionTree {
+                                        label: "170W".to_string(),
+                                        value: Leaf(single_value(170)),
+                                    },
+                                ]),
+                            },
+                        ]),
+                    },
+                    SelectionTree {
+                        label: "Ramp".to_string(),
+                        value: Leaf(ramp_test(120)),
+                    },
+                    SelectionTree {
+                        label: "1st Big Interval".to_string(),
+                        value: Leaf(create_big_start_interval(
+                            (Duration::from_secs(300), 140),
+                            14,
+                            Duration::from_secs(150),
+                            (Duration::from_secs(60), 320),
+                            (Duration::from_secs(90), 120),
+                            Some(160),
+                        )),
+                    },
+                ],
+            ),
+            &"Choose workout",
+        );
+        if confirm_workout(display, button_rx, will_exit, &workout) {
+            return workout;
+        }
+    }
+}
+
+// Shows the workout's shape until it's either started (true) or the rider
+// goes back (false).
+fn confirm_workout(
+    display: &mut display::Display,
+    button_rx: &std::sync::mpsc::Receiver<(crate::buttons::Button, bool)>,
+    will_exit: &Mutex<bool>,
+    workout: &workout::Workout,
+) -> bool {
+    let profile = workout.profile();
+    display.render_workout_preview(&profile, workout.tail());
+
+    // Clear all events that occurred before the display update
+    for _ in button_rx.try_iter() {}
+
+    loop {
+        // TODO: The simulator only flushes key events when the display is updated
+        #[cfg(feature = "simulator")]
+        display.render_workout_preview(&profile, workout.tail());
+
+        exit_if_stopping(display, will_exit);
+
+        for event in button_rx.try_iter() {
+            match event {
+                (buttons::Button::ButtonE, false) => return true,
+                (buttons::Button::ButtonD, false) => return false,
+                _ => (),
+            }
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
 fn selection<O: std::fmt::Display + Clone>(
     display: &mut display::Display,
     button_rx: &std::sync::mpsc::Receiver<(crate::buttons::Button, bool)>,
+    will_exit: &Mutex<bool>,
     options: &Vec<O>,
     label: &str,
 ) -> O {
@@ -848,6 +1829,8 @@ fn selection<O: std::fmt::Display + Clone>(
         #[cfg(feature = "simulator")]
         display.render_options(label, &strings.iter().map(|x| &**x).collect());
 
+        exit_if_stopping(display, will_exit);
+
         for event in button_rx.try_iter() {
             match event {
                 // Presses
@@ -912,17 +1895,10 @@ async fn setup_ble_and_discover_devices() -> btleplug::Result<Option<btleplug::p
     }
 }
 
-fn squish_error<T>(x: btleplug::Result<Option<T>>) -> btleplug::Result<T> {
-    match x {
-        Ok(None) => Err(DeviceNotFound),
-        Ok(Some(y)) => Ok(y),
-        Err(e) => Err(e),
-    }
-}
-
 fn user_connect_or_skip<T, E: std::fmt::Debug, F: Fn() -> Result<T, E>>(
     display: &mut display::Display,
     buttons_rx: &std::sync::mpsc::Receiver<(crate::buttons::Button, bool)>,
+    will_exit: &Mutex<bool>,
     in_use: bool,
     name: &str,
     f: F,
@@ -942,6 +1918,7 @@ fn user_connect_or_skip<T, E: std::fmt::Debug, F: Fn() -> Result<T, E>>(
                     let choice = selection_tree(
                         display,
                         buttons_rx,
+                        will_exit,
                         vec![
                             SelectionTree {
                                 label: "Try Again".to_string(),
@@ -979,6 +1956,7 @@ fn user_connect_or_skip<T, E: std::fmt::Debug, F: Fn() -> Result<T, E>>(
 fn prompt_ignore_or_exit(
     display: &mut display::Display,
     button_rx: &std::sync::mpsc::Receiver<(crate::buttons::Button, bool)>,
+    will_exit: &Mutex<bool>,
     msg: &str,
 ) -> IgnorableError {
     display.render_msg(&format!("{}", msg));
@@ -986,6 +1964,7 @@ fn prompt_ignore_or_exit(
     selection_tree(
         display,
         button_rx,
+        will_exit,
         vec![
             SelectionTree {
                 label: "Ignore and Continue".to_string(),
@@ -1000,11 +1979,267 @@ fn prompt_ignore_or_exit(
     )
 }
 
+// Records that a device is connected for this session (and what it is and its
+// battery level, if we can read them), so that it can later be included in
+// exports.  Returns the battery level.
+async fn record_device_status(
+    db: &telemetry_db::TelemetryDb,
+    session_key: u64,
+    start: SessionClock,
+    device: Device,
+    peripheral: &impl Peripheral,
+) -> Option<u8> {
+    match peripherals::read_device_information(peripheral).await {
+        Ok(info) => db
+            .insert(
+                session_key,
+                start.elapsed(),
+                telemetry_db::Notification::DeviceInfo((device, info)),
+            )
+            .unwrap(),
+        Err(e) => println!("Could not read device info for {}: {:?}", device.name(), e),
+    }
+    let battery_level = match peripherals::read_battery_level(peripheral).await {
+        Ok(battery_level) => battery_level,
+        Err(e) => {
+            println!("Could not read battery for {}: {:?}", device.name(), e);
+            None
+        }
+    };
+    db.insert(
+        session_key,
+        start.elapsed(),
+        telemetry_db::Notification::DeviceStatus((device, battery_level)),
+    )
+    .unwrap();
+    battery_level
+}
+
+// Shows a device's battery level (starting from what was read on connect)
+// and keeps it up to date, if the device notifies on changes.
+async fn follow_battery_level(
+    peripheral: &impl Peripheral,
+    device: Device,
+    o_battery_level: Option<u8>,
+    display_mutex: &Arc<Mutex<display::Display>>,
+    battery_levels: &Arc<Mutex<battery::BatteryLevels>>,
+) {
+    if let Some(battery_level) = o_battery_level {
+        show_battery_level(display_mutex, battery_levels, device, battery_level);
+    }
+    match battery::subscribe(peripheral).await {
+        Ok(Some(mut levels)) => {
+            let display_mutex = display_mutex.clone();
+            let battery_levels = battery_levels.clone();
+            tokio::spawn(async move {
+                while let Some(battery_level) = levels.next().await {
+                    show_battery_level(&display_mutex, &battery_levels, device, battery_level);
+                }
+            });
+        }
+        Ok(None) => (),
+        Err(e) => println!("Could not follow battery for {}: {:?}", device.name(), e),
+    }
+}
+
+fn show_battery_level(
+    display_mutex: &Mutex<display::Display>,
+    battery_levels: &Mutex<battery::BatteryLevels>,
+    device: Device,
+    battery_level: u8,
+) {
+    let mut battery_levels = lock_or_recover(battery_levels);
+    let is_newly_low = battery_levels.update(device, battery_level);
+    let mut display = lock_or_recover(display_mutex);
+    display.update_battery(battery_levels.lowest());
+    if is_newly_low {
+        display.show_toast(
+            &format!("{} battery {}%", device.name(), battery_level),
+            LOW_BATTERY_TOAST_DURATION,
+        );
+    }
+}
+
+// Guides the rider through a roll-out: starting with the valve at the bottom,
+// roll the bike straight over a measured distance, and we count the wheel
+// revolutions to find the effective circumference.  Returns None if the rider
+// cancels or the result isn't plausible.
+async fn calibrate_wheel_circumference(
+    display: &mut display::Display,
+    button_rx: &std::sync::mpsc::Receiver<(crate::buttons::Button, bool)>,
+    will_exit: &Mutex<bool>,
+    speed_measure: &impl Peripheral,
+) -> btleplug::Result<Option<f32>> {
+    use SelectionTreeValue::Leaf;
+    let o_distance = selection_tree(
+        display,
+        button_rx,
+        will_exit,
+        vec![
+            SelectionTree {
+                label: "10m".to_string(),
+                value: Leaf(Some(10.0)),
+            },
+            SelectionTree {
+                label: "25m".to_string(),
+                value: Leaf(Some(25.0)),
+            },
+            SelectionTree {
+                label: "50m".to_string(),
+                value: Leaf(Some(50.0)),
+            },
+            SelectionTree {
+                label: "100m".to_string(),
+                value: Leaf(Some(100.0)),
+            },
+            SelectionTree {
+                label: "Cancel".to_string(),
+                value: Leaf(None),
+            },
+        ],
+        &"Roll-out distance (longer is more accurate)",
+    );
+    let distance: f32 = match o_distance {
+        Some(distance) => distance,
+        None => return Ok(None),
+    };
+
+    let latest_wheel_count = Arc::new(Mutex::new(None));
+    let latest_wheel_count_for_notifications = latest_wheel_count.clone();
+    let mut notifications = speed_measure.notifications().await?;
+    let notifications_handle = tokio::spawn(async move {
+        while let Some(n) = notifications.next().await {
+            if let Some(wheel) = try_parse_csc_measurement(&n.value).ok().and_then(|x| x.wheel) {
+                let mut count = latest_wheel_count_for_notifications.lock().unwrap();
+                *count = Some(wheel.revolution_count);
+            }
+        }
+    });
+
+    // Sensors often don't send anything until the wheel has moved, so we
+    // need a reading before the rider lines up.
+    let start_count = loop {
+        selection(
+            display,
+            button_rx,
+            will_exit,
+            &vec!["Ready"],
+            &"Spin the wheel, then line up the valve at the start",
+        );
+        let o_count = *latest_wheel_count.lock().unwrap();
+        match o_count {
+            Some(count) => break count,
+            None => {
+                display.render_msg("No speed data yet");
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    };
+
+    selection(
+        display,
+        button_rx,
+        will_exit,
+        &vec!["Done"],
+        &format!("Roll {}m, stopping with the valve at the bottom", distance),
+    );
+    let end_count = latest_wheel_count.lock().unwrap().unwrap_or(start_count);
+    notifications_handle.abort();
+
+    // Counts may roll over
+    let revolutions = end_count.wrapping_sub(start_count);
+    let circumference = distance / revolutions as f32;
+    if revolutions == 0
+        || circumference < MIN_WHEEL_CIRCUMFERENCE
+        || circumference > MAX_WHEEL_CIRCUMFERENCE
+    {
+        display.render_msg(&format!(
+            "Calibration failed ({} revolutions), keeping previous value",
+            revolutions
+        ));
+        thread::sleep(Duration::from_secs(3));
+        Ok(None)
+    } else {
+        display.render_msg(&format!("Wheel circumference is {:.3}m", circumference));
+        thread::sleep(Duration::from_secs(3));
+        Ok(Some(circumference))
+    }
+}
+
+// Knowing what each CSC sensor supports lets exports attribute readings from
+// a combined sensor correctly.
+fn record_csc_feature(
+    db: &telemetry_db::TelemetryDb,
+    session_key: u64,
+    start: SessionClock,
+    device: Device,
+    o_feature: Option<CscFeature>,
+) {
+    if let Some(feature) = o_feature {
+        db.insert(
+            session_key,
+            start.elapsed(),
+            telemetry_db::Notification::DeviceCscFeature((device, feature)),
+        )
+        .unwrap();
+    }
+}
+
+// Holds are hard to feel on rough roads, so we confirm that they landed (and
+// what they land on) right away, rather than on the next render.  The target
+// itself updates as soon as the workout picks up the offset.
+fn show_offset_toast(
+    display_mutex: &Arc<Mutex<display::Display>>,
+    offset: i16,
+    o_target: Option<u16>,
+) {
+    let msg = match o_target {
+        Some(target) => format!("Offset {:+}W\nTarget {}W", offset, target),
+        None => format!("Offset {:+}W", offset),
+    };
+    let mut display = lock_or_recover(display_mutex);
+    display.show_toast(&msg, TOAST_DURATION);
+    display.render();
+}
+
+// Confirms what's running and whether the telemetry server will be reachable,
+// before we start riding.
+fn render_boot_splash(display: &mut display::Display, version: &str) {
+    // The version is the Nix store name, so we skip the hash
+    let name = version.splitn(2, '-').last().unwrap_or(version);
+    let interfaces = network::up_interfaces();
+    let network = if interfaces.is_empty() {
+        "No network".to_string()
+    } else {
+        format!("Net {}", interfaces.join(" "))
+    };
+    display.render_msg(&format!("{}\n{}", name, network));
+}
+
+fn seconds_since_unix_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        // This won't fail unless the clock is before epoch, which sounds like
+        // a bigger problem
+        .unwrap()
+        .as_secs()
+}
+
 fn lock_and_show(display_mutex: &Arc<Mutex<display::Display>>, msg: &str) {
-    let mut display = display_mutex.lock().unwrap();
+    let mut display = lock_or_recover(&display_mutex);
     display.render_msg(msg);
 }
 
+// Menus block until the rider chooses, so they check for an orderly stop (see
+// the signal handler) themselves.  The DB is already flushed by then, and
+// nothing is mid-write while we wait on a menu, so we can just go.
+fn exit_if_stopping(display: &mut display::Display, will_exit: &Mutex<bool>) {
+    if *lock_or_recover(will_exit) {
+        display.render_msg("Goodbye");
+        std::process::exit(0);
+    }
+}
+
 fn crash_with_msg<T>(display: &mut display::Display, msg: &'static str) -> T {
     display.render_msg(msg);
     thread::sleep(Duration::from_secs(1));
@@ -1018,44 +2253,536 @@ fn or_crash_with_msg<T>(display: &mut display::Display, x: Option<T>, msg: &'sta
     }
 }
 
+// When an entry recorded this long into a session is shown, when replaying
+// at the given multiple of real time
+fn replay_delay(elapsed: Duration, speed: f64) -> Duration {
+    elapsed.div_f64(speed)
+}
+
+// Turns recorded notifications back into the display updates they caused
+// live, so real rides can be replayed for demos (or display development).
+struct Replay {
+    last_power_measure: Option<CyclingPowerMeasurement>,
+    last_csc_measure: Option<CscMeasurement>,
+    acc_torque: f64,
+    crank_count: u32,
+    wheel_count: u32,
+    // Sessions from before calibration was possible don't record this
+    wheel_circumference: f32,
+    // Nor do sessions from before glitches were filtered, which are shown as
+    // they were recorded
+    power_filter: PowerFilter,
+}
+
+impl Replay {
+    fn new() -> Replay {
+        Replay {
+            last_power_measure: None,
+            last_csc_measure: None,
+            acc_torque: 0.0,
+            crank_count: 0,
+            wheel_count: 0,
+            wheel_circumference: DEFAULT_WHEEL_CIRCUMFERENCE,
+            power_filter: PowerFilter::new(i16::MAX),
+        }
+    }
+
+    fn apply(&mut self, display: &mut display::Display, notification: telemetry_db::Notification) {
+        match notification {
+            telemetry_db::Notification::Ble((assioma::MEASURE_UUID, v)) => {
+                let mut power_measure = parse_cycling_power_measurement(&v);
+                power_measure.instantaneous_power =
+                    self.power_filter.filter(power_measure.instantaneous_power);
+                let o_new_acc_torque = self
+                    .last_power_measure
+                    .as_ref()
+                    .and_then(|x| x.new_accumulated_torque(&power_measure));
+                if let Some(new_acc_torque) = o_new_acc_torque {
+                    self.acc_torque = self.acc_torque + new_acc_torque;
+                    display.update_external_energy(2.0 * std::f64::consts::PI * self.acc_torque);
+                }
+                let r = cycling_power_measurement::checked_crank_rpm_and_new_count(
+                    self.last_power_measure.as_ref(),
+                    &power_measure,
+                );
+                if let Some((rpm, new_crank_count)) = r {
+                    self.crank_count = self.crank_count + new_crank_count;
+                    display.update_cadence(Some(rpm as u8));
+                    display.update_crank_count(self.crank_count);
+                }
+                display.update_power(Some(power_measure.instantaneous_power));
+                self.last_power_measure = Some(power_measure);
+            }
+            telemetry_db::Notification::Ble((ftms::INDOOR_BIKE_DATA_UUID, v)) => {
+                let bike_data = parse_indoor_bike_data(&v);
+                if let Some(power) = bike_data.instantaneous_power {
+                    display.update_power(Some(self.power_filter.filter(power)));
+                }
+                if let Some(cadence) = bike_data.instantaneous_cadence {
+                    display.update_cadence(Some(cadence as u8));
+                }
+            }
+            telemetry_db::Notification::Ble((hrm::MEASURE_UUID, v)) => {
+                let hrm = parse_hrm(&v);
+                display.update_heart_rate(Some(hrm.bpm));
+                display.set_hr_sensor_contact(hrm.is_sensor_contact_detected);
+            }
+            telemetry_db::Notification::Ble((csc_measurement::MEASURE_UUID, v))
+            | telemetry_db::Notification::DeviceBle((_, csc_measurement::MEASURE_UUID, v)) => {
+                let csc_measure = parse_csc_measurement(&v);
+                let last = self.last_csc_measure.as_ref();
+                if let Some((wheel_rpm, new_wheel_count)) =
+                    checked_wheel_rpm_and_new_count(last, &csc_measure)
+                {
+                    self.wheel_count = self.wheel_count + new_wheel_count;
+                    display.update_speed(Some(wheel_rpm as f32 * self.wheel_circumference / 60.0));
+                    display.update_distance(
+                        self.wheel_count as f64 * self.wheel_circumference as f64,
+                    );
+                }
+                if let Some((rpm, new_crank_count)) =
+                    checked_crank_rpm_and_new_count(last, &csc_measure)
+                {
+                    self.crank_count = self.crank_count + new_crank_count;
+                    display.update_cadence(Some(rpm as u8));
+                    display.update_crank_count(self.crank_count);
+                }
+                self.last_csc_measure = Some(csc_measure);
+            }
+            telemetry_db::Notification::Gps(nmea0183::ParseResult::GGA(o_gga)) => {
+                display.set_gps_fix(o_gga.is_some());
+            }
+            telemetry_db::Notification::WheelCircumference(c) => {
+                self.wheel_circumference = c;
+            }
+            telemetry_db::Notification::MaxPower(max_power) => {
+                self.power_filter = PowerFilter::new(max_power);
+            }
+            _ => (),
+        }
+    }
+}
+
+// Each entry is shown once its (sped up) elapsed time has passed, and the
+// display keeps rendering in between, just as it would live.
+fn replay_session(
+    display: &mut display::Display,
+    db: &telemetry_db::TelemetryDb,
+    session_key: u64,
+    speed: f64,
+    intervals: &power_mode::SharedIntervals,
+) {
+    let mut replay = Replay::new();
+    let start = Instant::now();
+    display.set_start(Some(SessionClock::new()));
+    let mut next_render = start;
+    for entry in db.get_session_entries(session_key) {
+        let (elapsed, notification) = entry.unwrap();
+        let due = start + replay_delay(elapsed, speed);
+        loop {
+            let now = Instant::now();
+            if now >= next_render {
+                display.render();
+                next_render = now + lock_or_recover(intervals).render;
+            }
+            if now >= due {
+                break;
+            }
+            thread::sleep(std::cmp::min(due, next_render) - now);
+        }
+        replay.apply(display, notification);
+    }
+    display.render();
+}
+
+// The FIT file for the latest session, a single session key, or an inclusive
+// range of them ("a-b"), if they exist.
+fn export_fit(
+    db: &telemetry_db::TelemetryDb,
+    o_keys: Option<&str>,
+    compress: bool,
+) -> Option<sled::Result<Vec<u8>>> {
+    let (a, b) = match o_keys {
+        None => {
+            let k = db.get_most_recent_session().unwrap()?;
+            (k, k)
+        }
+        Some(keys) => match keys.split_once('-') {
+            Some((a, b)) => (a.parse().ok()?, b.parse().ok()?),
+            None => {
+                let k = keys.parse().ok()?;
+                (k, k)
+            }
+        },
+    };
+    let session_keys = db.sessions_between_inclusive(a, b).unwrap()?;
+    let window = fit::TimeWindow::default();
+    Some(db_sessions_to_fit(db, session_keys.into_iter(), window, compress))
+}
+
+// The window is relative to the start of the first session
 fn db_sessions_to_fit<I: Iterator<Item = u64>>(
     db: &telemetry_db::TelemetryDb,
     session_keys: I,
+    window: fit::TimeWindow,
+    compress: bool,
 ) -> sled::Result<Vec<u8>> {
-    // TODO: Ideally we could stay lazy through this whole process and
-    // fit::to_file would accept any generic iterator
-    let fit_records: sled::Result<Vec<fit::FitRecord>> = session_keys
-        .flat_map(|sk| db_session_to_fit_records(db, sk))
-        .collect();
-    fit_records.map(|frs| fit::to_file(&frs))
+    let mut bytes = Vec::new();
+    write_db_sessions_fit(db, session_keys, window, compress, &mut bytes)?;
+    Ok(bytes)
+}
+
+// Records are read from the DB as they're written (twice, since the FIT header
+// needs to know how long the file is), so rides of any length can be sent
+// without holding them in memory.
+fn write_db_sessions_fit<I: Iterator<Item = u64>>(
+    db: &telemetry_db::TelemetryDb,
+    session_keys: I,
+    window: fit::TimeWindow,
+    compress: bool,
+    w: impl Write,
+) -> sled::Result<()> {
+    let session_keys: Vec<u64> = session_keys.collect();
+    let sessions = with_session_starts(db, session_keys.iter().cloned())?;
+    let start = sessions.first().map_or(0, |(_, start)| *start as u32);
+    // Reading stops at the first error, which is returned once the file is
+    // finished (when it's too late to do anything but report it).
+    let o_error = RefCell::new(None);
+    let fit_records = || {
+        let records = sessions
+            .iter()
+            .flat_map(|(sk, start)| db_session_to_fit_records(db, *sk, *start))
+            .map_while(|x| x.map_err(|e| *o_error.borrow_mut() = Some(e)).ok());
+        fit::crop_iter(records, start, window)
+    };
+    let device_infos: Vec<fit::DeviceInfo> = sessions
+        .iter()
+        .flat_map(|(sk, start)| db_session_to_fit_device_infos(db, *sk, *start))
+        .collect::<sled::Result<_>>()?;
+    let o_sport = db_sessions_to_sport(db, &session_keys)?;
+    let lap_marks = db_sessions_to_lap_marks(db, &sessions)?;
+    fit::write_file_with_summary(w, fit_records, &device_infos, o_sport, &lap_marks, compress)
+        .map_err(sled::Error::Io)?;
+    match o_error.into_inner() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+// When each lap started (in seconds since the unix epoch), oldest first
+fn db_sessions_to_lap_marks(
+    db: &telemetry_db::TelemetryDb,
+    sessions: &[(u64, u64)],
+) -> sled::Result<Vec<u32>> {
+    let mut lap_marks = Vec::new();
+    for (session_key, start) in sessions {
+        for x in db.get_session_entries(*session_key) {
+            if let (d, telemetry_db::Notification::Lap) = x? {
+                lap_marks.push((start + d.as_secs()) as u32);
+            }
+        }
+    }
+    Ok(lap_marks)
+}
+
+// The workout a session was riding, and how far into it the rider got, from
+// what was recorded.  Time spent setting up or paused, and any gap after the
+// last entry (like a crash), doesn't move the workout along.  Sessions from
+// before workouts were recorded have none.
+fn db_session_to_workout(
+    db: &telemetry_db::TelemetryDb,
+    session_key: u64,
+) -> sled::Result<Option<(workout::Workout, Duration)>> {
+    let mut o_workout = None;
+    // When it was recorded, where the workout was, and if it was paused
+    let mut o_position: Option<(Duration, Duration, bool)> = None;
+    let mut last_elapsed = Duration::from_secs(0);
+    for x in db.get_session_entries(session_key) {
+        let (elapsed, notification) = x?;
+        last_elapsed = elapsed;
+        match notification {
+            telemetry_db::Notification::Workout((ct, tail)) => {
+                o_workout = Some(workout::Workout::new(ct, tail));
+                o_position = None;
+            }
+            telemetry_db::Notification::WorkoutPosition((at, is_paused)) => {
+                o_position = Some((elapsed, at, is_paused));
+            }
+            _ => (),
+        }
+    }
+    Ok(o_workout.map(|workout| {
+        let at = match o_position {
+            None => Duration::from_secs(0),
+            Some((_, at, true)) => at,
+            Some((recorded, at, false)) => at + last_elapsed.saturating_sub(recorded),
+        };
+        (workout, at)
+    }))
+}
+
+// Sessions from before this was recorded have no sport, and if sessions
+// disagree, the first one wins.
+fn db_sessions_to_sport(
+    db: &telemetry_db::TelemetryDb,
+    session_keys: &Vec<u64>,
+) -> sled::Result<Option<fit::Sport>> {
+    for session_key in session_keys {
+        for x in db.get_session_entries(*session_key) {
+            if let (_, telemetry_db::Notification::Sport(sport)) = x? {
+                return Ok(Some(sport));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn db_sessions_to_tcx<I: Iterator<Item = u64>>(
+    db: &telemetry_db::TelemetryDb,
+    session_keys: I,
+) -> sled::Result<String> {
+    let session_keys: Vec<u64> = session_keys.collect();
+    let fit_records: Vec<fit::FitRecord> = with_session_starts(db, session_keys.iter().cloned())?
+        .into_iter()
+        .flat_map(|(sk, start)| db_session_to_fit_records(db, sk, start))
+        .collect::<sled::Result<_>>()?;
+    let o_sport = db_sessions_to_sport(db, &session_keys)?;
+    Ok(tcx::to_tcx(&fit_records, o_sport))
+}
+
+fn db_sessions_to_csv<I: Iterator<Item = u64>>(
+    db: &telemetry_db::TelemetryDb,
+    session_keys: I,
+) -> sled::Result<String> {
+    let fit_records: Vec<fit::FitRecord> = with_session_starts(db, session_keys)?
+        .into_iter()
+        .flat_map(|(sk, start)| db_session_to_fit_records(db, sk, start))
+        .collect::<sled::Result<_>>()?;
+    Ok(csv::to_csv(&fit_records))
+}
+
+// Each session paired with when it started
+fn with_session_starts<I: Iterator<Item = u64>>(
+    db: &telemetry_db::TelemetryDb,
+    session_keys: I,
+) -> sled::Result<Vec<(u64, u64)>> {
+    session_keys
+        .map(|sk| Ok((sk, db.get_session_start(sk)?)))
+        .collect()
+}
+
+// One row per second with each power source in its own column.  The primary
+// source is the one that makes it into FIT files, and the others are only
+// present when they were connected as secondary sources.
+fn db_sessions_to_power_compare_csv<I: Iterator<Item = u64>>(
+    db: &telemetry_db::TelemetryDb,
+    session_keys: I,
+) -> sled::Result<String> {
+    let mut seconds: BTreeMap<u64, (Option<i16>, Option<i16>, Option<i16>)> = BTreeMap::new();
+    for session_key in session_keys {
+        let start = db.get_session_start(session_key)?;
+        for x in db.get_session_entries(session_key) {
+            let (d, notification) = x?;
+            let second = seconds
+                .entry(start + d.as_secs())
+                .or_insert((None, None, None));
+            match notification {
+                telemetry_db::Notification::Ble((assioma::MEASURE_UUID, v)) => {
+                    second.0 = Some(parse_cycling_power_measurement(&v).instantaneous_power);
+                }
+                telemetry_db::Notification::DeviceBle((Device::Assioma, assioma::MEASURE_UUID, v)) => {
+                    second.1 = Some(parse_cycling_power_measurement(&v).instantaneous_power);
+                }
+                telemetry_db::Notification::DeviceBle((Device::Kickr, kickr::MEASURE_UUID, v)) => {
+                    second.2 = Some(parse_cycling_power_measurement(&v).instantaneous_power);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let mut csv = "timestamp,power,assioma_power,kickr_power\n".to_string();
+    for (t, powers) in seconds {
+        if let (None, None, None) = powers {
+            continue;
+        }
+        let show = |o: Option<i16>| o.map_or("".to_string(), |p| format!("{}", p));
+        csv.push_str(&format!(
+            "{},{},{},{}\n",
+            t,
+            show(powers.0),
+            show(powers.1),
+            show(powers.2)
+        ));
+    }
+    Ok(csv)
+}
+
+// A session as it's listed in the index, in this order
+#[derive(Serialize)]
+struct SessionIndexJson {
+    key: u64,
+    start_unix: u64,
+    entry_count: usize,
+    profile: Option<String>,
+}
+
+// The most recent sessions (up to limit, listed oldest first), when each
+// started, how many entries it recorded (see list_sessions), and the profile
+// it was recorded under, if known.  When a profile is given, only its sessions
+// are included.
+fn db_sessions_to_index_json(
+    db: &telemetry_db::TelemetryDb,
+    o_profile: Option<&str>,
+    limit: usize,
+) -> sled::Result<String> {
+    let mut sessions = Vec::new();
+    for session_key in db.get_sessions()?.into_iter().rev() {
+        if sessions.len() == limit {
+            break;
+        }
+        let o_session_profile = db.get_session_profile(session_key)?;
+        if o_profile.map_or(true, |p| o_session_profile.as_deref() == Some(p)) {
+            sessions.push(SessionIndexJson {
+                key: session_key,
+                start_unix: db.get_session_start(session_key)?,
+                entry_count: db.count_session_entries(session_key)?,
+                profile: o_session_profile,
+            });
+        }
+    }
+    sessions.reverse();
+    // Plain data, so this can't fail
+    Ok(serde_json::to_string(&sessions).unwrap())
+}
+
+// A raw notification as it's exported, in this order.  The value is in hex.
+#[derive(Serialize)]
+struct RawNotificationJson {
+    session_key: u64,
+    elapsed_nanos: u128,
+    source: String,
+    uuid: String,
+    value: String,
+}
+
+// One JSON object per line, for each raw notification captured.  Sessions
+// recorded without raw capture are simply empty.
+fn db_sessions_to_raw_jsonl<I: Iterator<Item = u64>>(
+    db: &telemetry_db::TelemetryDb,
+    session_keys: I,
+) -> sled::Result<String> {
+    let mut jsonl = String::new();
+    for session_key in session_keys {
+        for raw in db.get_raw_entries(session_key)? {
+            // Plain data, so this can't fail
+            jsonl.push_str(
+                &serde_json::to_string(&RawNotificationJson {
+                    session_key,
+                    elapsed_nanos: raw.elapsed.as_nanos(),
+                    source: raw.source,
+                    uuid: raw.uuid.to_string(),
+                    value: raw.value.iter().map(|b| format!("{:02x}", b)).collect(),
+                })
+                .unwrap(),
+            );
+            jsonl.push('\n');
+        }
+    }
+    Ok(jsonl)
+}
+
+// Each connected device gets a device info at the start of the session, and
+// then again any time its battery level changes.
+fn db_session_to_fit_device_infos(
+    db: &telemetry_db::TelemetryDb,
+    session_key: u64,
+    start: u64,
+) -> impl Iterator<Item = sled::Result<fit::DeviceInfo>> + '_ {
+    let mut last_battery_levels: BTreeMap<Device, Option<u8>> = BTreeMap::new();
+    // Read just before each device's status, so it's known by then
+    let mut infos: BTreeMap<Device, DeviceInformation> = BTreeMap::new();
+
+    db.get_session_entries(session_key).filter_map(move |x| {
+        match x {
+            Ok((_, telemetry_db::Notification::DeviceInfo((device, info)))) => {
+                infos.insert(device, info);
+                None
+            }
+            Ok((d, telemetry_db::Notification::DeviceStatus((device, battery_level)))) => {
+                if last_battery_levels.insert(device, battery_level) == Some(battery_level) {
+                    None
+                } else {
+                    Some(Ok(fit::DeviceInfo {
+                        seconds_since_unix_epoch: (start + d.as_secs()) as u32,
+                        // 0 is reserved for the creator
+                        device_index: device as u8 + 1,
+                        // FIT's ble_device_type
+                        device_type: match device {
+                            Device::Assioma => 2,
+                            Device::Cadence => 5,
+                            Device::Hrm => 1,
+                            Device::Kickr => 7,
+                            Device::Speed => 4,
+                            Device::Rsc => 6,
+                        },
+                        product_name: device.name().to_string(),
+                        battery_level,
+                        serial_number: infos
+                            .get(&device)
+                            .and_then(|x| x.numeric_serial_number()),
+                        descriptor: infos.get(&device).and_then(|x| x.descriptor()),
+                    }))
+                }
+            }
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        }
+    })
+}
+
+// Any power meter reporting crank revolutions gives cadence, though a CSC
+// sensor is preferred when there is one, since that's all it measures.
+fn power_meter_cadence(
+    last: Option<&CyclingPowerMeasurement>,
+    power_measure: &CyclingPowerMeasurement,
+) -> Option<u8> {
+    cycling_power_measurement::checked_crank_rpm_and_new_count(last, power_measure)
+        .map(|(crank_rpm, _)| crank_rpm as u8)
 }
 
 fn db_session_to_fit_records(
     db: &telemetry_db::TelemetryDb,
     session_key: u64,
+    start: u64,
 ) -> impl Iterator<Item = sled::Result<fit::FitRecord>> + '_ {
     let mut last_power_measure: Option<CyclingPowerMeasurement> = None;
+    // Power meters that aren't the primary power source still provide cadence
+    let mut last_secondary_power_measures: BTreeMap<Device, CyclingPowerMeasurement> =
+        BTreeMap::new();
     let mut last_cadence_csc_measurement: Option<CscMeasurement> = None;
     let mut last_wheel_csc_measurement: Option<CscMeasurement> = None;
     let mut wheel_count = 0;
+    // GPS speed and distance are only used when no sensor provides them
+    let mut has_speed_sensor = false;
+    let mut odometer = geo::Odometer::new();
+    // Sessions from before calibration was possible don't record this
+    let mut wheel_circumference = DEFAULT_WHEEL_CIRCUMFERENCE;
+    // Nor do sessions from before glitches were filtered, which are kept as
+    // they were recorded
+    let mut power_filter = PowerFilter::new(i16::MAX);
+    // Unknown until a sensor tells us, in which case we trust each
+    // measurement's flags.  Older sessions only know what any of their
+    // sensors support, since their measurements weren't tagged.
+    let mut csc_features: BTreeMap<Device, CscFeature> = BTreeMap::new();
+    let mut o_csc_feature: Option<CscFeature> = None;
     let mut record: Option<fit::FitRecord> = None;
-    let empty_record = |t| fit::FitRecord {
-        seconds_since_unix_epoch: t,
-        power: None,
-        heart_rate: None,
-        cadence: None,
-        latitude: None,
-        longitude: None,
-        altitude: None,
-        distance: None,
-        speed: None,
-    };
 
     db.get_session_entries(session_key).filter_map(move |x| {
         match x {
             Ok((d, value)) => {
                 let mut finished_record = None;
-                let seconds_since_unix_epoch = (session_key + d.as_secs()) as u32;
+                let seconds_since_unix_epoch = (start + d.as_secs()) as u32;
                 let mut r = match record.take() {
                     Some(mut r) => {
                         if r.seconds_since_unix_epoch == seconds_since_unix_epoch {
@@ -1064,13 +2791,27 @@ fn db_session_to_fit_records(
                             if let None = r.power {
                                 r.power = last_power_measure
                                     .as_ref()
-                                    .map(|p| p.instantaneous_power as u16);
+                                    .map(|p| fit::power_from_signed(p.instantaneous_power));
                             }
                             finished_record = Some(r);
-                            empty_record(seconds_since_unix_epoch)
+                            fit::FitRecord::new(seconds_since_unix_epoch)
                         }
                     }
-                    None => empty_record(seconds_since_unix_epoch),
+                    None => fit::FitRecord::new(seconds_since_unix_epoch),
+                };
+
+                // CSC measurements are handled the same whichever sensor they
+                // came from, other than what it supports
+                let (value, o_csc_device) = match value {
+                    telemetry_db::Notification::DeviceBle((
+                        device,
+                        csc_measurement::MEASURE_UUID,
+                        v,
+                    )) => (
+                        telemetry_db::Notification::Ble((csc_measurement::MEASURE_UUID, v)),
+                        Some(device),
+                    ),
+                    value => (value, None),
                 };
 
                 match value {
@@ -1078,31 +2819,113 @@ fn db_session_to_fit_records(
                         r.latitude = Some(gga.latitude.as_f64());
                         r.longitude = Some(gga.longitude.as_f64());
                         r.altitude = Some(gga.altitude.meters);
+                        let distance =
+                            odometer.update(gga.latitude.as_f64(), gga.longitude.as_f64());
+                        if !has_speed_sensor {
+                            r.distance = Some(distance);
+                        }
                     }
-                    telemetry_db::Notification::Gps(_) => (),
-                    telemetry_db::Notification::Ble((hrm::MEASURE_UUID, v)) => {
-                        r.heart_rate = Some(parse_hrm(&v).bpm as u8);
+                    telemetry_db::Notification::Gps(s) => {
+                        if !has_speed_sensor {
+                            if let Some(speed) = gps::speed(&s) {
+                                r.speed = Some(speed);
+                            }
+                        }
                     }
-                    telemetry_db::Notification::Ble((assioma::MEASURE_UUID, v)) => {
+                    telemetry_db::Notification::DeviceStatus(_) => (),
+                    telemetry_db::Notification::DeviceInfo(_) => (),
+                    telemetry_db::Notification::DeviceBle((
+                        device,
+                        cycling_power_measurement::MEASURE_UUID,
+                        v,
+                    )) => {
                         let power_measure = parse_cycling_power_measurement(&v);
-                        r.power = Some(power_measure.instantaneous_power as u16);
-                        let o_crank_rpm =
-                            cycling_power_measurement::checked_crank_rpm_and_new_count(
-                                last_power_measure.as_ref(),
+                        if last_cadence_csc_measurement.is_none() {
+                            let o_cadence = power_meter_cadence(
+                                last_secondary_power_measures.get(&device),
                                 &power_measure,
-                            )
-                            .map(|x| x.0);
-                        if let Some(crank_rpm) = o_crank_rpm {
-                            r.cadence = Some(crank_rpm as u8);
+                            );
+                            if let Some(cadence) = o_cadence {
+                                r.cadence = Some(cadence);
+                            }
+                        }
+                        // Balance only ever comes from the pedals, so we keep
+                        // it even when they aren't the primary power source.
+                        if let Some(balance) = power_measure.pedal_power_balance_percent {
+                            r.pedal_power_balance = Some(balance);
+                        }
+                        last_secondary_power_measures.insert(device, power_measure);
+                    }
+                    telemetry_db::Notification::DeviceBle(_) => (),
+                    telemetry_db::Notification::Sport(_) => (),
+                    telemetry_db::Notification::Lap => (),
+                    telemetry_db::Notification::Workout(_) => (),
+                    telemetry_db::Notification::WorkoutPosition(_) => (),
+                    telemetry_db::Notification::WheelCircumference(c) => {
+                        wheel_circumference = c;
+                    }
+                    telemetry_db::Notification::MaxPower(max_power) => {
+                        power_filter = PowerFilter::new(max_power);
+                    }
+                    telemetry_db::Notification::CscFeature(feature) => {
+                        o_csc_feature = Some(
+                            o_csc_feature.map_or(feature, |f| f.union(&feature)),
+                        );
+                    }
+                    telemetry_db::Notification::DeviceCscFeature((device, feature)) => {
+                        csc_features.insert(device, feature);
+                    }
+                    telemetry_db::Notification::Ble((hrm::MEASURE_UUID, v)) => {
+                        r.heart_rate = Some(fit::heart_rate_from_bpm(parse_hrm(&v).bpm));
+                    }
+                    telemetry_db::Notification::Ble((ftms::INDOOR_BIKE_DATA_UUID, v)) => {
+                        let bike_data = parse_indoor_bike_data(&v);
+                        if let Some(power) = bike_data.instantaneous_power {
+                            r.power = Some(fit::power_from_signed(power_filter.filter(power)));
+                        }
+                        if last_cadence_csc_measurement.is_none() {
+                            if let Some(cadence) = bike_data.instantaneous_cadence {
+                                r.cadence = Some(cadence as u8);
+                            }
+                        }
+                    }
+                    telemetry_db::Notification::Ble((
+                        cycling_power_measurement::MEASURE_UUID,
+                        v,
+                    )) => {
+                        let mut power_measure = parse_cycling_power_measurement(&v);
+                        power_measure.instantaneous_power =
+                            power_filter.filter(power_measure.instantaneous_power);
+                        r.power = Some(fit::power_from_signed(power_measure.instantaneous_power));
+                        if last_cadence_csc_measurement.is_none() {
+                            let o_cadence =
+                                power_meter_cadence(last_power_measure.as_ref(), &power_measure);
+                            if let Some(cadence) = o_cadence {
+                                r.cadence = Some(cadence);
+                            }
+                        }
+                        if let Some(balance) = power_measure.pedal_power_balance_percent {
+                            r.pedal_power_balance = Some(balance);
                         }
                         last_power_measure = Some(power_measure);
                     }
                     telemetry_db::Notification::Ble((csc_measurement::MEASURE_UUID, v)) => {
                         // TODO: Clean up cloning here that supports crank and wheel
                         // data coming from different sources :/
-                        // We can't tell if this reading support just one or both,
-                        // given that the CSC UUID/characterstic supports both.
-                        let csc_measurement = parse_csc_measurement(&v);
+                        // The CSC UUID/characterstic supports both, so we
+                        // only trust data that the sensor actually claims to
+                        // support (if we know).
+                        let mut csc_measurement = parse_csc_measurement(&v);
+                        if let Some(feature) = o_csc_device.map_or(o_csc_feature, |device| {
+                            csc_features.get(&device).copied()
+                        }) {
+                            if !feature.wheel_revolution_data {
+                                csc_measurement.wheel = None;
+                            }
+                            if !feature.crank_revolution_data {
+                                csc_measurement.crank = None;
+                            }
+                        }
                         let o_crank_rpm = checked_crank_rpm_and_new_count(
                             last_cadence_csc_measurement.as_ref(),
                             &csc_measurement,
@@ -1116,9 +2939,10 @@ fn db_session_to_fit_records(
                             r.cadence = Some(crank_rpm as u8);
                         }
                         if let Some((wheel_rpm, new_wheel_count)) = o_wheel {
-                            r.speed = Some(wheel_rpm as f32 * WHEEL_CIRCUMFERENCE / 60.0);
+                            has_speed_sensor = true;
+                            r.speed = Some(wheel_rpm as f32 * wheel_circumference / 60.0);
                             wheel_count += new_wheel_count;
-                            r.distance = Some(wheel_count as f64 * WHEEL_CIRCUMFERENCE as f64);
+                            r.distance = Some(wheel_count as f64 * wheel_circumference as f64);
                         }
                         // We want to consider both the cases where we have
                         // individual devices and one that has both measures.
@@ -1129,6 +2953,16 @@ fn db_session_to_fit_records(
                             last_wheel_csc_measurement = Some(csc_measurement.clone());
                         }
                     }
+                    telemetry_db::Notification::Ble((rsc_measurement::MEASURE_UUID, v)) => {
+                        let rsc_measure = parse_rsc_measurement(&v);
+                        has_speed_sensor = true;
+                        r.speed = Some(rsc_measure.speed);
+                        // FIT counts running cadence in strides, not steps
+                        r.cadence = Some(rsc_measure.steps_per_minute / 2);
+                        if let Some(total_distance) = rsc_measure.total_distance {
+                            r.distance = Some(total_distance as f64);
+                        }
+                    }
                     _ => {
                         println!("UUID not matched");
                     }
@@ -1142,3 +2976,484 @@ fn db_session_to_fit_records(
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::db_session_to_fit_device_infos;
+    use super::db_session_to_fit_records;
+    use super::db_session_to_workout;
+    use super::db_sessions_to_index_json;
+    use super::db_sessions_to_raw_jsonl;
+    use super::export_fit;
+    use super::profile_tree;
+    use super::replay_delay;
+    use super::with_imported_workout;
+    use super::{OrExit, Resume, SelectionTree, SelectionTreeValue};
+    use crate::ble::csc_feature::CscFeature;
+    use crate::ble::csc_measurement;
+    use crate::ble::device_information::DeviceInformation;
+    use crate::cycle_tree::CycleTree;
+    use crate::fit;
+    use crate::peripherals::{assioma, ftms, hrm, kickr, Device};
+    use crate::profiles::{Profile, Profiles, SelectedDevices};
+    use crate::telemetry_db;
+    use std::time::Duration;
+
+    // 200W with crank revolution data
+    fn power_with_crank(revolutions: u8, event_time: u16) -> Vec<u8> {
+        let [t0, t1] = event_time.to_le_bytes();
+        vec![0x20, 0, 200, 0, revolutions, 0, t0, t1]
+    }
+
+    #[test]
+    fn replay_delay_is_scaled_by_the_speed() {
+        assert_eq!(Duration::from_secs(60), replay_delay(Duration::from_secs(60), 1.0));
+        assert_eq!(Duration::from_secs(15), replay_delay(Duration::from_secs(60), 4.0));
+        assert_eq!(Duration::from_secs(120), replay_delay(Duration::from_secs(60), 0.5));
+    }
+
+    #[test]
+    fn device_information_is_exported_with_the_device_status() {
+        let db = telemetry_db::open_temporary().unwrap();
+        let info = DeviceInformation {
+            manufacturer_name: Some("Wahoo Fitness".to_string()),
+            model_number: Some("KICKR".to_string()),
+            serial_number: Some("123456".to_string()),
+            firmware_revision: Some("4.2.1".to_string()),
+        };
+        db.insert(
+            100,
+            Duration::from_secs(0),
+            telemetry_db::Notification::DeviceInfo((Device::Kickr, info)),
+        )
+        .unwrap();
+        for (elapsed, device, battery_level) in [(1, Device::Kickr, 80), (2, Device::Hrm, 90)] {
+            db.insert(
+                100,
+                Duration::from_secs(elapsed),
+                telemetry_db::Notification::DeviceStatus((device, Some(battery_level))),
+            )
+            .unwrap();
+        }
+        let device_infos: Vec<_> = db_session_to_fit_device_infos(&db, 100, 100)
+            .collect::<sled::Result<_>>()
+            .unwrap();
+        assert_eq!(device_infos[0].serial_number, Some(123456));
+        assert_eq!(
+            device_infos[0].descriptor,
+            Some("Wahoo Fitness KICKR fw 4.2.1".to_string())
+        );
+        assert_eq!(device_infos[1].serial_number, None);
+        assert_eq!(device_infos[1].descriptor, None);
+    }
+
+    // The records exported from a session with these notifications (at each
+    // number of seconds in).  Records are only finished once the next second
+    // arrives, so a heart rate follows a second after the last one.
+    fn records_for(notifications: Vec<(u64, telemetry_db::Notification)>) -> Vec<fit::FitRecord> {
+        let db = telemetry_db::open_temporary().unwrap();
+        let mut last = 0;
+        for (secs, notification) in notifications {
+            db.insert(100, Duration::from_secs(secs), notification).unwrap();
+            last = u64::max(last, secs);
+        }
+        db.insert(
+            100,
+            Duration::from_secs(last + 1),
+            telemetry_db::Notification::Ble((hrm::MEASURE_UUID, vec![0, 120])),
+        )
+        .unwrap();
+        db_session_to_fit_records(&db, 100, 100)
+            .collect::<sled::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn indoor_bike_data_is_exported_as_power_and_cadence() {
+        // 250W at 90rpm, without speed
+        let bike_data = vec![0b1000101, 0, 180, 0, 250, 0];
+        let records = records_for(vec![(
+            0,
+            telemetry_db::Notification::Ble((ftms::INDOOR_BIKE_DATA_UUID, bike_data)),
+        )]);
+        assert_eq!(records[0].power, Some(250));
+        assert_eq!(records[0].cadence, Some(90));
+    }
+
+    #[test]
+    fn power_over_the_sessions_max_is_exported_as_the_last_plausible_power() {
+        let power = |v| telemetry_db::Notification::Ble((assioma::MEASURE_UUID, v));
+        let records = records_for(vec![
+            (0, telemetry_db::Notification::MaxPower(1500)),
+            // 200W, then 4000W, with no optional fields
+            (0, power(vec![0, 0, 200, 0])),
+            (1, power(vec![0, 0, 0xA0, 0x0F])),
+        ]);
+        assert_eq!(records[0].power, Some(200));
+        assert_eq!(records[1].power, Some(200));
+    }
+
+    #[test]
+    fn negative_power_is_exported_as_zero() {
+        // -5W, with no optional fields
+        let power = vec![0, 0, 0xFB, 0xFF];
+        let records =
+            records_for(vec![(0, telemetry_db::Notification::Ble((assioma::MEASURE_UUID, power)))]);
+        assert_eq!(records[0].power, Some(0));
+    }
+
+    #[test]
+    fn export_fit_only_for_recorded_sessions() {
+        let db = telemetry_db::open_temporary().unwrap();
+        db.insert(
+            100,
+            Duration::from_secs(0),
+            telemetry_db::Notification::Ble((hrm::MEASURE_UUID, vec![0, 120])),
+        )
+        .unwrap();
+        assert!(export_fit(&db, None, false).is_some());
+        assert!(export_fit(&db, Some("100"), false).is_some());
+        assert!(export_fit(&db, Some("100-100"), false).is_some());
+        assert!(export_fit(&db, Some("101"), false).is_none());
+        assert!(export_fit(&db, Some("latest"), false).is_none());
+    }
+
+    #[test]
+    fn pedal_power_balance_is_replayed() {
+        // 200W with a 52% balance
+        let power = vec![1, 0, 200, 0, 104];
+        let records =
+            records_for(vec![(0, telemetry_db::Notification::Ble((assioma::MEASURE_UUID, power)))]);
+        assert_eq!(records[0].pedal_power_balance, Some(52.0));
+        assert_eq!(records[0].power, Some(200));
+    }
+
+    #[test]
+    fn kickr_crank_data_is_replayed_as_cadence() {
+        let power = |v| telemetry_db::Notification::Ble((kickr::MEASURE_UUID, v));
+        let records = records_for(vec![
+            (0, power(power_with_crank(10, 0))),
+            // One revolution in one second
+            (1, power(power_with_crank(11, 1024))),
+        ]);
+        assert_eq!(records[1].cadence, Some(60));
+    }
+
+    #[test]
+    fn secondary_power_meter_crank_data_is_replayed_as_cadence() {
+        let secondary =
+            |v| telemetry_db::Notification::DeviceBle((Device::Kickr, kickr::MEASURE_UUID, v));
+        let records = records_for(vec![
+            (0, secondary(power_with_crank(10, 0))),
+            (1, secondary(power_with_crank(11, 1024))),
+        ]);
+        assert_eq!(records[1].cadence, Some(60));
+        assert_eq!(records[1].power, None);
+    }
+
+    #[test]
+    fn csc_cadence_is_preferred_over_a_power_meter() {
+        let db = telemetry_db::open_temporary().unwrap();
+        let insert = |ms, n| db.insert(100, Duration::from_millis(ms), n).unwrap();
+        let power = |v| telemetry_db::Notification::Ble((kickr::MEASURE_UUID, v));
+        // Crank revolution data only
+        let csc = |revolutions, event_time: u16| {
+            let [t0, t1] = event_time.to_le_bytes();
+            telemetry_db::Notification::Ble((
+                csc_measurement::MEASURE_UUID,
+                vec![0b10, revolutions, 0, t0, t1],
+            ))
+        };
+        insert(0, power(power_with_crank(10, 0)));
+        insert(500, csc(5, 0));
+        // The CSC sees one revolution in half a second, and the power meter
+        // one in a whole second
+        insert(1000, csc(6, 512));
+        insert(1500, power(power_with_crank(11, 1024)));
+        insert(2000, telemetry_db::Notification::Ble((hrm::MEASURE_UUID, vec![0, 120])));
+        let records: Vec<_> = db_session_to_fit_records(&db, 100, 100)
+            .collect::<sled::Result<_>>()
+            .unwrap();
+        assert_eq!(records[1].cadence, Some(120));
+    }
+
+    #[test]
+    fn csc_features_only_apply_to_their_own_sensor() {
+        let db = telemetry_db::open_temporary().unwrap();
+        let insert = |ms, n| db.insert(100, Duration::from_millis(ms), n).unwrap();
+        let feature = |device, wheel, crank| {
+            telemetry_db::Notification::DeviceCscFeature((
+                device,
+                CscFeature {
+                    wheel_revolution_data: wheel,
+                    crank_revolution_data: crank,
+                    multiple_sensor_locations: false,
+                },
+            ))
+        };
+        // Wheel and crank revolution data, from the speed sensor
+        let speed = |revolutions, event_time: u16| {
+            let [t0, t1] = event_time.to_le_bytes();
+            let data = vec![0b11, revolutions, 0, 0, 0, t0, t1, revolutions, 0, t0, t1];
+            telemetry_db::Notification::DeviceBle((
+                Device::Speed,
+                csc_measurement::MEASURE_UUID,
+                data,
+            ))
+        };
+        insert(0, feature(Device::Speed, true, false));
+        insert(1, feature(Device::Cadence, false, true));
+        insert(100, speed(5, 0));
+        insert(600, speed(6, 512));
+        insert(1000, telemetry_db::Notification::Ble((hrm::MEASURE_UUID, vec![0, 120])));
+        let records: Vec<_> = db_session_to_fit_records(&db, 100, 100)
+            .collect::<sled::Result<_>>()
+            .unwrap();
+        // Only the cadence sensor claims crank data
+        assert!(records[0].speed.is_some());
+        assert_eq!(records[0].cadence, None);
+    }
+
+    #[test]
+    fn index_json_lists_each_session_oldest_first() {
+        let db = telemetry_db::open_temporary().unwrap();
+        db.insert(100, Duration::from_secs(0), telemetry_db::Notification::Lap).unwrap();
+        db.insert(100, Duration::from_secs(1), telemetry_db::Notification::Lap).unwrap();
+        db.insert(200, Duration::from_secs(0), telemetry_db::Notification::Lap).unwrap();
+        db.set_session_start(200, 150).unwrap();
+        db.set_session_profile(200, "Zenia").unwrap();
+        assert_eq!(
+            db_sessions_to_index_json(&db, None, 10).unwrap(),
+            concat!(
+                r#"[{"key":100,"start_unix":100,"entry_count":2,"profile":null},"#,
+                r#"{"key":200,"start_unix":150,"entry_count":1,"profile":"Zenia"}]"#,
+            )
+        );
+        assert_eq!(
+            db_sessions_to_index_json(&db, Some("Zenia"), 10).unwrap(),
+            r#"[{"key":200,"start_unix":150,"entry_count":1,"profile":"Zenia"}]"#
+        );
+    }
+
+    #[test]
+    fn index_json_only_lists_the_most_recent_sessions() {
+        let db = telemetry_db::open_temporary().unwrap();
+        for session_key in [100, 200, 300] {
+            db.insert(session_key, Duration::from_secs(0), telemetry_db::Notification::Lap)
+                .unwrap();
+        }
+        db.set_session_profile(100, "Zenia").unwrap();
+        db.set_session_profile(300, "Zenia").unwrap();
+        assert_eq!(
+            db_sessions_to_index_json(&db, None, 2).unwrap(),
+            concat!(
+                r#"[{"key":200,"start_unix":200,"entry_count":1,"profile":null},"#,
+                r#"{"key":300,"start_unix":300,"entry_count":1,"profile":"Zenia"}]"#,
+            )
+        );
+        // The limit is of the profile's sessions, not of every session
+        assert_eq!(
+            db_sessions_to_index_json(&db, Some("Zenia"), 2).unwrap(),
+            concat!(
+                r#"[{"key":100,"start_unix":100,"entry_count":1,"profile":"Zenia"},"#,
+                r#"{"key":300,"start_unix":300,"entry_count":1,"profile":"Zenia"}]"#,
+            )
+        );
+        assert_eq!(db_sessions_to_index_json(&db, None, 0).unwrap(), "[]");
+    }
+
+    #[test]
+    fn raw_jsonl_has_a_line_per_notification() {
+        let db = telemetry_db::open_temporary().unwrap();
+        db.insert_raw(100, Duration::from_millis(1500), "AA:BB", hrm::MEASURE_UUID, &[0, 70])
+            .unwrap();
+        // Sources are whatever the adapter reported, so could be anything
+        db.insert_raw(100, Duration::from_secs(2), "\"odd\"\\", hrm::MEASURE_UUID, &[0, 255])
+            .unwrap();
+        let uuid = hrm::MEASURE_UUID.to_string();
+        assert_eq!(
+            db_sessions_to_raw_jsonl(&db, [100, 200].into_iter()).unwrap(),
+            format!(
+                concat!(
+                    r#"{{"session_key":100,"elapsed_nanos":1500000000,"source":"AA:BB","#,
+                    r#""uuid":"{}","value":"0046"}}"#,
+                    "\n",
+                    r#"{{"session_key":100,"elapsed_nanos":2000000000,"source":"\"odd\"\\","#,
+                    r#""uuid":"{}","value":"00ff"}}"#,
+                    "\n",
+                ),
+                uuid, uuid
+            )
+        );
+    }
+
+    #[test]
+    fn index_json_escapes_profile_names() {
+        let db = telemetry_db::open_temporary().unwrap();
+        db.insert(100, Duration::from_secs(0), telemetry_db::Notification::Lap).unwrap();
+        db.set_session_profile(100, "Zenia \"Z\" \\ Road\n").unwrap();
+        assert_eq!(
+            db_sessions_to_index_json(&db, None, 10).unwrap(),
+            r#"[{"key":100,"start_unix":100,"entry_count":1,"profile":"Zenia \"Z\" \\ Road\n"}]"#
+        );
+    }
+
+    fn labels<T>(tree: &[SelectionTree<T>]) -> Vec<&str> {
+        tree.iter().map(|t| t.label.as_str()).collect()
+    }
+
+    #[test]
+    fn default_profiles_overflow_onto_a_more_screen() {
+        let tree = profile_tree(Profiles::default().profiles(), None);
+        assert_eq!(
+            labels(&tree),
+            vec!["Zenia", "Nathan Outdoor", "Nathan Kickr", "More", "Exit"]
+        );
+        match &tree[3].value {
+            SelectionTreeValue::Node(more) => {
+                assert_eq!(labels(more), vec!["Nathan Rollers", "Nathan Treadmill"])
+            }
+            SelectionTreeValue::Leaf(_) => panic!("More should be a screen"),
+        }
+        assert!(matches!(tree[4].value, SelectionTreeValue::Leaf(OrExit::Exit)));
+    }
+
+    #[test]
+    fn an_imported_workout_is_offered_first() {
+        let workouts = || {
+            vec![SelectionTree {
+                label: "Ramp".to_string(),
+                value: SelectionTreeValue::Leaf(1),
+            }]
+        };
+        assert_eq!(labels(&with_imported_workout(None, workouts())), vec!["Ramp"]);
+        let tree = with_imported_workout(Some(&2), workouts());
+        assert_eq!(labels(&tree), vec!["Imported", "Ramp"]);
+        assert!(matches!(tree[0].value, SelectionTreeValue::Leaf(2)));
+    }
+
+    #[test]
+    fn every_profile_screen_fits_five_options() {
+        let profiles: Vec<Profile> = (0..12)
+            .map(|i| Profile {
+                name: format!("Rider {}", i),
+                devices: SelectedDevices::default(),
+            })
+            .collect();
+        let mut tree = profile_tree(&profiles, None);
+        let mut names = vec![];
+        loop {
+            assert!(tree.len() <= 5);
+            let mut more = None;
+            for t in tree {
+                match t.value {
+                    SelectionTreeValue::Leaf(OrExit::NotExit((p, _))) => names.push(p.name),
+                    SelectionTreeValue::Leaf(OrExit::Exit) => (),
+                    SelectionTreeValue::Node(next) => more = Some(next),
+                }
+            }
+            match more {
+                Some(next) => tree = next,
+                None => break,
+            }
+        }
+        let expected: Vec<String> = profiles.into_iter().map(|p| p.name).collect();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn a_few_profiles_fit_on_one_screen() {
+        let profiles = vec![Profile {
+            name: "Solo".to_string(),
+            devices: SelectedDevices::default(),
+        }];
+        assert_eq!(labels(&profile_tree(&profiles, None)), vec!["Solo", "Exit"]);
+    }
+
+    #[test]
+    fn resume_comes_first_with_its_profile() {
+        let profiles = Profiles::default();
+        let resume = Resume {
+            session_key: 1700000000,
+            elapsed: Duration::from_secs(600),
+        };
+        let nathan = profiles.profiles()[2].clone();
+        let tree = profile_tree(profiles.profiles(), Some((nathan.clone(), resume.clone())));
+        assert_eq!(labels(&tree), vec!["Resume", "Zenia", "Nathan Outdoor", "More", "Exit"]);
+        match &tree[0].value {
+            SelectionTreeValue::Leaf(OrExit::NotExit((p, o_resume))) => {
+                assert_eq!(p, &nathan);
+                assert_eq!(o_resume, &Some(resume));
+            }
+            _ => panic!("Resume should start a ride"),
+        }
+    }
+
+    fn two_step_plan() -> (CycleTree<(Duration, u16)>, Option<u16>) {
+        (
+            CycleTree::Node((
+                1,
+                vec![
+                    CycleTree::Leaf((Duration::from_secs(600), 150)),
+                    CycleTree::Leaf((Duration::from_secs(600), 250)),
+                ],
+            )),
+            Some(100),
+        )
+    }
+
+    #[test]
+    fn resuming_carries_on_from_where_the_workout_got_to() {
+        use telemetry_db::Notification::{Ble, Workout, WorkoutPosition};
+        let db = telemetry_db::open_temporary().unwrap();
+        let hr = || Ble((hrm::MEASURE_UUID, vec![0, 120]));
+        // 40s setting up before the workout starts
+        db.insert(1, Duration::from_secs(10), hr()).unwrap();
+        db.insert(1, Duration::from_secs(40), Workout(two_step_plan())).unwrap();
+        db.insert(1, Duration::from_secs(40), WorkoutPosition((Duration::from_secs(0), false)))
+            .unwrap();
+        db.insert(1, Duration::from_secs(340), WorkoutPosition((Duration::from_secs(300), false)))
+            .unwrap();
+        db.insert(1, Duration::from_secs(400), hr()).unwrap();
+        // A minute after the crash, the session is 460s in, but the gap after
+        // it doesn't count towards the workout, and neither does the setup.
+        assert_eq!(
+            db.interrupted_session(1 + 460, Duration::from_secs(300)).unwrap(),
+            Some((1, Duration::from_secs(460)))
+        );
+        let (workout, at) = db_session_to_workout(&db, 1).unwrap().unwrap();
+        assert_eq!(workout.plan(), two_step_plan());
+        assert_eq!(at, Duration::from_secs(360));
+    }
+
+    #[test]
+    fn resuming_does_not_count_time_spent_paused() {
+        use telemetry_db::Notification::{Ble, Workout, WorkoutPosition};
+        let db = telemetry_db::open_temporary().unwrap();
+        let hr = || Ble((hrm::MEASURE_UUID, vec![0, 120]));
+        db.insert(1, Duration::from_secs(40), Workout(two_step_plan())).unwrap();
+        db.insert(1, Duration::from_secs(40), WorkoutPosition((Duration::from_secs(0), false)))
+            .unwrap();
+        db.insert(1, Duration::from_secs(390), WorkoutPosition((Duration::from_secs(350), true)))
+            .unwrap();
+        db.insert(1, Duration::from_secs(450), WorkoutPosition((Duration::from_secs(350), false)))
+            .unwrap();
+        db.insert(1, Duration::from_secs(480), hr()).unwrap();
+        let (_, at) = db_session_to_workout(&db, 1).unwrap().unwrap();
+        assert_eq!(at, Duration::from_secs(380));
+
+        // Crashing while paused leaves it just where it was paused
+        db.insert(1, Duration::from_secs(500), WorkoutPosition((Duration::from_secs(400), true)))
+            .unwrap();
+        db.insert(1, Duration::from_secs(600), hr()).unwrap();
+        let (_, at) = db_session_to_workout(&db, 1).unwrap().unwrap();
+        assert_eq!(at, Duration::from_secs(400));
+    }
+
+    #[test]
+    fn sessions_without_a_recorded_workout_have_nothing_to_resume() {
+        let db = telemetry_db::open_temporary().unwrap();
+        db.insert(1, Duration::from_secs(400), telemetry_db::Notification::Lap).unwrap();
+        assert!(db_session_to_workout(&db, 1).unwrap().is_none());
+    }
+}
@@ -7,6 +7,7 @@ use embedded_graphics::{
     pixelcolor::BinaryColor,
     DrawTarget,
 };
+use crate::power_mode::SharedIntervals;
 use rppal::{
     gpio::{Gpio, OutputPin},
     spi::{reverse_bits, Bus, Error, Mode, SlaveSelect, Spi},
@@ -16,7 +17,6 @@ use std::{
     sync::{Arc, Mutex},
     thread,
     thread::JoinHandle,
-    time::Duration,
 };
 
 pub const HEIGHT: u32 = 168;
@@ -25,22 +25,70 @@ pub const WIDTH: u32 = 144;
 // TODO: Make this configurable
 pub const CS_PIN: u8 = 6;
 
-// TODO: If no change, just use this command to flip the VCOM Bit
-pub const _SHARPMEM_BIT_CHANGE_VCOM_CMD: u8 = 0; // LSB
+// When no lines have changed, this only flips the VCOM Bit
+pub const SHARPMEM_BIT_CHANGE_VCOM_CMD: u8 = 0; // LSB
 pub const SHARPMEM_BIT_WRITE_LINES_CMD: u8 = 0x80; // LSB
 pub const SHARPMEM_BIT_VCOM: u8 = 0x40; // LSB
 
 // TODO: Use this when optimizing for clear
 pub const _SHARPMEM_BIT_CLEAR: u8 = 0x20; // LSB
 
+// How far the UI is turned clockwise on the panel, for when the display is
+// mounted sideways or upside down.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Rotation {
+    Deg0,
+    Deg90,
+    Deg180,
+    Deg270,
+}
+
+impl Rotation {
+    pub fn from_degrees(degrees: u32) -> Option<Rotation> {
+        match degrees {
+            0 => Some(Rotation::Deg0),
+            90 => Some(Rotation::Deg90),
+            180 => Some(Rotation::Deg180),
+            270 => Some(Rotation::Deg270),
+            _ => None,
+        }
+    }
+
+    // The size that's drawn to, which is sideways for 90 and 270
+    fn size(&self) -> Size {
+        match self {
+            Rotation::Deg0 | Rotation::Deg180 => Size::new(WIDTH, HEIGHT),
+            Rotation::Deg90 | Rotation::Deg270 => Size::new(HEIGHT, WIDTH),
+        }
+    }
+
+    // Where a point that's drawn lands on the panel, if it's on screen at all
+    fn to_panel(&self, p: Point) -> Option<(u32, u32)> {
+        let Size { width, height } = self.size();
+        if p.x < 0 || p.y < 0 || p.x as u32 >= width || p.y as u32 >= height {
+            return None;
+        }
+        let (x, y) = (p.x as u32, p.y as u32);
+        Some(match self {
+            Rotation::Deg0 => (x, y),
+            Rotation::Deg90 => (WIDTH - 1 - y, x),
+            Rotation::Deg180 => (WIDTH - 1 - x, HEIGHT - 1 - y),
+            Rotation::Deg270 => (y, HEIGHT - 1 - x),
+        })
+    }
+}
+
 pub struct MemoryLcd {
     buffer: Arc<Mutex<Vec<u8>>>,
+    rotation: Rotation,
+    // Light on dark, which can be easier to read in bright sunlight
+    inverted: bool,
     running: Option<Arc<()>>,
     join_handle: Option<JoinHandle<()>>,
 }
 
 impl MemoryLcd {
-    pub fn new() -> Result<MemoryLcd, Error> {
+    pub fn new(intervals: SharedIntervals) -> Result<MemoryLcd, Error> {
         let mut spi = Spi::new(
             Bus::Spi0,
             // NOTE: THIS IS NOT USED!  It doesn't work!
@@ -67,6 +115,9 @@ impl MemoryLcd {
         let running = Some(running_for_thread.clone());
         let join_handle = Some(thread::spawn(move || {
             let mut vcom = false;
+            // What the panel is showing, so only lines that have changed since
+            // are sent (None until the first full update).
+            let mut last_sent: Option<Vec<u8>> = None;
             loop {
                 // The VCOM bit must be toggled at least every second (unless a
                 // the display is setup for and with a dedicated clock signal).
@@ -74,8 +125,14 @@ impl MemoryLcd {
                 vcom = !vcom;
                 {
                     let buffer = buffer_for_thread.lock().unwrap();
-                    // TODO: With a double buffer we could skip unchanged lines.
-                    update(&mut cs_pin, vcom, &mut spi, &buffer).unwrap();
+                    let lines = match &last_sent {
+                        Some(last) => changed_lines(last, &buffer),
+                        None => (0..HEIGHT as usize).collect(),
+                    };
+                    update(&mut cs_pin, vcom, &mut spi, &buffer, &lines).unwrap();
+                    if !lines.is_empty() {
+                        last_sent = Some(buffer.to_vec());
+                    }
                 }
 
                 // If the thread is the last owner of the Arc, then there are
@@ -84,18 +141,45 @@ impl MemoryLcd {
                     break;
                 }
 
-                thread::sleep(Duration::from_millis(100));
+                let lcd_refresh = intervals.lock().unwrap().lcd_refresh;
+                thread::sleep(lcd_refresh);
             }
         }));
 
         Ok(MemoryLcd {
             buffer,
+            rotation: Rotation::Deg0,
+            inverted: false,
             running,
             join_handle,
         })
     }
 }
 
+impl MemoryLcd {
+    pub fn set_rotation(&mut self, rotation: Rotation) {
+        self.rotation = rotation;
+    }
+
+    pub fn set_inverted(&mut self, inverted: bool) {
+        self.inverted = inverted;
+    }
+
+    pub fn is_inverted(&self) -> bool {
+        self.inverted
+    }
+}
+
+// Inverting here means everything drawn (text, backgrounds, indicators) swaps
+// together, without each drawable knowing about it.
+fn ink(color: BinaryColor, inverted: bool) -> BinaryColor {
+    match (color, inverted) {
+        (color, false) => color,
+        (BinaryColor::On, true) => BinaryColor::Off,
+        (BinaryColor::Off, true) => BinaryColor::On,
+    }
+}
+
 impl Drop for MemoryLcd {
     fn drop(&mut self) {
         // Drop the Arc immediately so the owner count is 1
@@ -119,19 +203,41 @@ pub fn set_pixel(p: (u32, u32), v: BinaryColor, buffer: &mut [u8]) {
     }
 }
 
-fn update(cs_pin: &mut OutputPin, vcom: bool, spi: &mut Spi, buffer: &[u8]) -> Result<(), Error> {
+// The lines (numbered from 0) whose bytes differ between two buffers
+fn changed_lines(last: &[u8], next: &[u8]) -> Vec<usize> {
+    let line_len = (WIDTH / 8) as usize;
+    last.chunks(line_len)
+        .zip(next.chunks(line_len))
+        .enumerate()
+        .filter(|(_, (a, b))| a != b)
+        .map(|(line, _)| line)
+        .collect()
+}
+
+// Writes only the given lines, though with none the VCOM bit is still sent
+// (and so toggled).
+fn update(
+    cs_pin: &mut OutputPin,
+    vcom: bool,
+    spi: &mut Spi,
+    buffer: &[u8],
+    lines: &[usize],
+) -> Result<(), Error> {
     // NOTE: we manually control the chip select pin (which is active high)
     cs_pin.set_high();
 
-    let mut b = [SHARPMEM_BIT_WRITE_LINES_CMD];
+    let mut b = [if lines.is_empty() {
+        SHARPMEM_BIT_CHANGE_VCOM_CMD
+    } else {
+        SHARPMEM_BIT_WRITE_LINES_CMD
+    }];
     if vcom {
         b[0] |= SHARPMEM_BIT_VCOM;
     }
     spi.write(&b)?;
 
-    let mut slice_from: usize = 0;
     let line_len = (WIDTH / 8) as usize;
-    for line in 0..HEIGHT {
+    for &line in lines {
         b[0] = line as u8 + 1;
         // The display is LSB, and the Pi only supports MSB, so we reverse the
         // bits here.
@@ -139,12 +245,13 @@ fn update(cs_pin: &mut OutputPin, vcom: bool, spi: &mut Spi, buffer: &[u8]) -> R
         spi.write(&b)?;
 
         // We expect the buffer is already in LSB format
+        let slice_from = line * line_len;
         spi.write(&buffer[slice_from..slice_from + line_len])?;
-        slice_from += line_len;
 
         b[0] = 0;
         spi.write(&b)?;
     }
+    b[0] = 0;
     spi.write(&b)?; // we send one last 0 byte
 
     cs_pin.set_low();
@@ -155,13 +262,106 @@ impl DrawTarget<BinaryColor> for MemoryLcd {
     type Error = core::convert::Infallible;
 
     fn draw_pixel(&mut self, pixel: Pixel<BinaryColor>) -> Result<(), Self::Error> {
-        let Pixel(Point { x, y }, color) = pixel;
-        let mut buffer = self.buffer.lock().unwrap();
-        set_pixel((x as u32, y as u32), color, &mut buffer);
+        let Pixel(p, color) = pixel;
+        if let Some(p) = self.rotation.to_panel(p) {
+            let mut buffer = self.buffer.lock().unwrap();
+            set_pixel(p, ink(color, self.inverted), &mut buffer);
+        }
         Ok(())
     }
 
     fn size(&self) -> Size {
-        Size::new(WIDTH, HEIGHT)
+        self.rotation.size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changed_lines, ink, set_pixel, Rotation, HEIGHT, WIDTH};
+    use embedded_graphics::geometry::{Point, Size};
+    use embedded_graphics::pixelcolor::BinaryColor;
+
+    #[test]
+    fn ink_is_unchanged_unless_inverted() {
+        assert_eq!(BinaryColor::On, ink(BinaryColor::On, false));
+        assert_eq!(BinaryColor::Off, ink(BinaryColor::Off, false));
+    }
+
+    #[test]
+    fn ink_swaps_when_inverted() {
+        assert_eq!(BinaryColor::Off, ink(BinaryColor::On, true));
+        assert_eq!(BinaryColor::On, ink(BinaryColor::Off, true));
+    }
+
+    #[test]
+    fn rotation_0_is_unchanged() {
+        let r = Rotation::Deg0;
+        assert_eq!(Size::new(WIDTH, HEIGHT), r.size());
+        assert_eq!(Some((0, 0)), r.to_panel(Point::new(0, 0)));
+        assert_eq!(Some((10, 20)), r.to_panel(Point::new(10, 20)));
+        assert_eq!(None, r.to_panel(Point::new(WIDTH as i32, 0)));
+    }
+
+    #[test]
+    fn rotation_90_turns_clockwise() {
+        let r = Rotation::Deg90;
+        assert_eq!(Size::new(HEIGHT, WIDTH), r.size());
+        assert_eq!(Some((WIDTH - 1, 0)), r.to_panel(Point::new(0, 0)));
+        assert_eq!(Some((WIDTH - 1 - 20, 10)), r.to_panel(Point::new(10, 20)));
+        assert_eq!(
+            Some((0, HEIGHT - 1)),
+            r.to_panel(Point::new(HEIGHT as i32 - 1, WIDTH as i32 - 1))
+        );
+        assert_eq!(None, r.to_panel(Point::new(0, WIDTH as i32)));
+    }
+
+    #[test]
+    fn rotation_180_flips_both_axes() {
+        let r = Rotation::Deg180;
+        assert_eq!(Size::new(WIDTH, HEIGHT), r.size());
+        assert_eq!(Some((WIDTH - 1, HEIGHT - 1)), r.to_panel(Point::new(0, 0)));
+        assert_eq!(Some((WIDTH - 11, HEIGHT - 21)), r.to_panel(Point::new(10, 20)));
+    }
+
+    #[test]
+    fn rotation_270_turns_counterclockwise() {
+        let r = Rotation::Deg270;
+        assert_eq!(Size::new(HEIGHT, WIDTH), r.size());
+        assert_eq!(Some((0, HEIGHT - 1)), r.to_panel(Point::new(0, 0)));
+        assert_eq!(Some((20, HEIGHT - 11)), r.to_panel(Point::new(10, 20)));
+        assert_eq!(None, r.to_panel(Point::new(HEIGHT as i32, 0)));
+    }
+
+    #[test]
+    fn offscreen_points_are_dropped() {
+        for r in [Rotation::Deg0, Rotation::Deg90, Rotation::Deg180, Rotation::Deg270] {
+            assert_eq!(None, r.to_panel(Point::new(-1, 0)));
+            assert_eq!(None, r.to_panel(Point::new(0, -1)));
+        }
+    }
+
+    fn blank() -> Vec<u8> {
+        vec![0b11111111; HEIGHT as usize * WIDTH as usize / 8]
+    }
+
+    #[test]
+    fn identical_buffers_have_no_changed_lines() {
+        assert_eq!(changed_lines(&blank(), &blank()), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn only_lines_with_changed_pixels_are_listed() {
+        let mut next = blank();
+        set_pixel((0, 0), BinaryColor::On, &mut next);
+        set_pixel((WIDTH - 1, 42), BinaryColor::On, &mut next);
+        set_pixel((70, 42), BinaryColor::On, &mut next);
+        set_pixel((5, HEIGHT - 1), BinaryColor::On, &mut next);
+        assert_eq!(changed_lines(&blank(), &next), vec![0, 42, HEIGHT as usize - 1]);
+    }
+
+    #[test]
+    fn rotation_from_degrees() {
+        assert_eq!(Some(Rotation::Deg270), Rotation::from_degrees(270));
+        assert_eq!(None, Rotation::from_degrees(45));
     }
 }
@@ -18,6 +18,7 @@ unsafe impl Send for SendWindow {}
 pub struct MemoryLcd {
     sim: SimulatorDisplay<BinaryColor>,
     window: SendWindow,
+    inverted: bool,
     tx: std::sync::mpsc::Sender<(crate::buttons::Button, bool)>
 }
 
@@ -35,7 +36,15 @@ impl MemoryLcd {
                 .build(),
         ));
 
-        Ok(MemoryLcd { sim, window, tx })
+        Ok(MemoryLcd { sim, window, inverted: false, tx })
+    }
+
+    pub fn set_inverted(&mut self, inverted: bool) {
+        self.inverted = inverted;
+    }
+
+    pub fn is_inverted(&self) -> bool {
+        self.inverted
     }
 
     pub fn update(&mut self) {
@@ -68,7 +77,13 @@ impl DrawTarget<BinaryColor> for MemoryLcd {
     type Error = core::convert::Infallible;
 
     fn draw_pixel(&mut self, pixel: Pixel<BinaryColor>) -> Result<(), Self::Error> {
-        self.sim.draw_pixel(pixel)
+        let Pixel(p, color) = pixel;
+        let color = match (color, self.inverted) {
+            (color, false) => color,
+            (BinaryColor::On, true) => BinaryColor::Off,
+            (BinaryColor::Off, true) => BinaryColor::On,
+        };
+        self.sim.draw_pixel(Pixel(p, color))
     }
 
     fn size(&self) -> Size {
@@ -0,0 +1,60 @@
+use std::fs;
+use std::path::Path;
+
+// Network interfaces (other than loopback) that are currently up, which tells
+// us whether the telemetry server can be reached.
+pub fn up_interfaces() -> Vec<String> {
+    up_interfaces_in(Path::new("/sys/class/net"))
+}
+
+fn up_interfaces_in(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            let operstate = fs::read_to_string(entry.path().join("operstate")).ok()?;
+            if name != "lo" && operstate.trim() == "up" {
+                Some(name)
+            } else {
+                None
+            }
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::up_interfaces_in;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn fake_sys_class_net(name: &str, interfaces: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust-cycle-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (interface, operstate) in interfaces {
+            fs::create_dir_all(dir.join(interface)).unwrap();
+            fs::write(dir.join(interface).join("operstate"), operstate).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn only_up_interfaces_are_listed() {
+        let dir = fake_sys_class_net(
+            "up",
+            &[("wlan0", "up\n"), ("eth0", "down\n"), ("lo", "unknown\n"), ("ap0", "up\n")],
+        );
+        assert_eq!(up_interfaces_in(&dir), vec!["ap0", "wlan0"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_directory_has_no_interfaces() {
+        let dir = fake_sys_class_net("missing", &[]);
+        assert_eq!(up_interfaces_in(&dir), Vec::<String>::new());
+    }
+}
@@ -1,5 +1,448 @@
 pub mod assioma;
+pub mod battery;
 pub mod cadence;
+pub mod ftms;
 pub mod hrm;
 pub mod kickr;
+#[cfg(test)]
+pub mod mock;
+pub mod rsc;
 pub mod speed;
+
+use crate::ble::csc_feature::{parse_csc_feature, CscFeature, FEATURE_UUID};
+use crate::ble::device_information::{self, DeviceInformation};
+use crate::utils::lock_or_recover;
+use btleplug::api::{bleuuid::uuid_from_u16, Characteristic, Peripheral};
+use btleplug::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+pub const BATTERY_LEVEL_UUID: Uuid = uuid_from_u16(0x2A19);
+
+// Our known peripherals, so we can tell which one something came from.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Device {
+    Assioma,
+    Cadence,
+    Hrm,
+    Kickr,
+    Speed,
+    Rsc,
+}
+
+impl Device {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Device::Assioma => "Assioma",
+            Device::Cadence => "Cadence",
+            Device::Hrm => "HRM",
+            Device::Kickr => "KICKR",
+            Device::Speed => "Speed",
+            Device::Rsc => "RSC",
+        }
+    }
+}
+
+// What a speed and cadence (CSC) sensor is used for.  Combined sensors report
+// both wheel and crank data in every notification, so they can fill either
+// role or both.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum CscRole {
+    Speed,
+    Cadence,
+    Both,
+}
+
+impl CscRole {
+    pub fn has_speed(&self) -> bool {
+        *self != CscRole::Cadence
+    }
+
+    pub fn has_cadence(&self) -> bool {
+        *self != CscRole::Speed
+    }
+}
+
+// How the trainer is controlled: Wahoo's own protocol, or the standard Fitness
+// Machine Service that most other smart trainers use.  Either way, it's
+// connected (and recorded) as the KICKR.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrainerProtocol {
+    Kickr,
+    Ftms,
+}
+
+impl TrainerProtocol {
+    pub fn parse(s: &str) -> Option<TrainerProtocol> {
+        match s {
+            "kickr" => Some(TrainerProtocol::Kickr),
+            "ftms" => Some(TrainerProtocol::Ftms),
+            _ => None,
+        }
+    }
+
+    pub async fn set_power(
+        &self,
+        peripheral: &impl Peripheral,
+        target_power_mutex: &Arc<tokio::sync::Mutex<Option<u16>>>,
+        power: u16,
+    ) -> Result<()> {
+        match self {
+            TrainerProtocol::Kickr => kickr::set_power(peripheral, target_power_mutex, power).await,
+            TrainerProtocol::Ftms => ftms::set_power(peripheral, target_power_mutex, power).await,
+        }
+    }
+
+    pub async fn release(
+        &self,
+        peripheral: &impl Peripheral,
+        target_power_mutex: &Arc<tokio::sync::Mutex<Option<u16>>>,
+    ) -> Result<()> {
+        match self {
+            TrainerProtocol::Kickr => kickr::release(peripheral, target_power_mutex).await,
+            TrainerProtocol::Ftms => ftms::release(peripheral, target_power_mutex).await,
+        }
+    }
+}
+
+// Case insensitive substrings of the advertised name that identify each kind
+// of device, so sensors that name themselves differently can still be found.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NamePatterns {
+    pub assioma: &'static [&'static str],
+    pub cadence: &'static [&'static str],
+    pub hrm: &'static [&'static str],
+    pub kickr: &'static [&'static str],
+    // Footpods are also found by their service, so no names are required
+    pub rsc: &'static [&'static str],
+    pub speed: &'static [&'static str],
+}
+
+pub const DEFAULT_NAME_PATTERNS: NamePatterns = NamePatterns {
+    assioma: &["ASSIOMA"],
+    cadence: &["CADENCE"],
+    hrm: &["Polar"],
+    kickr: &["KICKR"],
+    rsc: &[],
+    speed: &["SPEED"],
+};
+
+pub fn name_matches(name: &str, patterns: &[&str]) -> bool {
+    let name = name.to_lowercase();
+    patterns.iter().any(|p| name.contains(&p.to_lowercase()))
+}
+
+// Why we couldn't get a usable connection to a peripheral, so the rider can
+// be told something more useful than "connect error."
+#[derive(Debug)]
+pub enum ConnectError {
+    // Nothing matching the device was found in the scan
+    NotFound,
+    // The device was found and connected, but doesn't have a characteristic
+    // we rely on
+    MissingCharacteristic(Uuid),
+    // Anything that went wrong talking to the device
+    Ble(btleplug::Error),
+}
+
+impl From<btleplug::Error> for ConnectError {
+    fn from(e: btleplug::Error) -> ConnectError {
+        ConnectError::Ble(e)
+    }
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConnectError::NotFound => write!(f, "not found"),
+            ConnectError::MissingCharacteristic(uuid) => {
+                write!(f, "missing characteristic {}", uuid)
+            }
+            ConnectError::Ble(e) => write!(f, "failed to connect ({})", e),
+        }
+    }
+}
+
+// Finds a characteristic we can't work without among a peripheral's
+// (discovered) characteristics, so that a firmware without it is a connect
+// error rather than a panic later.
+pub fn required_characteristic(
+    characteristics: &BTreeSet<Characteristic>,
+    uuid: Uuid,
+) -> std::result::Result<Characteristic, ConnectError> {
+    characteristics
+        .iter()
+        .find(|c| c.uuid == uuid)
+        .cloned()
+        .ok_or(ConnectError::MissingCharacteristic(uuid))
+}
+
+// Whether every one of the uuids is among the characteristics
+fn has_characteristics(characteristics: &BTreeSet<Characteristic>, uuids: &[Uuid]) -> bool {
+    uuids.iter().all(|uuid| characteristics.iter().any(|c| c.uuid == *uuid))
+}
+
+// Service discovery is slow, but the host often still knows a device's
+// characteristics from the last time it was connected.  If everything we need
+// is already there, discovery is skipped, otherwise it's done in full.
+// Optional characteristics (like battery level) may be missing on this path.
+pub async fn discover_services_unless_known(
+    peripheral: &impl Peripheral,
+    required: &[Uuid],
+) -> Result<()> {
+    if has_characteristics(&peripheral.characteristics(), required) {
+        println!("Using known characteristics");
+    } else {
+        peripheral.discover_services().await?;
+        println!("All characteristics discovered");
+    }
+    Ok(())
+}
+
+// Reads the standard Battery Level characteristic (as a percent), if the
+// peripheral has one.  Services must already be discovered.
+pub async fn read_battery_level(peripheral: &impl Peripheral) -> Result<Option<u8>> {
+    let o_battery_level = peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.uuid == BATTERY_LEVEL_UUID);
+
+    match o_battery_level {
+        None => Ok(None),
+        Some(battery_level) => {
+            Ok(battery::parse_battery_level(&peripheral.read(&battery_level).await?))
+        }
+    }
+}
+
+// Reads whatever the peripheral has of the Device Information service.
+// Services must already be discovered.
+pub async fn read_device_information(peripheral: &impl Peripheral) -> Result<DeviceInformation> {
+    Ok(DeviceInformation {
+        manufacturer_name: read_string(peripheral, device_information::MANUFACTURER_NAME_UUID)
+            .await?,
+        model_number: read_string(peripheral, device_information::MODEL_NUMBER_UUID).await?,
+        serial_number: read_string(peripheral, device_information::SERIAL_NUMBER_UUID).await?,
+        firmware_revision: read_string(peripheral, device_information::FIRMWARE_REVISION_UUID)
+            .await?,
+    })
+}
+
+async fn read_string(peripheral: &impl Peripheral, uuid: Uuid) -> Result<Option<String>> {
+    let o_characteristic = peripheral.characteristics().into_iter().find(|c| c.uuid == uuid);
+
+    match o_characteristic {
+        None => Ok(None),
+        Some(c) => Ok(device_information::parse_string(&peripheral.read(&c).await?)),
+    }
+}
+
+// Reads what a CSC sensor supports (wheel, crank, or both), if the peripheral
+// exposes the CSC Feature characteristic.  Services must already be
+// discovered.  Without it, we just trust each measurement's flags, so a
+// failed read isn't worth failing the connect over.
+pub async fn read_csc_feature(peripheral: &impl Peripheral) -> Option<CscFeature> {
+    let feature = peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.uuid == FEATURE_UUID)?;
+
+    match peripheral.read(&feature).await {
+        Ok(data) if data.len() >= 2 => Some(parse_csc_feature(&data)),
+        Ok(_) => None,
+        Err(e) => {
+            println!("Could not read CSC features: {:?}", e);
+            None
+        }
+    }
+}
+
+// How hard we try to get a device back once it disconnects.  The wait
+// doubles after each failed attempt (up to max_wait), and we give up after
+// max_attempts (if set), since a device that's truly gone (like a dead
+// battery) isn't worth the power or the log noise.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_wait: Duration,
+    pub max_wait: Duration,
+    pub max_attempts: Option<u32>,
+}
+
+pub const DEFAULT_RECONNECT_POLICY: ReconnectPolicy = ReconnectPolicy {
+    initial_wait: Duration::from_secs(10),
+    max_wait: Duration::from_secs(60),
+    max_attempts: Some(20),
+};
+
+impl ReconnectPolicy {
+    // How long to wait before the nth (zero indexed) attempt, or None if we
+    // should give up instead.
+    pub fn wait_before(&self, attempt: u32) -> Option<Duration> {
+        if self.max_attempts.map_or(false, |max| attempt >= max) {
+            None
+        } else {
+            let wait = 2u32
+                .checked_pow(attempt)
+                .and_then(|factor| self.initial_wait.checked_mul(factor))
+                .unwrap_or(self.max_wait);
+            Some(Duration::min(wait, self.max_wait))
+        }
+    }
+}
+
+// Devices we've given up reconnecting to, so they can be shown as lost
+pub type LostDevices = Arc<Mutex<BTreeSet<Device>>>;
+
+// Tries to reconnect a disconnected device per the policy, returning whether
+// or not it came back.  The required characteristics are only rediscovered
+// if the host has forgotten them, the same as when we first connect.
+pub async fn reconnect<P: Peripheral>(
+    peripheral: &P,
+    device: Device,
+    required: &[Uuid],
+    policy: ReconnectPolicy,
+    lost: &LostDevices,
+) -> bool {
+    let mut attempt = 0;
+    while let Some(wait) = policy.wait_before(attempt) {
+        tokio::time::sleep(wait).await;
+        println!("Attempting {} reconnect.", device.name());
+        if peripheral.connect().await.is_ok()
+            && discover_services_unless_known(peripheral, required).await.is_ok()
+        {
+            println!("{} reconnected.", device.name());
+            lock_or_recover(lost).remove(&device);
+            return true;
+        }
+        println!("{} reconnect failed.", device.name());
+        attempt += 1;
+    }
+    println!("Gave up on {} reconnect.", device.name());
+    lock_or_recover(lost).insert(device);
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::has_characteristics;
+    use super::CscRole;
+    use super::name_matches;
+    use super::required_characteristic;
+    use super::ConnectError;
+    use super::ReconnectPolicy;
+    use super::TrainerProtocol;
+    use super::BATTERY_LEVEL_UUID;
+    use btleplug::api::{bleuuid::uuid_from_u16, CharPropFlags, Characteristic};
+    use std::collections::BTreeSet;
+    use std::time::Duration;
+
+    // What discover_services would find on a device with only these
+    fn characteristics(uuids: &[uuid::Uuid]) -> BTreeSet<Characteristic> {
+        uuids
+            .iter()
+            .map(|uuid| Characteristic {
+                uuid: *uuid,
+                service_uuid: uuid_from_u16(0x180F),
+                properties: CharPropFlags::READ,
+                descriptors: BTreeSet::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn csc_roles() {
+        assert!(CscRole::Speed.has_speed() && !CscRole::Speed.has_cadence());
+        assert!(!CscRole::Cadence.has_speed() && CscRole::Cadence.has_cadence());
+        assert!(CscRole::Both.has_speed() && CscRole::Both.has_cadence());
+    }
+
+    #[test]
+    fn trainer_protocols() {
+        assert_eq!(Some(TrainerProtocol::Kickr), TrainerProtocol::parse("kickr"));
+        assert_eq!(Some(TrainerProtocol::Ftms), TrainerProtocol::parse("ftms"));
+        assert_eq!(None, TrainerProtocol::parse("FTMS"));
+    }
+
+    #[test]
+    fn has_characteristics_needs_all_of_them() {
+        let hr = uuid_from_u16(0x2A37);
+        let known = characteristics(&[BATTERY_LEVEL_UUID, hr]);
+        assert!(has_characteristics(&known, &[hr]));
+        assert!(has_characteristics(&known, &[hr, BATTERY_LEVEL_UUID]));
+        assert!(!has_characteristics(&known, &[hr, uuid_from_u16(0x2A63)]));
+    }
+
+    #[test]
+    fn has_characteristics_with_none_known() {
+        let hr = uuid_from_u16(0x2A37);
+        assert!(!has_characteristics(&BTreeSet::new(), &[hr]));
+        assert!(has_characteristics(&BTreeSet::new(), &[]));
+    }
+
+    #[test]
+    fn required_characteristic_is_found() {
+        let found =
+            required_characteristic(&characteristics(&[BATTERY_LEVEL_UUID]), BATTERY_LEVEL_UUID);
+        assert_eq!(BATTERY_LEVEL_UUID, found.unwrap().uuid);
+    }
+
+    #[test]
+    fn missing_required_characteristic_is_a_connect_error() {
+        let missing = uuid_from_u16(0x2A63);
+        let result = required_characteristic(&characteristics(&[BATTERY_LEVEL_UUID]), missing);
+        assert!(matches!(
+            result,
+            Err(ConnectError::MissingCharacteristic(uuid)) if uuid == missing
+        ));
+    }
+
+    const POLICY: ReconnectPolicy = ReconnectPolicy {
+        initial_wait: Duration::from_secs(10),
+        max_wait: Duration::from_secs(60),
+        max_attempts: Some(5),
+    };
+
+    #[test]
+    fn name_matches_ignores_case() {
+        assert!(name_matches("Polar H10 1234", &["polar"]));
+        assert!(name_matches("Wahoo KICKR 1234", &["Kickr"]));
+    }
+
+    #[test]
+    fn name_matches_any_pattern() {
+        assert!(name_matches("XOSS CAD", &["CADENCE", "XOSS"]));
+        assert!(!name_matches("XOSS CAD", &["CADENCE"]));
+        assert!(!name_matches("XOSS CAD", &[]));
+    }
+
+    #[test]
+    fn reconnect_wait_doubles_up_to_the_max() {
+        assert_eq!(
+            (0..5).map(|n| POLICY.wait_before(n)).collect::<Vec<_>>(),
+            vec![
+                Some(Duration::from_secs(10)),
+                Some(Duration::from_secs(20)),
+                Some(Duration::from_secs(40)),
+                Some(Duration::from_secs(60)),
+                Some(Duration::from_secs(60)),
+            ]
+        );
+    }
+
+    #[test]
+    fn reconnect_gives_up_after_max_attempts() {
+        assert_eq!(POLICY.wait_before(5), None);
+    }
+
+    #[test]
+    fn reconnect_without_max_attempts_never_gives_up() {
+        let policy = ReconnectPolicy {
+            max_attempts: None,
+            ..POLICY
+        };
+        assert_eq!(policy.wait_before(1000), Some(Duration::from_secs(60)));
+    }
+}
@@ -1,12 +1,20 @@
+use crate::peripherals::{
+    discover_services_unless_known, name_matches, reconnect, ConnectError, Device, LostDevices,
+    ReconnectPolicy,
+};
 use btleplug::api::{Central, CentralEvent, Peripheral, bleuuid::uuid_from_u16};
 use uuid::Uuid;
 use btleplug::Result;
-use std::time::Duration;
 use futures::stream::StreamExt;
 
 pub const MEASURE_UUID: Uuid = uuid_from_u16(0x2A63);
 
-pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central: &C) -> Result<Option<P>> {
+pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(
+    central: &C,
+    names: &'static [&'static str],
+    policy: ReconnectPolicy,
+    lost: LostDevices,
+) -> std::result::Result<P, ConnectError> {
     // TODO: It may make sense to use Type States to separate out new (Optional)
     // and connect (Result).  For this app, we really only care about
     // permanently connecting (but it would be nice to clean up connections on
@@ -17,7 +25,7 @@ pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central:
     let mut o_peripheral: Option<P> = None;
     for peripheral in peripherals {
         println!("Checking if device is assioma");
-        let found_it = is_assioma(&peripheral).await?;
+        let found_it = is_assioma(&peripheral, names).await?;
         if found_it {
           o_peripheral = Some(peripheral);
           break;
@@ -25,13 +33,12 @@ pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central:
     }
 
     match o_peripheral {
-        None => Ok(None),
+        None => Err(ConnectError::NotFound),
         Some(peripheral) => {
             peripheral.connect().await?;
             println!("Connected to Assioma");
 
-            peripheral.discover_services().await?;
-            println!("All characteristics discovered");
+            discover_services_unless_known(&peripheral, &[MEASURE_UUID]).await?;
 
             let o_power_measurement = peripheral
                 .characteristics()
@@ -41,7 +48,7 @@ pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central:
             match o_power_measurement {
                 None => {
                     peripheral.disconnect().await?;
-                    Ok(None)
+                    Err(ConnectError::MissingCharacteristic(MEASURE_UUID))
                 },
                 Some(power_measurement) => {
                     peripheral.subscribe(&power_measurement).await?;
@@ -54,23 +61,15 @@ pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central:
                             if let CentralEvent::DeviceDisconnected(addr) = evt {
                                 println!("Assioma Disconnected.");
                                 let p = central_for_disconnects.peripheral(&addr).await.unwrap();
-                                if is_assioma(&p).await.unwrap() {
-                                    let wait = Duration::from_secs(10);
-                                    loop {
-                                        tokio::time::sleep(wait).await;
-                                        println!("Attempting Assioma reconnect.");
-                                        if p.connect().await.is_ok() {
-                                            println!("Assioma reconnected.");
-                                            break;
-                                        }
-                                        println!("Assioma reconnect failed.");
-                                    }
+                                if is_assioma(&p, names).await.unwrap() {
+                                    reconnect(&p, Device::Assioma, &[MEASURE_UUID], policy, &lost)
+                                        .await;
                                 }
                             }
                         };
                     });
 
-                    Ok(Some(peripheral))
+                    Ok(peripheral)
                 }
             }
 
@@ -78,14 +77,14 @@ pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central:
     }
 }
 
-async fn is_assioma(p: &impl Peripheral) -> Result<bool> {
+async fn is_assioma(p: &impl Peripheral, names: &[&str]) -> Result<bool> {
     let op = p.properties().await?;
     Ok(match op {
       Some(properties) =>
         properties
             .local_name
             .iter()
-            .any(|name| name.contains("ASSIOMA")),
+            .any(|name| name_matches(name, names)),
       None => false
     })
 }
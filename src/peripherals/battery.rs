@@ -0,0 +1,112 @@
+// The standard Battery Service, which most sensors have alongside their
+// measurements.  The level is read once on connect, and then followed through
+// notifications (on sensors that send them), so the rider can be warned
+// before one dies mid-ride.
+use crate::peripherals::{Device, BATTERY_LEVEL_UUID};
+use btleplug::api::{CharPropFlags, Peripheral};
+use btleplug::Result;
+use futures::stream::{Stream, StreamExt};
+use std::collections::BTreeMap;
+use std::pin::Pin;
+
+// At or below this percent, the rider is warned
+pub const LOW_LEVEL: u8 = 15;
+
+// A single byte percent, where anything over 100 is invalid
+pub fn parse_battery_level(data: &[u8]) -> Option<u8> {
+    data.first().copied().filter(|x| *x <= 100)
+}
+
+// Follows the battery level as a percent, if the peripheral has the
+// characteristic and can notify on it.  Services must already be discovered.
+// Once subscribed, the peripheral's other notifications include battery
+// levels too, so their handlers need to check the uuid.
+pub async fn subscribe<P: Peripheral>(
+    peripheral: &P,
+) -> Result<Option<Pin<Box<dyn Stream<Item = u8> + Send>>>> {
+    let o_battery_level = peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.uuid == BATTERY_LEVEL_UUID && c.properties.contains(CharPropFlags::NOTIFY));
+
+    match o_battery_level {
+        None => Ok(None),
+        Some(battery_level) => {
+            peripheral.subscribe(&battery_level).await?;
+            let notifications = peripheral.notifications().await?;
+            Ok(Some(Box::pin(notifications.filter_map(|n| async move {
+                if n.uuid == BATTERY_LEVEL_UUID {
+                    parse_battery_level(&n.value)
+                } else {
+                    None
+                }
+            }))))
+        }
+    }
+}
+
+// The last known level of each device, since only the lowest is shown
+#[derive(Debug, Default)]
+pub struct BatteryLevels {
+    levels: BTreeMap<Device, u8>,
+}
+
+impl BatteryLevels {
+    pub fn new() -> BatteryLevels {
+        BatteryLevels::default()
+    }
+
+    // Returns whether the device has just gone low, so the rider is only
+    // warned once (unless it's recharged in between)
+    pub fn update(&mut self, device: Device, level: u8) -> bool {
+        let was_low = self.levels.get(&device).map_or(false, |x| *x <= LOW_LEVEL);
+        self.levels.insert(device, level);
+        level <= LOW_LEVEL && !was_low
+    }
+
+    pub fn lowest(&self) -> Option<u8> {
+        self.levels.values().min().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_battery_level;
+    use super::BatteryLevels;
+    use crate::peripherals::Device;
+
+    #[test]
+    fn parse_battery_level_is_a_single_byte_percent() {
+        assert_eq!(Some(0), parse_battery_level(&[0]));
+        assert_eq!(Some(85), parse_battery_level(&[85]));
+        assert_eq!(Some(100), parse_battery_level(&[100]));
+    }
+
+    #[test]
+    fn parse_battery_level_rejects_invalid_levels() {
+        assert_eq!(None, parse_battery_level(&[101]));
+        assert_eq!(None, parse_battery_level(&[255]));
+        assert_eq!(None, parse_battery_level(&[]));
+    }
+
+    #[test]
+    fn battery_levels_show_the_lowest() {
+        let mut levels = BatteryLevels::new();
+        assert_eq!(None, levels.lowest());
+        levels.update(Device::Hrm, 80);
+        levels.update(Device::Assioma, 40);
+        assert_eq!(Some(40), levels.lowest());
+        levels.update(Device::Assioma, 90);
+        assert_eq!(Some(80), levels.lowest());
+    }
+
+    #[test]
+    fn battery_levels_warn_once_on_going_low() {
+        let mut levels = BatteryLevels::new();
+        assert!(!levels.update(Device::Hrm, 20));
+        assert!(levels.update(Device::Hrm, 15));
+        assert!(!levels.update(Device::Hrm, 10));
+        assert!(!levels.update(Device::Hrm, 100));
+        assert!(levels.update(Device::Hrm, 5));
+    }
+}
@@ -0,0 +1,201 @@
+// The standard Fitness Machine Service (FTMS), which most smart trainers that
+// aren't Wahoo's (like Tacx and Elite) are controlled through.  It fills the
+// same role as the KICKR (and is tracked as it), so its set_power and release
+// match the kickr module's.
+use crate::peripherals::{
+    discover_services_unless_known, name_matches, reconnect, required_characteristic,
+    ConnectError, Device, LostDevices, ReconnectPolicy,
+};
+use btleplug::api::{bleuuid::uuid_from_u16, Central, CentralEvent, Peripheral, WriteType};
+use btleplug::Result;
+use futures::stream::StreamExt;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+pub const SERVICE_UUID: Uuid = uuid_from_u16(0x1826);
+
+pub const INDOOR_BIKE_DATA_UUID: Uuid = uuid_from_u16(0x2AD2);
+
+pub const CONTROL_POINT_UUID: Uuid = uuid_from_u16(0x2AD9);
+
+// Everything we can't control the trainer without
+const REQUIRED_UUIDS: [Uuid; 2] = [INDOOR_BIKE_DATA_UUID, CONTROL_POINT_UUID];
+
+// Control point op codes
+const REQUEST_CONTROL: u8 = 0x00;
+const SET_TARGET_RESISTANCE_LEVEL: u8 = 0x04;
+const SET_TARGET_POWER: u8 = 0x05;
+
+// The trainer ignores any other command until we've asked for control
+pub fn request_control() -> Vec<u8> {
+    vec![REQUEST_CONTROL]
+}
+
+// In watts, which the trainer holds no matter the cadence (ERG mode)
+pub fn set_target_power(power: u16) -> Vec<u8> {
+    // The parameter is signed, but a negative target means nothing to us
+    let [lo, hi] = i16::try_from(power).unwrap_or(i16::MAX).to_le_bytes();
+    vec![SET_TARGET_POWER, lo, hi]
+}
+
+// In tenths of a (unitless) percent, where 0 is as easy as the trainer goes
+pub fn set_target_resistance_level(level: u8) -> Vec<u8> {
+    vec![SET_TARGET_RESISTANCE_LEVEL, level]
+}
+
+pub async fn connect<P: Peripheral, C: Central<Peripheral = P> + 'static>(
+    central: &C,
+    names: &'static [&'static str],
+    policy: ReconnectPolicy,
+    lost: LostDevices,
+) -> std::result::Result<(P, Arc<Mutex<Option<u16>>>), ConnectError> {
+    println!("Getting peripherals");
+    let peripherals: Vec<P> = central.peripherals().await?;
+    println!("Got peripherals list");
+    let mut o_peripheral: Option<P> = None;
+    for peripheral in peripherals {
+        println!("Checking if device is an FTMS trainer");
+        if is_trainer(&peripheral, names).await? {
+            o_peripheral = Some(peripheral);
+            break;
+        }
+    }
+
+    match o_peripheral {
+        None => Err(ConnectError::NotFound),
+        Some(peripheral) => {
+            println!("Found FTMS trainer");
+
+            peripheral.connect().await?;
+            println!("Connected to FTMS trainer");
+
+            discover_services_unless_known(&peripheral, &REQUIRED_UUIDS).await?;
+
+            first_time_setup(&peripheral).await?;
+
+            let target_power = Arc::new(Mutex::new(None));
+
+            let central_for_disconnects = central.clone();
+            let tp_for_disconnects = target_power.clone();
+
+            let mut events = central.events().await?;
+            tokio::spawn(async move {
+                while let Some(evt) = events.next().await {
+                    if let CentralEvent::DeviceDisconnected(addr) = evt {
+                        let p = central_for_disconnects.peripheral(&addr).await.unwrap();
+                        if is_trainer(&p, names).await.unwrap() {
+                            if reconnect(&p, Device::Kickr, &REQUIRED_UUIDS, policy, &lost).await {
+                                // Control is lost with the connection
+                                // TODO: Not sure what we could possibly do if these fail
+                                write_control_point(&p, &request_control()).await.unwrap();
+
+                                let guard = tp_for_disconnects.lock().await;
+                                if let Some(power) = *guard {
+                                    write_control_point(&p, &set_target_power(power))
+                                        .await
+                                        .unwrap();
+                                }
+                            }
+                        }
+                    }
+                }
+            });
+
+            Ok((peripheral, target_power))
+        }
+    }
+}
+
+// Trainers are also found by the service they advertise, since their names
+// vary by brand
+async fn is_trainer(p: &impl Peripheral, names: &[&str]) -> Result<bool> {
+    let op = p.properties().await?;
+    Ok(match op {
+        Some(properties) => {
+            properties.services.contains(&SERVICE_UUID)
+                || properties.local_name.iter().any(|name| name_matches(name, names))
+        }
+        None => false,
+    })
+}
+
+async fn first_time_setup(trainer: &impl Peripheral) -> std::result::Result<(), ConnectError> {
+    let characteristics = trainer.characteristics();
+
+    let indoor_bike_data = required_characteristic(&characteristics, INDOOR_BIKE_DATA_UUID)?;
+    trainer.subscribe(&indoor_bike_data).await?;
+    println!("Subscribed to indoor bike data");
+
+    // The trainer answers each command by indication, and won't accept
+    // commands from a client that isn't listening for them.
+    let control_point = required_characteristic(&characteristics, CONTROL_POINT_UUID)?;
+    trainer.subscribe(&control_point).await?;
+    println!("Subscribed to control point");
+
+    trainer.write(&control_point, &request_control(), WriteType::WithResponse).await?;
+    println!("FTMS control requested");
+    Ok(())
+}
+
+pub async fn set_power(
+    peripheral: &impl Peripheral,
+    target_power_mutex: &Arc<Mutex<Option<u16>>>,
+    power: u16,
+) -> Result<()> {
+    let mut tp_guard = target_power_mutex.lock().await;
+    *tp_guard = Some(power);
+
+    write_control_point(peripheral, &set_target_power(power)).await
+}
+
+// Takes the trainer out of ERG mode so the rider is free to ride by feel.  We
+// also forget the target, so that a reconnect doesn't put us back into ERG.
+pub async fn release(
+    peripheral: &impl Peripheral,
+    target_power_mutex: &Arc<Mutex<Option<u16>>>,
+) -> Result<()> {
+    let mut tp_guard = target_power_mutex.lock().await;
+    *tp_guard = None;
+
+    write_control_point(peripheral, &set_target_resistance_level(0)).await
+}
+
+async fn write_control_point(peripheral: &impl Peripheral, command: &[u8]) -> Result<()> {
+    let control_point = peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.uuid == CONTROL_POINT_UUID)
+        // Checked on connect, so this shouldn't happen
+        .ok_or(btleplug::Error::NoSuchCharacteristic)?;
+
+    peripheral.write(&control_point, command, WriteType::WithResponse).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::request_control;
+    use super::set_target_power;
+    use super::set_target_resistance_level;
+
+    #[test]
+    fn request_control_is_only_the_op_code() {
+        assert_eq!(vec![0x00], request_control());
+    }
+
+    #[test]
+    fn set_target_power_is_little_endian() {
+        assert_eq!(vec![0x05, 0xfa, 0x00], set_target_power(250));
+        assert_eq!(vec![0x05, 0x2c, 0x01], set_target_power(300));
+    }
+
+    #[test]
+    fn set_target_power_saturates_rather_than_going_negative() {
+        assert_eq!(vec![0x05, 0xff, 0x7f], set_target_power(40000));
+    }
+
+    #[test]
+    fn set_target_resistance_level_of_zero() {
+        assert_eq!(vec![0x04, 0x00], set_target_resistance_level(0));
+    }
+}
@@ -1,19 +1,27 @@
+use crate::peripherals::{
+    discover_services_unless_known, name_matches, reconnect, ConnectError, Device, LostDevices,
+    ReconnectPolicy,
+};
 use btleplug::api::{Central, CentralEvent, Peripheral, bleuuid::uuid_from_u16};
 use uuid::Uuid;
 use btleplug::Result;
-use std::time::Duration;
 use futures::stream::StreamExt;
 
 pub const MEASURE_UUID: Uuid = uuid_from_u16(0x2A37);
 
-pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central: &C) -> Result<Option<P>> {
+pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(
+    central: &C,
+    names: &'static [&'static str],
+    policy: ReconnectPolicy,
+    lost: LostDevices,
+) -> std::result::Result<P, ConnectError> {
     println!("Getting peripherals");
     let peripherals: Vec<P> = central.peripherals().await?;
     println!("Got peripherals list");
     let mut o_peripheral: Option<P> = None;
     for peripheral in peripherals {
         println!("Checking if device is hrm");
-        let found_it = is_hrm(&peripheral).await?;
+        let found_it = is_hrm(&peripheral, names).await?;
         if found_it {
           o_peripheral = Some(peripheral);
           break;
@@ -27,8 +35,7 @@ pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central:
             peripheral.connect().await?;
             println!("Connected to HRM");
 
-            peripheral.discover_services().await?;
-            println!("All characteristics discovered");
+            discover_services_unless_known(&peripheral, &[MEASURE_UUID]).await?;
 
             let o_hr_measurement = peripheral
                 .characteristics()
@@ -38,7 +45,7 @@ pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central:
             match o_hr_measurement {
                 None => {
                     peripheral.disconnect().await?;
-                    Ok(None)
+                    Err(ConnectError::MissingCharacteristic(MEASURE_UUID))
                 },
                 Some(hr_measurement) => {
                     peripheral.subscribe(&hr_measurement).await?;
@@ -51,38 +58,30 @@ pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central:
                             if let CentralEvent::DeviceDisconnected(addr) = evt {
                                 println!("HRM Disconnected.");
                                 let p = central_for_disconnects.peripheral(&addr).await.unwrap();
-                                if is_hrm(&p).await.unwrap() {
-                                    let wait = Duration::from_secs(10);
-                                    loop {
-                                        tokio::time::sleep(wait).await;
-                                        println!("Attempting HRM reconnect.");
-                                        if p.connect().await.is_ok() {
-                                            println!("HRM reconnected.");
-                                            break;
-                                        }
-                                        println!("HRM reconnect failed.");
-                                    }
+                                if is_hrm(&p, names).await.unwrap() {
+                                    reconnect(&p, Device::Hrm, &[MEASURE_UUID], policy, &lost)
+                                        .await;
                                 }
                             }
                         };
                     });
 
-                    Ok(Some(peripheral))
+                    Ok(peripheral)
                 }
             }
         }
-        None => Ok(None),
+        None => Err(ConnectError::NotFound),
     }
 }
 
-async fn is_hrm(p: &impl Peripheral) -> Result<bool> {
+async fn is_hrm(p: &impl Peripheral, names: &[&str]) -> Result<bool> {
     let op = p.properties().await?;
     Ok(match op {
       Some(properties) =>
         properties
             .local_name
             .iter()
-            .any(|name| name.contains("Polar")),
+            .any(|name| name_matches(name, names)),
       None => false
     })
 }
@@ -1,7 +1,10 @@
+use crate::peripherals::{
+    discover_services_unless_known, name_matches, reconnect, required_characteristic,
+    ConnectError, Device, LostDevices, ReconnectPolicy,
+};
 use btleplug::api::{Central, CentralEvent, Peripheral, bleuuid::uuid_from_u16, WriteType};
 use uuid::{Uuid, Builder};
 use btleplug::Result;
-use std::time::Duration;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use futures::stream::StreamExt;
@@ -20,14 +23,22 @@ pub const CONTROL_UUID: Uuid = Builder::from_bytes([
     0xA0, 0x26, 0xE0, 0x05, 0x0A, 0x7D, 0x4A, 0xB3, 0x97, 0xFA, 0xF1, 0x50, 0x0F, 0x9F, 0xEB, 0x8B,
 ]).into_uuid();
 
-pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central: &C) -> Result<Option<(P, Arc<Mutex<Option<u16>>>)>> {
+// Everything we can't control the trainer without
+const REQUIRED_UUIDS: [Uuid; 4] = [MEASURE_UUID, TRAINER_UUID, UNLOCK_UUID, CONTROL_UUID];
+
+pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(
+    central: &C,
+    names: &'static [&'static str],
+    policy: ReconnectPolicy,
+    lost: LostDevices,
+) -> std::result::Result<(P, Arc<Mutex<Option<u16>>>), ConnectError> {
     println!("Getting peripherals");
     let peripherals: Vec<P> = central.peripherals().await?;
     println!("Got peripherals list");
     let mut o_peripheral: Option<P> = None;
     for peripheral in peripherals {
         println!("Checking if device is kickr");
-        let found_it = is_kickr(&peripheral).await?;
+        let found_it = is_kickr(&peripheral, names).await?;
         if found_it {
           o_peripheral = Some(peripheral);
           break;
@@ -35,15 +46,14 @@ pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central:
     }
 
     match o_peripheral {
-        None => Ok(None),
+        None => Err(ConnectError::NotFound),
         Some(peripheral) => {
             println!("Found Kickr");
 
             peripheral.connect().await?;
             println!("Connected to Kickr");
 
-            peripheral.discover_services().await?;
-            println!("All characteristics discovered");
+            discover_services_unless_known(&peripheral, &REQUIRED_UUIDS).await?;
 
             first_time_setup(&peripheral).await?;
 
@@ -57,20 +67,14 @@ pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central:
                 while let Some(evt) = events.next().await {
                     if let CentralEvent::DeviceDisconnected(addr) = evt {
                         let p = central_for_disconnects.peripheral(&addr).await.unwrap();
-                        if is_kickr(&p).await.unwrap() {
-                            let wait = Duration::from_secs(10);
-                            loop {
-                                tokio::time::sleep(wait).await;
-                                if p.connect().await.is_ok() {
-                                    // TODO: Not sure what we could possibly do if these fail
-                                    unlock(&p).await.unwrap();
-
-                                    let guard = tp_for_disconnects.lock().await;
-                                    if let Some(power) = *guard {
-                                        write_power(&p, power).await.unwrap();
-                                    }
-
-                                    break;
+                        if is_kickr(&p, names).await.unwrap() {
+                            if reconnect(&p, Device::Kickr, &REQUIRED_UUIDS, policy, &lost).await {
+                                // TODO: Not sure what we could possibly do if these fail
+                                unlock(&p).await.unwrap();
+
+                                let guard = tp_for_disconnects.lock().await;
+                                if let Some(power) = *guard {
+                                    write_power(&p, power).await.unwrap();
                                 }
                             }
                         }
@@ -87,38 +91,37 @@ pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central:
             // GATT interface), and worries about connecting to them and
             // maintaining connections where appropriate.  This is the bulk of
             // our code in the main function.
-            Ok(Some((peripheral, target_power)))
+            Ok((peripheral, target_power))
         }
     }
 }
 
-async fn is_kickr(p: &impl Peripheral) -> Result<bool> {
+async fn is_kickr(p: &impl Peripheral, names: &[&str]) -> Result<bool> {
     let op = p.properties().await?;
     Ok(match op {
       Some(properties) =>
         properties
             .local_name
             .iter()
-            .any(|name| name.contains("KICKR")),
+            .any(|name| name_matches(name, names)),
       None => false
     })
 }
 
-async fn first_time_setup(kickr: &impl Peripheral) -> Result<()> {
-    let power_measurement = kickr
-        .characteristics()
-        .into_iter()
-        .find(|c| c.uuid == MEASURE_UUID)
-        .unwrap();
+async fn first_time_setup(kickr: &impl Peripheral) -> std::result::Result<(), ConnectError> {
+    let characteristics = kickr.characteristics();
+
+    // Checked up front, since these are only used later (to unlock and set
+    // power) where there'd be no good way to recover.
+    required_characteristic(&characteristics, UNLOCK_UUID)?;
+    required_characteristic(&characteristics, CONTROL_UUID)?;
+
+    let power_measurement = required_characteristic(&characteristics, MEASURE_UUID)?;
 
     kickr.subscribe(&power_measurement).await?;
     println!("Subscribed to power measure");
 
-    let trainer_characteristic = kickr
-        .characteristics()
-        .into_iter()
-        .find(|c| c.uuid == TRAINER_UUID)
-        .unwrap();
+    let trainer_characteristic = required_characteristic(&characteristics, TRAINER_UUID)?;
     println!("Trainer char found.");
 
     kickr.subscribe(&trainer_characteristic).await?;
@@ -133,7 +136,7 @@ async fn unlock(kickr: &impl Peripheral) -> Result<()> {
         .characteristics()
         .into_iter()
         .find(|c| c.uuid == UNLOCK_UUID)
-        .unwrap();
+        .ok_or(btleplug::Error::NoSuchCharacteristic)?;
     println!("Unlock char found.");
 
     kickr.write(
@@ -156,6 +159,30 @@ pub async fn set_power(
      write_power(peripheral, power).await
 }
 
+// Takes the Kickr out of ERG mode so the rider is free to ride by feel.  We
+// also forget the target, so that a reconnect doesn't put us back into ERG.
+pub async fn release(
+    peripheral: &impl Peripheral,
+    target_power_mutex: &Arc<Mutex<Option<u16>>>,
+) -> Result<()> {
+    let mut tp_guard = target_power_mutex.lock().await;
+    *tp_guard = None;
+
+    let power_control_char = peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.uuid == CONTROL_UUID)
+        // Checked on connect, so this shouldn't happen
+        .ok_or(btleplug::Error::NoSuchCharacteristic)?;
+
+    // Resistance mode (0x40) takes (1 - resistance) * 16383, so this is 0%
+    peripheral.write(
+        &power_control_char,
+        &[0x40, 0xff, 0x3f],
+        WriteType::WithResponse
+    ).await
+}
+
 async fn write_power(
     peripheral: &impl Peripheral,
     power: u16,
@@ -164,8 +191,8 @@ async fn write_power(
         .characteristics()
         .into_iter()
         .find(|c| c.uuid == CONTROL_UUID)
-        // Kickr with a Control UUID is an invariant
-        .unwrap();
+        // Checked on connect, so this shouldn't happen
+        .ok_or(btleplug::Error::NoSuchCharacteristic)?;
 
     peripheral.write(
         &power_control_char,
@@ -173,3 +200,36 @@ async fn write_power(
         WriteType::WithResponse
     ).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::first_time_setup;
+    use super::{CONTROL_UUID, MEASURE_UUID, REQUIRED_UUIDS, TRAINER_UUID, UNLOCK_UUID};
+    use crate::peripherals::mock::MockPeripheral;
+    use crate::peripherals::ConnectError;
+
+    #[tokio::test]
+    async fn first_time_setup_subscribes_and_unlocks() {
+        let kickr = MockPeripheral::new(&REQUIRED_UUIDS);
+        assert!(first_time_setup(&kickr).await.is_ok());
+        assert_eq!(vec![MEASURE_UUID, TRAINER_UUID], *kickr.subscribed.lock().unwrap());
+        assert_eq!(
+            vec![(UNLOCK_UUID, vec![0x20, 0xee, 0xfc])],
+            *kickr.written.lock().unwrap()
+        );
+    }
+
+    // A firmware without it can't be unlocked, which we find out before
+    // touching the trainer at all.
+    #[tokio::test]
+    async fn first_time_setup_without_unlock_is_a_connect_error() {
+        let kickr = MockPeripheral::new(&[MEASURE_UUID, TRAINER_UUID, CONTROL_UUID]);
+        let result = first_time_setup(&kickr).await;
+        assert!(matches!(
+            result,
+            Err(ConnectError::MissingCharacteristic(uuid)) if uuid == UNLOCK_UUID
+        ));
+        assert!(kickr.subscribed.lock().unwrap().is_empty());
+        assert!(kickr.written.lock().unwrap().is_empty());
+    }
+}
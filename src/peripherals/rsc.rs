@@ -0,0 +1,90 @@
+use crate::ble::rsc_measurement::MEASURE_UUID;
+use crate::peripherals::{
+    discover_services_unless_known, name_matches, reconnect, ConnectError, Device, LostDevices,
+    ReconnectPolicy,
+};
+use btleplug::api::{Central, CentralEvent, Peripheral, bleuuid::uuid_from_u16};
+use uuid::Uuid;
+use btleplug::Result;
+use futures::stream::StreamExt;
+
+// Foot pods don't have a consistent name, so we look for the service instead
+const SERVICE_UUID: Uuid = uuid_from_u16(0x1814);
+
+pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(
+    central: &C,
+    names: &'static [&'static str],
+    policy: ReconnectPolicy,
+    lost: LostDevices,
+) -> std::result::Result<P, ConnectError> {
+    println!("Getting peripherals");
+    let peripherals: Vec<P> = central.peripherals().await?;
+    println!("Got peripherals list");
+    let mut o_peripheral: Option<P> = None;
+    for peripheral in peripherals {
+        println!("Checking if device is rsc");
+        let found_it = is_rsc(&peripheral, names).await?;
+        if found_it {
+          o_peripheral = Some(peripheral);
+          break;
+        }
+    }
+
+    match o_peripheral {
+        Some(peripheral) => {
+            println!("Found RSC");
+
+            peripheral.connect().await?;
+            println!("Connected to RSC");
+
+            discover_services_unless_known(&peripheral, &[MEASURE_UUID]).await?;
+
+            let o_rsc_measurement = peripheral
+                .characteristics()
+                .into_iter()
+                .find(|c| c.uuid == MEASURE_UUID);
+
+            match o_rsc_measurement {
+                None => {
+                    peripheral.disconnect().await?;
+                    Err(ConnectError::MissingCharacteristic(MEASURE_UUID))
+                },
+                Some(rsc_measurement) => {
+                    peripheral.subscribe(&rsc_measurement).await?;
+                    println!("Subscribed to rsc measure");
+
+                    let central_for_disconnects = central.clone();
+                    let mut events = central.events().await?;
+                    tokio::spawn(async move {
+                        while let Some(evt) = events.next().await {
+                            if let CentralEvent::DeviceDisconnected(addr) = evt {
+                                println!("RSC Disconnected.");
+                                let p = central_for_disconnects.peripheral(&addr).await.unwrap();
+                                if is_rsc(&p, names).await.unwrap() {
+                                    reconnect(&p, Device::Rsc, &[MEASURE_UUID], policy, &lost)
+                                        .await;
+                                }
+                            }
+                        };
+                    });
+
+                    Ok(peripheral)
+                }
+            }
+        }
+        None => Err(ConnectError::NotFound),
+    }
+}
+
+async fn is_rsc(p: &impl Peripheral, names: &[&str]) -> Result<bool> {
+    let op = p.properties().await?;
+    Ok(match op {
+      Some(properties) =>
+        properties.services.contains(&SERVICE_UUID)
+            || properties
+                .local_name
+                .iter()
+                .any(|name| name_matches(name, names)),
+      None => false
+    })
+}
@@ -1,17 +1,26 @@
+use crate::ble::csc_feature::CscFeature;
 use crate::ble::csc_measurement::MEASURE_UUID;
+use crate::peripherals::{
+    discover_services_unless_known, name_matches, read_csc_feature, reconnect, ConnectError,
+    Device, LostDevices, ReconnectPolicy,
+};
 use btleplug::api::{Central, CentralEvent, Peripheral};
 use btleplug::Result;
-use std::time::Duration;
 use futures::stream::StreamExt;
 
-pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central: &C) -> Result<Option<P>> {
+pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(
+    central: &C,
+    names: &'static [&'static str],
+    policy: ReconnectPolicy,
+    lost: LostDevices,
+) -> std::result::Result<(P, Option<CscFeature>), ConnectError> {
     println!("Getting peripherals");
     let peripherals: Vec<P> = central.peripherals().await?;
     println!("Got peripherals list");
     let mut o_peripheral: Option<P> = None;
     for peripheral in peripherals {
         println!("Checking if device is speed sensor");
-        let found_it = is_speed(&peripheral).await?;
+        let found_it = is_speed(&peripheral, names).await?;
         if found_it {
           o_peripheral = Some(peripheral);
           break;
@@ -25,8 +34,7 @@ pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central:
             peripheral.connect().await?;
             println!("Connected to Speed Sensor");
 
-            peripheral.discover_services().await?;
-            println!("All characteristics discovered");
+            discover_services_unless_known(&peripheral, &[MEASURE_UUID]).await?;
 
             let o_speed_measurement = peripheral
                 .characteristics()
@@ -36,12 +44,15 @@ pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central:
             match o_speed_measurement {
                 None => {
                     peripheral.disconnect().await?;
-                    Ok(None)
+                    Err(ConnectError::MissingCharacteristic(MEASURE_UUID))
                 },
                 Some(speed_measurement) => {
                     peripheral.subscribe(&speed_measurement).await?;
                     println!("Subscribed to speed measure");
 
+                    let o_feature = read_csc_feature(&peripheral).await;
+                    println!("Speed features: {:?}", o_feature);
+
                     let central_for_disconnects = central.clone();
                     let mut events = central.events().await?;
                     tokio::spawn(async move {
@@ -49,38 +60,30 @@ pub async fn connect<P: Peripheral, C: Central<Peripheral=P> + 'static>(central:
                             if let CentralEvent::DeviceDisconnected(addr) = evt {
                                 println!("Speed Disconnected.");
                                 let p = central_for_disconnects.peripheral(&addr).await.unwrap();
-                                if is_speed(&p).await.unwrap() {
-                                    let wait = Duration::from_secs(10);
-                                    loop {
-                                        tokio::time::sleep(wait).await;
-                                        println!("Attempting Speed reconnect.");
-                                        if p.connect().await.is_ok() {
-                                            println!("Speed reconnected.");
-                                            break;
-                                        }
-                                        println!("Speed reconnect failed.");
-                                    }
+                                if is_speed(&p, names).await.unwrap() {
+                                    reconnect(&p, Device::Speed, &[MEASURE_UUID], policy, &lost)
+                                        .await;
                                 }
                             }
                         };
                     });
 
-                    Ok(Some(peripheral))
+                    Ok((peripheral, o_feature))
                 }
             }
         }
-        None => Ok(None),
+        None => Err(ConnectError::NotFound),
     }
 }
 
-async fn is_speed(p: &impl Peripheral) -> Result<bool> {
+async fn is_speed(p: &impl Peripheral, names: &[&str]) -> Result<bool> {
     let op = p.properties().await?;
     Ok(match op {
       Some(properties) =>
         properties
             .local_name
             .iter()
-            .any(|name| name.contains("SPEED")),
+            .any(|name| name_matches(name, names)),
       None => false
     })
 }
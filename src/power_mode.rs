@@ -0,0 +1,68 @@
+use crate::utils::lock_or_recover;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// How often each loop wakes up to do its periodic work.  Longer intervals use
+// less CPU (and battery) on long rides, but everything feels more sluggish.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Intervals {
+    // How stale the screen can be.  At a second, values still update every
+    // second, but toasts and page changes can take that long to appear.
+    pub render: Duration,
+    // How often the buttons are read.  Much longer and a quick tap can be
+    // missed entirely (and holds fire a little later).
+    pub button_poll: Duration,
+    // How often the frame buffer is sent to the LCD.  This can't exceed a
+    // second, since the LCD's VCOM must be toggled at least that often.
+    pub lcd_refresh: Duration,
+    // How often the workout checks whether to move on to its next step, which
+    // is as late as a step (or an offset) can be.  Only read as the workout
+    // starts.
+    pub workout: Duration,
+}
+
+pub const NORMAL: Intervals = Intervals {
+    render: Duration::from_millis(100),
+    button_poll: Duration::from_millis(50),
+    lcd_refresh: Duration::from_millis(100),
+    workout: Duration::from_millis(50),
+};
+
+pub const LOW_POWER: Intervals = Intervals {
+    render: Duration::from_secs(1),
+    button_poll: Duration::from_millis(200),
+    lcd_refresh: Duration::from_millis(500),
+    workout: Duration::from_millis(250),
+};
+
+// Shared by every loop, so the mode can be switched mid-ride
+pub type SharedIntervals = Arc<Mutex<Intervals>>;
+
+// Switches between normal and low power, returning whether we're now in low
+// power.
+pub fn toggle(intervals: &SharedIntervals) -> bool {
+    let mut intervals = lock_or_recover(intervals);
+    let is_low_power = *intervals != LOW_POWER;
+    *intervals = if is_low_power { LOW_POWER } else { NORMAL };
+    is_low_power
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{toggle, LOW_POWER, NORMAL};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn toggle_switches_back_and_forth() {
+        let intervals = Arc::new(Mutex::new(NORMAL));
+        assert!(toggle(&intervals));
+        assert_eq!(LOW_POWER, *intervals.lock().unwrap());
+        assert!(!toggle(&intervals));
+        assert_eq!(NORMAL, *intervals.lock().unwrap());
+    }
+
+    #[test]
+    fn low_power_still_refreshes_the_lcd_every_second() {
+        assert!(LOW_POWER.lcd_refresh <= std::time::Duration::from_secs(1));
+    }
+}
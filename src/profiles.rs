@@ -0,0 +1,298 @@
+// Who's riding and with what, kept in a file beside the DB so profiles can be
+// added (or tweaked) without recompiling.  A profile only needs to list what
+// differs from the defaults.
+use crate::display::{FieldKind, TargetChangePage, Units, DEFAULT_LAYOUT};
+use crate::peripherals::{
+    CscRole, Device, NamePatterns, ReconnectPolicy, DEFAULT_NAME_PATTERNS,
+    DEFAULT_RECONNECT_POLICY,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+pub const DEFAULT_PATH: &str = ".rust-cycle-profiles.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct SelectedDevices {
+    // Calibrations (like wheel circumference) are stored under this name, so
+    // profiles that share a bike share them too.
+    pub bike: String,
+    pub assioma: bool,
+    pub cadence: bool,
+    pub gps: bool,
+    pub hr: bool,
+    pub kickr: bool,
+    pub rsc: bool,
+    pub speed: bool,
+    // What the speed sensor is used for, so that a combined sensor connected
+    // once can provide cadence as well (or only cadence).
+    pub speed_sensor_role: CscRole,
+    // The lowest power we'll ever ask the trainer to hold.  This deviates from
+    // the authored workout by design, so recovery valleys (or negative
+    // offsets) never drop below a comfortable spin.
+    pub erg_floor: Option<u16>,
+    // How many seconds of power are averaged to show how well the trainer is
+    // holding the ERG target.
+    pub erg_error_window: usize,
+    // After this many consecutive failed writes of the ERG target, the trainer
+    // is released so the rider can ride by feel (None keeps trying forever).
+    pub erg_fallback_after: Option<u32>,
+    // Deviations from the ERG target (in watts) that are labeled on the
+    // PowerTrack page.
+    pub power_track_labels: Vec<i16>,
+    // When more than one power source is connected, this one is displayed and
+    // recorded as the power (others are only kept for comparison).  The
+    // trainer is always controlled by its target, regardless.
+    pub primary_power_source: Device,
+    // Power readings (in watts) above this are treated as glitches, and the
+    // previous reading is held instead (both live and in exports).
+    pub max_power: i16,
+    // The rider's critical power (roughly their FTP) in watts, which enables
+    // the W' balance bar, and their anaerobic work capacity (W') in joules.
+    pub critical_power: Option<u16>,
+    pub w_prime: u32,
+    // How we try to get a device back after it disconnects
+    pub reconnect: ReconnectPolicy,
+    // How each kind of device is recognized by its name.  These have to live
+    // as long as the reconnect tasks that use them, so they aren't stored.
+    #[serde(skip, default = "default_name_patterns")]
+    pub name_patterns: NamePatterns,
+    pub units: Units,
+    // What's shown in each slot of the Standard page
+    pub layout: Vec<FieldKind>,
+    // How far back speed readings are averaged for display (recordings are
+    // never smoothed).
+    pub speed_smoothing: Duration,
+    // How long a summary of each work interval is shown as it ends (None
+    // disables it).
+    pub interval_summary_dwell: Option<Duration>,
+    // Whether the display switches to the PowerTrack page when the workout's
+    // target changes (and whether it comes back).
+    pub target_change_page: TargetChangePage,
+}
+
+fn default_name_patterns() -> NamePatterns {
+    DEFAULT_NAME_PATTERNS
+}
+
+impl Default for SelectedDevices {
+    fn default() -> SelectedDevices {
+        SelectedDevices {
+            bike: String::new(),
+            assioma: false,
+            cadence: false,
+            gps: false,
+            hr: false,
+            kickr: false,
+            rsc: false,
+            speed: false,
+            speed_sensor_role: CscRole::Speed,
+            erg_floor: None,
+            erg_error_window: 5,
+            erg_fallback_after: Some(3),
+            power_track_labels: vec![10, 25, 50],
+            primary_power_source: Device::Kickr,
+            max_power: 1500,
+            critical_power: None,
+            w_prime: 20000,
+            reconnect: DEFAULT_RECONNECT_POLICY,
+            name_patterns: DEFAULT_NAME_PATTERNS,
+            units: Units::Metric,
+            layout: DEFAULT_LAYOUT.to_vec(),
+            speed_smoothing: Duration::from_secs(3),
+            interval_summary_dwell: Some(Duration::from_secs(10)),
+            target_change_page: TargetChangePage::PowerTrack,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Profile {
+    // Shown when choosing a profile, and sessions are tagged with it, so
+    // riders sharing a device can find their own.
+    pub name: String,
+    pub devices: SelectedDevices,
+}
+
+// In the order they're offered
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Profiles {
+    profiles: Vec<Profile>,
+}
+
+impl Profiles {
+    // A missing file just means no profiles have been set up yet, but one that
+    // can't be read is reported (and otherwise ignored) so a typo doesn't stop
+    // a ride.
+    pub fn load_or_default(path: &Path) -> Profiles {
+        match fs::read_to_string(path) {
+            Ok(json) => match serde_json::from_str(&json) {
+                Ok(profiles) => profiles,
+                Err(e) => {
+                    println!("Ignoring profiles in {}: {}", path.display(), e);
+                    Profiles::default()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Profiles::default(),
+            Err(e) => {
+                println!("Could not read profiles from {}: {}", path.display(), e);
+                Profiles::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::from)?;
+        fs::write(path, json)
+    }
+
+    pub fn profiles(&self) -> &[Profile] {
+        &self.profiles
+    }
+}
+
+impl Default for Profiles {
+    fn default() -> Profiles {
+        let profile = |name: &str, devices: SelectedDevices| Profile {
+            name: name.to_string(),
+            devices,
+        };
+        Profiles {
+            profiles: vec![
+                profile(
+                    "Zenia",
+                    SelectedDevices {
+                        bike: "Zenia".to_string(),
+                        cadence: true,
+                        kickr: true,
+                        ..SelectedDevices::default()
+                    },
+                ),
+                profile(
+                    "Nathan Outdoor",
+                    SelectedDevices {
+                        bike: "Nathan".to_string(),
+                        assioma: true,
+                        gps: true,
+                        hr: true,
+                        speed: true,
+                        primary_power_source: Device::Assioma,
+                        ..SelectedDevices::default()
+                    },
+                ),
+                profile(
+                    "Nathan Kickr",
+                    SelectedDevices {
+                        bike: "Nathan".to_string(),
+                        assioma: true,
+                        hr: true,
+                        kickr: true,
+                        primary_power_source: Device::Assioma,
+                        ..SelectedDevices::default()
+                    },
+                ),
+                profile(
+                    "Nathan Rollers",
+                    SelectedDevices {
+                        bike: "Nathan".to_string(),
+                        assioma: true,
+                        hr: true,
+                        speed: true,
+                        primary_power_source: Device::Assioma,
+                        ..SelectedDevices::default()
+                    },
+                ),
+                profile(
+                    "Nathan Treadmill",
+                    SelectedDevices {
+                        bike: "Nathan".to_string(),
+                        hr: true,
+                        rsc: true,
+                        primary_power_source: Device::Assioma,
+                        ..SelectedDevices::default()
+                    },
+                ),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Profile, Profiles, SelectedDevices};
+    use crate::display::{FieldKind, TargetChangePage};
+    use crate::peripherals::CscRole;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rust-cycle-{}-{}.json", name, std::process::id()))
+    }
+
+    fn commuter() -> Profile {
+        Profile {
+            name: "Commuter".to_string(),
+            devices: SelectedDevices {
+                bike: "Brompton".to_string(),
+                speed: true,
+                speed_sensor_role: CscRole::Both,
+                erg_floor: Some(100),
+                power_track_labels: vec![5, 15],
+                critical_power: Some(250),
+                layout: vec![FieldKind::Speed, FieldKind::Clock],
+                interval_summary_dwell: None,
+                target_change_page: TargetChangePage::AutoReturn(Duration::from_secs(20)),
+                ..SelectedDevices::default()
+            },
+        }
+    }
+
+    #[test]
+    fn round_trips_through_a_file() {
+        let path = temp_path("round-trip");
+        let profiles = Profiles {
+            profiles: vec![commuter()],
+        };
+        profiles.save(&path).unwrap();
+        assert_eq!(Profiles::load_or_default(&path), profiles);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_file_falls_back_to_the_defaults() {
+        let path = temp_path("missing");
+        let _ = fs::remove_file(&path);
+        assert_eq!(Profiles::load_or_default(&path), Profiles::default());
+    }
+
+    #[test]
+    fn malformed_file_falls_back_to_the_defaults() {
+        let path = temp_path("malformed");
+        fs::write(&path, "{\"profiles\": [").unwrap();
+        assert_eq!(Profiles::load_or_default(&path), Profiles::default());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn profiles_only_need_what_differs_from_the_defaults() {
+        let path = temp_path("sparse");
+        fs::write(
+            &path,
+            r#"{"profiles":[{"name":"Spin","devices":{"bike":"Spin","kickr":true}}]}"#,
+        )
+        .unwrap();
+        let expected = SelectedDevices {
+            bike: "Spin".to_string(),
+            kickr: true,
+            ..SelectedDevices::default()
+        };
+        let profiles = Profiles::load_or_default(&path);
+        assert_eq!(profiles.profiles().len(), 1);
+        assert_eq!(profiles.profiles()[0].devices, expected);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+// What the device is up to right now, so it can be checked on remotely (say,
+// to confirm it's recording mid-ride) without pulling a whole FIT file.
+use crate::activity::ActivityState;
+use crate::peripherals::{Device, LostDevices};
+use crate::utils::lock_or_recover;
+use serde::Serialize;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub type SharedStatus = Arc<Mutex<Status>>;
+
+pub struct Status {
+    version: String,
+    started: Instant,
+    ride: Option<Ride>,
+}
+
+// The session being recorded, the devices connected at its start (some of
+// which may since have been lost), and whether the rider is pedaling
+struct Ride {
+    session_key: u64,
+    devices: Vec<Device>,
+    lost: LostDevices,
+    activity: Arc<Mutex<ActivityState>>,
+}
+
+// What's actually sent, in this order
+#[derive(Serialize)]
+struct StatusJson<'a> {
+    version: &'a str,
+    uptime_seconds: u64,
+    riding: bool,
+    session_key: Option<u64>,
+    devices: Vec<DeviceJson>,
+    free_disk_bytes: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct DeviceJson {
+    name: &'static str,
+    connected: bool,
+}
+
+impl Status {
+    pub fn new(version: &str) -> Status {
+        Status {
+            version: version.to_string(),
+            started: Instant::now(),
+            ride: None,
+        }
+    }
+
+    pub fn start_ride(
+        &mut self,
+        session_key: u64,
+        devices: Vec<Device>,
+        lost: LostDevices,
+        activity: Arc<Mutex<ActivityState>>,
+    ) {
+        self.ride = Some(Ride {
+            session_key,
+            devices,
+            lost,
+            activity,
+        });
+    }
+
+    // A session can be recorded while the rider is stopped, so riding is only
+    // whether they're pedaling.
+    fn is_riding(&self, now: Instant) -> bool {
+        self.ride
+            .as_ref()
+            .is_some_and(|r| lock_or_recover(&r.activity).is_active(now))
+    }
+
+    pub fn end_ride(&mut self) {
+        self.ride = None;
+    }
+
+    // The session being recorded, if any
+    pub fn session_key(&self) -> Option<u64> {
+        self.ride.as_ref().map(|r| r.session_key)
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn to_json(&self, uptime: Duration, o_free_disk: Option<u64>) -> String {
+        let devices = match &self.ride {
+            Some(ride) => {
+                let lost = lock_or_recover(&ride.lost);
+                ride.devices
+                    .iter()
+                    .map(|d| DeviceJson {
+                        name: d.name(),
+                        connected: !lost.contains(d),
+                    })
+                    .collect()
+            }
+            None => vec![],
+        };
+        // Plain data, so this can't fail
+        serde_json::to_string(&StatusJson {
+            version: &self.version,
+            uptime_seconds: uptime.as_secs(),
+            riding: self.is_riding(Instant::now()),
+            session_key: self.session_key(),
+            devices,
+            free_disk_bytes: o_free_disk,
+        })
+        .unwrap()
+    }
+}
+
+// Space left on the filesystem holding the DB (the working directory)
+pub fn free_disk_bytes() -> Option<u64> {
+    let output = Command::new("df").arg("-Pk").arg(".").output().ok()?;
+    parse_df_available(&String::from_utf8_lossy(&output.stdout))
+}
+
+// POSIX df output is a header and then one line per filesystem, where the
+// fourth column is what's available (in 1024 byte blocks, with -k).
+fn parse_df_available(output: &str) -> Option<u64> {
+    let line = output.lines().nth(1)?;
+    let kb: u64 = line.split_whitespace().nth(3)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_df_available, Status};
+    use crate::activity::ActivityState;
+    use crate::peripherals::Device;
+    use std::collections::BTreeSet;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn to_json_when_idle() {
+        let status = Status::new("v1");
+        assert_eq!(
+            status.to_json(Duration::from_secs(90), None),
+            r#"{"version":"v1","uptime_seconds":90,"riding":false,"session_key":null,"devices":[],"free_disk_bytes":null}"#
+        );
+    }
+
+    #[test]
+    fn to_json_mid_ride_shows_lost_devices() {
+        let mut status = Status::new("v1");
+        let lost = Arc::new(Mutex::new(BTreeSet::new()));
+        let activity = Arc::new(Mutex::new(ActivityState::new()));
+        let devices = vec![Device::Kickr, Device::Hrm];
+        status.start_ride(1700000000, devices, lost.clone(), activity.clone());
+        lost.lock().unwrap().insert(Device::Hrm);
+        activity.lock().unwrap().update_cadence(90, Instant::now());
+        assert_eq!(
+            status.to_json(Duration::from_secs(90), Some(2048)),
+            r#"{"version":"v1","uptime_seconds":90,"riding":true,"session_key":1700000000,"devices":[{"name":"KICKR","connected":true},{"name":"HRM","connected":false}],"free_disk_bytes":2048}"#
+        );
+    }
+
+    #[test]
+    fn to_json_escapes_the_version() {
+        let status = Status::new("v1 \"dirty\"");
+        assert!(status
+            .to_json(Duration::from_secs(0), None)
+            .starts_with(r#"{"version":"v1 \"dirty\"","#));
+    }
+
+    #[test]
+    fn recording_without_pedaling_is_not_riding() {
+        let mut status = Status::new("v1");
+        let activity = Arc::new(Mutex::new(ActivityState::new()));
+        status.start_ride(
+            100,
+            vec![],
+            Arc::new(Mutex::new(BTreeSet::new())),
+            activity.clone(),
+        );
+        let json = status.to_json(Duration::from_secs(0), None);
+        assert!(json.contains(r#""riding":false,"session_key":100"#));
+        activity.lock().unwrap().update_cadence(90, Instant::now());
+        assert!(status
+            .to_json(Duration::from_secs(0), None)
+            .contains(r#""riding":true"#));
+    }
+
+    #[test]
+    fn end_ride_clears_the_session() {
+        let mut status = Status::new("v1");
+        let activity = Arc::new(Mutex::new(ActivityState::new()));
+        status.start_ride(100, vec![], Arc::new(Mutex::new(BTreeSet::new())), activity);
+        assert_eq!(status.session_key(), Some(100));
+        status.end_ride();
+        assert_eq!(status.session_key(), None);
+        assert!(status.to_json(Duration::from_secs(0), None).contains(r#""riding":false"#));
+    }
+
+    #[test]
+    fn parse_df_available_in_bytes() {
+        let output = "Filesystem     1024-blocks    Used Available Capacity Mounted on\n\
+                      /dev/root         30000000 1000000  28000000       4% /\n";
+        assert_eq!(parse_df_available(output), Some(28000000 * 1024));
+    }
+
+    #[test]
+    fn parse_df_available_of_nothing() {
+        assert_eq!(parse_df_available(""), None);
+    }
+}
@@ -0,0 +1,181 @@
+// Garmin's Training Center XML, for tools that don't ingest FIT
+use crate::fit::{FitRecord, Sport};
+use chrono::DateTime;
+use std::fmt::Write;
+
+fn format_time(seconds_since_unix_epoch: u32) -> String {
+    // Any u32 is well within chrono's range
+    DateTime::from_timestamp(seconds_since_unix_epoch as i64, 0)
+        .unwrap()
+        .format("%Y-%m-%dT%H:%M:%SZ")
+        .to_string()
+}
+
+// TCX only tells running and biking apart (and sessions from before the sport
+// was recorded were all rides)
+fn tcx_sport(o_sport: Option<Sport>) -> &'static str {
+    match o_sport {
+        Some(Sport::Treadmill) => "Running",
+        Some(Sport::Cycling) | Some(Sport::IndoorCycling) | None => "Biking",
+    }
+}
+
+fn trackpoint(record: &FitRecord) -> String {
+    let mut tp = String::new();
+    // Writing to a String cannot fail
+    write!(tp, "<Trackpoint>").unwrap();
+    write!(tp, "<Time>{}</Time>", format_time(record.seconds_since_unix_epoch)).unwrap();
+    if let (Some(lat), Some(lon)) = (record.latitude, record.longitude) {
+        write!(
+            tp,
+            "<Position><LatitudeDegrees>{}</LatitudeDegrees><LongitudeDegrees>{}</LongitudeDegrees></Position>",
+            lat, lon
+        )
+        .unwrap();
+    }
+    if let Some(a) = record.altitude {
+        write!(tp, "<AltitudeMeters>{}</AltitudeMeters>", a).unwrap();
+    }
+    if let Some(d) = record.distance {
+        write!(tp, "<DistanceMeters>{}</DistanceMeters>", d).unwrap();
+    }
+    if let Some(hr) = record.heart_rate {
+        write!(tp, "<HeartRateBpm><Value>{}</Value></HeartRateBpm>", hr).unwrap();
+    }
+    if let Some(c) = record.cadence {
+        write!(tp, "<Cadence>{}</Cadence>", c).unwrap();
+    }
+    // Speed and power only exist in the extension
+    if record.speed.is_some() || record.power.is_some() {
+        write!(tp, "<Extensions><ns3:TPX>").unwrap();
+        if let Some(s) = record.speed {
+            write!(tp, "<ns3:Speed>{}</ns3:Speed>", s).unwrap();
+        }
+        if let Some(p) = record.power {
+            write!(tp, "<ns3:Watts>{}</ns3:Watts>", p).unwrap();
+        }
+        write!(tp, "</ns3:TPX></Extensions>").unwrap();
+    }
+    write!(tp, "</Trackpoint>").unwrap();
+    tp
+}
+
+// The whole ride becomes a single activity with a single lap
+pub fn to_tcx(list: &[FitRecord], o_sport: Option<Sport>) -> String {
+    let mut tcx = String::new();
+    write!(tcx, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    write!(
+        tcx,
+        r#"<TrainingCenterDatabase xmlns="http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2" xmlns:ns3="http://www.garmin.com/xmlschemas/ActivityExtension/v2">"#
+    )
+    .unwrap();
+    write!(tcx, "<Activities>").unwrap();
+
+    if let (Some(first), Some(last)) = (list.first(), list.last()) {
+        let start = format_time(first.seconds_since_unix_epoch);
+        let total_distance = list.iter().rev().find_map(|r| r.distance).unwrap_or(0.0);
+        write!(tcx, r#"<Activity Sport="{}">"#, tcx_sport(o_sport)).unwrap();
+        write!(tcx, "<Id>{}</Id>", start).unwrap();
+        write!(tcx, r#"<Lap StartTime="{}">"#, start).unwrap();
+        write!(
+            tcx,
+            "<TotalTimeSeconds>{}</TotalTimeSeconds>",
+            last.seconds_since_unix_epoch - first.seconds_since_unix_epoch
+        )
+        .unwrap();
+        write!(tcx, "<DistanceMeters>{}</DistanceMeters>", total_distance).unwrap();
+        write!(tcx, "<Calories>0</Calories>").unwrap();
+        write!(tcx, "<Intensity>Active</Intensity>").unwrap();
+        write!(tcx, "<TriggerMethod>Manual</TriggerMethod>").unwrap();
+        write!(tcx, "<Track>").unwrap();
+        for record in list {
+            tcx.push_str(&trackpoint(record));
+        }
+        write!(tcx, "</Track>").unwrap();
+        write!(tcx, "</Lap>").unwrap();
+        write!(tcx, "</Activity>").unwrap();
+    }
+
+    write!(tcx, "</Activities>").unwrap();
+    write!(tcx, "</TrainingCenterDatabase>").unwrap();
+    tcx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_tcx;
+    use crate::fit::{FitRecord, Sport};
+
+    // Every tag is closed, and in the order it was opened
+    fn assert_balanced(xml: &str) {
+        let mut open: Vec<&str> = vec![];
+        for tag in xml.split('<').skip(1).map(|t| &t[..t.find('>').unwrap()]) {
+            if tag.starts_with('?') || tag.ends_with('/') {
+                continue;
+            }
+            match tag.strip_prefix('/') {
+                Some(name) => assert_eq!(open.pop(), Some(name)),
+                None => open.push(tag.split(' ').next().unwrap()),
+            }
+        }
+        assert_eq!(open, Vec::<&str>::new());
+    }
+
+    fn ride() -> Vec<FitRecord> {
+        vec![
+            FitRecord {
+                latitude: Some(45.5),
+                longitude: Some(-122.25),
+                altitude: Some(30.0),
+                distance: Some(0.0),
+                heart_rate: Some(120),
+                ..FitRecord::new(1700000000)
+            },
+            FitRecord {
+                power: Some(250),
+                cadence: Some(90),
+                distance: Some(8.5),
+                ..FitRecord::new(1700000001)
+            },
+        ]
+    }
+
+    #[test]
+    fn is_well_formed() {
+        assert_balanced(&to_tcx(&ride(), None));
+        assert_balanced(&to_tcx(&[], None));
+    }
+
+    #[test]
+    fn lap_covers_the_ride() {
+        let tcx = to_tcx(&ride(), None);
+        assert!(tcx.contains(r#"<Lap StartTime="2023-11-14T22:13:20Z">"#));
+        assert!(tcx.contains("<TotalTimeSeconds>1</TotalTimeSeconds>"));
+        assert!(tcx.contains("<DistanceMeters>8.5</DistanceMeters><Calories>"));
+    }
+
+    #[test]
+    fn trackpoints_carry_each_field() {
+        let tcx = to_tcx(&ride(), None);
+        assert!(tcx.contains(
+            "<Position><LatitudeDegrees>45.5</LatitudeDegrees>\
+             <LongitudeDegrees>-122.25</LongitudeDegrees></Position>"
+        ));
+        assert!(tcx.contains("<AltitudeMeters>30</AltitudeMeters>"));
+        assert!(tcx.contains("<HeartRateBpm><Value>120</Value></HeartRateBpm>"));
+        assert!(tcx.contains("<Cadence>90</Cadence>"));
+        assert!(tcx.contains("<ns3:Watts>250</ns3:Watts>"));
+    }
+
+    #[test]
+    fn sport_follows_the_activity() {
+        assert!(to_tcx(&ride(), None).contains(r#"<Activity Sport="Biking">"#));
+        assert!(to_tcx(&ride(), Some(Sport::IndoorCycling)).contains(r#"Sport="Biking""#));
+        assert!(to_tcx(&ride(), Some(Sport::Treadmill)).contains(r#"Sport="Running""#));
+    }
+
+    #[test]
+    fn no_records_is_no_activity() {
+        assert!(!to_tcx(&[], None).contains("<Activity"));
+    }
+}
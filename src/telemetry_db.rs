@@ -1,8 +1,14 @@
+use crate::ble::csc_feature::CscFeature;
+use crate::ble::device_information::DeviceInformation;
+use crate::cycle_tree::CycleTree;
+use crate::fit::Sport;
+use crate::peripherals::Device;
 use uuid::Uuid;
 use nmea0183::ParseResult;
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
-use std::time::Duration;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
 
 #[derive(Clone)]
 pub struct TelemetryDb {
@@ -20,12 +26,130 @@ pub struct TelemetryDb {
 pub enum Notification {
     Ble((Uuid, Vec<u8>)),
     Gps(ParseResult),
+    // A device that is connected for this session and its battery level (as a
+    // percent) if known.
+    DeviceStatus((Device, Option<u8>)),
+    // What a connected CSC sensor supports, read once on connect.  Only older
+    // sessions have these, from before each sensor was told apart (see
+    // DeviceCscFeature).
+    CscFeature(CscFeature),
+    // The wheel circumference (in meters) used for speed and distance.
+    WheelCircumference(f32),
+    // A BLE notification that is tagged with the device it came from.  This
+    // is for when a device's readings would otherwise be ambiguous (like a
+    // second power source).
+    DeviceBle((Device, Uuid, Vec<u8>)),
+    // What kind of ride the session is, as chosen by the profile
+    Sport(Sport),
+    // The workout moved on to its next step, which starts a new lap
+    Lap,
+    // What a connected device says it is (like its firmware), read once on
+    // connect.
+    DeviceInfo((Device, DeviceInformation)),
+    // Power readings (in watts) above this are glitches, as chosen by the
+    // profile, so exports can hold over them just as the display did.
+    MaxPower(i16),
+    // What a connected CSC sensor supports, read once on connect, which only
+    // applies to the CSC measurements tagged with the same device.
+    DeviceCscFeature((Device, CscFeature)),
+    // The workout being ridden (its plan and tail power), recorded as it
+    // starts.
+    Workout((CycleTree<(Duration, u16)>, Option<u16>)),
+    // How far into the workout it was (and whether it's now paused), recorded
+    // at each new step and each pause or resume, so a ride cut short can pick
+    // up where the workout got to.
+    WorkoutPosition((Duration, bool)),
+}
+
+// A notification exactly as it arrived, for debugging sensors.  These are
+// kept out of the session data entirely, so they never affect exports.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RawNotification {
+    pub elapsed: Duration,
+    // The address of the device that sent it
+    pub source: String,
+    pub uuid: Uuid,
+    pub value: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 enum NotificationType {
     Ble(Uuid),
     Gps,
+    DeviceStatus(Device),
+    CscFeature,
+    WheelCircumference,
+    DeviceBle((Device, Uuid)),
+    Sport,
+    Lap,
+    DeviceInfo(Device),
+    MaxPower,
+    DeviceCscFeature(Device),
+    Workout,
+    WorkoutPosition,
+}
+
+// Any clock reading before this (2020-01-01) means the clock has never been
+// set, so we can't use it to judge whether other timestamps are sane.
+const EARLIEST_PLAUSIBLE_NOW: u64 = 1577836800;
+
+// A session key this far ahead of a plausible clock must have been keyed by a
+// clock that was wildly wrong.
+const MAX_CLOCK_SKEW: u64 = 7 * 24 * 60 * 60;
+
+// How new sessions are keyed.  Keys only need to be unique and increasing, so
+// on a device that can't trust its clock (no RTC, and no network or GPS fix
+// yet) a counter is more reliable than the time.  Either way, when the session
+// started is recorded separately.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionKeyStrategy {
+    // The seconds since the unix epoch (bumped if needed to stay increasing)
+    UnixSeconds,
+    // One more than the previous session
+    Counter,
+}
+
+impl SessionKeyStrategy {
+    pub fn parse(s: &str) -> Option<SessionKeyStrategy> {
+        match s {
+            "seconds" => Some(SessionKeyStrategy::UnixSeconds),
+            "counter" => Some(SessionKeyStrategy::Counter),
+            _ => None,
+        }
+    }
+}
+
+// Starts every backup, so restoring something else fails loudly.  The trailing
+// digit is the backup format version.
+const BACKUP_MAGIC: &[u8] = b"rust-cycle-backup-1";
+
+// How far into a session we are, which is what its entries are keyed by.  A
+// resumed session may have started before this boot (so before any Instant
+// we could have), so it's kept as how far in it already was, plus however
+// long it's been since.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionClock {
+    since: Instant,
+    before: Duration,
+}
+
+impl SessionClock {
+    // A session starting now
+    pub fn new() -> SessionClock {
+        SessionClock::resumed(Duration::from_secs(0))
+    }
+
+    // A session that was already this far in
+    pub fn resumed(elapsed: Duration) -> SessionClock {
+        SessionClock {
+            since: Instant::now(),
+            before: elapsed,
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.before + self.since.elapsed()
+    }
 }
 
 pub fn open(path: String) -> sled::Result<TelemetryDb> {
@@ -38,6 +162,14 @@ pub fn open_default() -> sled::Result<TelemetryDb> {
     open(".rust-cycle.sled".to_string())
 }
 
+// A DB that's deleted once dropped
+#[cfg(test)]
+pub fn open_temporary() -> sled::Result<TelemetryDb> {
+    let db = sled::Config::new().temporary(true).open()?;
+    let serial_config = bincode::config().big_endian().clone();
+    Ok(TelemetryDb { db, serial_config })
+}
+
 impl TelemetryDb {
     pub fn insert(
         &self,
@@ -48,6 +180,19 @@ impl TelemetryDb {
         let nt = match notification {
             Notification::Gps(_) => NotificationType::Gps,
             Notification::Ble((uuid, _)) => NotificationType::Ble(uuid),
+            Notification::DeviceStatus((device, _)) => NotificationType::DeviceStatus(device),
+            Notification::CscFeature(_) => NotificationType::CscFeature,
+            Notification::WheelCircumference(_) => NotificationType::WheelCircumference,
+            Notification::DeviceBle((device, uuid, _)) => NotificationType::DeviceBle((device, uuid)),
+            Notification::Sport(_) => NotificationType::Sport,
+            Notification::Lap => NotificationType::Lap,
+            Notification::DeviceInfo((device, _)) => NotificationType::DeviceInfo(device),
+            Notification::MaxPower(_) => NotificationType::MaxPower,
+            Notification::DeviceCscFeature((device, _)) => {
+                NotificationType::DeviceCscFeature(device)
+            }
+            Notification::Workout(_) => NotificationType::Workout,
+            Notification::WorkoutPosition(_) => NotificationType::WorkoutPosition,
         };
         // I can't imagine why this would fail...
         let key = self
@@ -81,10 +226,285 @@ impl TelemetryDb {
         (self.decode_key(pair.0), self.decode_value(pair.1))
     }
 
+    // Calibrated wheel circumferences (in meters) are kept per bike, separate
+    // from session data.
+    pub fn get_wheel_circumference(&self, bike: &str) -> sled::Result<Option<f32>> {
+        let tree = self.db.open_tree("wheel_circumference")?;
+        Ok(tree
+            .get(bike)?
+            .map(|v| self.serial_config.deserialize(&v).unwrap()))
+    }
+
+    pub fn set_wheel_circumference(&self, bike: &str, circumference: f32) -> sled::Result<()> {
+        let tree = self.db.open_tree("wheel_circumference")?;
+        tree.insert(bike, self.serial_config.serialize(&circumference).unwrap())?;
+        Ok(())
+    }
+
+    // The profile each session was recorded under, also kept apart from the
+    // session data.
+    pub fn get_session_profile(&self, session_key: u64) -> sled::Result<Option<String>> {
+        let tree = self.db.open_tree("session_profile")?;
+        Ok(tree
+            .get(self.serial_config.serialize(&session_key).unwrap())?
+            .map(|v| self.serial_config.deserialize(&v).unwrap()))
+    }
+
+    pub fn set_session_profile(&self, session_key: u64, profile: &str) -> sled::Result<()> {
+        let tree = self.db.open_tree("session_profile")?;
+        tree.insert(
+            self.serial_config.serialize(&session_key).unwrap(),
+            self.serial_config.serialize(profile).unwrap(),
+        )?;
+        Ok(())
+    }
+
+    // When each session started (in seconds since the unix epoch), which is
+    // what exports are timestamped by.  Sessions from before this was recorded
+    // were always keyed by their start time, so that's used instead.
+    pub fn get_session_start(&self, session_key: u64) -> sled::Result<u64> {
+        let tree = self.db.open_tree("session_start")?;
+        Ok(tree
+            .get(self.serial_config.serialize(&session_key).unwrap())?
+            .map_or(session_key, |v| self.serial_config.deserialize(&v).unwrap()))
+    }
+
+    pub fn set_session_start(&self, session_key: u64, start: u64) -> sled::Result<()> {
+        let tree = self.db.open_tree("session_start")?;
+        tree.insert(
+            self.serial_config.serialize(&session_key).unwrap(),
+            self.serial_config.serialize(&start).unwrap(),
+        )?;
+        Ok(())
+    }
+
+    // Keys a new session by the chosen strategy and records when it started
+    pub fn start_session(&self, strategy: SessionKeyStrategy, now: u64) -> sled::Result<u64> {
+        let session_key = match strategy {
+            SessionKeyStrategy::UnixSeconds => self.next_session_key(now)?,
+            SessionKeyStrategy::Counter => self.next_counter_session_key()?,
+        };
+        self.set_session_start(session_key, now)?;
+        Ok(session_key)
+    }
+
+    // The last key handed out is persisted, so a session that never recorded
+    // anything still isn't reused.  It also never goes behind an existing
+    // session, which could have been keyed by the clock.
+    fn next_counter_session_key(&self) -> sled::Result<u64> {
+        let tree = self.db.open_tree("session_counter")?;
+        let last: Option<u64> = tree
+            .get("last")?
+            .map(|v| self.serial_config.deserialize(&v).unwrap());
+        let most_recent = self.get_most_recent_session()?;
+        let next = u64::max(last.map_or(1, |k| k + 1), most_recent.map_or(1, |k| k + 1));
+        tree.insert("last", self.serial_config.serialize(&next).unwrap())?;
+        Ok(next)
+    }
+
+    // Raw captures are keyed by source as well as time, so simultaneous
+    // notifications from different devices can't collide.
+    pub fn insert_raw(
+        &self,
+        session_key: u64,
+        elapsed: Duration,
+        source: &str,
+        uuid: Uuid,
+        value: &[u8],
+    ) -> sled::Result<()> {
+        let tree = self.db.open_tree("raw")?;
+        let key = self
+            .serial_config
+            .serialize(&(session_key, elapsed, source))
+            .unwrap();
+        let raw = RawNotification {
+            elapsed,
+            source: source.to_string(),
+            uuid,
+            value: value.to_vec(),
+        };
+        tree.insert(key, self.serial_config.serialize(&raw).unwrap())?;
+        Ok(())
+    }
+
+    pub fn get_raw_entries(&self, session_key: u64) -> sled::Result<Vec<RawNotification>> {
+        let tree = self.db.open_tree("raw")?;
+        let (start, end) = self.session_bounds(session_key);
+        tree.range(start..end)
+            .map(|x| x.map(|(_, v)| self.serial_config.deserialize(&v).unwrap()))
+            .collect()
+    }
+
+    // Writes every key/value of every tree as length-prefixed bytes, so rides
+    // can be moved to a DB written by a different version of sled.
+    pub fn export_all(&self, mut w: impl Write) -> sled::Result<()> {
+        w.write_all(BACKUP_MAGIC)?;
+        for name in self.db.tree_names() {
+            let tree = self.db.open_tree(&name)?;
+            for pair in tree.iter() {
+                let (k, v) = pair?;
+                write_chunk(&mut w, &name)?;
+                write_chunk(&mut w, &k)?;
+                write_chunk(&mut w, &v)?;
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    // Inserts everything from an export_all, overwriting any entries with the
+    // same key.
+    pub fn import_all(&self, mut r: impl Read) -> sled::Result<()> {
+        let mut magic = vec![0; BACKUP_MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if magic != BACKUP_MAGIC {
+            let e = io::Error::new(io::ErrorKind::InvalidData, "Not a rust-cycle backup");
+            return Err(e.into());
+        }
+        while let Some(name) = read_chunk(&mut r, true)? {
+            // A tree name without its key and value means the backup was cut short
+            let k = read_chunk(&mut r, false)?.unwrap();
+            let v = read_chunk(&mut r, false)?.unwrap();
+            self.db.open_tree(name)?.insert(k, v)?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // Blocks until everything inserted so far is durably on disk
+    pub fn flush(&self) -> sled::Result<usize> {
+        self.db.flush()
+    }
+
+    // To make sure we never go backwards (the real time clock is not reliable
+    // especially after a crash or if wifi is unavailable), we make the session
+    // key larger than the most recent previous one.  However, one very late
+    // clock would then force every future key to be huge, so once our clock
+    // looks sane again, sessions implausibly far in the future are ignored
+    // and we key relative to the latest believable session instead.
+    // TODO: The bogus session will still be considered the most recent one
+    // until real time catches up to it.
+    pub fn next_session_key(&self, now: u64) -> sled::Result<u64> {
+        let most_recent = if now >= EARLIEST_PLAUSIBLE_NOW {
+            let most_recent = self.get_most_recent_session()?;
+            if most_recent.map_or(false, |k| k > now + MAX_CLOCK_SKEW) {
+                println!(
+                    "Ignoring session keys after {}, they are implausibly ahead of the clock",
+                    now + MAX_CLOCK_SKEW
+                );
+                self.get_previous_session(now + MAX_CLOCK_SKEW)?
+            } else {
+                most_recent
+            }
+        } else {
+            self.get_most_recent_session()?
+        };
+        Ok(u64::max(now, most_recent.map_or(0, |k| k + 1)))
+    }
+
+    // Every session key, oldest first
+    pub fn get_sessions(&self) -> sled::Result<Vec<u64>> {
+        let mut sessions = Vec::new();
+        let mut o_session = self.get_most_recent_session()?;
+        while let Some(session) = o_session {
+            sessions.push(session);
+            o_session = self.get_previous_session(session)?;
+        }
+        sessions.reverse();
+        Ok(sessions)
+    }
+
+    // Up to limit sessions (most recent first), each with how many entries it
+    // has recorded.  That's every notification (so several a second), not the
+    // one a second records they're exported as, which would mean decoding the
+    // whole session.
+    pub fn list_sessions(&self, limit: usize) -> sled::Result<Vec<(u64, usize)>> {
+        self.get_sessions()?
+            .into_iter()
+            .rev()
+            .take(limit)
+            .map(|session| Ok((session, self.count_session_entries(session)?)))
+            .collect()
+    }
+
+    // How many entries a session has recorded.  They're only counted, not
+    // decoded, but that's still a read of every one.
+    pub fn count_session_entries(&self, session_key: u64) -> sled::Result<usize> {
+        let (start, end) = self.session_bounds(session_key);
+        self.db
+            .range(start..end)
+            .try_fold(0, |count, x| x.map(|_| count + 1))
+    }
+
+    // Every key of a session (or its raw captures) starts with its session
+    // key, so they're all in [start, end).
+    fn session_bounds(&self, session_key: u64) -> (Vec<u8>, Vec<u8>) {
+        (
+            self.serial_config.serialize(&session_key).unwrap(),
+            self.serial_config.serialize(&(session_key + 1)).unwrap(),
+        )
+    }
+
+    // Removes a session's entries, along with its raw captures, profile and
+    // start.  Returns how many entries there were.
+    pub fn delete_session(&self, session_key: u64) -> sled::Result<usize> {
+        let (start, end) = self.session_bounds(session_key);
+        let mut count = 0;
+        for x in self.db.range(start.clone()..end.clone()).keys() {
+            self.db.remove(x?)?;
+            count += 1;
+        }
+        let raw = self.db.open_tree("raw")?;
+        for x in raw.range(start.clone()..end).keys() {
+            raw.remove(x?)?;
+        }
+        self.db.open_tree("session_profile")?.remove(&start)?;
+        self.db.open_tree("session_start")?.remove(&start)?;
+        Ok(count)
+    }
+
     pub fn get_most_recent_session(&self) -> sled::Result<Option<u64>> {
         self.get_previous_session(u64::max_value())
     }
 
+    // How far into a session its last entry was (if it has any)
+    pub fn last_entry_elapsed(&self, session_key: u64) -> sled::Result<Option<Duration>> {
+        let (start, end) = self.session_bounds(session_key);
+        match self.db.range(start..end).next_back() {
+            Some(x) => Ok(Some(self.decode_key(x?.0).1)),
+            None => Ok(None),
+        }
+    }
+
+    // The most recent session, if its last entry was no more than max_gap
+    // before now (both in seconds since the unix epoch), which is what a ride
+    // cut short by a crash (or restart) looks like.  It comes with how far into
+    // the session now is, so that recording can pick up where it left off,
+    // with the gap in between.  That's session time, not how far into its
+    // workout the ride got (which is recorded separately).
+    pub fn interrupted_session(
+        &self,
+        now: u64,
+        max_gap: Duration,
+    ) -> sled::Result<Option<(u64, Duration)>> {
+        let session_key = match self.get_most_recent_session()? {
+            Some(session_key) => session_key,
+            None => return Ok(None),
+        };
+        let last_elapsed = match self.last_entry_elapsed(session_key)? {
+            Some(last_elapsed) => last_elapsed,
+            None => return Ok(None),
+        };
+        let start = self.get_session_start(session_key)?;
+        let last_entry = start + last_elapsed.as_secs();
+        Ok(now
+            .checked_sub(last_entry)
+            .filter(|gap| *gap <= max_gap.as_secs())
+            // Now is only known to the second, so it can't be allowed to fall
+            // before the last entry.
+            .map(|_| (session_key, Duration::from_secs(now - start).max(last_elapsed))))
+    }
+
     pub fn get_previous_session(&self, key: u64) -> sled::Result<Option<u64>> {
         let x = self
             .db
@@ -96,8 +516,7 @@ impl TelemetryDb {
         &self,
         session_key: u64,
     ) -> impl Iterator<Item = sled::Result<(Duration, Notification)>> + '_ {
-        let start = self.serial_config.serialize(&session_key).unwrap();
-        let end = self.serial_config.serialize(&(session_key + 1)).unwrap();
+        let (start, end) = self.session_bounds(session_key);
         self.db.range(start..end).map(move |x| {
             x.map(|xx| {
                 let decoded = self.decode(xx);
@@ -138,3 +557,301 @@ impl TelemetryDb {
         }
     }
 }
+
+fn write_chunk(w: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    w.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    w.write_all(bytes)
+}
+
+// None only if the input has cleanly ended (and that's allowed here)
+fn read_chunk(r: &mut impl Read, may_end: bool) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match r.read(&mut len[read..])? {
+            0 if read == 0 && may_end => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => read += n,
+        }
+    }
+    let mut bytes = vec![0; u32::from_be_bytes(len) as usize];
+    r.read_exact(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        open_temporary, Notification, RawNotification, SessionClock, SessionKeyStrategy,
+        TelemetryDb, MAX_CLOCK_SKEW,
+    };
+    use crate::peripherals::Device;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    const NOW: u64 = 1700000000;
+
+    fn temporary_db(session_keys: &[u64]) -> TelemetryDb {
+        let telemetry_db = open_temporary().unwrap();
+        for k in session_keys {
+            telemetry_db
+                .insert(
+                    *k,
+                    Duration::from_secs(0),
+                    Notification::DeviceStatus((Device::Hrm, None)),
+                )
+                .unwrap();
+        }
+        telemetry_db
+    }
+
+    #[test]
+    fn next_session_key_uses_the_clock() {
+        let db = temporary_db(&[NOW - 100]);
+        assert_eq!(db.next_session_key(NOW).unwrap(), NOW);
+    }
+
+    #[test]
+    fn next_session_key_never_goes_backwards() {
+        let db = temporary_db(&[NOW + 100]);
+        assert_eq!(db.next_session_key(NOW).unwrap(), NOW + 101);
+    }
+
+    #[test]
+    fn next_session_key_ignores_implausible_future_sessions() {
+        let db = temporary_db(&[NOW - 100, NOW + MAX_CLOCK_SKEW * 100]);
+        assert_eq!(db.next_session_key(NOW).unwrap(), NOW);
+    }
+
+    #[test]
+    fn next_session_key_stays_monotonic_with_an_unset_clock() {
+        let db = temporary_db(&[NOW]);
+        assert_eq!(db.next_session_key(100).unwrap(), NOW + 1);
+    }
+
+    #[test]
+    fn counter_session_keys_count_up_from_one() {
+        let db = temporary_db(&[]);
+        assert_eq!(db.start_session(SessionKeyStrategy::Counter, NOW).unwrap(), 1);
+        assert_eq!(db.start_session(SessionKeyStrategy::Counter, NOW).unwrap(), 2);
+    }
+
+    #[test]
+    fn counter_session_keys_follow_existing_sessions() {
+        let db = temporary_db(&[NOW]);
+        assert_eq!(db.start_session(SessionKeyStrategy::Counter, 100).unwrap(), NOW + 1);
+    }
+
+    #[test]
+    fn session_start_is_kept_apart_from_the_key() {
+        let db = temporary_db(&[]);
+        let session_key = db.start_session(SessionKeyStrategy::Counter, NOW).unwrap();
+        assert_eq!(db.get_session_start(session_key).unwrap(), NOW);
+        let session_key = db.start_session(SessionKeyStrategy::UnixSeconds, NOW).unwrap();
+        assert_eq!(db.get_session_start(session_key).unwrap(), NOW);
+    }
+
+    #[test]
+    fn session_start_defaults_to_the_key() {
+        let db = temporary_db(&[NOW]);
+        assert_eq!(db.get_session_start(NOW).unwrap(), NOW);
+    }
+
+    #[test]
+    fn session_key_strategy_from_name() {
+        assert_eq!(SessionKeyStrategy::parse("seconds"), Some(SessionKeyStrategy::UnixSeconds));
+        assert_eq!(SessionKeyStrategy::parse("counter"), Some(SessionKeyStrategy::Counter));
+        assert_eq!(SessionKeyStrategy::parse("random"), None);
+    }
+
+    #[test]
+    fn raw_entries_are_kept_per_session_and_in_order() {
+        let db = temporary_db(&[]);
+        let uuid = Uuid::from_u128(0x2A37);
+        let later = Duration::from_nanos(1_000_000_001);
+        let earlier = Duration::from_nanos(1_000_000_000);
+        db.insert_raw(NOW, later, "AA:BB", uuid, &[2]).unwrap();
+        db.insert_raw(NOW, earlier, "AA:BB", uuid, &[1]).unwrap();
+        db.insert_raw(NOW + 1, earlier, "AA:BB", uuid, &[3]).unwrap();
+        assert_eq!(
+            db.get_raw_entries(NOW).unwrap(),
+            vec![
+                RawNotification {
+                    elapsed: earlier,
+                    source: "AA:BB".to_string(),
+                    uuid,
+                    value: vec![1],
+                },
+                RawNotification {
+                    elapsed: later,
+                    source: "AA:BB".to_string(),
+                    uuid,
+                    value: vec![2],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_entries_do_not_create_sessions() {
+        let db = temporary_db(&[]);
+        db.insert_raw(NOW, Duration::from_secs(0), "AA:BB", Uuid::nil(), &[1])
+            .unwrap();
+        assert_eq!(db.get_most_recent_session().unwrap(), None);
+    }
+
+    #[test]
+    fn list_sessions_is_most_recent_first_with_entry_counts() {
+        let db = temporary_db(&[NOW, NOW + 2, NOW + 5]);
+        db.insert(NOW + 2, Duration::from_secs(1), Notification::Lap).unwrap();
+        db.insert(NOW + 2, Duration::from_secs(2), Notification::Lap).unwrap();
+        assert_eq!(
+            db.list_sessions(10).unwrap(),
+            vec![(NOW + 5, 1), (NOW + 2, 3), (NOW, 1)]
+        );
+    }
+
+    #[test]
+    fn list_sessions_stops_at_the_limit() {
+        let db = temporary_db(&[NOW, NOW + 2, NOW + 5]);
+        assert_eq!(db.list_sessions(2).unwrap(), vec![(NOW + 5, 1), (NOW + 2, 1)]);
+        assert_eq!(db.list_sessions(0).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn list_sessions_without_any() {
+        let db = temporary_db(&[]);
+        assert_eq!(db.list_sessions(10).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn delete_session_leaves_its_neighbors() {
+        let db = temporary_db(&[NOW, NOW + 1, NOW + 2]);
+        db.insert(NOW + 1, Duration::from_secs(1), Notification::Lap).unwrap();
+        db.insert(NOW + 1, Duration::from_secs(u32::MAX as u64), Notification::Lap).unwrap();
+        db.set_session_profile(NOW + 1, "Zenia").unwrap();
+        db.set_session_profile(NOW + 2, "Zenia").unwrap();
+        db.insert_raw(NOW + 1, Duration::from_secs(0), "AA:BB", Uuid::nil(), &[1])
+            .unwrap();
+        db.insert_raw(NOW + 2, Duration::from_secs(0), "AA:BB", Uuid::nil(), &[2])
+            .unwrap();
+
+        assert_eq!(db.delete_session(NOW + 1).unwrap(), 3);
+
+        assert_eq!(db.get_sessions().unwrap(), vec![NOW, NOW + 2]);
+        assert_eq!(db.get_session_entries(NOW + 1).count(), 0);
+        assert_eq!(db.get_session_entries(NOW).count(), 1);
+        assert_eq!(db.get_session_entries(NOW + 2).count(), 1);
+        assert_eq!(db.get_session_profile(NOW + 1).unwrap(), None);
+        assert_eq!(db.get_session_profile(NOW + 2).unwrap(), Some("Zenia".to_string()));
+        assert_eq!(db.get_raw_entries(NOW + 1).unwrap(), vec![]);
+        assert_eq!(db.get_raw_entries(NOW + 2).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn delete_missing_session_deletes_nothing() {
+        let db = temporary_db(&[NOW]);
+        assert_eq!(db.delete_session(NOW + 1).unwrap(), 0);
+        assert_eq!(db.get_sessions().unwrap(), vec![NOW]);
+    }
+
+    #[test]
+    fn last_entry_elapsed_of_each_session() {
+        let db = temporary_db(&[NOW, NOW + 1]);
+        db.insert(NOW, Duration::from_millis(90500), Notification::Lap).unwrap();
+        assert_eq!(db.last_entry_elapsed(NOW).unwrap(), Some(Duration::from_millis(90500)));
+        assert_eq!(db.last_entry_elapsed(NOW + 1).unwrap(), Some(Duration::from_secs(0)));
+        assert_eq!(db.last_entry_elapsed(NOW + 2).unwrap(), None);
+    }
+
+    #[test]
+    fn interrupted_session_is_the_most_recent_when_its_last_entry_is_recent() {
+        let db = temporary_db(&[NOW - 5000, NOW]);
+        db.set_session_start(NOW, NOW + 10).unwrap();
+        db.insert(NOW, Duration::from_millis(90500), Notification::Lap).unwrap();
+        let max_gap = Duration::from_secs(300);
+        // Last written at NOW + 100
+        assert_eq!(
+            db.interrupted_session(NOW + 130, max_gap).unwrap(),
+            Some((NOW, Duration::from_secs(120)))
+        );
+        assert_eq!(
+            db.interrupted_session(NOW + 400, max_gap).unwrap(),
+            Some((NOW, Duration::from_secs(390)))
+        );
+        assert_eq!(db.interrupted_session(NOW + 401, max_gap).unwrap(), None);
+        // Never earlier than the last entry
+        assert_eq!(
+            db.interrupted_session(NOW + 100, max_gap).unwrap(),
+            Some((NOW, Duration::from_millis(90500)))
+        );
+        // A clock that's gone backwards can't tell us anything
+        assert_eq!(db.interrupted_session(NOW, max_gap).unwrap(), None);
+    }
+
+    #[test]
+    fn interrupted_session_of_nothing() {
+        let db = temporary_db(&[]);
+        assert_eq!(db.interrupted_session(NOW, Duration::from_secs(300)).unwrap(), None);
+    }
+
+    // Even when the session is far longer than we've been up
+    #[test]
+    fn a_resumed_session_clock_carries_on_from_where_it_was() {
+        let clock = SessionClock::resumed(Duration::from_secs(36000));
+        assert!(clock.elapsed() >= Duration::from_secs(36000));
+        assert!(clock.elapsed() < Duration::from_secs(36001));
+    }
+
+    #[test]
+    fn get_sessions_is_oldest_first() {
+        let db = temporary_db(&[NOW + 5, NOW, NOW + 2]);
+        assert_eq!(db.get_sessions().unwrap(), vec![NOW, NOW + 2, NOW + 5]);
+    }
+
+    #[test]
+    fn export_then_import_round_trips() {
+        let db = temporary_db(&[NOW, NOW + 1]);
+        db.set_session_profile(NOW, "Zenia").unwrap();
+        db.set_wheel_circumference("Road", 2.105).unwrap();
+        db.insert_raw(NOW, Duration::from_secs(1), "AA:BB", Uuid::nil(), &[1])
+            .unwrap();
+        let mut backup = Vec::new();
+        db.export_all(&mut backup).unwrap();
+
+        let restored = temporary_db(&[]);
+        restored.import_all(&backup[..]).unwrap();
+        assert_eq!(restored.get_sessions().unwrap(), vec![NOW, NOW + 1]);
+        assert_eq!(restored.get_session_profile(NOW).unwrap(), Some("Zenia".to_string()));
+        assert_eq!(restored.get_wheel_circumference("Road").unwrap(), Some(2.105));
+        assert_eq!(restored.get_raw_entries(NOW).unwrap(), db.get_raw_entries(NOW).unwrap());
+        assert_eq!(
+            restored.get_session_entries(NOW).count(),
+            db.get_session_entries(NOW).count()
+        );
+    }
+
+    #[test]
+    fn import_rejects_other_files() {
+        let db = temporary_db(&[]);
+        assert!(db.import_all(&b"not a backup at all"[..]).is_err());
+    }
+
+    #[test]
+    fn import_rejects_truncated_backups() {
+        let db = temporary_db(&[NOW]);
+        let mut backup = Vec::new();
+        db.export_all(&mut backup).unwrap();
+        backup.pop();
+        assert!(temporary_db(&[]).import_all(&backup[..]).is_err());
+    }
+
+    #[test]
+    fn session_profiles_are_kept_per_session() {
+        let db = temporary_db(&[NOW, NOW + 1]);
+        db.set_session_profile(NOW, "Zenia").unwrap();
+        assert_eq!(db.get_session_profile(NOW).unwrap(), Some("Zenia".to_string()));
+        assert_eq!(db.get_session_profile(NOW + 1).unwrap(), None);
+        assert_eq!(db.get_sessions().unwrap(), vec![NOW, NOW + 1]);
+    }
+}
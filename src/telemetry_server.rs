@@ -1,27 +1,73 @@
-use crate::db_sessions_to_fit;
+use crate::{
+    db_sessions_to_csv, db_sessions_to_index_json, db_sessions_to_power_compare_csv,
+    db_sessions_to_raw_jsonl, db_sessions_to_tcx, write_db_sessions_fit,
+};
+use crate::fit::TimeWindow;
+use crate::live::LiveBuffer;
+use crate::status::{free_disk_bytes, SharedStatus};
 use crate::telemetry_db::TelemetryDb;
+use crate::utils::lock_or_recover;
+use flate2::{write::GzEncoder, Compression};
 use nom::{
     branch::alt,
     bytes::complete::tag,
     character::complete::digit1,
-    combinator::map,
-    sequence::{pair, preceded, terminated},
+    combinator::{all_consuming, map, opt, rest},
+    sequence::{pair, preceded},
     IResult,
 };
-use std::{mem, str::FromStr, sync::Arc, thread, thread::JoinHandle, time::Duration};
+use std::{
+    fs::File,
+    io::Read,
+    mem,
+    net::SocketAddr,
+    os::unix::net::UnixStream,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 use tiny_http::{Header, Method, Response, Server, StatusCode};
 
+// A six digit PIN can be guessed, given enough tries, so after this many wrong
+// ones in a row, every request is refused for a while.
+const MAX_PIN_FAILURES: u32 = 5;
+const PIN_LOCKOUT: Duration = Duration::from_secs(60);
+
+// Listing a session means counting all of its entries, so the index only goes
+// back this many sessions.
+const INDEX_LIMIT: usize = 100;
+
 pub struct TelemetryServer {
     running: Option<Arc<()>>,
     join_handle: Option<JoinHandle<()>>,
 }
 
 impl TelemetryServer {
-    pub fn new(db: TelemetryDb) -> TelemetryServer {
+    // Every request must include the PIN, either as a "pin" query parameter or
+    // as a bearer token in its Authorization header.
+    pub fn new(
+        db: TelemetryDb,
+        live: Arc<Mutex<LiveBuffer>>,
+        status: SharedStatus,
+        pin: String,
+    ) -> TelemetryServer {
+        TelemetryServer::bind(([0, 0, 0, 0], 8080).into(), db, live, status, pin)
+    }
+
+    fn bind(
+        addr: SocketAddr,
+        db: TelemetryDb,
+        live: Arc<Mutex<LiveBuffer>>,
+        status: SharedStatus,
+        pin: String,
+    ) -> TelemetryServer {
         let running_for_thread = Arc::new(());
         let running = Some(running_for_thread.clone());
         let join_handle = Some(thread::spawn(move || {
-            let server = Server::http("0.0.0.0:8080").unwrap();
+            let server = Server::http(addr).unwrap();
+            let mut pin_guard = PinGuard::new();
             loop {
                 // Every 100 millis, we check to see if the server should
                 // continue running
@@ -37,18 +83,82 @@ impl TelemetryServer {
                     Some(request) => {
                         #[allow(unused_assignments)]
                         let mut session: Vec<u8> = vec![];
-                        // TODO: Some sort of simple auth (maybe a random pin on
-                        // device?)
+                        // Sent instead of the session, when it's too big to buffer
+                        let mut o_stream: Option<UnixStream> = None;
+                        let (url, o_query_pin) = take_pin(request.url());
+                        let pin_check = pin_guard.check(
+                            &pin,
+                            o_query_pin.or_else(|| bearer_token(request.headers())),
+                            Instant::now(),
+                        );
                         // TODO: Handle more than just the latest
-                        let response = match parse_url(request.url()) {
-                            Ok(url) => {
+                        let response = match (&url[..], parse_url(&url)) {
+                            _ if pin_check == PinCheck::LockedOut => {
+                                Response::new(StatusCode(429), vec![], &[][..], None, None)
+                            }
+                            _ if pin_check == PinCheck::Wrong => Response::new(
+                                StatusCode(401),
+                                vec![Header::from_bytes(
+                                    &b"WWW-Authenticate"[..],
+                                    &b"Bearer"[..],
+                                )
+                                .unwrap()],
+                                &[][..],
+                                None,
+                                None,
+                            ),
+                            ("/live/recent.json", _) => {
+                                if request.method() == &Method::Get {
+                                    session = lock_or_recover(&live).to_json().into_bytes();
+                                    Response::new(
+                                        StatusCode(200),
+                                        vec![Header::from_bytes(
+                                            &b"Content-Type"[..],
+                                            &b"application/json"[..],
+                                        )
+                                        .unwrap()],
+                                        &session[..],
+                                        None,
+                                        None,
+                                    )
+                                } else {
+                                    Response::new(StatusCode(405), vec![], &[][..], None, None)
+                                }
+                            }
+                            ("/status", _) => {
+                                if request.method() == &Method::Get {
+                                    // Checking the disk is slow, so it's done
+                                    // without holding the lock
+                                    let free_disk = free_disk_bytes();
+                                    let status = lock_or_recover(&status);
+                                    session =
+                                        status.to_json(status.uptime(), free_disk).into_bytes();
+                                    Response::new(
+                                        StatusCode(200),
+                                        vec![Header::from_bytes(
+                                            &b"Content-Type"[..],
+                                            &b"application/json"[..],
+                                        )
+                                        .unwrap()],
+                                        &session[..],
+                                        None,
+                                        None,
+                                    )
+                                } else {
+                                    Response::new(StatusCode(405), vec![], &[][..], None, None)
+                                }
+                            }
+                            (_, Ok((query, _))) if parse_time_window(query).is_none() => {
+                                Response::new(StatusCode(400), vec![], &[][..], None, None)
+                            }
+                            (_, Ok((query, (url_key, file_type)))) => {
                                 if request.method() == &Method::Get {
-                                    let key = match url {
-                                        (_, UrlKey::Latest) => {
+                                    let key = match url_key {
+                                        UrlKey::Latest => {
                                             db.get_most_recent_session().unwrap().map(|k| (k, k))
                                         }
-                                        (_, UrlKey::Key(k)) => Some((k, k)),
-                                        (_, UrlKey::KeyRange((a, b))) => Some((a, b)),
+                                        UrlKey::Key(k) => Some((k, k)),
+                                        UrlKey::KeyRange((a, b)) => Some((a, b)),
                                     };
                                     match key {
                                         Some((a, b)) => {
@@ -64,19 +174,58 @@ impl TelemetryServer {
                                                     None,
                                                 ),
                                                 Some(session_keys) => {
-                                                    session = db_sessions_to_fit(
-                                                        &db,
-                                                        session_keys.into_iter(),
-                                                    )
+                                                    // FIT files are binary and
+                                                    // big, so are worth compressing
+                                                    let is_gzip = file_type == FileType::Fit
+                                                        && accepts_gzip(request.headers());
                                                     // TODO: 500
-                                                    .unwrap();
+                                                    session = match file_type {
+                                                        FileType::Fit => {
+                                                            o_stream = Some(stream_fit(
+                                                                db.clone(),
+                                                                session_keys,
+                                                                // Already checked
+                                                                parse_time_window(query).unwrap(),
+                                                                is_gzip,
+                                                            ));
+                                                            vec![]
+                                                        }
+                                                        FileType::Tcx => db_sessions_to_tcx(
+                                                            &db,
+                                                            session_keys.into_iter(),
+                                                        )
+                                                        .unwrap()
+                                                        .into_bytes(),
+                                                        FileType::Csv => db_sessions_to_csv(
+                                                            &db,
+                                                            session_keys.into_iter(),
+                                                        )
+                                                        .unwrap()
+                                                        .into_bytes(),
+                                                        FileType::PowerCompareCsv => {
+                                                            db_sessions_to_power_compare_csv(
+                                                                &db,
+                                                                session_keys.into_iter(),
+                                                            )
+                                                            .unwrap()
+                                                            .into_bytes()
+                                                        }
+                                                        FileType::RawJsonl => {
+                                                            db_sessions_to_raw_jsonl(
+                                                                &db,
+                                                                session_keys.into_iter(),
+                                                            )
+                                                            .unwrap()
+                                                            .into_bytes()
+                                                        }
+                                                    };
                                                     let mut r = Response::new(
                                                         StatusCode(200),
                                                         // TODO; Header for next most recent
                                                         vec![
                                                             Header::from_bytes(
                                                                 &b"Content-Type"[..],
-                                                                &b"application/vnd.ant.fit"[..],
+                                                                file_type.content_type(),
                                                             )
                                                             .unwrap(),
                                                             Header::from_bytes(
@@ -94,6 +243,15 @@ impl TelemetryServer {
                                                         None,
                                                         None,
                                                     );
+                                                    if is_gzip {
+                                                        r.add_header(
+                                                            Header::from_bytes(
+                                                                &b"Content-Encoding"[..],
+                                                                &b"gzip"[..],
+                                                            )
+                                                            .unwrap(),
+                                                        )
+                                                    }
                                                     if let Ok(Some(key)) =
                                                         db.get_previous_session(a)
                                                     {
@@ -125,9 +283,97 @@ impl TelemetryServer {
                                     Response::new(StatusCode(405), vec![], &[][..], None, None)
                                 }
                             }
-                            Err(_) => Response::new(StatusCode(404), vec![], &[][..], None, None),
+                            (url, Err(_)) if parse_session_url(url).is_ok() => {
+                                if request.method() == &Method::Delete {
+                                    // Already checked
+                                    let (_, key) = parse_session_url(url).unwrap();
+                                    // The session being recorded can't be
+                                    // deleted out from under the ride
+                                    let recording =
+                                        lock_or_recover(&status).session_key() == Some(key);
+                                    let deleted = if recording {
+                                        None
+                                    } else {
+                                        Some(db.delete_session(key))
+                                    };
+                                    match deleted {
+                                        None => Response::new(
+                                            StatusCode(409),
+                                            vec![],
+                                            &[][..],
+                                            None,
+                                            None,
+                                        ),
+                                        Some(Ok(0)) => Response::new(
+                                            StatusCode(404),
+                                            vec![],
+                                            &[][..],
+                                            None,
+                                            None,
+                                        ),
+                                        Some(Ok(count)) => {
+                                            session = format!(r#"{{"deleted":{}}}"#, count)
+                                                .into_bytes();
+                                            Response::new(
+                                                StatusCode(200),
+                                                vec![Header::from_bytes(
+                                                    &b"Content-Type"[..],
+                                                    &b"application/json"[..],
+                                                )
+                                                .unwrap()],
+                                                &session[..],
+                                                None,
+                                                None,
+                                            )
+                                        }
+                                        Some(Err(_)) => Response::new(
+                                            StatusCode(500),
+                                            vec![],
+                                            &[][..],
+                                            None,
+                                            None,
+                                        ),
+                                    }
+                                } else {
+                                    Response::new(StatusCode(405), vec![], &[][..], None, None)
+                                }
+                            }
+                            (url, Err(_)) => match parse_index_url(url) {
+                                Ok((_, o_profile)) => {
+                                    if request.method() == &Method::Get {
+                                        // TODO: 500
+                                        session = db_sessions_to_index_json(
+                                            &db,
+                                            o_profile.as_ref().map(|p| &p[..]),
+                                            INDEX_LIMIT,
+                                        )
+                                        .unwrap()
+                                        .into_bytes();
+                                        Response::new(
+                                            StatusCode(200),
+                                            vec![Header::from_bytes(
+                                                &b"Content-Type"[..],
+                                                &b"application/json"[..],
+                                            )
+                                            .unwrap()],
+                                            &session[..],
+                                            None,
+                                            None,
+                                        )
+                                    } else {
+                                        Response::new(StatusCode(405), vec![], &[][..], None, None)
+                                    }
+                                }
+                                Err(_) => {
+                                    Response::new(StatusCode(404), vec![], &[][..], None, None)
+                                }
+                            },
                         };
-                        request.respond(response).unwrap();
+                        match o_stream {
+                            Some(stream) => request.respond(response.with_data(stream, None)),
+                            None => request.respond(response),
+                        }
+                        .unwrap();
                     }
                 }
             }
@@ -156,50 +402,597 @@ enum UrlKey {
     KeyRange((u64, u64)),
 }
 
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum FileType {
+    Fit,
+    Tcx,
+    // The same records as the FIT file, one row per second
+    Csv,
+    // Every power source side by side, for comparing power meters
+    PowerCompareCsv,
+    // Every raw notification, only present if captured
+    RawJsonl,
+}
+
+impl FileType {
+    fn content_type(&self) -> &'static [u8] {
+        match self {
+            FileType::Fit => b"application/vnd.ant.fit",
+            FileType::Tcx => b"application/vnd.garmin.tcx+xml",
+            FileType::Csv => b"text/csv",
+            FileType::PowerCompareCsv => b"text/csv",
+            FileType::RawJsonl => b"application/x-ndjson",
+        }
+    }
+}
+
+// Six random digits, fresh every boot
+pub fn generate_pin() -> String {
+    let mut bytes = [0; 8];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .expect("Could not generate a PIN");
+    format!("{:06}", u64::from_le_bytes(bytes) % 1_000_000)
+}
+
+// Compares every byte regardless of where they first differ, so response
+// times don't reveal how much of a guess was right.  Only the length (which
+// is the same for every PIN) can end it early.
+fn pin_matches(pin: &str, given: &str) -> bool {
+    pin.len() == given.len()
+        && pin
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PinCheck {
+    Right,
+    // Also when no PIN was given at all
+    Wrong,
+    // Too many wrong guesses, so even the right PIN isn't checked
+    LockedOut,
+}
+
+// Counts wrong guesses, so the PIN can't be found by trying every one.  Only
+// PINs that were actually given count against it, so visiting without one
+// (like from a bookmark missing it) doesn't lock anyone out.
+struct PinGuard {
+    failures: u32,
+    locked_until: Option<Instant>,
+}
+
+impl PinGuard {
+    fn new() -> PinGuard {
+        PinGuard { failures: 0, locked_until: None }
+    }
+
+    fn check(&mut self, pin: &str, o_given: Option<&str>, now: Instant) -> PinCheck {
+        if self.locked_until.map_or(false, |until| now < until) {
+            return PinCheck::LockedOut;
+        }
+        self.locked_until = None;
+        match o_given {
+            None => PinCheck::Wrong,
+            Some(given) if pin_matches(pin, given) => {
+                self.failures = 0;
+                PinCheck::Right
+            }
+            Some(_) => {
+                self.failures += 1;
+                if self.failures >= MAX_PIN_FAILURES {
+                    self.failures = 0;
+                    self.locked_until = Some(now + PIN_LOCKOUT);
+                }
+                PinCheck::Wrong
+            }
+        }
+    }
+}
+
+// Removes the "pin" query parameter (if any), so routing never sees it
+fn take_pin(url: &str) -> (String, Option<&str>) {
+    let (path, query) = match url.split_once('?') {
+        Some(split) => split,
+        None => return (url.to_string(), None),
+    };
+    let mut o_pin = None;
+    let params: Vec<&str> = query
+        .split('&')
+        .filter(|param| match param.strip_prefix("pin=") {
+            Some(pin) => {
+                o_pin = Some(pin);
+                false
+            }
+            None => true,
+        })
+        .collect();
+    if params.is_empty() {
+        (path.to_string(), o_pin)
+    } else {
+        (format!("{}?{}", path, params.join("&")), o_pin)
+    }
+}
+
+fn bearer_token(headers: &[Header]) -> Option<&str> {
+    headers
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+}
+
+// Whether gzip is among the listed encodings (and not refused with q=0)
+fn accepts_gzip(headers: &[Header]) -> bool {
+    headers
+        .iter()
+        .filter(|h| h.field.equiv("Accept-Encoding"))
+        .flat_map(|h| h.value.as_str().split(','))
+        .any(|encoding| {
+            let mut parts = encoding.split(';').map(str::trim);
+            let is_refused =
+                |p: &str| p.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0);
+            parts.next() == Some("gzip") && !parts.any(is_refused)
+        })
+}
+
+// Written on its own thread as it's read, so a long ride is never held in
+// memory all at once.  If writing fails partway, all we can do is stop early
+// (and log it), since the response has already started.
+fn stream_fit(
+    db: TelemetryDb,
+    session_keys: Vec<u64>,
+    window: TimeWindow,
+    is_gzip: bool,
+) -> UnixStream {
+    let (reader, writer) = UnixStream::pair().unwrap();
+    thread::spawn(move || {
+        let result = if is_gzip {
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            write_db_sessions_fit(&db, session_keys.into_iter(), window, false, &mut encoder)
+                .and_then(|()| encoder.finish().map(|_| ()).map_err(sled::Error::Io))
+        } else {
+            write_db_sessions_fit(&db, session_keys.into_iter(), window, false, writer)
+        };
+        if let Err(e) = result {
+            println!("Could not send FIT file: {:?}", e);
+        }
+    });
+    reader
+}
+
 // TODO: Terminate
 // TODO: This is a bit silly not to first put this through a standard URL parser
 // that would first break it into components (which _then_ could be more
 // thoroughly parsed).
-fn parse_url(i: &str) -> IResult<&str, UrlKey> {
-    terminated(
-        preceded(
-            tag("/workouts/"),
+fn parse_url(i: &str) -> IResult<&str, (UrlKey, FileType)> {
+    alt((
+        pair(
+            preceded(tag("/workouts/"), parse_url_key),
             alt((
-                map(tag("latest"), |_| UrlKey::Latest),
-                map(
-                    pair(
-                        map(digit1, |s| u64::from_str(s).unwrap()),
-                        preceded(tag("-"), map(digit1, |s| u64::from_str(s).unwrap())),
-                    ),
-                    |t| UrlKey::KeyRange(t),
-                ),
-                map(digit1, |s| UrlKey::Key(u64::from_str(s).unwrap())),
+                map(tag(".fit"), |_| FileType::Fit),
+                map(tag(".tcx"), |_| FileType::Tcx),
+                map(tag(".csv"), |_| FileType::Csv),
+                map(tag("/power-compare.csv"), |_| FileType::PowerCompareCsv),
             )),
         ),
-        tag(".fit"),
-    )(i)
+        pair(
+            preceded(tag("/debug/"), parse_url_key),
+            map(tag("/raw.jsonl"), |_| FileType::RawJsonl),
+        ),
+    ))(i)
+}
+
+// The session index, optionally only for one profile
+fn parse_index_url(i: &str) -> IResult<&str, Option<String>> {
+    all_consuming(preceded(
+        tag("/workouts"),
+        opt(map(preceded(tag("?profile="), rest), percent_decode)),
+    ))(i)
+}
+
+// A single session, by its key (with no file type)
+fn parse_session_url(i: &str) -> IResult<&str, u64> {
+    all_consuming(preceded(
+        tag("/workouts/"),
+        map(digit1, |s| u64::from_str(s).unwrap()),
+    ))(i)
+}
+
+// Query values are form encoded, so spaces may be '+' or '%20'.  Anything
+// that isn't a valid escape is left as is.
+fn percent_decode(s: &str) -> String {
+    let raw = s.as_bytes();
+    let mut bytes = Vec::with_capacity(raw.len());
+    let mut i = 0;
+    while i < raw.len() {
+        let escaped = if raw[i] == b'%' && i + 2 < raw.len() {
+            std::str::from_utf8(&raw[i + 1..i + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match (raw[i], escaped) {
+            (_, Some(b)) => {
+                bytes.push(b);
+                i += 3;
+            }
+            (b'+', None) => {
+                bytes.push(b' ');
+                i += 1;
+            }
+            (b, None) => {
+                bytes.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Bounds (in elapsed seconds) from a query like "?start=600&end=1200", for
+// exporting only part of a session.  Either may be left out, and other
+// parameters are ignored, but bounds that aren't numbers are None.
+fn parse_time_window(query: &str) -> Option<TimeWindow> {
+    let mut window = TimeWindow::default();
+    let params = match query.strip_prefix('?') {
+        Some(params) => params,
+        None if query.is_empty() => return Some(window),
+        None => return None,
+    };
+    for param in params.split('&') {
+        match param.split_once('=') {
+            Some(("start", value)) => window.start = Some(value.parse().ok()?),
+            Some(("end", value)) => window.end = Some(value.parse().ok()?),
+            _ => (),
+        }
+    }
+    Some(window)
+}
+
+fn parse_url_key(i: &str) -> IResult<&str, UrlKey> {
+    alt((
+        map(tag("latest"), |_| UrlKey::Latest),
+        map(
+            pair(
+                map(digit1, |s| u64::from_str(s).unwrap()),
+                preceded(tag("-"), map(digit1, |s| u64::from_str(s).unwrap())),
+            ),
+            |t| UrlKey::KeyRange(t),
+        ),
+        map(digit1, |s| UrlKey::Key(u64::from_str(s).unwrap())),
+    ))(i)
 }
 
 #[cfg(test)]
 mod tests {
+    use super::accepts_gzip;
+    use super::bearer_token;
+    use super::parse_index_url;
+    use super::parse_session_url;
+    use super::parse_time_window;
     use super::parse_url;
+    use super::percent_decode;
+    use super::pin_matches;
+    use super::take_pin;
+    use super::PinCheck;
+    use super::PinGuard;
+    use super::MAX_PIN_FAILURES;
+    use super::PIN_LOCKOUT;
+    use super::FileType;
+    use super::TelemetryServer;
     use super::UrlKey;
+    use crate::db_sessions_to_fit;
+    use crate::fit::TimeWindow;
+    use crate::live::LiveBuffer;
+    use crate::status::Status;
+    use crate::telemetry_db::{open_temporary, Notification, TelemetryDb};
+    use flate2::read::GzDecoder;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tiny_http::Header;
+
+    fn free_local_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    fn request(addr: SocketAddr, request: &str) -> Vec<u8> {
+        // The server binds on its own thread, so may not be listening yet
+        let mut stream = (0..50)
+            .find_map(|_| {
+                TcpStream::connect(addr).ok().or_else(|| {
+                    thread::sleep(Duration::from_millis(20));
+                    None
+                })
+            })
+            .unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    // Split into the status code, headers and body
+    fn get(addr: SocketAddr, url: &str, headers: &str) -> (String, String, Vec<u8>) {
+        let response = request(
+            addr,
+            &format!("GET {} HTTP/1.0\r\nConnection: close\r\n{}\r\n", url, headers),
+        );
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        let status = head.split(' ').nth(1).unwrap().to_string();
+        (status, head, response[split + 4..].to_vec())
+    }
+
+    fn serve(db: TelemetryDb) -> (TelemetryServer, SocketAddr) {
+        let addr = free_local_addr();
+        let server = TelemetryServer::bind(
+            addr,
+            db,
+            Arc::new(Mutex::new(LiveBuffer::new(1))),
+            Arc::new(Mutex::new(Status::new("v1"))),
+            "123456".to_string(),
+        );
+        (server, addr)
+    }
+
+    #[test]
+    fn requests_need_the_pin() {
+        let (_server, addr) = serve(open_temporary().unwrap());
+        let status = |url: &str, headers: &str| get(addr, url, headers).0;
+        assert_eq!(status("/status", ""), "401");
+        assert_eq!(status("/status?pin=654321", ""), "401");
+        assert_eq!(status("/status", "Authorization: Bearer 654321\r\n"), "401");
+        assert_eq!(status("/status?pin=123456", ""), "200");
+        assert_eq!(status("/status", "Authorization: Bearer 123456\r\n"), "200");
+    }
+
+    #[test]
+    fn too_many_wrong_pins_locks_everyone_out_for_a_while() {
+        let mut guard = PinGuard::new();
+        let now = Instant::now();
+        for _ in 1..MAX_PIN_FAILURES {
+            assert_eq!(guard.check("123456", Some("000000"), now), PinCheck::Wrong);
+        }
+        assert_eq!(guard.check("123456", Some("123456"), now), PinCheck::Right);
+        for _ in 0..MAX_PIN_FAILURES {
+            assert_eq!(guard.check("123456", Some("000000"), now), PinCheck::Wrong);
+        }
+        assert_eq!(guard.check("123456", Some("123456"), now), PinCheck::LockedOut);
+        let later = now + PIN_LOCKOUT;
+        assert_eq!(guard.check("123456", Some("123456"), later), PinCheck::Right);
+    }
+
+    #[test]
+    fn a_missing_pin_is_not_a_guess() {
+        let mut guard = PinGuard::new();
+        let now = Instant::now();
+        for _ in 0..MAX_PIN_FAILURES {
+            assert_eq!(guard.check("123456", None, now), PinCheck::Wrong);
+        }
+        assert_eq!(guard.check("123456", Some("123456"), now), PinCheck::Right);
+    }
+
+    #[test]
+    fn fit_is_gzipped_when_accepted() {
+        let db = open_temporary().unwrap();
+        db.insert(1700000000, Duration::from_secs(0), Notification::Lap).unwrap();
+        db.insert(1700000000, Duration::from_secs(60), Notification::Lap).unwrap();
+        let fit =
+            db_sessions_to_fit(&db, vec![1700000000].into_iter(), TimeWindow::default(), false)
+                .unwrap();
+        let (_server, addr) = serve(db);
+
+        let (status, head, body) = get(
+            addr,
+            "/workouts/latest.fit?pin=123456",
+            "Accept-Encoding: deflate, gzip\r\n",
+        );
+        assert_eq!(status, "200");
+        assert!(head.contains("Content-Encoding: gzip"));
+        let mut decompressed = vec![];
+        GzDecoder::new(&body[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, fit);
+
+        let (_, head, body) = get(addr, "/workouts/latest.fit?pin=123456", "");
+        assert!(!head.contains("Content-Encoding"));
+        assert_eq!(body, fit);
+    }
+
+    #[test]
+    fn accepts_gzip_unless_refused() {
+        let accept = |value: &[u8]| {
+            accepts_gzip(&[Header::from_bytes(&b"Accept-Encoding"[..], value).unwrap()])
+        };
+        assert!(accept(b"gzip"));
+        assert!(accept(b"deflate, gzip;q=0.5"));
+        assert!(!accept(b"deflate"));
+        assert!(!accept(b"gzip;q=0"));
+        assert!(!accepts_gzip(&[]));
+    }
+
+    #[test]
+    fn pin_matches_only_the_whole_pin() {
+        assert!(pin_matches("123456", "123456"));
+        assert!(!pin_matches("123456", "123457"));
+        assert!(!pin_matches("123456", "12345"));
+        assert!(!pin_matches("123456", ""));
+    }
+
+    #[test]
+    fn take_pin_leaves_other_parameters() {
+        assert_eq!(take_pin("/status"), ("/status".to_string(), None));
+        assert_eq!(take_pin("/status?pin=1"), ("/status".to_string(), Some("1")));
+        assert_eq!(
+            take_pin("/workouts/latest.fit?start=60&pin=1&end=120"),
+            ("/workouts/latest.fit?start=60&end=120".to_string(), Some("1"))
+        );
+        assert_eq!(
+            take_pin("/workouts?profile=Zenia"),
+            ("/workouts?profile=Zenia".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn bearer_token_from_the_authorization_header() {
+        let headers = vec![
+            Header::from_bytes(&b"Accept"[..], &b"*/*"[..]).unwrap(),
+            Header::from_bytes(&b"Authorization"[..], &b"Bearer 123456"[..]).unwrap(),
+        ];
+        assert_eq!(bearer_token(&headers), Some("123456"));
+        assert_eq!(bearer_token(&headers[..1]), None);
+    }
 
     #[test]
     fn parse_url_latest() {
-        assert_eq!(parse_url("/workouts/latest.fit"), Ok(("", UrlKey::Latest)))
+        assert_eq!(
+            parse_url("/workouts/latest.fit"),
+            Ok(("", (UrlKey::Latest, FileType::Fit)))
+        )
     }
 
     #[test]
     fn parse_url_key() {
-        assert_eq!(parse_url("/workouts/1234.fit"), Ok(("", UrlKey::Key(1234))))
+        assert_eq!(
+            parse_url("/workouts/1234.fit"),
+            Ok(("", (UrlKey::Key(1234), FileType::Fit)))
+        )
     }
 
     #[test]
     fn parse_url_key_range() {
         assert_eq!(
             parse_url("/workouts/1234-9382.fit"),
-            Ok(("", UrlKey::KeyRange((1234, 9382))))
+            Ok(("", (UrlKey::KeyRange((1234, 9382)), FileType::Fit)))
         )
     }
+
+    #[test]
+    fn parse_url_power_compare_csv() {
+        assert_eq!(
+            parse_url("/workouts/latest/power-compare.csv"),
+            Ok(("", (UrlKey::Latest, FileType::PowerCompareCsv)))
+        )
+    }
+
+    #[test]
+    fn parse_url_tcx() {
+        assert_eq!(
+            parse_url("/workouts/1234.tcx"),
+            Ok(("", (UrlKey::Key(1234), FileType::Tcx)))
+        )
+    }
+
+    #[test]
+    fn parse_url_csv() {
+        assert_eq!(
+            parse_url("/workouts/latest.csv"),
+            Ok(("", (UrlKey::Latest, FileType::Csv)))
+        );
+        assert_eq!(
+            parse_url("/workouts/1234-9382.csv"),
+            Ok(("", (UrlKey::KeyRange((1234, 9382)), FileType::Csv)))
+        );
+    }
+
+    #[test]
+    fn parse_url_csv_is_not_power_compare() {
+        assert_eq!(
+            parse_url("/workouts/1234/power-compare.csv"),
+            Ok(("", (UrlKey::Key(1234), FileType::PowerCompareCsv)))
+        );
+    }
+
+    #[test]
+    fn parse_url_raw_jsonl() {
+        assert_eq!(
+            parse_url("/debug/1234/raw.jsonl"),
+            Ok(("", (UrlKey::Key(1234), FileType::RawJsonl)))
+        )
+    }
+
+    #[test]
+    fn parse_url_raw_jsonl_only_for_debug() {
+        assert!(parse_url("/workouts/1234/raw.jsonl").is_err())
+    }
+
+    #[test]
+    fn parse_url_leaves_the_query() {
+        assert_eq!(
+            parse_url("/workouts/1234.fit?start=600&end=1200"),
+            Ok(("?start=600&end=1200", (UrlKey::Key(1234), FileType::Fit)))
+        );
+    }
+
+    #[test]
+    fn parse_time_window_both_bounds() {
+        assert_eq!(
+            parse_time_window("?start=600&end=1200"),
+            Some(TimeWindow {
+                start: Some(600),
+                end: Some(1200)
+            })
+        );
+    }
+
+    #[test]
+    fn parse_time_window_open_ended() {
+        assert_eq!(
+            parse_time_window("?end=1200"),
+            Some(TimeWindow {
+                start: None,
+                end: Some(1200)
+            })
+        );
+        assert_eq!(parse_time_window(""), Some(TimeWindow::default()));
+    }
+
+    #[test]
+    fn parse_time_window_rejects_bad_bounds() {
+        assert_eq!(parse_time_window("?start=soon"), None);
+        assert_eq!(parse_time_window("garbage"), None);
+    }
+
+    #[test]
+    fn parse_index_url_all() {
+        assert_eq!(parse_index_url("/workouts"), Ok(("", None)))
+    }
+
+    #[test]
+    fn parse_index_url_profile() {
+        assert_eq!(
+            parse_index_url("/workouts?profile=Nathan%20Kickr"),
+            Ok(("", Some("Nathan Kickr".to_string())))
+        )
+    }
+
+    #[test]
+    fn parse_index_url_rejects_other_paths() {
+        assert!(parse_index_url("/workouts/latest.fit").is_err())
+    }
+
+    #[test]
+    fn parse_session_url_key() {
+        assert_eq!(parse_session_url("/workouts/1700000000"), Ok(("", 1700000000)))
+    }
+
+    #[test]
+    fn parse_session_url_rejects_exports() {
+        assert!(parse_session_url("/workouts/1700000000.fit").is_err());
+        assert!(parse_session_url("/workouts/latest").is_err());
+        assert!(parse_session_url("/workouts/1-2").is_err());
+    }
+
+    #[test]
+    fn percent_decode_form_values() {
+        assert_eq!(percent_decode("Nathan+Kickr"), "Nathan Kickr");
+        assert_eq!(percent_decode("100%25"), "100%");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
 }
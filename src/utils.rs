@@ -1,3 +1,5 @@
+use std::sync::{Mutex, MutexGuard};
+
 pub fn lift_a2_option<A, B, C, F: Fn(A, B) -> C>(a: Option<A>, b: Option<B>, f: F) -> Option<C> {
     match (a, b) {
         (Some(a), Some(b)) => Some(f(a, b)),
@@ -17,3 +19,11 @@ pub fn sequence_option_option<T>(a: Option<Option<T>>) -> Option<Option<T>> {
         None => Some(None),
     }
 }
+
+// A panic while holding a lock poisons it, and then every other `.lock().unwrap()`
+// panics too, cascading into a hung device with a blank screen.  For shared UI
+// state (display, targets, and anything drawn), a possibly half-updated value
+// is far better than that, since it's corrected on the next update anyway.
+pub fn lock_or_recover<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
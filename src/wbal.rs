@@ -0,0 +1,142 @@
+// W' balance: how much of the rider's anaerobic work capacity (W', in joules)
+// remains, using Skiba's differential model.  Riding above critical power
+// (CP) drains W' by the excess, while riding below recovers it, quickly when
+// far below CP and when there's much to recover, and slower as it refills.
+use std::time::{Duration, Instant};
+
+// A reading is never assumed to hold for longer than this, so a dropout
+// doesn't drain (or refill) the balance based on a stale reading.
+const MAX_READING_DURATION: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct WBal {
+    critical_power: f64,
+    w_prime: f64,
+    // In joules, which goes negative if the rider outperforms their W'
+    balance: f64,
+    last_reading: Option<(i16, Instant)>,
+}
+
+impl WBal {
+    pub fn new(critical_power: u16, w_prime: u32) -> WBal {
+        WBal {
+            critical_power: critical_power as f64,
+            w_prime: w_prime as f64,
+            balance: w_prime as f64,
+            last_reading: None,
+        }
+    }
+
+    // Each reading is assumed to hold until the next arrives
+    pub fn update(&mut self, power: i16, now: Instant) {
+        if let Some((last_power, last_instant)) = self.last_reading {
+            let held = std::cmp::min(now.duration_since(last_instant), MAX_READING_DURATION);
+            self.integrate(last_power as f64, held.as_secs_f64());
+        }
+        self.last_reading = Some((power, now));
+    }
+
+    fn integrate(&mut self, power: f64, seconds: f64) {
+        if power > self.critical_power {
+            self.balance -= (power - self.critical_power) * seconds;
+        } else {
+            // The exact solution of dW'bal/dt = (W' - W'bal)(CP - P)/W' for
+            // constant power, so it doesn't depend on how often readings come.
+            let deficit = self.w_prime - self.balance;
+            let rate = (self.critical_power - power) / self.w_prime;
+            self.balance = self.w_prime - deficit * (-rate * seconds).exp();
+        }
+    }
+
+    // What's left, from 0 (exhausted) to 1 (fully recovered)
+    pub fn remaining(&self) -> f64 {
+        (self.balance / self.w_prime).max(0.0).min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WBal;
+    use std::time::{Duration, Instant};
+
+    fn ride(w_bal: &mut WBal, start: Instant, powers: &[i16]) {
+        for (i, power) in powers.iter().enumerate() {
+            w_bal.update(*power, start + Duration::from_secs(i as u64));
+        }
+    }
+
+    fn assert_close(expected: f64, actual: f64) {
+        assert!(
+            (expected - actual).abs() < 0.001,
+            "expected {} but got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn starts_full() {
+        let w_bal = WBal::new(250, 20000);
+        assert_close(20000.0, w_bal.balance);
+        assert_close(1.0, w_bal.remaining());
+    }
+
+    #[test]
+    fn drains_by_the_work_above_critical_power() {
+        let mut w_bal = WBal::new(250, 20000);
+        // Ten seconds at 350W
+        ride(&mut w_bal, Instant::now(), &[350; 11]);
+        assert_close(19000.0, w_bal.balance);
+    }
+
+    #[test]
+    fn riding_at_critical_power_neither_drains_nor_recovers() {
+        let mut w_bal = WBal::new(250, 20000);
+        // Ten seconds at 450W, then ten at 250W
+        let powers: Vec<i16> = [450; 10].iter().chain([250; 11].iter()).cloned().collect();
+        ride(&mut w_bal, Instant::now(), &powers);
+        assert_close(18000.0, w_bal.balance);
+    }
+
+    #[test]
+    fn recovers_exponentially_below_critical_power() {
+        let mut w_bal = WBal::new(250, 20000);
+        let start = Instant::now();
+        ride(&mut w_bal, start, &[450, 50]);
+        assert_close(19800.0, w_bal.balance);
+        w_bal.update(50, start + Duration::from_secs(4));
+        // 3s at 200W below CP, with 200J to recover
+        let expected = 20000.0 - 200.0 * (-200.0 * 3.0 / 20000.0f64).exp();
+        assert_close(expected, w_bal.balance);
+    }
+
+    #[test]
+    fn recovery_is_the_same_however_often_readings_arrive() {
+        let start = Instant::now();
+        let mut often = WBal::new(250, 20000);
+        ride(&mut often, start, &[450, 450, 450, 100, 100, 100, 100, 100]);
+        let mut rarely = WBal::new(250, 20000);
+        rarely.update(450, start);
+        rarely.update(100, start + Duration::from_secs(3));
+        rarely.update(100, start + Duration::from_secs(5));
+        rarely.update(100, start + Duration::from_secs(7));
+        assert_close(often.balance, rarely.balance);
+    }
+
+    #[test]
+    fn gaps_between_readings_are_capped() {
+        let mut w_bal = WBal::new(250, 20000);
+        let start = Instant::now();
+        w_bal.update(350, start);
+        w_bal.update(350, start + Duration::from_secs(60));
+        assert_close(19500.0, w_bal.balance);
+    }
+
+    #[test]
+    fn remaining_is_clamped() {
+        let mut w_bal = WBal::new(250, 1000);
+        ride(&mut w_bal, Instant::now(), &[650; 4]);
+        assert_close(-200.0, w_bal.balance);
+        assert_close(0.0, w_bal.remaining());
+    }
+}
@@ -6,19 +6,77 @@ use std::{
 };
 use std::future::Future;
 use tokio::task::JoinHandle;
+use tokio::time;
 
 #[derive(Clone)]
 pub struct Workout {
     ct: CycleTree<(Duration, u16)>,
     tail: Option<u16>,
+    // How often we check for offset changes, termination, and the next step
+    interval: Duration,
+    // How far into the workout it starts
+    at: Duration,
 }
 
 #[derive(Clone)]
 pub struct WorkoutState {
     running: bool,
+    // Set once every step of the workout has been played through (this never
+    // happens for workouts with a tail, which is held indefinitely).
+    finished: bool,
+    // While paused, the workout's clock stops and the power is left as is
+    paused: bool,
+    // A skip that hasn't been acted on yet
+    o_skip: Option<Skip>,
     offset: i16,
+    // As of the last check (None until the first step starts, and again once
+    // the workout is finished)
+    o_progress: Option<Progress>,
+    // The current step's power, before the offset (None until the first step
+    // starts, and again once the workout is finished)
+    o_power: Option<u16>,
 }
 
+// How far along a running workout is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    // Of every timed step (the tail is held indefinitely, so it adds nothing)
+    pub total: Duration,
+    pub elapsed: Duration,
+    // Until the current step ends (None while holding the tail)
+    pub step_remaining: Option<Duration>,
+    // What the target will be once the current step ends (with the offset,
+    // but before any floor), if there's anything after it
+    pub o_next: Option<u16>,
+}
+
+impl Progress {
+    // Until the last timed step ends
+    pub fn remaining(&self) -> Duration {
+        self.total.checked_sub(self.elapsed).unwrap_or(Duration::from_secs(0))
+    }
+
+    // From 0 to 1, where a workout that's only a tail is always done
+    pub fn fraction(&self) -> f64 {
+        if self.total == Duration::from_secs(0) {
+            1.0
+        } else {
+            (self.elapsed.as_secs_f64() / self.total.as_secs_f64()).min(1.0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Skip {
+    // To the start of the next step
+    Forward,
+    // To the start of this step (or the last one, if this one just started)
+    Back,
+}
+
+// Skipping back within this long of a step starting goes to the step before
+const SKIP_BACK_GRACE: Duration = Duration::from_secs(3);
+
 pub struct WorkoutHandle {
     pub state: Arc<Mutex<WorkoutState>>,
     join_handle: Option<JoinHandle<()>>,
@@ -38,10 +96,255 @@ impl WorkoutHandle {
 
 // TODO: This helper is only here because it's clunky to access the state,
 // because WorkoutHandle can't be clone (because of JoinHandle).
-pub fn add_offset(state: &Arc<Mutex<WorkoutState>>, offset: i16) {
-    {
-        let mut state = state.lock().unwrap();
-        state.offset += offset;
+// Returns the new total offset.
+pub fn add_offset(state: &Arc<Mutex<WorkoutState>>, offset: i16) -> i16 {
+    let mut state = state.lock().unwrap();
+    state.offset += offset;
+    state.offset
+}
+
+// TODO: Same as add_offset, this is only here because WorkoutHandle can't be
+// clone.
+pub fn is_finished(state: &Arc<Mutex<WorkoutState>>) -> bool {
+    state.lock().unwrap().finished
+}
+
+// TODO: Same as add_offset.
+pub fn progress(state: &Arc<Mutex<WorkoutState>>) -> Option<Progress> {
+    state.lock().unwrap().o_progress
+}
+
+// TODO: Same as add_offset.
+// The current target, with the offset (but before any floor).
+pub fn target(state: &Arc<Mutex<WorkoutState>>) -> Option<u16> {
+    let state = state.lock().unwrap();
+    state.o_power.map(|power| ((power as i16) + state.offset) as u16)
+}
+
+// TODO: Same as add_offset.
+pub fn skip_forward(state: &Arc<Mutex<WorkoutState>>) {
+    state.lock().unwrap().o_skip = Some(Skip::Forward);
+}
+
+// TODO: Same as add_offset.
+pub fn skip_back(state: &Arc<Mutex<WorkoutState>>) {
+    state.lock().unwrap().o_skip = Some(Skip::Back);
+}
+
+// TODO: Same as add_offset.
+// Returns whether the workout is now paused.
+pub fn toggle_pause(state: &Arc<Mutex<WorkoutState>>) -> bool {
+    let mut state = state.lock().unwrap();
+    state.paused = !state.paused;
+    state.paused
+}
+
+// Counts consecutive failed writes of the ERG target, so that a trainer that
+// has stopped accepting them isn't left silently holding a stale target.
+pub struct ErgFailures {
+    // None never gives up on ERG
+    limit: Option<u32>,
+    consecutive: u32,
+    gave_up: bool,
+}
+
+impl ErgFailures {
+    pub fn new(limit: Option<u32>) -> ErgFailures {
+        ErgFailures {
+            limit,
+            consecutive: 0,
+            gave_up: false,
+        }
+    }
+
+    // Returns true only for the failure that makes us give up on ERG
+    pub fn record(&mut self, succeeded: bool) -> bool {
+        if succeeded {
+            self.consecutive = 0;
+            return false;
+        }
+        self.consecutive += 1;
+        let should_give_up =
+            !self.gave_up && self.limit.map_or(false, |limit| self.consecutive >= limit);
+        self.gave_up |= should_give_up;
+        should_give_up
+    }
+
+    pub fn gave_up(&self) -> bool {
+        self.gave_up
+    }
+}
+
+// Averages over a single interval (step) of a workout, reset each time a new
+// step starts.
+pub struct IntervalStats {
+    target: Option<(i16, Instant)>,
+    power: (i64, u32),
+    heart_rate: (u32, u32),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct IntervalSummary {
+    pub duration: Duration,
+    pub average_power: Option<i16>,
+    pub average_heart_rate: Option<u16>,
+}
+
+impl IntervalStats {
+    pub fn new() -> IntervalStats {
+        IntervalStats {
+            target: None,
+            power: (0, 0),
+            heart_rate: (0, 0),
+        }
+    }
+
+    pub fn add_power(&mut self, power: i16) {
+        self.power = (self.power.0 + power as i64, self.power.1 + 1);
+    }
+
+    pub fn add_heart_rate(&mut self, heart_rate: u16) {
+        self.heart_rate = (self.heart_rate.0 + heart_rate as u32, self.heart_rate.1 + 1);
+    }
+
+    // When a new step's target drops, the one that just ended was work, so its
+    // summary is returned.  A target that changes within a step (an offset) is
+    // still the same interval.
+    pub fn set_target(
+        &mut self,
+        target: i16,
+        is_new_step: bool,
+        now: Instant,
+    ) -> Option<IntervalSummary> {
+        match self.target {
+            Some((_, start)) if !is_new_step => {
+                self.target = Some((target, start));
+                None
+            }
+            o_current => {
+                let summary = o_current
+                    .filter(|(current, _)| target < *current)
+                    .map(|(_, start)| IntervalSummary {
+                        duration: now.saturating_duration_since(start),
+                        average_power: average(self.power).map(|x| x as i16),
+                        average_heart_rate: average(self.heart_rate).map(|x| x as u16),
+                    });
+                *self = IntervalStats::new();
+                self.target = Some((target, now));
+                summary
+            }
+        }
+    }
+}
+
+fn average<T: Into<i64>>((sum, count): (T, u32)) -> Option<i64> {
+    if count == 0 {
+        None
+    } else {
+        Some(sum.into() / count as i64)
+    }
+}
+
+// A single step of a running workout.  The tail is held until the workout is
+// exited, rather than for some very long time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    Timed(Duration, u16),
+    Indefinite(u16),
+}
+
+fn steps(ct: CycleTree<(Duration, u16)>, tail: Option<u16>) -> impl Iterator<Item = Step> {
+    ct.into_iter()
+        .map(|(wait, power)| Step::Timed(wait, power))
+        .chain(tail.map(Step::Indefinite))
+}
+
+// When each step starts (as a total time), and then when the last one ends
+// (where the tail, which never does, is treated as taking no time at all)
+fn step_starts(steps: &[Step]) -> Vec<Duration> {
+    let mut starts = vec![Duration::from_secs(0)];
+    for step in steps {
+        let last = *starts.last().unwrap();
+        starts.push(match step {
+            // Overflow is not a consideration for the timeline of a single workout
+            Step::Timed(wait, _) => last.checked_add(*wait).unwrap(),
+            Step::Indefinite(_) => last,
+        });
+    }
+    starts
+}
+
+// Where the workout is at, elapsed into step i (where starts are from
+// step_starts)
+fn progress_at(
+    steps: &[Step],
+    starts: &[Duration],
+    i: usize,
+    elapsed: Duration,
+    offset: i16,
+) -> Progress {
+    Progress {
+        total: *starts.last().unwrap(),
+        elapsed,
+        step_remaining: match steps[i] {
+            Step::Timed(_, _) => {
+                Some(starts[i + 1].checked_sub(elapsed).unwrap_or(Duration::from_secs(0)))
+            }
+            Step::Indefinite(_) => None,
+        },
+        o_next: steps.get(i + 1).map(|step| {
+            let power = match step {
+                Step::Timed(_, power) => power,
+                Step::Indefinite(power) => power,
+            };
+            ((*power as i16) + offset) as u16
+        }),
+    }
+}
+
+// What a step's loop ends with
+enum Next {
+    Exit,
+    // This step has run its course
+    Continue,
+    SkipTo(usize),
+}
+
+// The workout's own time, which doesn't advance while paused and can be moved
+// (when skipping).  It's kept with tokio's clock, so that it can be paused in
+// tests.
+struct Clock {
+    // The time as of since (or for good, while paused)
+    at: Duration,
+    o_since: Option<time::Instant>,
+}
+
+impl Clock {
+    fn new(start: Instant, at: Duration) -> Clock {
+        Clock {
+            at,
+            o_since: Some(time::Instant::from_std(start)),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.at + self.o_since.map_or(Duration::from_secs(0), |since| since.elapsed())
+    }
+
+    fn pause(&mut self) {
+        self.at = self.elapsed();
+        self.o_since = None;
+    }
+
+    fn resume(&mut self) {
+        if self.o_since.is_none() {
+            self.o_since = Some(time::Instant::now());
+        }
+    }
+
+    fn set(&mut self, at: Duration) {
+        self.at = at;
+        self.o_since = self.o_since.map(|_| time::Instant::now());
     }
 }
 
@@ -50,75 +353,182 @@ impl Workout {
     // amount of power should be held for, and then optionally a final power
     // that is held indefinitely at the end of the workout (defaults to 0).
     pub fn new(ct: CycleTree<(Duration, u16)>, tail: Option<u16>) -> Workout {
-        Workout { ct, tail }
+        Workout {
+            ct,
+            tail,
+            interval: Duration::from_millis(50),
+            at: Duration::from_secs(0),
+        }
+    }
+
+    // A shorter interval makes very short steps (like a smooth ramp) and
+    // offsets more responsive, at the cost of more wake ups.
+    pub fn with_interval(self, interval: Duration) -> Workout {
+        Workout { interval, ..self }
+    }
+
+    // Starts part way through (like when resuming a ride), where any steps
+    // that would have already ended are skipped over.
+    pub fn starting_at(self, at: Duration) -> Workout {
+        Workout { at, ..self }
+    }
+
+    // Every step of the plan in order (without the tail), for previewing
+    pub fn profile(&self) -> Vec<(Duration, u16)> {
+        self.ct.clone().into_iter().collect()
+    }
+
+    pub fn tail(&self) -> Option<u16> {
+        self.tail
+    }
+
+    // The full plan, so that it can be recorded and picked up again
+    pub fn plan(&self) -> (CycleTree<(Duration, u16)>, Option<u16>) {
+        (self.ct.clone(), self.tail)
     }
 
     // This also eventually self-corrects any drift, because we always target the
-    // correct total time for our changes.
-    pub fn run<Fut: Future<Output = ()> + Send, F: Fn(u16) -> Fut + 'static + Send>(self, start: Instant, set_power: F) -> WorkoutHandle {
+    // correct total time for our changes.  Time spent paused isn't part of that
+    // total, so steps pick up just where they left off, and skipping moves it to
+    // another step's boundary.
+    // set_power is also told where in the workout a new step has just started
+    // (or None, when it's the offset that changed).
+    pub fn run<Fut: Future<Output = ()> + Send, F: Fn(u16, Option<Duration>) -> Fut + 'static + Send>(self, start: Instant, set_power: F) -> WorkoutHandle {
         // TODO: There must be a more elegant way to do this
         let state = Arc::new(Mutex::new(WorkoutState {
             running: true,
+            finished: false,
+            paused: false,
+            o_skip: None,
             offset: 0,
+            o_progress: None,
+            o_power: None,
         }));
         let state_for_thread = state.clone();
-        let Workout { ct, tail } = self;
-        let tail_iter = tail.map(|x| (Duration::from_secs(1000000), x)).into_iter();
+        let Workout { ct, tail, interval, at } = self;
         let join_handle = Some(tokio::task::spawn(async move {
-            let mut d = Duration::from_secs(0);
+            let steps: Vec<Step> = steps(ct, tail).collect();
+            let starts = step_starts(&steps);
+            let mut clock = Clock::new(start, at);
             let mut last_offset: i16 = 0;
+            let mut i = 0;
 
-            for (wait, power) in ct.into_iter().chain(tail_iter) {
-                // Overflow is not a consideration for the timeline of a single workout
-                d = d.checked_add(wait).unwrap();
-                let e = start.elapsed();
+            while i < steps.len() {
+                // When this step ends (as a total time), if it ever does
+                let (o_end, power) = match steps[i] {
+                    Step::Timed(wait, power) => {
+                        // Overflow is not a consideration for the timeline of a single workout
+                        (Some(starts[i].checked_add(wait).unwrap()), power)
+                    }
+                    Step::Indefinite(power) => (None, power),
+                };
+                let e = clock.elapsed();
                 // If duration is negative, we continue on.
-                if let Some(_) = d.checked_sub(e) {
-                    let set_power_fut = set_power(((power as i16) + last_offset) as u16);
-                    set_power_fut.await;
-
-                    // We loop and check every 50ms if we should move to the
-                    // next power or if the workout is teriminated.
-                    let terminate = loop {
-                        // TODO: It likely makes more sense to truly commit to
-                        // async semantics here, where we poll for power
-                        // changes.
-                        tokio::time::sleep(Duration::from_millis(50)).await;
-                        // We immediately drop the guard because the guard for
-                        // a typical mutex cannot be used across awaits.  While
-                        // we might consider using an async Mutex, in this
-                        // case, this is the one and only caller that actually
-                        // does a side-effect, and all other users simply set
-                        // the data.  So there should be no risk that
-                        // side-effects occur out of order.  Other than that,
-                        // just data is involved, which makes a traditional
-                        // mutex better, if not more appropriate.
-                        let state = {
-                            let guard = state_for_thread.lock().unwrap();
-                            guard.clone()
-                        };
-                        if !state.running {
-                            break true;
+                if o_end.map_or(false, |end| end.checked_sub(e).is_none()) {
+                    i += 1;
+                    continue;
+                }
+
+                let set_power_fut =
+                    set_power(((power as i16) + last_offset) as u16, Some(clock.elapsed()));
+                set_power_fut.await;
+                {
+                    let mut state = state_for_thread.lock().unwrap();
+                    state.o_progress =
+                        Some(progress_at(&steps, &starts, i, clock.elapsed(), last_offset));
+                    state.o_power = Some(power);
+                }
+
+                // We loop and check every interval if we should move to
+                // another step or if the workout is teriminated.  Since the
+                // ends are total times, we're never late by more than a
+                // single interval, no matter how many steps there are.
+                let next = loop {
+                    // TODO: It likely makes more sense to truly commit to
+                    // async semantics here, where we poll for power
+                    // changes.
+                    tokio::time::sleep(interval).await;
+                    // We immediately drop the guard because the guard for
+                    // a typical mutex cannot be used across awaits.  While
+                    // we might consider using an async Mutex, in this
+                    // case, this is the one and only caller that actually
+                    // does a side-effect, and all other users simply set
+                    // the data.  So there should be no risk that
+                    // side-effects occur out of order.  Other than that,
+                    // just data is involved, which makes a traditional
+                    // mutex better, if not more appropriate.  Skips are
+                    // taken, so each is only acted on once, but not while
+                    // paused, where they wait to be acted on once resumed.
+                    let state = {
+                        let mut guard = state_for_thread.lock().unwrap();
+                        let state = guard.clone();
+                        if !guard.paused {
+                            guard.o_skip = None;
                         }
+                        state
+                    };
+                    if !state.running {
+                        break Next::Exit;
+                    }
 
-                        // Check to see if the offset has changed, if so,
-                        // record the new offset and immediately update the
-                        // power.
-                        if state.offset != last_offset {
-                            last_offset = state.offset;
-                            let set_power_fut = set_power(((power as i16) + last_offset) as u16);
-                            set_power_fut.await;
+                    // Nothing changes while paused (offsets included,
+                    // which are picked up once resumed).
+                    if state.paused {
+                        clock.pause();
+                        continue;
+                    }
+                    clock.resume();
+                    state_for_thread.lock().unwrap().o_progress =
+                        Some(progress_at(&steps, &starts, i, clock.elapsed(), state.offset));
+
+                    match state.o_skip {
+                        // There's nothing after the tail to skip to
+                        Some(Skip::Forward) if o_end.is_some() => break Next::SkipTo(i + 1),
+                        Some(Skip::Back) => {
+                            // Going back right after a step starts goes to the
+                            // one before, so that it can be repeated.
+                            let into_step = clock.elapsed().saturating_sub(starts[i]);
+                            break Next::SkipTo(if into_step < SKIP_BACK_GRACE {
+                                i.saturating_sub(1)
+                            } else {
+                                i
+                            });
                         }
+                        _ => (),
+                    }
 
-                        if let None = d.checked_sub(start.elapsed()) {
-                            break false;
+                    // Check to see if the offset has changed, if so,
+                    // record the new offset and immediately update the
+                    // power.
+                    if state.offset != last_offset {
+                        last_offset = state.offset;
+                        let set_power_fut =
+                            set_power(((power as i16) + last_offset) as u16, None);
+                        set_power_fut.await;
+                    }
+
+                    if let Some(end) = o_end {
+                        if let None = end.checked_sub(clock.elapsed()) {
+                            break Next::Continue;
                         }
-                    };
-                    if terminate {
-                        break;
+                    }
+                };
+                match next {
+                    Next::Exit => return,
+                    Next::Continue => i += 1,
+                    Next::SkipTo(next) => {
+                        clock.set(starts[next]);
+                        i = next;
                     }
                 }
             }
+
+            // We've played through the whole workout without being asked to
+            // exit, so let any interested parties know.
+            let mut state = state_for_thread.lock().unwrap();
+            state.finished = true;
+            state.o_progress = None;
+            state.o_power = None;
         }));
 
         WorkoutHandle { join_handle, state }
@@ -191,3 +601,483 @@ pub fn ramp_test(warmup_power: u16) -> Workout {
     }
     Workout::new(CycleTree::Node((1, v)), None)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{is_finished, ErgFailures, IntervalStats, IntervalSummary, Workout};
+    use super::{add_offset, single_value, skip_back, skip_forward, step_starts, steps};
+    use super::{progress, progress_at, target, toggle_pause, Progress, Step};
+    use crate::cycle_tree::CycleTree;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::time;
+
+    #[test]
+    fn steps_end_with_an_indefinite_tail() {
+        let ct = CycleTree::Node((2, vec![CycleTree::Leaf((Duration::from_secs(60), 100))]));
+        assert_eq!(
+            vec![
+                Step::Timed(Duration::from_secs(60), 100),
+                Step::Timed(Duration::from_secs(60), 100),
+                Step::Indefinite(150),
+            ],
+            steps(ct, Some(150)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn steps_without_a_tail_are_all_timed() {
+        let ct = CycleTree::Leaf((Duration::from_secs(60), 100));
+        assert_eq!(
+            vec![Step::Timed(Duration::from_secs(60), 100)],
+            steps(ct, None).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn run_holds_the_tail_until_exited() {
+        let workout = Workout::new(CycleTree::Leaf((Duration::from_millis(20), 100)), Some(150))
+            .with_interval(Duration::from_millis(5));
+        let powers = Arc::new(Mutex::new(Vec::new()));
+        let powers_for_run = powers.clone();
+
+        let mut handle = workout.run(Instant::now(), move |p, o_step_start| {
+            powers_for_run.lock().unwrap().push((p, o_step_start.is_some()));
+            async {}
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(vec![(100, true), (150, true)], *powers.lock().unwrap());
+        assert!(!is_finished(&handle.state));
+
+        // Still running, so offsets apply to the tail (without starting a new
+        // step)
+        add_offset(&handle.state, 5);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(vec![(100, true), (150, true), (155, false)], *powers.lock().unwrap());
+
+        handle.exit().await;
+        assert!(!is_finished(&handle.state));
+    }
+
+    // With tokio's clock paused, the only lateness is from the interval itself
+    #[tokio::test(start_paused = true)]
+    async fn run_does_not_accumulate_drift_at_coarse_intervals() {
+        let steps: u32 = 10;
+        let step = Duration::from_millis(20);
+        let interval = Duration::from_millis(15);
+        let workout = Workout::new(
+            CycleTree::Node((steps as usize, vec![CycleTree::Leaf((step, 100))])),
+            None,
+        )
+        .with_interval(interval);
+
+        let start = time::Instant::now();
+        let handle = workout.run(start.into_std(), |_, _| async {});
+        while !is_finished(&handle.state) {
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        let elapsed = start.elapsed();
+
+        assert!(elapsed >= step * steps);
+        // If each step could be up to an interval late, we'd be nearly a
+        // whole interval late per step.
+        assert!(elapsed <= step * steps + interval);
+    }
+
+    // Tokio's clock is paused (and only moves while every task is waiting on
+    // it), so the timing here is exact.
+    #[tokio::test(start_paused = true)]
+    async fn paused_time_does_not_count_toward_the_step() {
+        let workout = Workout::new(CycleTree::Leaf((Duration::from_millis(100), 100)), Some(200))
+            .with_interval(Duration::from_millis(5));
+        let powers = Arc::new(Mutex::new(Vec::new()));
+        let powers_for_run = powers.clone();
+
+        let mut handle = workout.run(time::Instant::now().into_std(), move |p, o_step_start| {
+            powers_for_run.lock().unwrap().push((p, o_step_start.is_some()));
+            async {}
+        });
+        time::sleep(Duration::from_millis(30)).await;
+        assert!(toggle_pause(&handle.state));
+        // Well past when the first step would have ended, and the offset
+        // waits too
+        add_offset(&handle.state, 5);
+        time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(vec![(100, true)], *powers.lock().unwrap());
+        // Though the target it'll be is known right away
+        assert_eq!(Some(105), target(&handle.state));
+
+        // About 50ms into the first step
+        assert!(!toggle_pause(&handle.state));
+        time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(vec![(100, true), (105, false)], *powers.lock().unwrap());
+
+        // The first step ends 50ms after that (and not before)
+        time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(vec![(100, true), (105, false)], *powers.lock().unwrap());
+        time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(
+            vec![(100, true), (105, false), (205, true)],
+            *powers.lock().unwrap()
+        );
+        handle.exit().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn starting_part_way_skips_the_steps_already_done() {
+        let ct = CycleTree::Node((
+            1,
+            vec![
+                CycleTree::Leaf((Duration::from_secs(60), 100)),
+                CycleTree::Leaf((Duration::from_secs(60), 200)),
+            ],
+        ));
+        let workout = Workout::new(ct, Some(50))
+            .with_interval(Duration::from_millis(5))
+            .starting_at(Duration::from_secs(90));
+        let powers = Arc::new(Mutex::new(Vec::new()));
+        let powers_for_run = powers.clone();
+
+        let mut handle = workout.run(time::Instant::now().into_std(), move |p, o_step_start| {
+            powers_for_run.lock().unwrap().push((p, o_step_start.is_some()));
+            async {}
+        });
+        time::sleep(Duration::from_secs(20)).await;
+        assert_eq!(vec![(200, true)], *powers.lock().unwrap());
+        time::sleep(Duration::from_secs(15)).await;
+        assert_eq!(vec![(200, true), (50, true)], *powers.lock().unwrap());
+        handle.exit().await;
+    }
+
+    #[test]
+    fn step_starts_end_with_the_tail() {
+        let steps = [
+            Step::Timed(Duration::from_secs(60), 100),
+            Step::Timed(Duration::from_secs(30), 200),
+            Step::Indefinite(150),
+        ];
+        assert_eq!(
+            vec![
+                Duration::from_secs(0),
+                Duration::from_secs(60),
+                Duration::from_secs(90),
+                Duration::from_secs(90),
+            ],
+            step_starts(&steps)
+        );
+    }
+
+    #[test]
+    fn progress_mid_step_counts_down_to_the_next() {
+        let steps = [
+            Step::Timed(Duration::from_secs(60), 100),
+            Step::Timed(Duration::from_secs(30), 200),
+            Step::Indefinite(150),
+        ];
+        let starts = step_starts(&steps);
+        let progress = progress_at(&steps, &starts, 0, Duration::from_secs(15), 5);
+        assert_eq!(
+            Progress {
+                total: Duration::from_secs(90),
+                elapsed: Duration::from_secs(15),
+                step_remaining: Some(Duration::from_secs(45)),
+                o_next: Some(205),
+            },
+            progress
+        );
+        assert_eq!(Duration::from_secs(75), progress.remaining());
+
+        // Into the last timed step, where what's next is the tail
+        let progress = progress_at(&steps, &starts, 1, Duration::from_secs(80), 0);
+        assert_eq!(Some(Duration::from_secs(10)), progress.step_remaining);
+        assert_eq!(Some(150), progress.o_next);
+        assert_eq!(Duration::from_secs(10), progress.remaining());
+    }
+
+    #[test]
+    fn progress_in_the_tail_has_nothing_left() {
+        let steps = [Step::Timed(Duration::from_secs(60), 100), Step::Indefinite(150)];
+        let starts = step_starts(&steps);
+        let progress = progress_at(&steps, &starts, 1, Duration::from_secs(75), 0);
+        assert_eq!(None, progress.step_remaining);
+        assert_eq!(None, progress.o_next);
+        assert_eq!(Duration::from_secs(0), progress.remaining());
+        assert_eq!(1.0, progress.fraction());
+    }
+
+    #[test]
+    fn progress_runs_late_without_going_negative() {
+        let steps = [Step::Timed(Duration::from_secs(60), 100)];
+        let starts = step_starts(&steps);
+        let progress = progress_at(&steps, &starts, 0, Duration::from_millis(60_005), 0);
+        assert_eq!(Some(Duration::from_secs(0)), progress.step_remaining);
+        assert_eq!(None, progress.o_next);
+        assert_eq!(Duration::from_secs(0), progress.remaining());
+        assert_eq!(1.0, progress.fraction());
+    }
+
+    // Tokio's clock is paused, and only moves when advanced, so the progress
+    // is exact.
+    #[tokio::test(start_paused = true)]
+    async fn run_reports_progress_until_finished() {
+        let ct = CycleTree::Node((
+            1,
+            vec![
+                CycleTree::Leaf((Duration::from_millis(40), 100)),
+                CycleTree::Leaf((Duration::from_millis(40), 200)),
+            ],
+        ));
+        let workout = Workout::new(ct, None).with_interval(Duration::from_millis(5));
+        let handle = workout.run(time::Instant::now().into_std(), |_, _| async {});
+        // Advancing fires the workout's timer, and then it needs a turn to run
+        time::advance(Duration::from_millis(20)).await;
+        tokio::task::yield_now().await;
+        let p = progress(&handle.state).unwrap();
+        assert_eq!(Duration::from_millis(80), p.total);
+        assert_eq!(Duration::from_millis(20), p.elapsed);
+        assert_eq!(Some(200), p.o_next);
+        assert_eq!(Some(Duration::from_millis(20)), p.step_remaining);
+
+        time::advance(Duration::from_millis(30)).await;
+        tokio::task::yield_now().await;
+        let p = progress(&handle.state).unwrap();
+        assert_eq!(Duration::from_millis(50), p.elapsed);
+        assert_eq!(None, p.o_next);
+        assert_eq!(Some(Duration::from_millis(30)), p.step_remaining);
+
+        // Steps only end once they're past due
+        time::advance(Duration::from_millis(50)).await;
+        tokio::task::yield_now().await;
+        assert!(is_finished(&handle.state));
+        assert_eq!(None, progress(&handle.state));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skipping_moves_between_steps() {
+        let ct = CycleTree::Node((
+            1,
+            vec![
+                CycleTree::Leaf((Duration::from_secs(60), 100)),
+                CycleTree::Leaf((Duration::from_secs(60), 200)),
+                CycleTree::Leaf((Duration::from_secs(60), 300)),
+            ],
+        ));
+        let workout = Workout::new(ct, Some(50)).with_interval(Duration::from_millis(5));
+        let powers = Arc::new(Mutex::new(Vec::new()));
+        let powers_for_run = powers.clone();
+        let last_power = || *powers.lock().unwrap().last().unwrap();
+
+        let mut handle = workout.run(time::Instant::now().into_std(), move |p, o_step_start| {
+            powers_for_run.lock().unwrap().push((p, o_step_start.is_some()));
+            async {}
+        });
+        time::sleep(Duration::from_millis(20)).await;
+        assert_eq!((100, true), last_power());
+
+        skip_forward(&handle.state);
+        time::sleep(Duration::from_millis(20)).await;
+        assert_eq!((200, true), last_power());
+
+        // Just started, so this goes back to the step before
+        skip_back(&handle.state);
+        time::sleep(Duration::from_millis(20)).await;
+        assert_eq!((100, true), last_power());
+
+        skip_forward(&handle.state);
+        time::sleep(Duration::from_millis(20)).await;
+        skip_forward(&handle.state);
+        time::sleep(Duration::from_millis(20)).await;
+        assert_eq!((300, true), last_power());
+
+        // Into the tail, which can't be skipped
+        skip_forward(&handle.state);
+        time::sleep(Duration::from_millis(20)).await;
+        skip_forward(&handle.state);
+        time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(
+            vec![(100, true), (200, true), (100, true), (200, true), (300, true), (50, true)],
+            *powers.lock().unwrap()
+        );
+        assert!(!is_finished(&handle.state));
+        handle.exit().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skipping_forward_from_the_last_step_finishes() {
+        let workout = Workout::new(CycleTree::Leaf((Duration::from_secs(60), 100)), None)
+            .with_interval(Duration::from_millis(5));
+        let handle = workout.run(time::Instant::now().into_std(), |_, _| async {});
+        time::sleep(Duration::from_millis(20)).await;
+        skip_forward(&handle.state);
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(is_finished(&handle.state));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skipping_while_paused_waits_until_resumed() {
+        let ct = CycleTree::Node((
+            1,
+            vec![
+                CycleTree::Leaf((Duration::from_secs(60), 100)),
+                CycleTree::Leaf((Duration::from_secs(60), 200)),
+            ],
+        ));
+        let workout = Workout::new(ct, None).with_interval(Duration::from_millis(5));
+        let powers = Arc::new(Mutex::new(Vec::new()));
+        let powers_for_run = powers.clone();
+
+        let mut handle = workout.run(time::Instant::now().into_std(), move |p, o_step_start| {
+            powers_for_run.lock().unwrap().push((p, o_step_start.is_some()));
+            async {}
+        });
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(toggle_pause(&handle.state));
+        skip_forward(&handle.state);
+        time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(vec![(100, true)], *powers.lock().unwrap());
+
+        assert!(!toggle_pause(&handle.state));
+        time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(vec![(100, true), (200, true)], *powers.lock().unwrap());
+        handle.exit().await;
+    }
+
+    #[tokio::test]
+    async fn toggle_pause_flips_back_and_forth() {
+        let mut handle = single_value(100).run(Instant::now(), |_, _| async {});
+        assert!(toggle_pause(&handle.state));
+        assert!(!toggle_pause(&handle.state));
+        handle.exit().await;
+    }
+
+    #[test]
+    fn erg_failures_give_up_once_after_consecutive_failures() {
+        let mut failures = ErgFailures::new(Some(3));
+        assert!(!failures.record(false));
+        assert!(!failures.record(false));
+        assert!(failures.record(false));
+        assert!(failures.gave_up());
+        assert!(!failures.record(false));
+    }
+
+    #[test]
+    fn erg_failures_reset_on_success() {
+        let mut failures = ErgFailures::new(Some(2));
+        assert!(!failures.record(false));
+        assert!(!failures.record(true));
+        assert!(!failures.record(false));
+        assert!(!failures.gave_up());
+    }
+
+    #[test]
+    fn erg_failures_without_a_limit_never_give_up() {
+        let mut failures = ErgFailures::new(None);
+        for _ in 0..100 {
+            assert!(!failures.record(false));
+        }
+        assert!(!failures.gave_up());
+    }
+
+    #[test]
+    fn interval_stats_summarize_work_when_the_target_drops() {
+        let start = Instant::now();
+        let mut stats = IntervalStats::new();
+        assert_eq!(None, stats.set_target(300, true, start));
+        stats.add_power(290);
+        stats.add_power(310);
+        stats.add_heart_rate(150);
+        stats.add_heart_rate(160);
+        assert_eq!(
+            Some(IntervalSummary {
+                duration: Duration::from_secs(240),
+                average_power: Some(300),
+                average_heart_rate: Some(155),
+            }),
+            stats.set_target(150, true, start + Duration::from_secs(240))
+        );
+    }
+
+    #[test]
+    fn interval_stats_are_reset_for_each_interval() {
+        let start = Instant::now();
+        let mut stats = IntervalStats::new();
+        stats.set_target(150, true, start);
+        stats.add_power(150);
+        stats.set_target(300, true, start + Duration::from_secs(60));
+        assert_eq!(
+            Some(IntervalSummary {
+                duration: Duration::from_secs(30),
+                average_power: None,
+                average_heart_rate: None,
+            }),
+            stats.set_target(150, true, start + Duration::from_secs(90))
+        );
+    }
+
+    #[test]
+    fn interval_stats_treat_offsets_as_the_same_interval() {
+        let start = Instant::now();
+        let mut stats = IntervalStats::new();
+        stats.set_target(300, true, start);
+        // However far the offset goes
+        for i in 1..10 {
+            assert_eq!(None, stats.set_target(300 - i * 10, false, start));
+        }
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            stats
+                .set_target(150, true, start + Duration::from_secs(60))
+                .map(|s| s.duration)
+        );
+    }
+
+    #[test]
+    fn interval_stats_end_on_a_new_step_however_small_the_change() {
+        let start = Instant::now();
+        let mut stats = IntervalStats::new();
+        stats.set_target(300, true, start);
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            stats
+                .set_target(295, true, start + Duration::from_secs(60))
+                .map(|s| s.duration)
+        );
+    }
+
+    #[test]
+    fn interval_stats_do_not_summarize_rising_targets() {
+        let start = Instant::now();
+        let mut stats = IntervalStats::new();
+        stats.set_target(100, true, start);
+        assert_eq!(None, stats.set_target(120, true, start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn profile_flattens_repeats() {
+        let workout = Workout::new(
+            CycleTree::Node((
+                2,
+                vec![
+                    CycleTree::Leaf((Duration::from_secs(60), 300)),
+                    CycleTree::Leaf((Duration::from_secs(30), 100)),
+                ],
+            )),
+            Some(120),
+        );
+        assert_eq!(
+            vec![
+                (Duration::from_secs(60), 300),
+                (Duration::from_secs(30), 100),
+                (Duration::from_secs(60), 300),
+                (Duration::from_secs(30), 100),
+            ],
+            workout.profile()
+        );
+        assert_eq!(Some(120), workout.tail());
+    }
+
+    #[test]
+    fn single_value_has_an_empty_profile() {
+        assert_eq!(Vec::<(Duration, u16)>::new(), single_value(150).profile());
+    }
+}
@@ -0,0 +1,361 @@
+// Workouts from .zwo files (Zwift's format, which is widely shared), where
+// powers are relative to the rider's FTP.  Only the structure of the workout
+// is kept, so things like text events and cadence targets are ignored.  This
+// isn't a general XML parser, just enough of one for how these files are
+// written.
+use crate::cycle_tree::CycleTree;
+use crate::workout::Workout;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Warmups, cooldowns and ramps are held as steps of (at most) this long
+const RAMP_STEP: Duration = Duration::from_secs(10);
+
+// No real workout goes beyond these, so anything more is a broken file (and
+// an endless duration would never finish being split into ramp steps).
+const MAX_SECONDS: f64 = 24.0 * 60.0 * 60.0;
+const MAX_FTP_RATIO: f64 = 10.0;
+const MAX_REPEAT: usize = 1000;
+
+// Why a .zwo file couldn't be imported
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportError {
+    // The file isn't XML we can make sense of
+    Malformed(&'static str),
+    // There's no <workout> element
+    NoWorkout,
+    // A part of the workout we don't know how to play (like a free ride)
+    UnsupportedElement(String),
+    MissingAttribute { element: String, attribute: &'static str },
+    InvalidAttribute { element: String, attribute: &'static str, value: String },
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ImportError::Malformed(why) => write!(f, "malformed XML: {}", why),
+            ImportError::NoWorkout => write!(f, "there's no workout element"),
+            ImportError::UnsupportedElement(e) => write!(f, "{} isn't supported", e),
+            ImportError::MissingAttribute { element, attribute } => {
+                write!(f, "{} is missing {}", element, attribute)
+            }
+            ImportError::InvalidAttribute { element, attribute, value } => {
+                write!(f, "{} has an invalid {} of {:?}", element, attribute, value)
+            }
+        }
+    }
+}
+
+pub fn from_zwo(xml: &str, ftp: u16) -> Result<Workout, ImportError> {
+    let mut segments = Vec::new();
+    let mut is_found = false;
+    // How deeply nested we are within the workout element, while in it
+    let mut o_depth: Option<usize> = None;
+    for tag in tags(xml)? {
+        match (o_depth, tag) {
+            (None, Tag::Start { name: "workout", is_empty, .. }) => {
+                is_found = true;
+                if !is_empty {
+                    o_depth = Some(0);
+                }
+            }
+            (None, _) => (),
+            (Some(0), Tag::End(_)) => o_depth = None,
+            // Each child of the workout is a segment of it
+            (Some(0), Tag::Start { name, attributes, is_empty }) => {
+                segments.push(segment(name, &attributes, ftp)?);
+                if !is_empty {
+                    o_depth = Some(1);
+                }
+            }
+            (Some(depth), Tag::Start { is_empty: false, .. }) => o_depth = Some(depth + 1),
+            (Some(_), Tag::Start { .. }) => (),
+            (Some(depth), Tag::End(_)) => o_depth = Some(depth - 1),
+        }
+    }
+    match (is_found, o_depth) {
+        (false, _) => Err(ImportError::NoWorkout),
+        (true, Some(_)) => Err(ImportError::Malformed("the workout is never closed")),
+        (true, None) => Ok(Workout::new(CycleTree::Node((1, segments)), None)),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Tag<'a> {
+    Start {
+        name: &'a str,
+        attributes: Vec<(&'a str, &'a str)>,
+        // Closed by itself, like <SteadyState ... />
+        is_empty: bool,
+    },
+    End(&'a str),
+}
+
+// Every start and end tag, in order, skipping comments, declarations and any
+// text between them.
+fn tags(xml: &str) -> Result<Vec<Tag<'_>>, ImportError> {
+    let mut tags = Vec::new();
+    let mut rest = xml;
+    while let Some(i) = rest.find('<') {
+        rest = &rest[i..];
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").ok_or(ImportError::Malformed("unclosed comment"))?;
+            rest = &rest[end + 3..];
+            continue;
+        }
+        let end = tag_end(rest).ok_or(ImportError::Malformed("unclosed tag"))?;
+        let inner = &rest[1..end];
+        rest = &rest[end + 1..];
+        if inner.starts_with('?') || inner.starts_with('!') {
+            continue;
+        }
+        if let Some(name) = inner.strip_prefix('/') {
+            tags.push(Tag::End(name.trim()));
+            continue;
+        }
+        let (inner, is_empty) = match inner.strip_suffix('/') {
+            Some(inner) => (inner, true),
+            None => (inner, false),
+        };
+        let name_end = inner.find(char::is_whitespace).unwrap_or(inner.len());
+        tags.push(Tag::Start {
+            name: &inner[..name_end],
+            attributes: attributes(&inner[name_end..])?,
+            is_empty,
+        });
+    }
+    Ok(tags)
+}
+
+// Where the tag at the start of s ends, where a quoted > (say, in a text
+// event's message) doesn't count.
+fn tag_end(s: &str) -> Option<usize> {
+    let mut o_quote: Option<char> = None;
+    for (i, c) in s.char_indices() {
+        match (o_quote, c) {
+            (None, '>') => return Some(i),
+            (None, '"') | (None, '\'') => o_quote = Some(c),
+            (Some(quote), c) if quote == c => o_quote = None,
+            _ => (),
+        }
+    }
+    None
+}
+
+// Every name="value" pair (values may use either kind of quote)
+fn attributes(s: &str) -> Result<Vec<(&str, &str)>, ImportError> {
+    let mut attributes = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let eq = rest.find('=').ok_or(ImportError::Malformed("attribute without a value"))?;
+        let name = rest[..eq].trim();
+        let quoted = rest[eq + 1..].trim_start();
+        let quote = quoted
+            .chars()
+            .next()
+            .filter(|&c| c == '"' || c == '\'')
+            .ok_or(ImportError::Malformed("unquoted attribute"))?;
+        let len = quoted[1..].find(quote).ok_or(ImportError::Malformed("unclosed attribute"))?;
+        attributes.push((name, &quoted[1..1 + len]));
+        rest = quoted[len + 2..].trim_start();
+    }
+    Ok(attributes)
+}
+
+fn segment(
+    element: &str,
+    attributes: &[(&str, &str)],
+    ftp: u16,
+) -> Result<CycleTree<(Duration, u16)>, ImportError> {
+    let duration =
+        |attribute| attribute_value(element, attributes, attribute, MAX_SECONDS).map(seconds);
+    let ratio = |attribute| attribute_value(element, attributes, attribute, MAX_FTP_RATIO);
+    // Element names aren't always capitalized consistently
+    match element.to_ascii_lowercase().as_str() {
+        "steadystate" => Ok(CycleTree::Leaf((duration("Duration")?, watts(ratio("Power")?, ftp)))),
+        "warmup" | "cooldown" | "ramp" => Ok(ramp(
+            duration("Duration")?,
+            ratio("PowerLow")?,
+            ratio("PowerHigh")?,
+            ftp,
+        )),
+        "intervalst" => Ok(CycleTree::Node((
+            attribute_value(element, attributes, "Repeat", MAX_REPEAT)?,
+            vec![
+                CycleTree::Leaf((duration("OnDuration")?, watts(ratio("OnPower")?, ftp))),
+                CycleTree::Leaf((duration("OffDuration")?, watts(ratio("OffPower")?, ftp))),
+            ],
+        ))),
+        _ => Err(ImportError::UnsupportedElement(element.to_string())),
+    }
+}
+
+// Durations and powers are never negative, so neither is anything we read,
+// and nothing can be over its max (which also rules out infinity and NaN).
+fn attribute_value<T: FromStr + PartialOrd + Default>(
+    element: &str,
+    attributes: &[(&str, &str)],
+    attribute: &'static str,
+    max: T,
+) -> Result<T, ImportError> {
+    let value = attributes
+        .iter()
+        .find(|(name, _)| *name == attribute)
+        .map(|(_, value)| *value)
+        .ok_or_else(|| ImportError::MissingAttribute {
+            element: element.to_string(),
+            attribute,
+        })?;
+    match value.trim().parse::<T>() {
+        Ok(x) if x >= T::default() && x <= max => Ok(x),
+        _ => Err(ImportError::InvalidAttribute {
+            element: element.to_string(),
+            attribute,
+            value: value.to_string(),
+        }),
+    }
+}
+
+// Durations are in (possibly fractional) seconds, which we keep to the
+// millisecond
+fn seconds(secs: f64) -> Duration {
+    Duration::from_millis((secs * 1000.0).round() as u64)
+}
+
+fn watts(ratio: f64, ftp: u16) -> u16 {
+    (ratio * ftp as f64).round() as u16
+}
+
+// Power moves steadily from low to high (or high to low, to cool down), so
+// each step holds the power from its middle.
+fn ramp(duration: Duration, low: f64, high: f64, ftp: u16) -> CycleTree<(Duration, u16)> {
+    let mut steps = Vec::new();
+    let mut start = Duration::from_secs(0);
+    while start < duration {
+        let step = std::cmp::min(RAMP_STEP, duration - start);
+        let middle = (start + step / 2).as_secs_f64() / duration.as_secs_f64();
+        steps.push(CycleTree::Leaf((step, watts(low + (high - low) * middle, ftp))));
+        start += step;
+    }
+    CycleTree::Node((1, steps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_zwo, ImportError};
+    use std::time::Duration;
+
+    const SHORT_AND_SHARP: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<workout_file>
+    <author>Someone</author>
+    <name>Short &amp; Sharp</name>
+    <sportType>bike</sportType>
+    <workout>
+        <!-- Easing in -->
+        <Warmup Duration="30" PowerLow="0.50" PowerHigh="0.80"/>
+        <SteadyState Duration="120" Power="0.75">
+            <textevent timeoffset="10" message="Settle in, then -> push"/>
+        </SteadyState>
+        <IntervalsT Repeat="2" OnDuration="60" OffDuration="30" OnPower="1.2" OffPower="0.5"/>
+        <Cooldown Duration='20' PowerLow='0.6' PowerHigh='0.4' />
+    </workout>
+</workout_file>
+"#;
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn from_zwo_flattens_every_segment() {
+        let workout = from_zwo(SHORT_AND_SHARP, 200).unwrap();
+        assert_eq!(
+            vec![
+                (secs(10), 110),
+                (secs(10), 130),
+                (secs(10), 150),
+                (secs(120), 150),
+                (secs(60), 240),
+                (secs(30), 100),
+                (secs(60), 240),
+                (secs(30), 100),
+                (secs(10), 110),
+                (secs(10), 90),
+            ],
+            workout.profile()
+        );
+        assert_eq!(None, workout.tail());
+    }
+
+    #[test]
+    fn from_zwo_ramps_end_with_a_short_step() {
+        let xml = r#"<workout><Ramp Duration="25" PowerLow="0.5" PowerHigh="1.0"/></workout>"#;
+        assert_eq!(
+            vec![(secs(10), 120), (secs(10), 160), (secs(5), 190)],
+            from_zwo(xml, 200).unwrap().profile()
+        );
+    }
+
+    #[test]
+    fn from_zwo_without_a_workout() {
+        let xml = "<workout_file><name>Nothing</name></workout_file>";
+        assert_eq!(Some(ImportError::NoWorkout), from_zwo(xml, 200).err());
+    }
+
+    #[test]
+    fn from_zwo_rejects_what_it_cannot_play() {
+        let xml = r#"<workout><FreeRide Duration="600"/></workout>"#;
+        assert_eq!(
+            Some(ImportError::UnsupportedElement("FreeRide".to_string())),
+            from_zwo(xml, 200).err()
+        );
+    }
+
+    #[test]
+    fn from_zwo_reports_bad_attributes() {
+        let missing = r#"<workout><SteadyState Duration="60"/></workout>"#;
+        assert_eq!(
+            Some(ImportError::MissingAttribute {
+                element: "SteadyState".to_string(),
+                attribute: "Power",
+            }),
+            from_zwo(missing, 200).err()
+        );
+        let negative = r#"<workout><SteadyState Duration="-60" Power="0.5"/></workout>"#;
+        assert_eq!(
+            Some(ImportError::InvalidAttribute {
+                element: "SteadyState".to_string(),
+                attribute: "Duration",
+                value: "-60".to_string(),
+            }),
+            from_zwo(negative, 200).err()
+        );
+    }
+
+    #[test]
+    fn from_zwo_rejects_endless_durations() {
+        for value in ["inf", "1e30", "NaN"] {
+            let xml = format!(
+                r#"<workout><Ramp Duration="{}" PowerLow="0.5" PowerHigh="1.0"/></workout>"#,
+                value
+            );
+            assert_eq!(
+                Some(ImportError::InvalidAttribute {
+                    element: "Ramp".to_string(),
+                    attribute: "Duration",
+                    value: value.to_string(),
+                }),
+                from_zwo(&xml, 200).err()
+            );
+        }
+    }
+
+    #[test]
+    fn from_zwo_of_an_unclosed_workout() {
+        let xml = r#"<workout><SteadyState Duration="60" Power="0.5"/>"#;
+        assert_eq!(
+            Some(ImportError::Malformed("the workout is never closed")),
+            from_zwo(xml, 200).err()
+        );
+    }
+}
@@ -0,0 +1,96 @@
+// Runs our parsers over notifications captured from real sensors, so that
+// packets from sensors that behave oddly can be kept as regression cases.  To
+// add some, drop a file into tests/fixtures/ble/ where each line is the
+// characteristic's UUID (full or 16-bit short form) followed by the bytes of
+// the notification in hex, like:
+//   2a63 2c 00 b4 00 10 27 45 01 d2 04
+// Blank lines and lines starting with # are ignored.
+use btleplug::api::bleuuid::uuid_from_u16;
+use rust_cycle::ble::csc_measurement::{self, try_parse_csc_measurement};
+use rust_cycle::ble::cycling_power_measurement::{self, try_parse_cycling_power_measurement};
+use rust_cycle::ble::heart_rate_measurement::try_parse_hrm;
+use rust_cycle::ble::ParseError;
+use std::{fs, path::PathBuf};
+use uuid::Uuid;
+
+const HRM_MEASURE_UUID: Uuid = uuid_from_u16(0x2A37);
+
+fn fixtures_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join("ble")
+}
+
+fn parse_line(line: &str) -> (Uuid, Vec<u8>) {
+    let mut parts = line.split_whitespace();
+    let uuid_str = parts.next().unwrap();
+    let uuid = if uuid_str.len() == 4 {
+        uuid_from_u16(u16::from_str_radix(uuid_str, 16).unwrap())
+    } else {
+        Uuid::parse_str(uuid_str).unwrap()
+    };
+    let bytes = parts.map(|b| u8::from_str_radix(b, 16).unwrap()).collect();
+    (uuid, bytes)
+}
+
+// Every notification, along with where it came from (for failure messages)
+fn load_fixtures() -> Vec<(String, Uuid, Vec<u8>)> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(fixtures_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    // Keep failures reproducible
+    paths.sort();
+
+    let mut fixtures = Vec::new();
+    for path in paths {
+        let contents = fs::read_to_string(&path).unwrap();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                let (uuid, bytes) = parse_line(line);
+                fixtures.push((format!("{}:{}", path.display(), i + 1), uuid, bytes));
+            }
+        }
+    }
+    fixtures
+}
+
+// A notification that's too short for its flags is an error (and is skipped
+// when riding), but anything that parses must have sane readings.
+fn check_notification(source: &str, uuid: Uuid, bytes: &[u8]) -> Result<(), ParseError> {
+    if uuid == csc_measurement::MEASURE_UUID {
+        let csc_measure = try_parse_csc_measurement(bytes)?;
+        for revolution_data in csc_measure.wheel.iter().chain(csc_measure.crank.iter()) {
+            assert!(
+                revolution_data.last_revolution_event_time < 64.0,
+                "{}",
+                source
+            );
+        }
+    } else if uuid == cycling_power_measurement::MEASURE_UUID {
+        let power_measure = try_parse_cycling_power_measurement(bytes)?;
+        assert!(power_measure.instantaneous_power.abs() < 3000, "{}", source);
+        if let Some(balance) = power_measure.pedal_power_balance_percent {
+            assert!((0.0..=100.0).contains(&balance), "{}", source);
+        }
+    } else if uuid == HRM_MEASURE_UUID {
+        let hr_measure = try_parse_hrm(bytes)?;
+        assert!(hr_measure.bpm > 0 && hr_measure.bpm < 300, "{}", source);
+    } else {
+        panic!("{}: no parser for {}", source, uuid);
+    }
+    Ok(())
+}
+
+#[test]
+fn captured_notifications_parse_sanely() {
+    let fixtures = load_fixtures();
+    assert!(!fixtures.is_empty());
+    for (source, uuid, bytes) in fixtures {
+        // Truncated notifications are expected from flaky connections, they
+        // just can't panic.
+        let _ = check_notification(&source, uuid, &bytes);
+    }
+}